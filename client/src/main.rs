@@ -1,5 +1,5 @@
 use db::*;
-use db::rpc::ServiceClient;
+use db::rpc::{DbRpcError, ServiceClient, SessionId, WireFormat};
 use std::net::{IpAddr, Ipv6Addr};
 use std::ops::{Deref, DerefMut};
 use std::time::Duration;
@@ -7,16 +7,19 @@ use std::time::Duration;
 use druid::widget::{BackgroundBrush, Button, Flex, Label, TextBox};
 use druid::{AppLauncher, Color, Data, Lens, PlatformError, Widget, WidgetExt, WindowDesc};
 use tarpc::tokio_serde::formats::Json;
+use tarpc::tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tarpc::client::RpcError;
 use tarpc::{client, context};
+use tokio::net::TcpStream;
 use tokio::runtime::Handle;
 
 // Wrapper around SavedDatabase
 #[derive(Debug, Clone)]
 struct WrappedDb(Box<SavedDatabase>);
 
-// Wrapper around ServiceClient
+// Wrapper around ServiceClient and the session its calls pass
 #[derive(Debug, Clone)]
-struct WrappedClient(ServiceClient);
+struct WrappedClient(ServiceClient, SessionId);
 
 impl Data for WrappedDb {
     fn same(&self, _other: &Self) -> bool {
@@ -75,15 +78,34 @@ struct AppData {
     counter: usize,
 }
 
+/// Prints why a call failed, whether it didn't reach the server or the server refused it.
+fn report<T>(what: &str, result: Result<Result<T, DbRpcError>, RpcError>) {
+    match result {
+        Ok(Ok(_)) => {}
+        Ok(Err(error)) => eprintln!("Cannot {what}: {error}"),
+        Err(error) => eprintln!("Cannot {what}: {error}"),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), PlatformError> {
     let server_addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), 8080);
-    let mut transport = tarpc::serde_transport::tcp::connect(server_addr, Json::default);
-    transport.config_mut().max_frame_length(usize::MAX);
+    let mut stream = TcpStream::connect(server_addr).await.unwrap();
+    WireFormat::Json.handshake(&mut stream).await.unwrap();
+    let codec = LengthDelimitedCodec::builder().max_frame_length(usize::MAX).new_codec();
+    let transport = tarpc::serde_transport::new(Framed::new(stream, codec), Json::default());
 
     // WorldClient is generated by the service attribute. It has a constructor `new` that takes a
     // config and any Transport as input.
-    let client = ServiceClient::new(client::Config::default(), transport.await.unwrap()).spawn();
+    let client = ServiceClient::new(client::Config::default(), transport).spawn();
+    let mut session = SessionId::NONE;
+    if let Ok(token) = std::env::var("DB_AUTH_TOKEN") {
+        match client.authenticate(context::current(), token).await {
+            Ok(Ok(started)) => session = started,
+            Ok(Err(DbRpcError::Unauthorized)) => eprintln!("Cannot authenticate: the server rejected DB_AUTH_TOKEN"),
+            result => report("authenticate", result),
+        }
+    }
 
     let main_window = WindowDesc::new(ui_builder()).window_size((1500.0f64, 500.0f64));
     let data = AppData {
@@ -98,7 +120,7 @@ async fn main() -> Result<(), PlatformError> {
         table_schema: String::new(),
         row_data: String::new(),
         row_index: String::new(),
-        client: WrappedClient(client),
+        client: WrappedClient(client, session),
         counter: 0,
     };
     AppLauncher::with_window(main_window)
@@ -110,10 +132,14 @@ fn ui_builder() -> impl Widget<AppData> {
     let label = Label::dynamic(|data: &AppData, _| {
         let r = Handle::current();
         let c = data.client.clone();
-        let x = std::thread::spawn(move || r.block_on(c.get_name(context::current())))
+        let x = std::thread::spawn(move || r.block_on(c.status(context::current())))
             .join()
             .unwrap();
-        let res = x.unwrap_or(None).unwrap_or("none".to_string());
+        let res = x
+            .ok()
+            .and_then(Result::ok)
+            .and_then(|status| status.database)
+            .map_or("none".to_string(), |db| if db.dirty { format!("{} (unsaved changes)", db.name) } else { db.name });
         format!("DB name: {}", res)
     }).align_left()
         .background(BackgroundBrush::Color(Color::BLUE));
@@ -123,9 +149,10 @@ fn ui_builder() -> impl Widget<AppData> {
             let r = Handle::current();
             let c = data.client.clone();
             let p = data.path.clone();
-            std::thread::spawn(move || r.block_on(c.open(context::current(), p)))
+            let opened = std::thread::spawn(move || r.block_on(c.open(context::current(), c.1, p, false)))
                 .join()
                 .unwrap();
+            report("open the database", opened);
             data.counter += 1;
         })
         .padding(10.0);
@@ -144,9 +171,10 @@ fn ui_builder() -> impl Widget<AppData> {
             let c = data.client.clone();
             let n = data.db_name.clone();
             let p = data.path_new.clone();
-            std::thread::spawn(move || r.block_on(c.create(context::current(), n, p)))
+            let created = std::thread::spawn(move || r.block_on(c.create(context::current(), c.1, n, p, Format::Bincode, false)))
                 .join()
                 .unwrap();
+            report("create the database", created);
             data.counter += 1;
         })
         .padding(10.0);
@@ -168,10 +196,10 @@ fn ui_builder() -> impl Widget<AppData> {
     let table_list_label: Label<AppData> = Label::dynamic(|data: &AppData, _| {
         let r = Handle::current();
         let c = data.client.clone();
-        let x = std::thread::spawn(move || r.block_on(c.get_table_names(context::current())))
+        let x = std::thread::spawn(move || r.block_on(c.get_table_names(context::current(), c.1)))
             .join()
             .unwrap();
-        let tables = x.unwrap_or(None).unwrap_or_default();
+        let tables = x.ok().and_then(Result::ok).unwrap_or_default();
         format!("Tables: {:?}", tables)
     });
     let tb_open_table = TextBox::new()
@@ -187,10 +215,10 @@ fn ui_builder() -> impl Widget<AppData> {
         let r = Handle::current();
         let c = data.client.clone();
         let n = data.table_name.clone();
-        let x = std::thread::spawn(move || r.block_on(c.get_table_schema(context::current(), n)))
+        let x = std::thread::spawn(move || r.block_on(c.get_table_schema(context::current(), c.1, n)))
             .join()
             .unwrap();
-        let schema = x.unwrap_or(None).unwrap_or_default();
+        let schema = x.ok().and_then(Result::ok).unwrap_or_default();
         format!("{:?}", schema)
     }).align_left();
 
@@ -203,12 +231,12 @@ fn ui_builder() -> impl Widget<AppData> {
         }
 
         let x = std::thread::spawn(move || {
-            r.block_on(c.get_rows(context::current(), n))
+            r.block_on(c.get_rows(context::current(), c.1, n))
         })
         .join()
         .unwrap();
 
-        let rows = x.unwrap_or(None).unwrap_or_default();
+        let rows = x.ok().and_then(Result::ok).unwrap_or_default();
         rows.into_iter()
             .map(|row| format!("{row}"))
             .collect::<Vec<_>>()
@@ -232,8 +260,8 @@ fn ui_builder() -> impl Widget<AppData> {
             let c = data.client.clone();
             let n = data.table_name.clone();
             std::thread::spawn(move || {
-                r.block_on(c.insert_row(context::current(), n, row));
-                r.block_on(c.save(context::current()));
+                report("insert the row", r.block_on(c.insert_row(context::current(), c.1, n, row)));
+                report("save", r.block_on(c.save(context::current(), c.1)));
             })
             .join()
             .unwrap();
@@ -261,8 +289,28 @@ fn ui_builder() -> impl Widget<AppData> {
             let c = data.client.clone();
             let n = data.table_name.clone();
             std::thread::spawn(move || {
-                r.block_on(c.remove_row(context::current(), n, index));
-                r.block_on(c.save(context::current()));
+                report("remove the row", r.block_on(c.remove_row(context::current(), c.1, n, index)));
+                report("save", r.block_on(c.save(context::current(), c.1)));
+            })
+            .join()
+            .unwrap();
+            data.counter += 1;
+        })
+        .padding(10.0);
+    let button_update_row = Button::new("update row")
+        .on_click(|_ctx, data: &mut AppData, _env| {
+            let Ok(index) = data.row_index.parse::<usize>() else {
+                return;
+            };
+            let Ok(row) = serde_json::from_str(&data.row_data) else {
+                return;
+            };
+            let r = Handle::current();
+            let c = data.client.clone();
+            let n = data.table_name.clone();
+            std::thread::spawn(move || {
+                report("update the row", r.block_on(c.update_row(context::current(), c.1, n, index, row)));
+                report("save", r.block_on(c.save(context::current(), c.1)));
             })
             .join()
             .unwrap();
@@ -272,6 +320,7 @@ fn ui_builder() -> impl Widget<AppData> {
     let table_row_remove = Flex::row()
         .with_child(tb_row_idx)
         .with_child(button_remove_row)
+        .with_child(button_update_row)
         .align_left();
 
     let button_create_table = Button::new("create table")
@@ -284,8 +333,8 @@ fn ui_builder() -> impl Widget<AppData> {
             let c = data.client.clone();
             let n = data.table_name_to_create.clone();
             std::thread::spawn(move || {
-                r.block_on(c.create_table(context::current(), n, schema));
-                r.block_on(c.save(context::current()));
+                report("create the table", r.block_on(c.create_table(context::current(), c.1, n, schema)));
+                report("save", r.block_on(c.save(context::current(), c.1)));
             })
             .join()
             .unwrap();
@@ -314,8 +363,8 @@ fn ui_builder() -> impl Widget<AppData> {
             let c = data.client.clone();
             let n = data.table_name_to_remove.clone();
             std::thread::spawn(move || {
-                r.block_on(c.remove_table(context::current(), n));
-                r.block_on(c.save(context::current()));
+                report("remove the table", r.block_on(c.remove_table(context::current(), c.1, n)));
+                report("save", r.block_on(c.save(context::current(), c.1)));
             })
             .join()
             .unwrap();
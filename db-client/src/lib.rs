@@ -0,0 +1,232 @@
+//! Client for the tarpc service of `db-server`, hiding the connection setup and the
+//! `Context` and session every call takes. Each method is the `Service` call of the same
+//! name, with transport failures and the server's errors both turned into `ClientError`.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use db::diff::DatabaseDiff;
+use db::rpc::ServiceClient;
+use db::{ChangeEvent, CheckConstraint, DatabaseSnapshot, DbStats, DbType, DbValue, Format, IntegrityReport, Query, QueryResult, Row, SaveSummary, SearchHit, TableInfo, TableStats};
+use tarpc::client::{self, RpcError};
+use tarpc::context::{self, Context};
+use tarpc::tokio_serde::formats::{Bincode, Json};
+use tarpc::tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{self, Certificate, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+pub use db::rpc::{DbRpcError, DbStatus, RowsPage, ServerStatus, SessionId, TableOperation, WireFormat, PROTOCOL_VERSION};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("Cannot connect: {0}")]
+    Connect(#[from] std::io::Error),
+    /// The call didn't reach the server or its answer didn't arrive in time.
+    #[error("Call failed: {0}")]
+    Rpc(#[from] RpcError),
+    /// The server refused the call.
+    #[error(transparent)]
+    Db(#[from] DbRpcError),
+    #[error("Server speaks protocol version {server}, this client {client}")]
+    ProtocolMismatch { server: u32, client: u32 },
+}
+
+/// Certificates `Client::connect_tls` accepts from the server.
+#[derive(Debug, Clone)]
+pub enum TlsTrust {
+    /// Those signed by one of the PEM certificates in this file, e.g. the server's own
+    /// self-signed one.
+    Ca(PathBuf),
+    /// Any certificate, which keeps the traffic from being read but not the server from
+    /// being impersonated.
+    Insecure,
+}
+
+/// Verifier of `TlsTrust::Insecure`.
+struct AcceptAny;
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _: &Certificate,
+        _: &[Certificate],
+        _: &ServerName,
+        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &[u8],
+        _: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Connection to a `db-server`. Clones share the connection and the session, but not
+/// the table selected by `use_table`, which belongs to the connection.
+#[derive(Clone)]
+pub struct Client {
+    inner: ServiceClient,
+    timeout: Duration,
+    /// Passed to every call needing one, `SessionId::NONE` until `authenticate`.
+    session: Arc<Mutex<SessionId>>,
+}
+
+impl Client {
+    /// Connects to the server at `addr`, which has to use `format` too, failing with
+    /// `ProtocolMismatch` unless it speaks `PROTOCOL_VERSION`.
+    pub async fn connect(addr: impl ToSocketAddrs, format: WireFormat) -> Result<Self, ClientError> {
+        Self::start(TcpStream::connect(addr).await?, format).await
+    }
+
+    /// `connect` to a server started with `--tls-cert`, at `addr` given as `host:port`.
+    /// The certificate has to be valid for `host` unless `trust` is `Insecure`.
+    pub async fn connect_tls(addr: &str, trust: TlsTrust, format: WireFormat) -> Result<Self, ClientError> {
+        let invalid = |error: String| io::Error::new(io::ErrorKind::InvalidInput, error);
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        let server_name = ServerName::try_from(host).map_err(|_| invalid(format!("invalid server name {host:?}")))?;
+        let config = rustls::ClientConfig::builder().with_safe_defaults();
+        let config = match trust {
+            TlsTrust::Ca(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(&path)?))? {
+                    roots.add(&Certificate(cert)).map_err(|error| invalid(format!("{}: {error}", path.display())))?;
+                }
+                config.with_root_certificates(roots).with_no_client_auth()
+            }
+            TlsTrust::Insecure => config.with_custom_certificate_verifier(Arc::new(AcceptAny)).with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+        Self::start(connector.connect(server_name, TcpStream::connect(addr).await?).await?, format).await
+    }
+
+    async fn start(mut stream: impl AsyncRead + AsyncWrite + Send + Unpin + 'static, format: WireFormat) -> Result<Self, ClientError> {
+        format.handshake(&mut stream).await?;
+        // The server limits what it accepts, the client takes any answer.
+        let codec = LengthDelimitedCodec::builder().max_frame_length(usize::MAX).new_codec();
+        let framed = Framed::new(stream, codec);
+        let config = client::Config::default();
+        let inner = match format {
+            WireFormat::Json => ServiceClient::new(config, tarpc::serde_transport::new(framed, Json::default())).spawn(),
+            WireFormat::Bincode => ServiceClient::new(config, tarpc::serde_transport::new(framed, Bincode::default())).spawn(),
+        };
+        let client = Self::new(inner);
+        let server = client.protocol_version().await?;
+        if server != PROTOCOL_VERSION {
+            return Err(ClientError::ProtocolMismatch { server, client: PROTOCOL_VERSION });
+        }
+        Ok(client)
+    }
+
+    /// Wraps a client set up by other means, e.g. over another transport.
+    pub fn new(inner: ServiceClient) -> Self {
+        Self { inner, timeout: Duration::from_secs(10), session: Arc::default() }
+    }
+
+    /// How long calls wait for the server, 10 seconds unless set.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn context(&self) -> Context {
+        let mut context = context::current();
+        context.deadline = SystemTime::now() + self.timeout;
+        context
+    }
+
+    fn session(&self) -> SessionId {
+        *self.session.lock().unwrap()
+    }
+
+    pub async fn protocol_version(&self) -> Result<u32, ClientError> {
+        Ok(self.inner.protocol_version(self.context()).await??)
+    }
+
+    pub async fn status(&self) -> Result<ServerStatus, ClientError> {
+        Ok(self.inner.status(self.context()).await??)
+    }
+
+    /// Starts a session with the server's auth token, which the other calls then pass.
+    /// Call it again once the session expired and calls fail with `Unauthorized`.
+    pub async fn authenticate(&self, token: String) -> Result<(), ClientError> {
+        let session = self.inner.authenticate(self.context(), token).await??;
+        *self.session.lock().unwrap() = session;
+        Ok(())
+    }
+}
+
+macro_rules! calls {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        impl Client {
+            $(
+                pub async fn $name(&self, $($arg: $ty),*) -> Result<$ret, ClientError> {
+                    Ok(self.inner.$name(self.context(), self.session(), $($arg),*).await??)
+                }
+            )*
+        }
+    };
+}
+
+calls! {
+    fn create(name: String, path: String, format: Format, force: bool) -> ();
+    fn open(path: String, force: bool) -> ();
+    fn close(save: bool) -> ();
+    fn get_name() -> String;
+    fn get_table_names() -> Vec<String>;
+    fn table_count() -> usize;
+    fn get_stats() -> DbStats;
+    fn save() -> SaveSummary;
+    fn save_as(path: String, switch: bool, overwrite: bool) -> ();
+    fn reload() -> ();
+    fn remove_table(name: String) -> ();
+    fn create_table(name: String, schema: Vec<DbType>) -> ();
+    fn rename_table(old: String, new: String) -> ();
+    fn copy_table(src: String, dst: String, with_rows: bool) -> ();
+    fn remove_row(table: String, index: usize) -> ();
+    fn insert_row(table: String, row: Row) -> Row;
+    fn update_row(table: String, index: usize, row: Row) -> ();
+    fn update_cell(table: String, row: usize, col: usize, value: DbValue) -> ();
+    fn validate_row(table: String, row: Row) -> bool;
+    fn use_table(name: String) -> ();
+    fn get_rows_current() -> Vec<Row>;
+    fn get_table_schema_current() -> Vec<DbType>;
+    fn insert_row_current(row: Row) -> Row;
+    fn add_check(table: String, constraint: CheckConstraint) -> ();
+    fn get_table_schema(table: String) -> Vec<DbType>;
+    fn get_rows(table: String) -> Vec<Row>;
+    fn get_rows_page(table: String, offset: usize, limit: usize) -> RowsPage;
+    fn get_rows_desc(table: String, offset: usize, limit: usize) -> RowsPage;
+    fn get_rows_multi(tables: Vec<String>) -> HashMap<String, Option<Vec<Row>>>;
+    fn get_row(table: String, index: usize) -> Row;
+    fn get_rows_by_indices(table: String, indices: Vec<usize>) -> Vec<Row>;
+    fn contains_row(table: String, row: Row) -> bool;
+    fn table_projection(table: String, rows: Vec<bool>, new_table: String) -> ();
+    fn project_many(specs: Vec<(String, Vec<bool>, String)>) -> ();
+    fn create_materialized_projection(table: String, rows: Vec<bool>, new_table: String) -> ();
+    fn refresh_materialized(table: String) -> ();
+    fn get_table_info(table: String) -> TableInfo;
+    fn table_stats(table: String) -> TableStats;
+    fn get_catalog() -> Vec<Row>;
+    fn check_integrity() -> IntegrityReport;
+    fn diff_database(path: String) -> DatabaseDiff;
+    fn run_query(query: Query) -> Vec<Row>;
+    fn execute_sql(query: String) -> QueryResult;
+    fn search(value: DbValue, contains: bool) -> Vec<(SearchHit, Row)>;
+    fn poll_changes(since_seq: u64) -> Vec<(u64, ChangeEvent)>;
+    fn create_savepoint() -> u64;
+    fn restore_savepoint(id: u64) -> ();
+    fn backup(dir: Option<String>) -> String;
+    fn list_backups() -> Vec<String>;
+    fn restore_backup(path: String) -> ();
+    fn export_json(path: String, pretty: bool) -> ();
+    fn snapshot() -> DatabaseSnapshot;
+    fn import_json(json_path: String, path: String, force: bool) -> ();
+    fn export_table(name: String, path: String) -> ();
+    fn import_table(path: String, rename: Option<String>) -> String;
+    fn set_table_acl(token: String, table: String, operations: Option<HashSet<TableOperation>>) -> ();
+}
@@ -0,0 +1,38 @@
+use std::collections::{HashMap, HashSet};
+
+use db::rpc::{DbRpcError, TableOperation};
+
+/// Changes allowed per table, set through `set_table_acl`. Tables without an entry allow
+/// every change. Entries are kept by name, so they also apply to a table created later
+/// under a restricted name.
+#[derive(Debug, Default)]
+pub struct TableAcl {
+    tables: HashMap<String, HashSet<TableOperation>>,
+}
+
+impl TableAcl {
+    pub fn set(&mut self, table: String, operations: Option<HashSet<TableOperation>>) {
+        match operations {
+            Some(operations) => self.tables.insert(table, operations),
+            None => self.tables.remove(&table),
+        };
+    }
+
+    pub fn check(&self, table: &str, operation: TableOperation) -> Result<(), DbRpcError> {
+        match self.tables.get(table) {
+            Some(allowed) if !allowed.contains(&operation) => Err(DbRpcError::Forbidden {
+                table: table.to_string(),
+                operation,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `operation` against every entry, in order of table name, for calls that replace
+    /// the whole database and so change whatever tables it has.
+    pub fn check_all(&self, operation: TableOperation) -> Result<(), DbRpcError> {
+        let mut tables: Vec<&String> = self.tables.keys().collect();
+        tables.sort();
+        tables.into_iter().try_for_each(|table| self.check(table, operation))
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::broadcast::Receiver;
+
+use db::ChangeEvent;
+
+/// Events kept for pollers; older ones are dropped first.
+const RETAINED: usize = 1024;
+
+/// Numbers the change events of the open database for `poll_changes`. Events a poller
+/// missed, because they were dropped or the subscription lagged, show up as a gap in
+/// the sequence numbers.
+#[derive(Default)]
+pub struct ChangeLog(Mutex<State>);
+
+#[derive(Default)]
+struct State {
+    receiver: Option<Receiver<ChangeEvent>>,
+    events: VecDeque<(u64, ChangeEvent)>,
+    next_seq: u64,
+}
+
+impl ChangeLog {
+    /// Starts numbering the events of a newly opened database, continuing the sequence.
+    pub fn follow(&self, receiver: Receiver<ChangeEvent>) {
+        let mut state = self.0.lock().unwrap();
+        state.drain();
+        state.receiver = Some(receiver);
+    }
+
+    /// Returns the retained events with a sequence number of at least `since_seq`.
+    pub fn since(&self, since_seq: u64) -> Vec<(u64, ChangeEvent)> {
+        let mut state = self.0.lock().unwrap();
+        state.drain();
+        state
+            .events
+            .iter()
+            .filter(|(seq, _)| *seq >= since_seq)
+            .cloned()
+            .collect()
+    }
+}
+
+impl State {
+    fn drain(&mut self) {
+        let Some(receiver) = self.receiver.as_mut() else {
+            return;
+        };
+        loop {
+            match receiver.try_recv() {
+                Ok(event) => {
+                    self.events.push_back((self.next_seq, event));
+                    self.next_seq += 1;
+                    if self.events.len() > RETAINED {
+                        self.events.pop_front();
+                    }
+                }
+                Err(TryRecvError::Lagged(skipped)) => self.next_seq += skipped,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => {
+                    self.receiver = None;
+                    break;
+                }
+            }
+        }
+    }
+}
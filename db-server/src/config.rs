@@ -0,0 +1,289 @@
+use anyhow::{bail, Context};
+use db::rpc::WireFormat;
+use db::FsyncPolicy;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Database opened before serving, instead of waiting for an `open` or `create` call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Startup {
+    Open(String),
+    Create { name: String, path: String },
+}
+
+/// PEM files of the certificate chain and the private key the tarpc service presents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// Addresses the tarpc service listens on; give both `0.0.0.0:port` and `[::]:port`
+    /// to accept IPv4 and IPv6 clients. Port 0 picks a free port, which is printed on
+    /// startup.
+    pub listen: Vec<SocketAddr>,
+    pub http: SocketAddr,
+    pub startup: Option<Startup>,
+    /// Savepoints kept by `create_savepoint` before the oldest is dropped.
+    pub max_savepoints: usize,
+    /// Most rows a single call may return; larger results fail with `ResultTooLarge`.
+    pub max_result_rows: usize,
+    /// Most rows a single `get_rows_page` call returns, whatever limit it asks for.
+    pub max_page_rows: usize,
+    /// Write-ahead logging for every database the server opens, off by default.
+    pub wal: Option<FsyncPolicy>,
+    /// Backups kept per directory by `backup`, all of them if unset.
+    pub max_backups: Option<usize>,
+    /// Passphrase every database the server opens or creates is encrypted with. Taken
+    /// from the `DB_PASSPHRASE` environment variable rather than a flag, so that it
+    /// doesn't show up in process listings.
+    pub passphrase: Option<String>,
+    /// Token `set_table_acl` requires, from the `DB_ADMIN_TOKEN` environment variable.
+    /// Without one, table ACLs can't be changed.
+    pub admin_token: Option<String>,
+    /// Token clients pass to `authenticate` for the session every other call needs, from
+    /// `--auth-token`, `--auth-token-file` or the `DB_AUTH_TOKEN` environment variable.
+    /// Without one, no call needs a session.
+    pub auth_token: Option<String>,
+    /// How long a session lasts without being used.
+    pub session_ttl: Duration,
+    /// Largest tarpc frame accepted, in bytes, so that a client can't make the server
+    /// buffer an arbitrarily large request.
+    pub max_frame_length: usize,
+    /// tarpc connections a single client IP may have open; further ones are closed.
+    pub max_channels_per_ip: usize,
+    /// tarpc connections served at once; further ones wait for one to close.
+    pub max_connections: usize,
+    /// How long a new connection may take for its TLS and wire format handshakes before
+    /// it is closed, so that stalled clients don't hold on to connection slots.
+    pub handshake_timeout: Duration,
+    /// Seconds between checks saving the open database if it has unsaved changes, never
+    /// if unset.
+    pub autosave_secs: Option<u64>,
+    /// What to log, like `info` or `warn,db_server=debug`, see `logging::init`. Without
+    /// it `RUST_LOG` is used.
+    pub log_level: Option<String>,
+    /// Serve the tarpc service over TLS instead of plain TCP.
+    pub tls: Option<TlsFiles>,
+    /// Serialization of the tarpc messages, which clients have to use as well.
+    pub wire_format: WireFormat,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::new(DEFAULT_HOST, DEFAULT_PORT)],
+            http: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8081),
+            startup: None,
+            max_savepoints: 8,
+            max_result_rows: 100_000,
+            max_page_rows: 10_000,
+            wal: None,
+            max_backups: None,
+            passphrase: None,
+            admin_token: None,
+            auth_token: None,
+            session_ttl: Duration::from_secs(3600),
+            max_frame_length: 16 << 20,
+            max_channels_per_ip: 8,
+            max_connections: 64,
+            handshake_timeout: Duration::from_secs(10),
+            autosave_secs: None,
+            log_level: None,
+            tls: None,
+            wire_format: WireFormat::Json,
+        }
+    }
+}
+
+const DEFAULT_HOST: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+const DEFAULT_PORT: u16 = 8080;
+
+/// A flag of `from_args`, with the placeholders of the values following it.
+struct Flag {
+    name: &'static str,
+    values: &'static [&'static str],
+    help: &'static str,
+}
+
+const fn flag(name: &'static str, values: &'static [&'static str], help: &'static str) -> Flag {
+    Flag { name, values, help }
+}
+
+/// Every flag `from_args` reads, in the order `usage` lists them.
+const FLAGS: &[Flag] = &[
+    flag("--listen", &["<addr>"], "address of the tarpc service, repeatable; port 0 picks a free one"),
+    flag("--host", &["<ip>"], "shorthand for a single --listen, with --port"),
+    flag("--port", &["<n>"], "shorthand for a single --listen, with --host"),
+    flag("--http", &["<addr>"], "address of the HTTP gateway"),
+    flag("--db", &["<path>"], "database to open on startup"),
+    flag("--create", &["<name>", "<path>"], "database to create on startup"),
+    flag("--max-savepoints", &["<n>"], "savepoints kept before the oldest is dropped"),
+    flag("--max-result-rows", &["<n>"], "most rows a single call may return"),
+    flag("--max-page-rows", &["<n>"], "most rows a single page holds"),
+    flag("--max-backups", &["<n>"], "backups kept per directory"),
+    flag("--max-frame-length", &["<bytes>"], "largest tarpc frame accepted"),
+    flag("--max-channels-per-ip", &["<n>"], "tarpc connections a single client IP may have open"),
+    flag("--max-connections", &["<n>"], "tarpc connections served at once"),
+    flag("--handshake-timeout-secs", &["<n>"], "time a new connection has for its handshakes"),
+    flag("--autosave-secs", &["<n>"], "interval of saving unsaved changes"),
+    flag("--log-level", &["<filter>"], "what to log, like info or warn,db_server=debug"),
+    flag("--auth-token", &["<secret>"], "token clients authenticate with"),
+    flag("--auth-token-file", &["<path>"], "file holding the auth token"),
+    flag("--session-ttl-secs", &["<n>"], "how long an unused session lasts"),
+    flag("--tls-cert", &["<path>"], "PEM certificate chain of the tarpc service, with --tls-key"),
+    flag("--tls-key", &["<path>"], "PEM private key of the tarpc service, with --tls-cert"),
+    flag("--wire-format", &["<json|bincode>"], "serialization of the tarpc messages"),
+    flag("--wal", &["<always|never>"], "write-ahead logging with this fsync policy"),
+];
+
+/// What `db-server --help` prints, listing `FLAGS` and the environment `from_env` reads.
+pub fn usage() -> String {
+    let with_values = |flag: &Flag| std::iter::once(flag.name).chain(flag.values.iter().copied()).collect::<Vec<_>>().join(" ");
+    let width = FLAGS.iter().map(|flag| with_values(flag).len()).max().unwrap_or(0);
+    let mut usage = "usage: db-server [flags]\n       db-server migrate <path>\n\nflags:\n".to_string();
+    for flag in FLAGS {
+        usage += &format!("  {:width$}  {}\n", with_values(flag), flag.help);
+    }
+    usage += &format!("  {:width$}  {}\n", "--help", "print this and exit");
+    usage += "\nenvironment:\n";
+    for (name, help) in [
+        ("DB_SERVER_ADDR", "address to listen on without --listen, --host and --port"),
+        ("DB_AUTH_TOKEN", "auth token without --auth-token and --auth-token-file"),
+        ("DB_ADMIN_TOKEN", "token changing table ACLs needs"),
+        ("DB_PASSPHRASE", "passphrase every database is encrypted with"),
+    ] {
+        usage += &format!("  {name:width$}  {help}\n");
+    }
+    usage
+}
+
+impl ServerConfig {
+    /// Reads the flags listed by `usage`, keeping the defaults for whatever is not given.
+    #[cfg(test)]
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        Self::from_args_or_addr(args, None)
+    }
+
+    /// `from_args`, listening on `fallback` if none of `--listen`, `--host` and `--port`
+    /// is given.
+    pub fn from_args_or_addr(args: impl IntoIterator<Item = String>, fallback: Option<&str>) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        let mut listen = Vec::new();
+        let (mut host, mut port) = (None, None);
+        let (mut tls_cert, mut tls_key) = (None, None);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = FLAGS.iter().find(|flag| flag.name == arg).with_context(|| format!("unknown argument {arg}, see --help"))?;
+            let value = args.next().with_context(|| format!("{arg} needs {}", flag.values.join(" ")))?;
+            match arg.as_str() {
+                "--listen" => listen.push(parse_addr(&value)?),
+                "--host" => host = Some(parse_host(&value)?),
+                "--port" => port = Some(value.parse().with_context(|| format!("invalid port {value:?}"))?),
+                "--http" => config.http = parse_addr(&value)?,
+                "--db" | "--create" if config.startup.is_some() => bail!("only one of --db and --create can be given"),
+                "--db" => config.startup = Some(Startup::Open(value)),
+                "--create" => {
+                    let path = args.next().context("--create needs <name> <path>")?;
+                    config.startup = Some(Startup::Create { name: value, path });
+                }
+                "--max-savepoints" => {
+                    config.max_savepoints = value.parse().with_context(|| format!("invalid count {value:?}"))?
+                }
+                "--max-result-rows" => {
+                    config.max_result_rows = value.parse().with_context(|| format!("invalid count {value:?}"))?
+                }
+                "--max-page-rows" => {
+                    config.max_page_rows = value.parse().with_context(|| format!("invalid count {value:?}"))?
+                }
+                "--max-backups" => {
+                    config.max_backups = Some(value.parse().with_context(|| format!("invalid count {value:?}"))?)
+                }
+                "--max-frame-length" => {
+                    config.max_frame_length = value.parse().with_context(|| format!("invalid length {value:?}"))?
+                }
+                "--max-channels-per-ip" => match value.parse() {
+                    Ok(0) | Err(_) => bail!("invalid count {value:?}, expected a positive number of connections"),
+                    Ok(count) => config.max_channels_per_ip = count,
+                },
+                "--max-connections" => match value.parse() {
+                    Ok(0) | Err(_) => bail!("invalid count {value:?}, expected a positive number of connections"),
+                    Ok(count) => config.max_connections = count,
+                },
+                "--autosave-secs" => match value.parse() {
+                    Ok(0) | Err(_) => bail!("invalid interval {value:?}, expected a positive number of seconds"),
+                    Ok(secs) => config.autosave_secs = Some(secs),
+                },
+                "--log-level" => {
+                    crate::logging::parse_filter(&value)?;
+                    config.log_level = Some(value);
+                }
+                "--auth-token" => config.auth_token = Some(value),
+                "--auth-token-file" => {
+                    let token = std::fs::read_to_string(&value).with_context(|| format!("cannot read {value}"))?;
+                    config.auth_token = Some(token.trim_end().to_string());
+                }
+                "--handshake-timeout-secs" => match value.parse() {
+                    Ok(0) | Err(_) => bail!("invalid timeout {value:?}, expected a positive number of seconds"),
+                    Ok(secs) => config.handshake_timeout = Duration::from_secs(secs),
+                },
+                "--session-ttl-secs" => match value.parse() {
+                    Ok(0) | Err(_) => bail!("invalid lifetime {value:?}, expected a positive number of seconds"),
+                    Ok(secs) => config.session_ttl = Duration::from_secs(secs),
+                },
+                "--tls-cert" => tls_cert = Some(PathBuf::from(value)),
+                "--tls-key" => tls_key = Some(PathBuf::from(value)),
+                "--wire-format" => config.wire_format = value.parse().map_err(anyhow::Error::msg)?,
+                "--wal" => config.wal = Some(value.parse().map_err(anyhow::Error::msg)?),
+                _ => unreachable!("{arg} is listed in FLAGS but not read"),
+            }
+        }
+        config.tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+            (None, None) => None,
+            _ => bail!("--tls-cert and --tls-key must be given together"),
+        };
+        if host.is_some() || port.is_some() {
+            if !listen.is_empty() {
+                bail!("--listen can't be combined with --host or --port");
+            }
+            listen.push(SocketAddr::new(host.unwrap_or(DEFAULT_HOST), port.unwrap_or(DEFAULT_PORT)));
+        }
+        if listen.is_empty() {
+            if let Some(addr) = fallback {
+                listen.push(parse_addr(addr).context("invalid DB_SERVER_ADDR")?);
+            }
+        }
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        Ok(config)
+    }
+
+    /// `from_args` plus the settings read from the environment: `DB_SERVER_ADDR` is the
+    /// address to listen on and `DB_AUTH_TOKEN` the auth token, unless given as flags.
+    pub fn from_env(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let addr = std::env::var("DB_SERVER_ADDR").ok();
+        let mut config = Self::from_args_or_addr(args, addr.as_deref())?;
+        config.passphrase = std::env::var("DB_PASSPHRASE").ok();
+        config.admin_token = std::env::var("DB_ADMIN_TOKEN").ok();
+        if config.auth_token.is_none() {
+            config.auth_token = std::env::var("DB_AUTH_TOKEN").ok();
+        }
+        Ok(config)
+    }
+}
+
+/// Accepts an IPv4 or IPv6 address, the latter optionally in brackets.
+fn parse_host(s: &str) -> anyhow::Result<IpAddr> {
+    let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    ip.parse().with_context(|| format!("invalid host {s:?}, expected an IP address"))
+}
+
+/// Accepts `ip:port` for IPv4 and `[ip]:port` for IPv6.
+pub fn parse_addr(s: &str) -> anyhow::Result<SocketAddr> {
+    s.parse().with_context(|| format!("invalid address {s:?}, expected ip:port or [ipv6]:port"))
+}
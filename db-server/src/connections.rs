@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Channels open per client IP, each counted until its `ChannelSlot` is dropped.
+#[derive(Clone)]
+pub struct ChannelCounts {
+    max: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// An open channel of `ip`, counted by `ChannelCounts` as long as it lives.
+pub struct ChannelSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ChannelCounts {
+    pub fn new(max: usize) -> Self {
+        Self { max, open: Arc::default() }
+    }
+
+    /// Counts a new channel of `ip`, or `None` if it already has `max` open.
+    pub fn acquire(&self, ip: IpAddr) -> Option<ChannelSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ChannelSlot { ip, open: self.open.clone() })
+    }
+}
+
+impl Drop for ChannelSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
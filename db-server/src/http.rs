@@ -0,0 +1,95 @@
+use actix_web::web::{self, Data, ServiceConfig};
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use tarpc::context;
+
+use db::rpc::{DbRpcError, Service, SessionId};
+use db::Row;
+
+use crate::Server;
+
+/// Header carrying the session `POST /sessions` started, as a decimal number. Requests
+/// without it use `SessionId::NONE`, which only a server without an auth token takes.
+const SESSION_HEADER: &str = "x-session";
+
+/// Registers the HTTP routes, which go through the calls of `server` and so through the
+/// same session, ACL and result size checks as the tarpc service.
+pub fn configure(server: Server) -> impl FnOnce(&mut ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(Data::new(server))
+            .route("/sessions", web::post().to(authenticate))
+            .route("/tables", web::get().to(get_tables))
+            .route("/tables/{name}/rows", web::get().to(get_rows))
+            .route("/tables/{name}/rows", web::post().to(insert_row));
+    }
+}
+
+fn session(request: &HttpRequest) -> Result<SessionId, HttpResponse> {
+    match request.headers().get(SESSION_HEADER) {
+        None => Ok(SessionId::NONE),
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(SessionId)
+            .ok_or_else(|| HttpResponse::BadRequest().body(format!("invalid {SESSION_HEADER} header"))),
+    }
+}
+
+fn error_response(error: DbRpcError) -> HttpResponse {
+    let mut response = match error {
+        DbRpcError::Unauthorized => HttpResponse::Unauthorized(),
+        DbRpcError::Forbidden { .. } => HttpResponse::Forbidden(),
+        DbRpcError::NoDatabaseOpen => HttpResponse::Conflict(),
+        DbRpcError::ResultTooLarge { .. } => HttpResponse::PayloadTooLarge(),
+        _ => HttpResponse::BadRequest(),
+    };
+    response.body(error.to_string())
+}
+
+/// Reads answer `null` when there is no database or table to read from.
+fn read_response<T: serde::Serialize>(result: Result<T, DbRpcError>) -> HttpResponse {
+    match result {
+        Ok(value) => HttpResponse::Ok().json(Some(value)),
+        Err(DbRpcError::NoDatabaseOpen | DbRpcError::TableIsMissing(_)) => HttpResponse::Ok().json(None::<()>),
+        Err(error) => error_response(error),
+    }
+}
+
+/// Takes the auth token as the body and answers with the id for the session header.
+async fn authenticate(server: Data<Server>, token: String) -> impl Responder {
+    match server.get_ref().clone().authenticate(context::current(), token).await {
+        Ok(session) => HttpResponse::Ok().body(session.0.to_string()),
+        Err(error) => error_response(error),
+    }
+}
+
+async fn get_tables(server: Data<Server>, request: HttpRequest) -> impl Responder {
+    let session = match session(&request) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    let names = server.get_ref().clone().get_table_names(context::current(), session).await;
+    read_response(names.map(|mut names| {
+        names.sort();
+        names
+    }))
+}
+
+async fn get_rows(server: Data<Server>, request: HttpRequest, name: web::Path<String>) -> impl Responder {
+    let session = match session(&request) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    read_response(server.get_ref().clone().get_rows(context::current(), session, name.into_inner()).await)
+}
+
+async fn insert_row(server: Data<Server>, request: HttpRequest, name: web::Path<String>, row: web::Json<Row>) -> impl Responder {
+    let session = match session(&request) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    match server.get_ref().clone().insert_row(context::current(), session, name.into_inner(), row.into_inner()).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(error) => error_response(error),
+    }
+}
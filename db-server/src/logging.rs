@@ -0,0 +1,106 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use db::rpc::{DbRpcError, ServeService, Service, ServiceRequest, ServiceResponse};
+use tarpc::context::Context;
+use tarpc::server::Serve;
+use tracing::{error, info_span, warn, Instrument};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+use crate::{Server, Shared};
+
+/// Filter used unless `--log-level` or `RUST_LOG` give one. tarpc logs the start and end
+/// of every request at info level, which only the span of `Logged` is needed for.
+const DEFAULT_FILTER: &str = "info,tarpc=warn";
+
+/// Logs to stderr what `filter` lets through, a comma-separated list of levels for
+/// targets like `info,db_server=debug`, falling back to `RUST_LOG` and then to info.
+pub fn init(filter: Option<&str>) -> anyhow::Result<()> {
+    let filter = match filter {
+        Some(filter) => filter.to_string(),
+        None => std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_string()),
+    };
+    let targets = parse_filter(&filter)?;
+    let layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    tracing_subscriber::registry().with(layer.with_filter(targets)).init();
+    Ok(())
+}
+
+pub fn parse_filter(filter: &str) -> anyhow::Result<Targets> {
+    filter.parse().map_err(|error| anyhow::anyhow!("invalid log filter {filter:?}: {error}"))
+}
+
+/// Serves the calls of one connection inside a span naming the method, the peer and the
+/// request's number since the server started, and logs every error returned.
+#[derive(Clone)]
+pub struct Logged {
+    serve: ServeService<Server>,
+    peer: String,
+    shared: Arc<Shared>,
+}
+
+impl Logged {
+    pub fn new(server: Server, peer: Option<SocketAddr>) -> Self {
+        let shared = server.shared.clone();
+        let peer = peer.map_or_else(|| "local".to_string(), |peer| peer.to_string());
+        Self { serve: server.serve(), peer, shared }
+    }
+}
+
+impl Serve<ServiceRequest> for Logged {
+    type Resp = ServiceResponse;
+    type Fut = Pin<Box<dyn Future<Output = ServiceResponse> + Send>>;
+
+    fn method(&self, request: &ServiceRequest) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: ServiceRequest) -> Self::Fut {
+        let method = self.serve.method(&request).unwrap_or_default();
+        let number = self.shared.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        let span = info_span!("rpc", method, peer = %self.peer, request = number);
+        let response = self.serve.serve(ctx, request);
+        Box::pin(
+            async move {
+                let response = response.await;
+                match response_error(&response) {
+                    // Errors of the database that aren't one of the client's mistakes.
+                    Some(error @ DbRpcError::Db(_)) => error!(%error, "call failed"),
+                    Some(error) => warn!(%error, "call failed"),
+                    None => {}
+                }
+                response
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Lists every call, so that a new one fails to compile until it is added.
+macro_rules! response_error {
+    ($response:expr; $($call:ident),* $(,)?) => {
+        match $response {
+            $(ServiceResponse::$call(result) => result.as_ref().err(),)*
+        }
+    };
+}
+
+/// The error a call returned, if it failed.
+fn response_error(response: &ServiceResponse) -> Option<&DbRpcError> {
+    response_error!(response;
+        ProtocolVersion, Status, Authenticate, Create, Open, Close, GetName, GetTableNames,
+        TableCount, GetStats, Save, SaveAs, Reload, RemoveTable, CreateTable, RenameTable,
+        CopyTable, RemoveRow, InsertRow, UpdateRow, UpdateCell, ValidateRow, UseTable,
+        GetRowsCurrent, GetTableSchemaCurrent, InsertRowCurrent, AddCheck, GetTableSchema,
+        GetRows, GetRowsPage, GetRowsDesc, GetRowsMulti, GetRow, GetRowsByIndices, ContainsRow,
+        TableProjection, ProjectMany, CreateMaterializedProjection, RefreshMaterialized,
+        GetTableInfo, TableStats, GetCatalog, CheckIntegrity, DiffDatabase, RunQuery,
+        ExecuteSql, Search, PollChanges, CreateSavepoint, RestoreSavepoint, Backup,
+        ListBackups, RestoreBackup, ExportJson, Snapshot, ImportJson, ExportTable,
+        ImportTable, SetTableAcl,
+    )
+}
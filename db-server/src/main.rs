@@ -1,165 +1,876 @@
-use std::io;
-use futures::{future, prelude::*};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::sync::Arc;
+use actix_web::{App, HttpServer};
+use anyhow::Context as _;
+use futures::{future, prelude::*, stream};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
 use tarpc::{
-    server::{self, incoming::Incoming, Channel},
-    tokio_serde::formats::Json,
+    server::{self, Channel},
+    tokio_serde::formats::{Bincode, Json},
+    tokio_util::codec::{Framed, LengthDelimitedCodec},
 };
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tarpc::context::Context;
-use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
-use db::{DbType, Row, SavedDatabase};
+use db::diff::DatabaseDiff;
+use db::rpc::{DbRpcError, DbStatus, RowsPage, ServerStatus, Service, SessionId, TableOperation, WireFormat, PROTOCOL_VERSION};
+use db::{sql_operation, ChangeEvent, CheckConstraint, DatabaseSnapshot, DbError, DbStats, DbType, DbValue, Format, FsyncPolicy, IntegrityReport, Query, QueryResult, Row, SaveSummary, SavedDatabase, SearchHit, SharedDatabase, TableInfo, TableStats};
+
+mod acl;
+mod changes;
+mod config;
+mod connections;
+mod http;
+mod logging;
+mod savepoints;
+mod sessions;
+mod tls;
+#[cfg(test)]
+mod tests;
+
+use acl::TableAcl;
+use changes::ChangeLog;
+use config::{ServerConfig, Startup};
+use connections::ChannelCounts;
+use logging::Logged;
+use savepoints::Savepoints;
+use sessions::Sessions;
+
+/// The open database, if any. The mutex only guards swapping it; the database itself
+/// is read concurrently through its `SharedDatabase` handle.
+pub type DbSlot = Arc<Mutex<Option<SharedDatabase>>>;
+
+/// State shared by every connection.
+struct Shared {
+    changes: ChangeLog,
+    savepoints: Mutex<Savepoints>,
+    max_result_rows: usize,
+    max_page_rows: usize,
+    wal: Option<FsyncPolicy>,
+    max_backups: Option<usize>,
+    passphrase: Option<String>,
+    admin_token: Option<String>,
+    auth_token: Option<String>,
+    sessions: Sessions,
+    acl: Mutex<TableAcl>,
+    started: Instant,
+    last_autosave: Mutex<Option<DateTime<Utc>>>,
+    /// Calls received so far, numbering them in the logs.
+    requests: AtomicU64,
+}
+
+impl Shared {
+    fn new(config: &ServerConfig) -> Self {
+        Self {
+            changes: ChangeLog::default(),
+            savepoints: Mutex::new(Savepoints::new(config.max_savepoints)),
+            max_result_rows: config.max_result_rows,
+            max_page_rows: config.max_page_rows,
+            wal: config.wal,
+            max_backups: config.max_backups,
+            passphrase: config.passphrase.clone(),
+            admin_token: config.admin_token.clone(),
+            auth_token: config.auth_token.clone(),
+            sessions: Sessions::new(config.session_ttl),
+            acl: Mutex::default(),
+            started: Instant::now(),
+            last_autosave: Mutex::default(),
+            requests: AtomicU64::new(0),
+        }
+    }
+}
 
 #[derive(Clone)]
-struct Server(pub Arc<Mutex<Option<SavedDatabase>>>);
-
-#[tarpc::service]
-pub trait Service {
-    async fn create(name: String, path: String);
-    async fn open(path: String);
-    async fn get_name() -> Option<String>;
-    async fn get_table_names() -> Option<Vec<String>>;
-    async fn save();
-    async fn remove_table(name: String);
-    async fn create_table(name: String, schema: Vec<DbType>);
-    async fn remove_row(table: String, index: usize);
-    async fn insert_row(table: String, row: Row);
-    async fn get_table_schema(table: String) -> Option<Vec<DbType>>;
-    async fn get_rows(table: String) -> Option<Vec<Row>>;
-    async fn table_projection(table: String, rows: Vec<bool>, new_table: String);
+struct Server {
+    db: DbSlot,
+    shared: Arc<Shared>,
+    /// Table used by the `*_current` calls, separate for every connection.
+    current_table: Arc<Mutex<Option<String>>>,
 }
 
-#[tarpc::server]
-impl Service for Server {
-    async fn create(self, _: tarpc::context::Context, name: String, path: String) {
-        let mut lock = self.0.lock().await;
-        let new_db = SavedDatabase::create(name, path).unwrap();
-        lock.replace(new_db);
+impl Server {
+    fn new(db: DbSlot, shared: Arc<Shared>) -> Self {
+        Self {
+            db,
+            shared,
+            current_table: Arc::default(),
+        }
     }
 
-    async fn open(self, _: tarpc::context::Context, path: String) {
-        let mut lock = self.0.lock().await;
-        let new_db = SavedDatabase::load_from_disk(path).unwrap();
-        lock.replace(new_db);
+    fn current_table(&self) -> Option<String> {
+        self.current_table.lock().unwrap().clone()
     }
 
-    async fn get_name(self, _: tarpc::context::Context) -> Option<String> {
-        let lock = self.0.lock().await;
-        lock.as_ref().map(|db| db.get_name().to_string())
+    /// Fails unless the open database, if any, may be replaced.
+    fn check_replaceable(open: Option<&SharedDatabase>, force: bool) -> Result<(), DbRpcError> {
+        if !force && open.is_some_and(|db| db.read(SavedDatabase::is_dirty)) {
+            return Err(DbRpcError::UnsavedChanges);
+        }
+        Ok(())
     }
 
-    async fn get_table_names(self, _: tarpc::context::Context) -> Option<Vec<String>> {
-        let lock = self.0.lock().await;
-        lock.as_ref().map(|db| db.get_table_names())
+    /// Whether `path` is the file of the open database, which holds its lock.
+    fn is_open_path(&self, path: &Path) -> bool {
+        let canonical = |path: &Path| std::fs::canonicalize(path).ok();
+        let open = self.read(|db| canonical(db.path())).flatten();
+        open.is_some() && canonical(path) == open
     }
 
-    async fn save(self, _: tarpc::context::Context) {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            db.save().unwrap();
+    /// Swaps the database `load` returns in for the open one, which needs `force` if it
+    /// has unsaved changes. The open database is kept if `load` fails, unless `path` is
+    /// its file: that one is closed first, releasing the lock `load` takes.
+    fn load_replacing(&self, path: &Path, force: bool, load: impl FnOnce() -> Result<SavedDatabase, DbError>) -> Result<(), DbRpcError> {
+        if self.is_open_path(path) {
+            let mut slot = self.db.lock().unwrap();
+            Self::check_replaceable(slot.as_ref(), force)?;
+            slot.take();
+            self.install(&mut slot, load()?);
+            return Ok(());
         }
+        let new_db = load()?;
+        let mut slot = self.db.lock().unwrap();
+        Self::check_replaceable(slot.as_ref(), force)?;
+        self.install(&mut slot, new_db);
+        Ok(())
     }
 
-    async fn remove_table(self, _: tarpc::context::Context, name: String) {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            db.remove_table(name).unwrap();
+    fn install(&self, slot: &mut Option<SharedDatabase>, mut db: SavedDatabase) {
+        if let Some(sync) = self.shared.wal {
+            db.enable_wal(sync);
         }
+        db.set_max_backups(self.shared.max_backups);
+        self.shared.changes.follow(db.subscribe());
+        // Savepoints belong to the database they were taken of.
+        self.shared.savepoints.lock().unwrap().clear();
+        *slot = Some(SharedDatabase::new(db));
     }
 
-    async fn create_table(self, _: tarpc::context::Context, name: String, schema: Vec<DbType>) {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            db.create_table(name, schema).unwrap();
+    /// Runs `f` with a handle to this server off the async workers, for calls blocking on
+    /// file IO, the way `autosave` saves. It stays in the span of the call.
+    async fn blocking<R: Send + 'static>(&self, f: impl FnOnce(Server) -> Result<R, DbRpcError> + Send + 'static) -> Result<R, DbRpcError> {
+        let (server, span) = (self.clone(), tracing::Span::current());
+        tokio::task::spawn_blocking(move || span.in_scope(|| f(server)))
+            .await
+            .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+    }
+
+    fn read<R>(&self, f: impl FnOnce(&SavedDatabase) -> R) -> Option<R> {
+        let db = self.db.lock().unwrap().clone();
+        db.map(|db| db.read(f))
+    }
+
+    fn write<R>(&self, f: impl FnOnce(&mut SavedDatabase) -> R) -> Option<R> {
+        let db = self.db.lock().unwrap().clone();
+        db.map(|db| db.write(f))
+    }
+
+    fn try_read<R>(&self, f: impl FnOnce(&SavedDatabase) -> Result<R, DbRpcError>) -> Result<R, DbRpcError> {
+        self.read(f).unwrap_or(Err(DbRpcError::NoDatabaseOpen))
+    }
+
+    fn try_write<R>(&self, f: impl FnOnce(&mut SavedDatabase) -> Result<R, DbRpcError>) -> Result<R, DbRpcError> {
+        self.write(f).unwrap_or(Err(DbRpcError::NoDatabaseOpen))
+    }
+
+    /// Calls need a live session from `authenticate` if the server has an auth token.
+    fn check_session(&self, session: SessionId) -> Result<(), DbRpcError> {
+        if self.shared.auth_token.is_some() && !self.shared.sessions.touch(session) {
+            return Err(DbRpcError::Unauthorized);
         }
+        Ok(())
     }
 
-    async fn remove_row(self, _: tarpc::context::Context, table: String, index: usize) {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            if let Ok(table) = db.get_table_mut(table) {
-                table.remove_row(index);
-            }
+    fn check_acl(&self, table: &str, operation: TableOperation) -> Result<(), DbRpcError> {
+        self.shared.acl.lock().unwrap().check(table, operation)
+    }
+
+    /// Replacing the whole database needs Alter on every table with an ACL entry.
+    fn check_replace_acl(&self) -> Result<(), DbRpcError> {
+        self.shared.acl.lock().unwrap().check_all(TableOperation::Alter)
+    }
+
+    fn check_result_size(&self, rows: usize) -> Result<(), DbRpcError> {
+        let limit = self.shared.max_result_rows;
+        if rows > limit {
+            return Err(DbRpcError::ResultTooLarge { limit });
         }
+        Ok(())
     }
+}
 
-    async fn insert_row(self, _: tarpc::context::Context, table: String, row: Row) {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            if let Ok(table) = db.get_table_mut(table) {
-                let _ = table.insert_row(row);
-            }
+#[tarpc::server]
+impl Service for Server {
+    async fn protocol_version(self, _: Context) -> Result<u32, DbRpcError> {
+        Ok(PROTOCOL_VERSION)
+    }
+
+    async fn status(self, _: Context) -> Result<ServerStatus, DbRpcError> {
+        let database = self.read(|db| {
+            let stats = db.stats()?;
+            Ok::<_, DbError>(DbStatus {
+                name: db.get_name().to_string(),
+                path: db.path().to_string_lossy().into_owned(),
+                table_count: stats.table_count,
+                total_rows: stats.row_count,
+                dirty: stats.dirty,
+            })
+        });
+        Ok(ServerStatus {
+            database: database.transpose()?,
+            uptime_secs: self.shared.started.elapsed().as_secs(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            last_autosave: *self.shared.last_autosave.lock().unwrap(),
+        })
+    }
+
+    async fn authenticate(self, _: Context, token: String) -> Result<SessionId, DbRpcError> {
+        if self.shared.auth_token.as_ref().is_some_and(|expected| *expected != token) {
+            return Err(DbRpcError::Unauthorized);
         }
+        Ok(self.shared.sessions.start())
     }
 
-    async fn get_table_schema(
-        self,
-        _: tarpc::context::Context,
-        table: String,
-    ) -> Option<Vec<DbType>> {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            if let Ok(table) = db.get_table(table) {
-                return Some(table.schema().to_vec());
+    async fn create(self, _: Context, session: SessionId, name: String, path: String, format: Format, force: bool) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        info!(name, path, ?format, force, "creating database");
+        self.check_replace_acl()?;
+        self.blocking(move |server| {
+            server.load_replacing(Path::new(&path), force, || match &server.shared.passphrase {
+                Some(passphrase) => SavedDatabase::create_encrypted(name, &path, passphrase)
+                    .and_then(|mut db| db.save_in(format).map(|_| db)),
+                None => SavedDatabase::create_with(name, &path, format),
+            })
+        })
+        .await
+    }
+
+    async fn open(self, _: Context, session: SessionId, path: String, force: bool) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        info!(path, force, "opening database");
+        self.check_replace_acl()?;
+        self.blocking(move |server| {
+            server.load_replacing(Path::new(&path), force, || match &server.shared.passphrase {
+                Some(passphrase) => SavedDatabase::load_from_disk_encrypted(&path, passphrase),
+                None => SavedDatabase::load_from_disk(&path),
+            })
+        })
+        .await
+    }
+
+    async fn close(self, _: Context, session: SessionId, save: bool) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        // Saving under the slot's lock, so that no call picks up the database between
+        // the save and closing it. Its file lock is released once running calls are done
+        // with it.
+        self.blocking(move |server| {
+            let mut slot = server.db.lock().unwrap();
+            let db = slot.as_ref().ok_or(DbRpcError::NoDatabaseOpen)?;
+            if save {
+                db.write(SavedDatabase::save)?;
             }
+            slot.take();
+            server.shared.savepoints.lock().unwrap().clear();
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_name(self, _: Context, session: SessionId) -> Result<String, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.get_name().to_string()))
+    }
+
+    async fn get_table_names(self, _: Context, session: SessionId) -> Result<Vec<String>, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.get_table_names()))
+    }
+
+    async fn table_count(self, _: Context, session: SessionId) -> Result<usize, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.table_count()))
+    }
+
+    async fn get_stats(self, _: Context, session: SessionId) -> Result<DbStats, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.stats()?))
+    }
+
+    async fn save(self, _: Context, session: SessionId) -> Result<SaveSummary, DbRpcError> {
+        self.check_session(session)?;
+        info!("saving database");
+        self.blocking(|server| server.try_write(|db| Ok(db.save()?))).await
+    }
+
+    async fn save_as(self, _: Context, session: SessionId, path: String, switch: bool, overwrite: bool) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.blocking(move |server| server.try_write(|db| Ok(db.save_as(path, switch, overwrite)?))).await
+    }
+
+    async fn reload(self, _: Context, session: SessionId) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_replace_acl()?;
+        self.blocking(|server| server.try_write(|db| Ok(db.reload()?))).await
+    }
+
+    async fn remove_table(self, _: Context, session: SessionId, name: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        info!(name, "removing table");
+        self.check_acl(&name, TableOperation::Alter)?;
+        self.try_write(|db| Ok(db.remove_table(name)?))
+    }
+
+    async fn create_table(self, _: Context, session: SessionId, name: String, schema: Vec<DbType>) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&name, TableOperation::Alter)?;
+        self.try_write(|db| Ok(db.create_table(name, schema)?))
+    }
+
+    async fn rename_table(self, _: Context, session: SessionId, old: String, new: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&old, TableOperation::Alter)?;
+        self.check_acl(&new, TableOperation::Alter)?;
+        self.try_write(|db| Ok(db.rename_table(old, new)?))
+    }
+
+    async fn copy_table(self, _: Context, session: SessionId, src: String, dst: String, with_rows: bool) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&dst, TableOperation::Alter)?;
+        self.try_write(|db| Ok(db.copy_table(src, dst, with_rows)?))
+    }
+
+    async fn remove_row(self, _: Context, session: SessionId, table: String, index: usize) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&table, TableOperation::Delete)?;
+        self.try_write(|db| Ok(db.remove_row(table, index)?))
+    }
+
+    async fn insert_row(self, _: Context, session: SessionId, table: String, row: Row) -> Result<Row, DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&table, TableOperation::Insert)?;
+        self.try_write(|db| {
+            db.insert_row(table.clone(), row)?;
+            Ok(db.get_table(table)?.rows().last().expect("row was just inserted").clone())
+        })
+    }
+
+    async fn update_row(self, _: Context, session: SessionId, table: String, index: usize, row: Row) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&table, TableOperation::Update)?;
+        self.try_write(|db| Ok(db.update_row(table, index, row).map(drop)?))
+    }
+
+    /// Reads and replaces the row under a single lock, so concurrent changes to its
+    /// other columns aren't lost.
+    async fn update_cell(self, _: Context, session: SessionId, table: String, row: usize, col: usize, value: DbValue) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&table, TableOperation::Update)?;
+        self.try_write(|db| {
+            let mut updated = db.get_table(table.clone())?.row_at(row).cloned().ok_or(DbRpcError::RowIndexOutOfRange(row))?;
+            *updated.0.get_mut(col).ok_or(DbRpcError::ColumnOutOfRange(col))? = value;
+            Ok(db.update_row(table, row, updated).map(drop)?)
+        })
+    }
+
+    async fn add_check(self, _: Context, session: SessionId, table: String, constraint: CheckConstraint) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&table, TableOperation::Alter)?;
+        self.try_write(|db| Ok(db.add_check(table, constraint)?))
+    }
+
+    async fn get_table_schema(self, _: Context, session: SessionId, table: String) -> Result<Vec<DbType>, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.get_table(table)?.schema().to_vec()))
+    }
+
+    /// A single page as large as the result size limit, so a larger table fails before
+    /// any row is copied.
+    async fn get_rows(self, _: Context, session: SessionId, table: String) -> Result<Vec<Row>, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| {
+            let page = rows_page(db, table, 0, self.shared.max_result_rows)?;
+            self.check_result_size(page.total)?;
+            Ok(page.rows)
+        })
+    }
+
+    async fn get_rows_page(self, _: Context, session: SessionId, table: String, offset: usize, limit: usize) -> Result<RowsPage, DbRpcError> {
+        self.check_session(session)?;
+        let limit = limit.min(self.shared.max_page_rows);
+        self.try_read(|db| rows_page(db, table, offset, limit))
+    }
+
+    async fn get_rows_desc(self, _: Context, session: SessionId, table: String, offset: usize, limit: usize) -> Result<RowsPage, DbRpcError> {
+        self.check_session(session)?;
+        let limit = limit.min(self.shared.max_page_rows);
+        self.try_read(|db| {
+            let table = db.get_table(table)?;
+            let total = table.rows().len();
+            let offset = offset.min(total);
+            Ok(RowsPage { rows: table.rows_rev().skip(offset).take(limit).cloned().collect(), total, offset })
+        })
+    }
+
+    /// Reads every table under a single lock; the result size limit applies to the rows
+    /// of all tables together.
+    async fn get_rows_multi(self, _: Context, session: SessionId, tables: Vec<String>) -> Result<HashMap<String, Option<Vec<Row>>>, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| {
+            let found: Vec<_> = tables.iter().map(|name| db.get_table(name.clone()).ok()).collect();
+            self.check_result_size(found.iter().flatten().map(|table| table.rows().len()).sum())?;
+            Ok(tables
+                .into_iter()
+                .zip(found)
+                .map(|(name, table)| (name, table.map(|table| table.rows().to_vec())))
+                .collect())
+        })
+    }
+
+    async fn get_row(self, _: Context, session: SessionId, table: String, index: usize) -> Result<Row, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| {
+            let row = db.get_table(table)?.row_at(index);
+            row.cloned().ok_or(DbRpcError::RowIndexOutOfRange(index))
+        })
+    }
+
+    async fn get_rows_by_indices(self, _: Context, session: SessionId, table: String, indices: Vec<usize>) -> Result<Vec<Row>, DbRpcError> {
+        self.check_session(session)?;
+        self.check_result_size(indices.len())?;
+        self.try_read(|db| Ok(db.get_table(table)?.rows_at(&indices)?.into_iter().cloned().collect()))
+    }
+
+    async fn contains_row(self, _: Context, session: SessionId, table: String, row: Row) -> Result<bool, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.get_table(table)?.contains_row(&row)))
+    }
+
+    async fn use_table(self, _: Context, session: SessionId, name: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.current_table.lock().unwrap().replace(name);
+        Ok(())
+    }
+
+    async fn get_rows_current(self, context: Context, session: SessionId) -> Result<Vec<Row>, DbRpcError> {
+        let table = self.current_table().ok_or(DbRpcError::NoTableSelected)?;
+        self.get_rows(context, session, table).await
+    }
+
+    async fn get_table_schema_current(self, context: Context, session: SessionId) -> Result<Vec<DbType>, DbRpcError> {
+        let table = self.current_table().ok_or(DbRpcError::NoTableSelected)?;
+        self.get_table_schema(context, session, table).await
+    }
+
+    async fn insert_row_current(self, context: Context, session: SessionId, row: Row) -> Result<Row, DbRpcError> {
+        let table = self.current_table().ok_or(DbRpcError::NoTableSelected)?;
+        self.insert_row(context, session, table, row).await
+    }
+
+    async fn validate_row(self, _: Context, session: SessionId, table: String, row: Row) -> Result<bool, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.get_table(table)?.row_fits(&row)))
+    }
+
+    async fn table_projection(self, _: Context, session: SessionId, table: String, rows: Vec<bool>, new_table: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&new_table, TableOperation::Alter)?;
+        self.try_write(|db| Ok(db.projection(table, rows, new_table, None)?))
+    }
+
+    async fn project_many(self, _: Context, session: SessionId, specs: Vec<(String, Vec<bool>, String)>) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        for (_, _, new_table) in &specs {
+            self.check_acl(new_table, TableOperation::Alter)?;
         }
-        None
-    }
-
-    async fn get_rows(
-        self,
-        _: tarpc::context::Context,
-        table: String,
-    ) -> Option<Vec<Row>> {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            if let Ok(table) = db.get_table(table) {
-                return Some(table.rows().to_vec());
+        self.try_write(|db| Ok(db.project_many(specs)?))
+    }
+
+    async fn create_materialized_projection(self, _: Context, session: SessionId, table: String, rows: Vec<bool>, new_table: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&new_table, TableOperation::Alter)?;
+        self.try_write(|db| Ok(db.create_materialized_projection(table, rows, new_table)?))
+    }
+
+    async fn refresh_materialized(self, _: Context, session: SessionId, table: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_acl(&table, TableOperation::Update)?;
+        self.try_write(|db| Ok(db.refresh_materialized(table)?))
+    }
+
+    async fn get_table_info(self, _: Context, session: SessionId, table: String) -> Result<TableInfo, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.table_info(table)?))
+    }
+
+    async fn table_stats(self, _: Context, session: SessionId, table: String) -> Result<TableStats, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.get_table(table)?.stats()))
+    }
+
+    async fn get_catalog(self, _: Context, session: SessionId) -> Result<Vec<Row>, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.catalog()?.rows().to_vec()))
+    }
+
+    async fn check_integrity(self, _: Context, session: SessionId) -> Result<IntegrityReport, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.check_integrity()?))
+    }
+
+    async fn diff_database(self, _: Context, session: SessionId, path: String) -> Result<DatabaseDiff, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| Ok(db.diff_against(&path)?))
+    }
+
+    async fn run_query(self, _: Context, session: SessionId, query: Query) -> Result<Vec<Row>, DbRpcError> {
+        self.check_session(session)?;
+        // Stops one row past the limit like a `SELECT` in `execute_sql`.
+        let max_rows = self.shared.max_result_rows.saturating_add(1);
+        let rows = self.try_read(|db| Ok(query.rows_within(db, max_rows)?))?;
+        self.check_result_size(rows.len())?;
+        Ok(rows)
+    }
+
+    async fn execute_sql(self, _: Context, session: SessionId, query: String) -> Result<QueryResult, DbRpcError> {
+        self.check_session(session)?;
+        // Only statements changing the database keep other calls waiting. A `SELECT` stops
+        // one row past the limit, which is enough to tell that the result is too large.
+        let result = match sql_operation(&query)? {
+            Some((table, operation)) => {
+                self.check_acl(&table, operation)?;
+                self.try_write(|db| Ok(db.execute_sql(&query)?))?
+            }
+            None => {
+                let max_rows = self.shared.max_result_rows.saturating_add(1);
+                self.try_read(|db| Ok(db.query_sql_within(&query, max_rows)?))?
             }
+        };
+        if let QueryResult::Rows { rows, .. } = &result {
+            self.check_result_size(rows.len())?;
         }
-        None
+        Ok(result)
+    }
+
+    async fn search(self, _: Context, session: SessionId, value: DbValue, contains: bool) -> Result<Vec<(SearchHit, Row)>, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| {
+            let hits = db.search(&value, contains)?;
+            self.check_result_size(hits.len())?;
+            Ok(hits
+                .into_iter()
+                .map(|hit| {
+                    let row = db.get_table(hit.table.clone()).expect("hit refers to a table").rows()[hit.row].clone();
+                    (hit, row)
+                })
+                .collect())
+        })
+    }
+
+    async fn poll_changes(self, _: Context, session: SessionId, since_seq: u64) -> Result<Vec<(u64, ChangeEvent)>, DbRpcError> {
+        self.check_session(session)?;
+        Ok(self.shared.changes.since(since_seq))
+    }
+
+    async fn create_savepoint(self, _: Context, session: SessionId) -> Result<u64, DbRpcError> {
+        self.check_session(session)?;
+        // Under the slot's lock, so that the savepoint can't outlive its database being
+        // replaced in between.
+        let slot = self.db.lock().unwrap();
+        let savepoint = slot.as_ref().ok_or(DbRpcError::NoDatabaseOpen)?.read(SavedDatabase::savepoint)?;
+        Ok(self.shared.savepoints.lock().unwrap().push(savepoint))
+    }
+
+    async fn restore_savepoint(self, _: Context, session: SessionId, id: u64) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_replace_acl()?;
+        let (db, savepoint) = {
+            let slot = self.db.lock().unwrap();
+            let savepoint = self.shared.savepoints.lock().unwrap().get(id).cloned();
+            let savepoint = savepoint.ok_or(DbRpcError::UnknownSavepoint(id))?;
+            (slot.clone().ok_or(DbRpcError::NoDatabaseOpen)?, savepoint)
+        };
+        Ok(db.write(|db| db.restore(savepoint))?)
+    }
+
+    async fn backup(self, _: Context, session: SessionId, dir: Option<String>) -> Result<String, DbRpcError> {
+        self.check_session(session)?;
+        let path = self.blocking(move |server| server.try_read(|db| Ok(db.backup(dir.as_ref().map(Path::new))?))).await?;
+        rpc_path(path)
+    }
+
+    async fn list_backups(self, _: Context, session: SessionId) -> Result<Vec<String>, DbRpcError> {
+        self.check_session(session)?;
+        let backups = self.blocking(|server| server.try_read(|db| Ok(db.list_backups(None)?))).await?;
+        backups.into_iter().map(rpc_path).collect()
+    }
+
+    async fn restore_backup(self, _: Context, session: SessionId, path: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.check_replace_acl()?;
+        self.blocking(move |server| server.try_write(|db| Ok(db.restore_backup(path)?))).await
+    }
+
+    async fn export_json(self, _: Context, session: SessionId, path: String, pretty: bool) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.blocking(move |server| {
+            server.try_read(|db| {
+                let file = File::create(path).map_err(DbError::from)?;
+                Ok(db.export_json(file, pretty)?)
+            })
+        })
+        .await
     }
 
-    async fn table_projection(self, context: Context, table: String, rows: Vec<bool>, new_table: String) {
-        let mut lock = self.0.lock().await;
-        if let Some(db) = lock.as_mut() {
-            let _ = db.projection(table, rows, new_table);
+    async fn snapshot(self, _: Context, session: SessionId) -> Result<DatabaseSnapshot, DbRpcError> {
+        self.check_session(session)?;
+        self.try_read(|db| {
+            self.check_result_size(db.stats()?.row_count)?;
+            Ok(db.snapshot()?)
+        })
+    }
+
+    async fn import_json(self, _: Context, session: SessionId, json_path: String, path: String, force: bool) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        info!(json_path, path, force, "importing database");
+        self.check_replace_acl()?;
+        self.blocking(move |server| {
+            server.load_replacing(Path::new(&path), force, || {
+                let file = File::open(&json_path)?;
+                SavedDatabase::import_json(&path, BufReader::new(file), false)
+            })
+        })
+        .await
+    }
+
+    async fn export_table(self, _: Context, session: SessionId, name: String, path: String) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        self.blocking(move |server| server.try_read(|db| Ok(db.export_table(&name, Path::new(&path))?))).await
+    }
+
+    /// The ACL is checked for the name the table is imported under, so without `rename`
+    /// the file is read first.
+    async fn import_table(self, _: Context, session: SessionId, path: String, rename: Option<String>) -> Result<String, DbRpcError> {
+        self.check_session(session)?;
+        let name = match &rename {
+            Some(name) => name.clone(),
+            None => SavedDatabase::table_file_name(Path::new(&path))?,
+        };
+        self.check_acl(&name, TableOperation::Alter)?;
+        self.blocking(move |server| server.try_write(|db| Ok(db.import_table(Path::new(&path), rename)?))).await
+    }
+
+    async fn set_table_acl(self, _: Context, session: SessionId, token: String, table: String, operations: Option<HashSet<TableOperation>>) -> Result<(), DbRpcError> {
+        self.check_session(session)?;
+        if self.shared.admin_token.as_ref() != Some(&token) {
+            return Err(DbRpcError::Unauthorized);
         }
+        self.shared.acl.lock().unwrap().set(table, operations);
+        Ok(())
     }
 }
 
-const PATH: &str = "/Users/antond/Desktop/ITLab1/database";
+/// Up to `limit` rows of `table` from `offset`, both clamped to the rows there are.
+fn rows_page(db: &SavedDatabase, table: String, offset: usize, limit: usize) -> Result<RowsPage, DbRpcError> {
+    let rows = db.get_table(table)?.rows();
+    let offset = offset.min(rows.len());
+    let end = offset.saturating_add(limit).min(rows.len());
+    Ok(RowsPage { rows: rows[offset..end].to_vec(), total: rows.len(), offset })
+}
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let db = Arc::new(Mutex::new(None));
-    Arc::new(Mutex::new(
-        SavedDatabase::load_from_disk(PATH.to_string()).unwrap(),
-    ));
-
-    let server_addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), 8080);
-
-    // JSON transport is provided by the json_transport tarpc module. It makes it easy
-    // to start up a serde-powered json serialization strategy over TCP.
-    let mut listener = tarpc::serde_transport::tcp::listen(&server_addr, Json::default).await?;
-    listener.config_mut().max_frame_length(usize::MAX);
-    listener
+/// Paths cross the RPC as strings. Every string is a valid path, but a path that isn't
+/// UTF-8 can't be sent back without mangling it, so it fails with `PathNotUtf8` instead.
+fn rpc_path(path: PathBuf) -> Result<String, DbRpcError> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| DbRpcError::PathNotUtf8(path.to_string_lossy().into_owned()))
+}
+
+/// Key limiting channels per client: the peer's IP, with IPv4-mapped IPv6 peers counted
+/// as their IPv4 address. Peers whose address can't be read, e.g. because the socket
+/// already closed, share the unspecified address instead of crashing the accept loop.
+fn channel_key(peer_addr: std::io::Result<SocketAddr>) -> IpAddr {
+    peer_addr
+        .map(|addr| addr.ip().to_canonical())
+        .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+}
+
+/// A connection's byte stream, whether TLS or plain TCP.
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// Binds the tarpc service to every address of `config.listen`. Returns the bound
+/// addresses, with the port the OS picked where 0 was asked for, and the future serving
+/// connections on all of them, over TLS if `config.tls` is set and in
+/// `config.wire_format`. Connections of a client
+/// IP that already has `config.max_channels_per_ip` open are closed right away, while
+/// ones beyond `config.max_connections` in total wait until another one closes.
+async fn listen(config: &ServerConfig, db: DbSlot, shared: Arc<Shared>) -> anyhow::Result<(Vec<SocketAddr>, impl Future<Output = ()>)> {
+    let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
+    let mut listeners = Vec::new();
+    let mut addrs = Vec::new();
+    for addr in &config.listen {
+        let listener = TcpListener::bind(addr).await.with_context(|| format!("cannot listen on {addr}"))?;
+        addrs.push(listener.local_addr()?);
+        listeners.push(stream::poll_fn(move |cx| listener.poll_accept(cx).map(Some)).boxed());
+    }
+    let counts = ChannelCounts::new(config.max_channels_per_ip);
+    let max_channels_per_ip = config.max_channels_per_ip;
+    let max_frame_length = config.max_frame_length;
+    let wire_format = config.wire_format;
+    let handshake_timeout = config.handshake_timeout;
+    let server = stream::select_all(listeners)
         // Ignore accept errors.
         .filter_map(|r| future::ready(r.ok()))
-        .map(server::BaseChannel::with_defaults)
-        // Limit channels to 1 per IP.
-        .max_channels_per_key(1, |t| t.transport().peer_addr().unwrap().ip())
-        // serve is generated by the service attribute. It takes as input any type implementing
-        // the generated World trait.
-        .map(|channel| {
-            let server = Server(db.clone());
-            channel.execute(server.serve())
-        })
-        // Max 10 channels.
-        .buffer_unordered(10)
-        .for_each(|_| async {})
+        .filter_map(move |(stream, _)| {
+            let ip = channel_key(stream.peer_addr());
+            let slot = counts.acquire(ip);
+            if slot.is_none() {
+                warn!(%ip, "rejected a connection, the client has {max_channels_per_ip} open already");
+            }
+            future::ready(slot.map(|slot| (stream, slot)))
+        })
+        .map(move |(stream, slot)| {
+            let peer = stream.peer_addr().ok();
+            let server = Logged::new(Server::new(db.clone(), shared.clone()), peer);
+            let tls = tls.clone();
+            async move {
+                // The handshakes are timed out since the connection holds its slots meanwhile.
+                let mut stream: Box<dyn Connection> = match tls {
+                    Some(acceptor) => match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => Box::new(stream),
+                        Ok(Err(error)) => return warn!(?peer, %error, "TLS handshake failed"),
+                        Err(_) => return warn!(?peer, "TLS handshake timed out"),
+                    },
+                    None => Box::new(stream),
+                };
+                match tokio::time::timeout(handshake_timeout, wire_format.handshake(&mut stream)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => return warn!(?peer, %error, "wire format handshake failed"),
+                    Err(_) => return warn!(?peer, "wire format handshake timed out"),
+                }
+                let codec = LengthDelimitedCodec::builder().max_frame_length(max_frame_length).new_codec();
+                let framed = Framed::new(stream, codec);
+                match wire_format {
+                    WireFormat::Json => {
+                        let transport = tarpc::serde_transport::new(framed, Json::default());
+                        server::BaseChannel::with_defaults(transport).execute(server).await
+                    }
+                    WireFormat::Bincode => {
+                        let transport = tarpc::serde_transport::new(framed, Bincode::default());
+                        server::BaseChannel::with_defaults(transport).execute(server).await
+                    }
+                }
+                // The slot counts the channel until it is done.
+                drop(slot);
+            }
+        })
+        .buffer_unordered(config.max_connections)
+        .for_each(|_| async {});
+    Ok((addrs, server))
+}
+
+/// Saves the open database every `period` if it has unsaved changes, until the server
+/// stops. A failed save is logged and retried at the next check.
+async fn autosave(db: DbSlot, shared: Arc<Shared>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes right away.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(db) = db.lock().unwrap().clone() else {
+            continue;
+        };
+        let started = Instant::now();
+        // Saving blocks on file IO, so it runs off the async workers.
+        let saved = tokio::task::spawn_blocking(move || {
+            db.write(|db| db.is_dirty().then(|| (db.path().display().to_string(), db.save())))
+        })
         .await;
+        match saved {
+            Ok(None) => {}
+            Ok(Some((path, Ok(_)))) => {
+                *shared.last_autosave.lock().unwrap() = Some(Utc::now());
+                info!(path, elapsed = ?started.elapsed(), "autosaved");
+            }
+            Ok(Some((path, Err(error)))) => error!(path, %error, "autosave failed"),
+            Err(error) => error!(%error, "autosave failed"),
+        }
+    }
+}
+
+/// Opens or creates the database `config.startup` asks for, like the `open` and `create`
+/// calls would, without needing the auth token.
+async fn open_startup_db(config: &ServerConfig, server: Server) -> anyhow::Result<()> {
+    let session = server.shared.sessions.start();
+    match &config.startup {
+        None => Ok(()),
+        Some(Startup::Open(path)) => server
+            .open(tarpc::context::current(), session, path.clone(), false)
+            .await
+            .with_context(|| format!("cannot open database {path}")),
+        Some(Startup::Create { name, path }) => server
+            .create(tarpc::context::current(), session, name.clone(), path.clone(), Format::default(), false)
+            .await
+            .with_context(|| format!("cannot create database {path}")),
+    }
+}
+
+/// `db-server migrate <path>`: rewrites the database at `path` in the current format
+/// version instead of serving it.
+fn migrate(args: &[String]) -> anyhow::Result<()> {
+    let [path] = args else {
+        anyhow::bail!("usage: db-server migrate <path>");
+    };
+    let version = SavedDatabase::file_version(path).with_context(|| format!("cannot read database {path}"))?;
+    let backup = SavedDatabase::migrate(path).with_context(|| format!("cannot migrate database {path}"))?;
+    println!("Migrated {path} from format version {version}, the original is kept at {}", backup.display());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|command| command == "migrate") {
+        return migrate(&args[1..]);
+    }
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{}", config::usage());
+        return Ok(());
+    }
+    let config = ServerConfig::from_env(args)?;
+    logging::init(config.log_level.as_deref())?;
+    let db = DbSlot::default();
+    let shared = Arc::new(Shared::new(&config));
+    open_startup_db(&config, Server::new(db.clone(), shared.clone())).await?;
+
+    // The HTTP gateway serves the same database as the tarpc service.
+    let http_api = Server::new(db.clone(), shared.clone());
+    let http_server = HttpServer::new(move || App::new().configure(http::configure(http_api.clone())))
+        .bind(config.http)?;
+    for addr in http_server.addrs() {
+        println!("HTTP gateway listening on {addr}");
+    }
+    let http_server = http_server.run();
+
+    if let Some(secs) = config.autosave_secs {
+        tokio::spawn(autosave(db.clone(), shared.clone(), Duration::from_secs(secs)));
+    }
+    let (addrs, tarpc_server) = listen(&config, db, shared).await?;
+    for addr in addrs {
+        println!("tarpc service listening on {addr}");
+    }
+
+    let (http_result, _) = tokio::join!(http_server, tarpc_server);
+    http_result?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,41 @@
+use std::collections::VecDeque;
+
+use db::DbSnapshot;
+
+/// Savepoints taken through the RPC of the open database, oldest first; the oldest is
+/// evicted once there are more than `max`.
+pub struct Savepoints {
+    max: usize,
+    next_id: u64,
+    entries: VecDeque<(u64, DbSnapshot)>,
+}
+
+impl Savepoints {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            next_id: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, snapshot: DbSnapshot) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push_back((id, snapshot));
+        while self.entries.len() > self.max {
+            self.entries.pop_front();
+        }
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&DbSnapshot> {
+        self.entries.iter().find(|(entry, _)| *entry == id).map(|(_, snapshot)| snapshot)
+    }
+
+    /// Drops every savepoint, for when another database is opened. Ids keep counting up,
+    /// so one of the old ones never matches a new savepoint.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
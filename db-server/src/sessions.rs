@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use db::rpc::SessionId;
+
+/// Sessions started by `authenticate`, each expiring once unused for `ttl`.
+pub struct Sessions {
+    ttl: Duration,
+    last_used: Mutex<HashMap<SessionId, Instant>>,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, last_used: Mutex::default() }
+    }
+
+    /// Starts a session with an id nobody can guess, dropping the expired ones meanwhile.
+    pub fn start(&self) -> SessionId {
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock().unwrap();
+        last_used.retain(|_, used| now.duration_since(*used) < self.ttl);
+        let session = loop {
+            let session = SessionId(rand::random());
+            if session != SessionId::NONE && !last_used.contains_key(&session) {
+                break session;
+            }
+        };
+        last_used.insert(session, now);
+        session
+    }
+
+    /// Whether `session` was started and hasn't expired, counting this as a use of it.
+    pub fn touch(&self, session: SessionId) -> bool {
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock().unwrap();
+        match last_used.get_mut(&session) {
+            Some(used) if now.duration_since(*used) < self.ttl => {
+                *used = now;
+                true
+            }
+            Some(_) => {
+                last_used.remove(&session);
+                false
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,1124 @@
+use actix_web::{test as actix_test, App};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::tempdir;
+
+use db::rpc::{DbRpcError, DbStatus, RowsPage, ServerStatus, Service, ServiceClient, SessionId, TableOperation, WireFormat, PROTOCOL_VERSION};
+use chrono::Utc;
+use db::{ChangeEvent, ColumnDefault, DbStats, FsyncPolicy, Query, DbType, Format, DbValue, Row, SavedDatabase, SharedDatabase, TxOp};
+use tarpc::server::{BaseChannel, Channel};
+use tarpc::tokio_serde::formats::Json;
+use tarpc::tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tarpc::{client, context};
+
+use crate::config::{parse_addr, ServerConfig, Startup};
+use crate::logging::Logged;
+use crate::{autosave, channel_key, http, listen, open_startup_db, DbSlot, Server, Shared};
+
+#[actix_web::test]
+async fn http_list_tables_and_rows() {
+    let dir = tempdir().unwrap();
+    let state = DbSlot::default();
+    let server = Server::new(state.clone(), Arc::new(Shared::new(&ServerConfig::default())));
+    let app = actix_test::init_service(App::new().configure(http::configure(server))).await;
+
+    let request = actix_test::TestRequest::get().uri("/tables").to_request();
+    let names: Option<Vec<String>> = actix_test::call_and_read_body_json(&app, request).await;
+    assert_eq!(names, None);
+
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    db.create_table("b".to_string(), vec![DbType::Int]).unwrap();
+    db.create_table("a".to_string(), vec![DbType::String]).unwrap();
+    state.lock().unwrap().replace(SharedDatabase::new(db));
+
+    let request = actix_test::TestRequest::get().uri("/tables").to_request();
+    let names: Option<Vec<String>> = actix_test::call_and_read_body_json(&app, request).await;
+    assert_eq!(names, Some(vec!["a".to_string(), "b".to_string()]));
+
+    let row = Row(vec![DbValue::Int(7)]);
+    let request = actix_test::TestRequest::post().uri("/tables/b/rows").set_json(&row).to_request();
+    assert!(actix_test::call_service(&app, request).await.status().is_success());
+    let request = actix_test::TestRequest::post().uri("/tables/a/rows").set_json(&row).to_request();
+    assert_eq!(actix_test::call_service(&app, request).await.status(), 400);
+
+    let request = actix_test::TestRequest::get().uri("/tables/b/rows").to_request();
+    let rows: Option<Vec<Row>> = actix_test::call_and_read_body_json(&app, request).await;
+    assert_eq!(rows, Some(vec![row]));
+    let db = state.lock().unwrap().clone().unwrap();
+    assert_eq!(db.get_rows("b".to_string()).unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn http_checks() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let config = ServerConfig { auth_token: Some("secret".to_string()), admin_token: Some("admin".to_string()), max_result_rows: 1, ..ServerConfig::default() };
+    let server = new_server(&config);
+    let app = actix_test::init_service(App::new().configure(http::configure(server.clone()))).await;
+
+    let request = actix_test::TestRequest::get().uri("/tables").to_request();
+    assert_eq!(actix_test::call_service(&app, request).await.status(), 401);
+    let request = actix_test::TestRequest::post().uri("/sessions").set_payload("guess").to_request();
+    assert_eq!(actix_test::call_service(&app, request).await.status(), 401);
+    let request = actix_test::TestRequest::post().uri("/sessions").set_payload("secret").to_request();
+    let session = String::from_utf8(actix_test::call_and_read_body(&app, request).await.to_vec()).unwrap();
+    let id = SessionId(session.parse().unwrap());
+
+    server.clone().create(context::current(), id, "db".to_string(), path, Format::Bincode, false).await.unwrap();
+    server.clone().create_table(context::current(), id, "t".to_string(), vec![DbType::Int]).await.unwrap();
+    let insert = || {
+        let request = actix_test::TestRequest::post().uri("/tables/t/rows").insert_header(("x-session", session.as_str()));
+        request.set_json(Row(vec![DbValue::Int(1)])).to_request()
+    };
+    for _ in 0..2 {
+        assert!(actix_test::call_service(&app, insert()).await.status().is_success());
+    }
+    let request = actix_test::TestRequest::get().uri("/tables/t/rows").insert_header(("x-session", session.as_str())).to_request();
+    assert_eq!(actix_test::call_service(&app, request).await.status(), 413);
+
+    server.clone().set_table_acl(context::current(), id, "admin".to_string(), "t".to_string(), Some(HashSet::new())).await.unwrap();
+    assert_eq!(actix_test::call_service(&app, insert()).await.status(), 403);
+    let request = actix_test::TestRequest::post().uri("/tables/t/rows").set_json(Row(vec![DbValue::Int(1)])).to_request();
+    assert_eq!(actix_test::call_service(&app, request).await.status(), 401);
+}
+
+/// Serves `server` in-process and connects a client to it, so calls go through tarpc.
+fn connect(server: Server) -> ServiceClient {
+    let (client_transport, server_transport) = tarpc::transport::channel::unbounded();
+    tokio::spawn(BaseChannel::with_defaults(server_transport).execute(Logged::new(server, None)));
+    ServiceClient::new(client::Config::default(), client_transport).spawn()
+}
+
+/// A server with `config` and no database open yet.
+fn new_server(config: &ServerConfig) -> Server {
+    Server::new(DbSlot::default(), Arc::new(Shared::new(config)))
+}
+
+/// Path of the database the tests create in `dir`.
+fn db_path(dir: &tempfile::TempDir) -> String {
+    dir.path().join("db").to_str().unwrap().to_string()
+}
+
+/// A server with `config` that has just created the empty bincode database "db" at
+/// [`db_path`] in `dir`.
+async fn server_with_db(dir: &tempfile::TempDir, config: &ServerConfig) -> Server {
+    let server = new_server(config);
+    server.clone().create(context::current(), SessionId::NONE, "db".to_string(), db_path(dir), Format::Bincode, false).await.unwrap();
+    server
+}
+
+/// Connects a client to the tarpc service at `addr` over plain TCP with the JSON format.
+async fn connect_tcp(addr: std::net::SocketAddr) -> std::io::Result<ServiceClient> {
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    WireFormat::Json.handshake(&mut stream).await?;
+    let transport = tarpc::serde_transport::new(Framed::new(stream, LengthDelimitedCodec::new()), Json::default());
+    Ok(ServiceClient::new(client::Config::default(), transport).spawn())
+}
+
+/// Level, message and fields of every event, each followed by the fields of the spans
+/// it is in.
+#[derive(Clone, Default)]
+struct Captured(Arc<std::sync::Mutex<Vec<(tracing::Level, String)>>>);
+
+#[derive(Default)]
+struct Fields(String);
+
+impl tracing::field::Visit for Fields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0 += &format!("{}={value:?} ", field.name());
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Captured
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        ctx.span(id).unwrap().extensions_mut().insert(fields);
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        for span in ctx.event_scope(event).into_iter().flatten() {
+            fields.0 += &span.extensions().get::<Fields>().map_or(String::new(), |span| span.0.clone());
+        }
+        self.0.lock().unwrap().push((*event.metadata().level(), fields.0));
+    }
+}
+
+#[tokio::test]
+async fn logs_errors() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let captured = Captured::default();
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(captured.clone()));
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let client = connect(new_server(&ServerConfig::default()));
+    client.create(context::current(), SessionId::NONE, "db".to_string(), path, Format::Bincode, false).await.unwrap().unwrap();
+    let removed = client.remove_table(context::current(), SessionId::NONE, "missing".to_string()).await.unwrap();
+    assert_eq!(removed, Err(DbRpcError::TableIsMissing("missing".to_string())));
+
+    let events = captured.0.lock().unwrap();
+    let logged = |level, text: &str| events.iter().any(|(l, fields)| *l == level && fields.contains(text));
+    assert!(logged(tracing::Level::INFO, "message=creating database name=\"db\""));
+    assert!(logged(tracing::Level::INFO, "message=removing table name=\"missing\""));
+    let failed = events.iter().filter(|(level, fields)| *level == tracing::Level::WARN && fields.contains("call failed"));
+    let failed: Vec<&String> = failed.map(|(_, fields)| fields).collect();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0].contains("error=Table missing is missing"), "{}", failed[0]);
+    assert!(failed[0].contains("method=\"Service.remove_table\" peer=local request="), "{}", failed[0]);
+}
+
+#[tokio::test]
+async fn client_receives_errors() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let client = connect(new_server(&ServerConfig::default()));
+    assert_eq!(client.protocol_version(context::current()).await.unwrap(), Ok(PROTOCOL_VERSION));
+
+    let names = client.get_table_names(context::current(), SessionId::NONE).await.unwrap();
+    assert_eq!(names, Err(DbRpcError::NoDatabaseOpen));
+    let row = Row(vec![DbValue::Int(1)]);
+    let inserted = client.insert_row(context::current(), SessionId::NONE, "t".to_string(), row.clone()).await.unwrap();
+    assert_eq!(inserted, Err(DbRpcError::NoDatabaseOpen));
+    let opened = client.open(context::current(), SessionId::NONE, path.clone(), false).await.unwrap();
+    assert_eq!(opened, Err(DbRpcError::FileNotFound(path.clone())));
+
+    client.create(context::current(), SessionId::NONE, "db".to_string(), path, Format::Bincode, false).await.unwrap().unwrap();
+    let inserted = client.insert_row(context::current(), SessionId::NONE, "bogus".to_string(), row.clone()).await.unwrap();
+    assert_eq!(inserted, Err(DbRpcError::TableIsMissing("bogus".to_string())));
+    let removed = client.remove_table(context::current(), SessionId::NONE, "bogus".to_string()).await.unwrap();
+    assert_eq!(removed, Err(DbRpcError::TableIsMissing("bogus".to_string())));
+
+    client.create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::String]).await.unwrap().unwrap();
+    let inserted = client.insert_row(context::current(), SessionId::NONE, "t".to_string(), row).await.unwrap();
+    let mismatch = DbRpcError::ColumnTypeMismatch { column: 0, expected: DbType::String, got: DbType::Int };
+    assert_eq!(inserted, Err(mismatch));
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap(), Ok(vec![]));
+}
+
+#[tokio::test]
+async fn update_rows() {
+    let dir = tempdir().unwrap();
+    let client = connect(server_with_db(&dir, &ServerConfig::default()).await);
+    let table = || "t".to_string();
+    client.create_table(context::current(), SessionId::NONE, table(), vec![DbType::Int, DbType::String]).await.unwrap().unwrap();
+    for i in 0..2 {
+        let row = Row(vec![DbValue::Int(i), DbValue::String(i.to_string())]);
+        client.insert_row(context::current(), SessionId::NONE, table(), row).await.unwrap().unwrap();
+    }
+
+    let row = Row(vec![DbValue::Int(5), DbValue::String("five".to_string())]);
+    client.update_row(context::current(), SessionId::NONE, table(), 0, row.clone()).await.unwrap().unwrap();
+    client.update_cell(context::current(), SessionId::NONE, table(), 1, 0, DbValue::Int(7)).await.unwrap().unwrap();
+    let expected = vec![row.clone(), Row(vec![DbValue::Int(7), DbValue::String("1".to_string())])];
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, table()).await.unwrap(), Ok(expected.clone()));
+
+    let updated = client.update_row(context::current(), SessionId::NONE, table(), 2, row).await.unwrap();
+    assert_eq!(updated, Err(DbRpcError::RowIndexOutOfRange(2)));
+    let updated = client.update_cell(context::current(), SessionId::NONE, table(), 2, 0, DbValue::Int(1)).await.unwrap();
+    assert_eq!(updated, Err(DbRpcError::RowIndexOutOfRange(2)));
+    let updated = client.update_cell(context::current(), SessionId::NONE, table(), 0, 2, DbValue::Int(1)).await.unwrap();
+    assert_eq!(updated, Err(DbRpcError::ColumnOutOfRange(2)));
+
+    let wrong = Row(vec![DbValue::String("five".to_string()), DbValue::String("five".to_string())]);
+    let mismatch = DbRpcError::ColumnTypeMismatch { column: 0, expected: DbType::Int, got: DbType::String };
+    assert_eq!(client.update_row(context::current(), SessionId::NONE, table(), 0, wrong).await.unwrap(), Err(mismatch.clone()));
+    let updated = client.update_cell(context::current(), SessionId::NONE, table(), 0, 0, DbValue::String("x".to_string())).await.unwrap();
+    assert_eq!(updated, Err(mismatch));
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, table()).await.unwrap(), Ok(expected));
+}
+
+#[tokio::test]
+async fn point_reads() {
+    let dir = tempdir().unwrap();
+    let client = connect(server_with_db(&dir, &ServerConfig::default()).await);
+    let table = || "t".to_string();
+    client.create_table(context::current(), SessionId::NONE, table(), vec![DbType::Int]).await.unwrap().unwrap();
+    let row = |i| Row(vec![DbValue::Int(i)]);
+    for i in 0..3 {
+        client.insert_row(context::current(), SessionId::NONE, table(), row(i)).await.unwrap().unwrap();
+    }
+
+    assert_eq!(client.get_row(context::current(), SessionId::NONE, table(), 1).await.unwrap(), Ok(row(1)));
+    assert_eq!(client.get_row(context::current(), SessionId::NONE, table(), 3).await.unwrap(), Err(DbRpcError::RowIndexOutOfRange(3)));
+    let rows = client.get_rows_by_indices(context::current(), SessionId::NONE, table(), vec![2, 0, 2]).await.unwrap();
+    assert_eq!(rows, Ok(vec![row(2), row(0), row(2)]));
+    let rows = client.get_rows_by_indices(context::current(), SessionId::NONE, table(), vec![0, 5, 1]).await.unwrap();
+    assert_eq!(rows, Err(DbRpcError::RowIndicesOutOfRange(vec![5])));
+    assert_eq!(client.contains_row(context::current(), SessionId::NONE, table(), row(2)).await.unwrap(), Ok(true));
+    assert_eq!(client.contains_row(context::current(), SessionId::NONE, table(), row(3)).await.unwrap(), Ok(false));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn readers_share_the_database() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let (db, shared) = (DbSlot::default(), Arc::new(Shared::new(&ServerConfig::default())));
+    let (reader, other) = (connect(Server::new(db.clone(), shared.clone())), connect(Server::new(db.clone(), shared)));
+    reader.create(context::current(), SessionId::NONE, "db".to_string(), path, Format::Bincode, false).await.unwrap().unwrap();
+    reader.create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::Int]).await.unwrap().unwrap();
+    reader.insert_row(context::current(), SessionId::NONE, "t".to_string(), Row(vec![DbValue::Int(1)])).await.unwrap().unwrap();
+    let handle = db.lock().unwrap().clone().unwrap();
+    // Holds the database the way a slow call would until told to stop.
+    let hold = |write: bool| {
+        let handle = handle.clone();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let (locked, is_locked) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let wait = || {
+                locked.send(()).unwrap();
+                released.recv().unwrap();
+            };
+            if write { handle.write(|_| wait()) } else { handle.read(|_| wait()) }
+        });
+        is_locked.recv().unwrap();
+        (release, thread)
+    };
+
+    let (release, thread) = hold(false);
+    let page = tokio::time::timeout(Duration::from_secs(5), reader.get_rows_page(context::current(), SessionId::NONE, "t".to_string(), 0, 10));
+    assert_eq!(page.await.unwrap().unwrap().unwrap().total, 1);
+    let names = tokio::time::timeout(Duration::from_secs(5), other.get_table_names(context::current(), SessionId::NONE));
+    assert_eq!(names.await.unwrap().unwrap(), Ok(vec!["t".to_string()]));
+    release.send(()).unwrap();
+    thread.join().unwrap();
+
+    let (release, thread) = hold(true);
+    let names = tokio::spawn(async move { other.get_table_names(context::current(), SessionId::NONE).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!names.is_finished());
+    release.send(()).unwrap();
+    thread.join().unwrap();
+    assert_eq!(names.await.unwrap().unwrap(), Ok(vec!["t".to_string()]));
+}
+
+#[tokio::test]
+async fn paged_rows() {
+    let dir = tempdir().unwrap();
+    let config = ServerConfig { max_page_rows: 4, ..ServerConfig::default() };
+    let client = connect(server_with_db(&dir, &config).await);
+    let table = || "t".to_string();
+    client.create_table(context::current(), SessionId::NONE, table(), vec![DbType::Int]).await.unwrap().unwrap();
+    let row = |i| Row(vec![DbValue::Int(i)]);
+    for i in 0..10 {
+        client.insert_row(context::current(), SessionId::NONE, table(), row(i)).await.unwrap().unwrap();
+    }
+    let page = |offset, limit| client.get_rows_page(context::current(), SessionId::NONE, table(), offset, limit);
+
+    let expected = RowsPage { rows: (3..6).map(row).collect(), total: 10, offset: 3 };
+    assert_eq!(page(3, 3).await.unwrap(), Ok(expected));
+    let expected = RowsPage { rows: (8..10).map(row).collect(), total: 10, offset: 8 };
+    assert_eq!(page(8, 5).await.unwrap(), Ok(expected));
+    let expected = RowsPage { rows: vec![], total: 10, offset: 10 };
+    assert_eq!(page(12, 5).await.unwrap(), Ok(expected.clone()));
+    assert_eq!(page(usize::MAX, usize::MAX).await.unwrap(), Ok(expected));
+    // The limit is capped at `max_page_rows`.
+    let expected = RowsPage { rows: (1..5).map(row).collect(), total: 10, offset: 1 };
+    assert_eq!(page(1, 100).await.unwrap(), Ok(expected));
+    let rows = client.get_rows(context::current(), SessionId::NONE, table()).await.unwrap();
+    assert_eq!(rows, Ok((0..10).map(row).collect()));
+}
+
+#[tokio::test]
+async fn reversed_rows() {
+    let dir = tempdir().unwrap();
+    let client = connect(server_with_db(&dir, &ServerConfig::default()).await);
+    let table = || "t".to_string();
+    client.create_table(context::current(), SessionId::NONE, table(), vec![DbType::Int]).await.unwrap().unwrap();
+    let row = |i| Row(vec![DbValue::Int(i)]);
+    for i in 0..5 {
+        client.insert_row(context::current(), SessionId::NONE, table(), row(i)).await.unwrap().unwrap();
+    }
+    let page = |offset, limit| client.get_rows_desc(context::current(), SessionId::NONE, table(), offset, limit);
+
+    let expected = RowsPage { rows: vec![row(4), row(3)], total: 5, offset: 0 };
+    assert_eq!(page(0, 2).await.unwrap(), Ok(expected));
+    let expected = RowsPage { rows: vec![row(1), row(0)], total: 5, offset: 3 };
+    assert_eq!(page(3, 10).await.unwrap(), Ok(expected));
+    let expected = RowsPage { rows: vec![], total: 5, offset: 5 };
+    assert_eq!(page(usize::MAX, 1).await.unwrap(), Ok(expected));
+}
+
+#[tokio::test]
+async fn insert_returns_stored_row() {
+    let dir = tempdir().unwrap();
+    let server = server_with_db(&dir, &ServerConfig::default()).await;
+    let db = server.db.clone();
+    let client = connect(server);
+    client.create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::String, DbType::Time]).await.unwrap().unwrap();
+    let shared = db.lock().unwrap().clone().unwrap();
+    shared.write(|db| db.set_default("t".to_string(), 1, Some(ColumnDefault::Now))).unwrap();
+
+    let before = Utc::now();
+    let stored = client.insert_row(context::current(), SessionId::NONE, "t".to_string(), Row(vec![DbValue::String("a".to_string())])).await;
+    let stored = stored.unwrap().unwrap();
+    let [DbValue::String(name), DbValue::Time(time)] = &stored.0[..] else {
+        panic!("unexpected row {stored}");
+    };
+    assert_eq!(name, "a");
+    assert!((before..=Utc::now()).contains(time));
+    assert_eq!(client.get_row(context::current(), SessionId::NONE, "t".to_string(), 0).await.unwrap(), Ok(stored.clone()));
+    client.use_table(context::current(), SessionId::NONE, "t".to_string()).await.unwrap().unwrap();
+    let row = Row(vec![DbValue::String("b".to_string()), DbValue::Time(*time)]);
+    assert_eq!(client.insert_row_current(context::current(), SessionId::NONE, row.clone()).await.unwrap(), Ok(row));
+}
+
+#[tokio::test]
+async fn rename_and_copy_tables() {
+    let dir = tempdir().unwrap();
+    let client = connect(server_with_db(&dir, &ServerConfig::default()).await);
+    for table in ["a", "b"] {
+        client.create_table(context::current(), SessionId::NONE, table.to_string(), vec![DbType::Int]).await.unwrap().unwrap();
+    }
+    client.insert_row(context::current(), SessionId::NONE, "a".to_string(), Row(vec![DbValue::Int(1)])).await.unwrap().unwrap();
+    let names = || async {
+        let mut names = client.get_table_names(context::current(), SessionId::NONE).await.unwrap().unwrap();
+        names.sort();
+        names
+    };
+
+    let renamed = client.rename_table(context::current(), SessionId::NONE, "a".to_string(), "b".to_string()).await.unwrap();
+    assert_eq!(renamed, Err(DbRpcError::TableIsAlreadyPresent("b".to_string())));
+    let renamed = client.rename_table(context::current(), SessionId::NONE, "x".to_string(), "y".to_string()).await.unwrap();
+    assert_eq!(renamed, Err(DbRpcError::TableIsMissing("x".to_string())));
+    client.rename_table(context::current(), SessionId::NONE, "a".to_string(), "c".to_string()).await.unwrap().unwrap();
+    assert_eq!(names().await, ["b", "c"]);
+
+    let copied = client.copy_table(context::current(), SessionId::NONE, "c".to_string(), "b".to_string(), true).await.unwrap();
+    assert_eq!(copied, Err(DbRpcError::TableIsAlreadyPresent("b".to_string())));
+    let copied = client.copy_table(context::current(), SessionId::NONE, "a".to_string(), "d".to_string(), true).await.unwrap();
+    assert_eq!(copied, Err(DbRpcError::TableIsMissing("a".to_string())));
+    client.copy_table(context::current(), SessionId::NONE, "c".to_string(), "d".to_string(), true).await.unwrap().unwrap();
+    client.copy_table(context::current(), SessionId::NONE, "c".to_string(), "e".to_string(), false).await.unwrap().unwrap();
+    assert_eq!(names().await, ["b", "c", "d", "e"]);
+    let rows = |table: &str| client.get_rows(context::current(), SessionId::NONE, table.to_string());
+    assert_eq!(rows("d").await.unwrap(), Ok(vec![Row(vec![DbValue::Int(1)])]));
+    assert_eq!(rows("e").await.unwrap(), Ok(vec![]));
+}
+
+#[tokio::test]
+async fn listens_on_picked_port() {
+    let config = ServerConfig::from_args(["--host", "127.0.0.1", "--port", "0"].map(String::from)).unwrap();
+    let (addrs, server) = listen(&config, DbSlot::default(), Arc::new(Shared::new(&config))).await.unwrap();
+    assert_eq!(addrs.len(), 1);
+    assert!(addrs[0].is_ipv4());
+    assert_ne!(addrs[0].port(), 0);
+    tokio::spawn(server);
+
+    let client = connect_tcp(addrs[0]).await.unwrap();
+    assert_eq!(client.protocol_version(context::current()).await.unwrap(), Ok(PROTOCOL_VERSION));
+    assert_eq!(client.get_name(context::current(), SessionId::NONE).await.unwrap(), Err(DbRpcError::NoDatabaseOpen));
+}
+
+#[tokio::test]
+async fn connection_limits() {
+    let args = ["--host", "127.0.0.1", "--port", "0", "--max-channels-per-ip", "2"].map(String::from);
+    let config = ServerConfig::from_args(args).unwrap();
+    let (addrs, server) = listen(&config, DbSlot::default(), Arc::new(Shared::new(&config))).await.unwrap();
+    tokio::spawn(server);
+    let (first, second) = (connect_tcp(addrs[0]).await.unwrap(), connect_tcp(addrs[0]).await.unwrap());
+    let (a, b) = tokio::join!(
+        first.protocol_version(context::current()),
+        second.protocol_version(context::current()),
+    );
+    assert_eq!((a.unwrap(), b.unwrap()), (Ok(PROTOCOL_VERSION), Ok(PROTOCOL_VERSION)));
+    assert!(connect_tcp(addrs[0]).await.is_err());
+    assert_eq!(first.get_name(context::current(), SessionId::NONE).await.unwrap(), Err(DbRpcError::NoDatabaseOpen));
+}
+
+#[tokio::test]
+async fn stalled_handshake() {
+    use tokio::io::AsyncReadExt;
+
+    let args = ["--host", "127.0.0.1", "--port", "0", "--max-channels-per-ip", "1", "--handshake-timeout-secs", "1"];
+    let config = ServerConfig::from_args(args.map(String::from)).unwrap();
+    let (addrs, server) = listen(&config, DbSlot::default(), Arc::new(Shared::new(&config))).await.unwrap();
+    tokio::spawn(server);
+    let mut stalled = tokio::net::TcpStream::connect(addrs[0]).await.unwrap();
+    let mut preamble = [0; 1];
+    stalled.read_exact(&mut preamble).await.unwrap();
+    assert!(connect_tcp(addrs[0]).await.is_err());
+
+    // The server gives up on the client that never answered, freeing its slot.
+    let closed = tokio::time::timeout(Duration::from_secs(5), stalled.read(&mut preamble)).await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+    let client = connect_tcp(addrs[0]).await.unwrap();
+    assert_eq!(client.protocol_version(context::current()).await.unwrap(), Ok(PROTOCOL_VERSION));
+}
+
+#[tokio::test]
+async fn client_library() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let args = ["--host", "127.0.0.1", "--port", "0"].map(String::from);
+    let config = ServerConfig { auth_token: Some("secret".to_string()), ..ServerConfig::from_args(args).unwrap() };
+    let (addrs, server) = listen(&config, DbSlot::default(), Arc::new(Shared::new(&config))).await.unwrap();
+    tokio::spawn(server);
+
+    let client = db_client::Client::connect(addrs[0], WireFormat::Json).await.unwrap();
+    let created = client.create("db".to_string(), path.clone(), Format::Bincode, false).await;
+    assert!(matches!(created, Err(db_client::ClientError::Db(DbRpcError::Unauthorized))));
+    let wrong = client.authenticate("guess".to_string()).await;
+    assert!(matches!(wrong, Err(db_client::ClientError::Db(DbRpcError::Unauthorized))));
+    client.authenticate("secret".to_string()).await.unwrap();
+    client.create("db".to_string(), path, Format::Bincode, false).await.unwrap();
+    client.create_table("t".to_string(), vec![DbType::Int]).await.unwrap();
+    let row = Row(vec![DbValue::Int(1)]);
+    assert_eq!(client.insert_row("t".to_string(), row.clone()).await.unwrap(), row);
+    assert_eq!(client.get_rows("t".to_string()).await.unwrap(), [row]);
+    let missing = client.get_rows("missing".to_string()).await;
+    assert!(matches!(missing, Err(db_client::ClientError::Db(DbRpcError::TableIsMissing(_)))));
+}
+
+#[tokio::test]
+async fn tls_transport() {
+    let dir = tempdir().unwrap();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    let args = ["--host", "127.0.0.1", "--port", "0", "--tls-cert", cert_path.to_str().unwrap(), "--tls-key", key_path.to_str().unwrap()];
+    let config = ServerConfig::from_args(args.map(String::from)).unwrap();
+    let (addrs, server) = listen(&config, DbSlot::default(), Arc::new(Shared::new(&config))).await.unwrap();
+    tokio::spawn(server);
+    let addr = format!("localhost:{}", addrs[0].port());
+
+    let client = db_client::Client::connect_tls(&addr, db_client::TlsTrust::Ca(cert_path), WireFormat::Json).await.unwrap();
+    let path = db_path(&dir);
+    client.create("db".to_string(), path, Format::Bincode, false).await.unwrap();
+    client.create_table("t".to_string(), vec![DbType::Int]).await.unwrap();
+    assert_eq!(client.get_table_names().await.unwrap(), ["t"]);
+    let insecure = db_client::Client::connect_tls(&addr, db_client::TlsTrust::Insecure, WireFormat::Json).await.unwrap();
+    assert_eq!(insecure.get_table_names().await.unwrap(), ["t"]);
+
+    // A plain client fails the handshake, and the server keeps serving the others.
+    assert!(db_client::Client::connect(addrs[0], WireFormat::Json).await.is_err());
+    assert_eq!(client.table_count().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn bincode_transport() {
+    let dir = tempdir().unwrap();
+    let args = ["--host", "127.0.0.1", "--port", "0", "--wire-format", "bincode"].map(String::from);
+    let config = ServerConfig::from_args(args).unwrap();
+    let (addrs, server) = listen(&config, DbSlot::default(), Arc::new(Shared::new(&config))).await.unwrap();
+    tokio::spawn(server);
+
+    let client = db_client::Client::connect(addrs[0], WireFormat::Bincode).await.unwrap();
+    let path = db_path(&dir);
+    client.create("db".to_string(), path, Format::Bincode, false).await.unwrap();
+    let schema = vec![DbType::Int, DbType::Real, DbType::Char, DbType::String, DbType::Time, DbType::UInt, DbType::Blob];
+    client.create_table("t".to_string(), schema.clone()).await.unwrap();
+    let time = chrono::DateTime::parse_from_rfc3339("2024-02-29T23:59:30.250+05:30").unwrap();
+    let row = Row(vec![
+        DbValue::Int(-7),
+        DbValue::Real(f64::NAN),
+        DbValue::Char('ё'),
+        DbValue::String("tea".to_string()),
+        DbValue::Time(time),
+        DbValue::UInt(u64::MAX),
+        DbValue::Blob(vec![0, 255, 10]),
+    ]);
+    client.insert_row("t".to_string(), row.clone()).await.unwrap();
+    assert_eq!(client.get_table_schema("t".to_string()).await.unwrap(), schema);
+    let rows = client.get_rows("t".to_string()).await.unwrap();
+    assert!(matches!(rows[0].0[1], DbValue::Real(x) if x.is_nan()));
+    let without_nan = |row: &Row| Row(row.0.iter().filter(|value| value.get_type() != DbType::Real).cloned().collect());
+    assert_eq!(without_nan(&rows[0]), without_nan(&row));
+    let DbValue::Time(got) = rows[0].0[4] else { panic!("expected a time") };
+    assert_eq!(got.offset(), time.offset());
+
+    // A JSON client is turned away instead of waiting for answers it can't read.
+    let error = db_client::Client::connect(addrs[0], WireFormat::Json).await.err().unwrap();
+    assert_eq!(error.to_string(), "Cannot connect: peer speaks bincode, expected json");
+}
+
+#[tokio::test]
+async fn server_status() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let client = connect(new_server(&ServerConfig::default()));
+    let status = client.status(context::current()).await.unwrap().unwrap();
+    assert_eq!(status, ServerStatus { database: None, uptime_secs: 0, version: env!("CARGO_PKG_VERSION").to_string(), last_autosave: None });
+
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    db.save().unwrap();
+    drop(db);
+    client.open(context::current(), SessionId::NONE, path.clone(), false).await.unwrap().unwrap();
+    let database = |dirty, total_rows| DbStatus {
+        name: "db".to_string(),
+        path: path.clone(),
+        table_count: 1,
+        total_rows,
+        dirty,
+    };
+    let status = client.status(context::current()).await.unwrap().unwrap();
+    assert_eq!(status.database, Some(database(false, 1)));
+
+    client.insert_row(context::current(), SessionId::NONE, "t".to_string(), Row(vec![DbValue::Int(2)])).await.unwrap().unwrap();
+    let status = client.status(context::current()).await.unwrap().unwrap();
+    assert_eq!(status.database, Some(database(true, 2)));
+    client.save(context::current(), SessionId::NONE).await.unwrap().unwrap();
+    let status = client.status(context::current()).await.unwrap().unwrap();
+    assert_eq!(status.database, Some(database(false, 2)));
+}
+
+#[tokio::test]
+async fn close_database() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let other = dir.path().join("other").to_str().unwrap().to_string();
+    let client = connect(server_with_db(&dir, &ServerConfig::default()).await);
+    let insert = |value| client.insert_row(context::current(), SessionId::NONE, "t".to_string(), Row(vec![DbValue::Int(value)]));
+    client.create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::Int]).await.unwrap().unwrap();
+    insert(1).await.unwrap().unwrap();
+
+    let replaced = client.create(context::current(), SessionId::NONE, "other".to_string(), other.clone(), Format::Bincode, false).await;
+    assert_eq!(replaced.unwrap(), Err(DbRpcError::UnsavedChanges));
+    let reopened = client.open(context::current(), SessionId::NONE, path.clone(), false).await.unwrap();
+    assert_eq!(reopened, Err(DbRpcError::UnsavedChanges));
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap().unwrap().len(), 1);
+    // A load that fails leaves the open database as it was, even when forced.
+    let missing = dir.path().join("missing").to_str().unwrap().to_string();
+    assert!(client.open(context::current(), SessionId::NONE, missing, true).await.unwrap().is_err());
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap().unwrap().len(), 1);
+
+    client.close(context::current(), SessionId::NONE, true).await.unwrap().unwrap();
+    assert_eq!(client.get_name(context::current(), SessionId::NONE).await.unwrap(), Err(DbRpcError::NoDatabaseOpen));
+    assert_eq!(client.close(context::current(), SessionId::NONE, false).await.unwrap(), Err(DbRpcError::NoDatabaseOpen));
+    client.open(context::current(), SessionId::NONE, path.clone(), false).await.unwrap().unwrap();
+    let rows = client.get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap().unwrap();
+    assert_eq!(rows, vec![Row(vec![DbValue::Int(1)])]);
+
+    insert(2).await.unwrap().unwrap();
+    client.close(context::current(), SessionId::NONE, false).await.unwrap().unwrap();
+    assert_eq!(insert(3).await.unwrap(), Err(DbRpcError::NoDatabaseOpen));
+    client.open(context::current(), SessionId::NONE, path.clone(), false).await.unwrap().unwrap();
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap().unwrap().len(), 1);
+
+    // Forcing the open file to be opened again drops its unsaved changes.
+    insert(2).await.unwrap().unwrap();
+    client.open(context::current(), SessionId::NONE, path.clone(), true).await.unwrap().unwrap();
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap().unwrap().len(), 1);
+
+    insert(2).await.unwrap().unwrap();
+    client.create(context::current(), SessionId::NONE, "other".to_string(), other, Format::Bincode, true).await.unwrap().unwrap();
+    assert_eq!(client.get_name(context::current(), SessionId::NONE).await.unwrap().unwrap(), "other");
+
+    // Importing replaces the open database like `create` does.
+    let json = dir.path().join("other.json").to_str().unwrap().to_string();
+    client.export_json(context::current(), SessionId::NONE, json.clone(), false).await.unwrap().unwrap();
+    client.create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::Int]).await.unwrap().unwrap();
+    let imported = dir.path().join("imported").to_str().unwrap().to_string();
+    let import = |force| client.import_json(context::current(), SessionId::NONE, json.clone(), imported.clone(), force);
+    assert_eq!(import(false).await.unwrap(), Err(DbRpcError::UnsavedChanges));
+    assert_eq!(client.table_count(context::current(), SessionId::NONE).await.unwrap(), Ok(1));
+    import(true).await.unwrap().unwrap();
+    assert_eq!(client.table_count(context::current(), SessionId::NONE).await.unwrap(), Ok(0));
+}
+
+#[tokio::test]
+async fn periodic_autosave() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let (db, shared) = (DbSlot::default(), Arc::new(Shared::new(&ServerConfig::default())));
+    let client = connect(Server::new(db.clone(), shared.clone()));
+    tokio::spawn(autosave(db, shared, Duration::from_secs(1)));
+    client.create(context::current(), SessionId::NONE, "db".to_string(), path.clone(), Format::Bincode, false).await.unwrap().unwrap();
+    client.create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::Int]).await.unwrap().unwrap();
+    let row = Row(vec![DbValue::Int(1)]);
+    client.insert_row(context::current(), SessionId::NONE, "t".to_string(), row.clone()).await.unwrap().unwrap();
+
+    let mut status = client.status(context::current()).await.unwrap().unwrap();
+    for _ in 0..50 {
+        // The autosave is only recorded once it let go of the database, whose lock the
+        // `open` below needs.
+        if !status.database.as_ref().unwrap().dirty && status.last_autosave.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        status = client.status(context::current()).await.unwrap().unwrap();
+    }
+    assert!(!status.database.unwrap().dirty);
+    assert!(status.last_autosave.is_some());
+
+    client.close(context::current(), SessionId::NONE, false).await.unwrap().unwrap();
+    client.open(context::current(), SessionId::NONE, path, false).await.unwrap().unwrap();
+    assert_eq!(client.get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap(), Ok(vec![row]));
+}
+
+#[tokio::test]
+async fn table_acl() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let config = ServerConfig { admin_token: Some("secret".to_string()), ..ServerConfig::default() };
+    let server = server_with_db(&dir, &config).await;
+    for table in ["logs", "scratch"] {
+        server.clone().create_table(context::current(), SessionId::NONE, table.to_string(), vec![DbType::Int]).await.unwrap();
+    }
+    let acl = |token: &str, table: &str, operations: Option<HashSet<TableOperation>>| {
+        server.clone().set_table_acl(context::current(), SessionId::NONE, token.to_string(), table.to_string(), operations)
+    };
+    assert_eq!(acl("guess", "logs", Some(HashSet::new())).await, Err(DbRpcError::Unauthorized));
+    acl("secret", "logs", Some(HashSet::new())).await.unwrap();
+
+    let insert = |table: &str| server.clone().insert_row(context::current(), SessionId::NONE, table.to_string(), Row(vec![DbValue::Int(1)]));
+    let forbidden = |operation| DbRpcError::Forbidden { table: "logs".to_string(), operation };
+    assert_eq!(insert("logs").await, Err(forbidden(TableOperation::Insert)));
+    let sql = server.clone().execute_sql(context::current(), SessionId::NONE, "INSERT INTO logs VALUES (1)".to_string()).await;
+    assert_eq!(sql.unwrap_err(), forbidden(TableOperation::Insert));
+    let removed = server.clone().remove_table(context::current(), SessionId::NONE, "logs".to_string()).await;
+    assert_eq!(removed, Err(forbidden(TableOperation::Alter)));
+    assert_eq!(server.clone().get_rows(context::current(), SessionId::NONE, "logs".to_string()).await, Ok(vec![]));
+    insert("scratch").await.unwrap();
+
+    acl("secret", "logs", Some(HashSet::from([TableOperation::Insert]))).await.unwrap();
+    insert("logs").await.unwrap();
+    let removed = server.clone().remove_row(context::current(), SessionId::NONE, "logs".to_string(), 0).await;
+    assert_eq!(removed, Err(forbidden(TableOperation::Delete)));
+    acl("secret", "logs", None).await.unwrap();
+    server.clone().remove_row(context::current(), SessionId::NONE, "logs".to_string(), 0).await.unwrap();
+
+    // Calls replacing the whole database need Alter on every restricted table.
+    server.clone().save(context::current(), SessionId::NONE).await.unwrap();
+    let savepoint = server.clone().create_savepoint(context::current(), SessionId::NONE).await.unwrap();
+    let backup = server.clone().backup(context::current(), SessionId::NONE, None).await.unwrap();
+    let json = dir.path().join("db.json").to_str().unwrap().to_string();
+    server.clone().export_json(context::current(), SessionId::NONE, json.clone(), false).await.unwrap();
+    acl("secret", "logs", Some(HashSet::from([TableOperation::Insert]))).await.unwrap();
+    let forbidden = Err(forbidden(TableOperation::Alter));
+    assert_eq!(server.clone().reload(context::current(), SessionId::NONE).await, forbidden);
+    assert_eq!(server.clone().restore_savepoint(context::current(), SessionId::NONE, savepoint).await, forbidden);
+    assert_eq!(server.clone().restore_backup(context::current(), SessionId::NONE, backup).await, forbidden);
+    let imported = dir.path().join("imported").to_str().unwrap().to_string();
+    assert_eq!(server.clone().import_json(context::current(), SessionId::NONE, json, imported, true).await, forbidden);
+    let other = dir.path().join("other").to_str().unwrap().to_string();
+    let created = server.clone().create(context::current(), SessionId::NONE, "other".to_string(), other.clone(), Format::Bincode, false).await;
+    assert_eq!(created, forbidden);
+    assert_eq!(server.clone().open(context::current(), SessionId::NONE, path.clone(), true).await, forbidden);
+    acl("secret", "logs", Some(HashSet::from([TableOperation::Alter]))).await.unwrap();
+    server.clone().reload(context::current(), SessionId::NONE).await.unwrap();
+
+    // Without a configured token nobody can change ACLs.
+    let server = new_server(&ServerConfig::default());
+    let changed = server.clone().set_table_acl(context::current(), SessionId::NONE, String::new(), "logs".to_string(), None).await;
+    assert_eq!(changed, Err(DbRpcError::Unauthorized));
+}
+
+#[tokio::test]
+async fn authentication() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let config = ServerConfig { auth_token: Some("secret".to_string()), ..ServerConfig::default() };
+    let shared = Arc::new(Shared::new(&config));
+    let db = DbSlot::default();
+    let server = Server::new(db.clone(), shared.clone());
+    let created = server.clone().create(context::current(), SessionId::NONE, "db".to_string(), path.clone(), Format::Bincode, false).await;
+    assert_eq!(created, Err(DbRpcError::Unauthorized));
+    assert_eq!(server.clone().authenticate(context::current(), "guess".to_string()).await, Err(DbRpcError::Unauthorized));
+    let session = server.clone().authenticate(context::current(), "secret".to_string()).await.unwrap();
+    server.clone().create(context::current(), session, "db".to_string(), path, Format::Bincode, false).await.unwrap();
+    server.clone().create_table(context::current(), session, "t".to_string(), vec![DbType::Int]).await.unwrap();
+
+    // Reads need the session too, only `protocol_version` and `status` work without one.
+    let rows = |session| server.clone().get_rows(context::current(), session, "t".to_string());
+    assert_eq!(rows(SessionId::NONE).await, Err(DbRpcError::Unauthorized));
+    assert_eq!(rows(SessionId(session.0.wrapping_add(1))).await, Err(DbRpcError::Unauthorized));
+    assert_eq!(rows(session).await, Ok(Vec::new()));
+    assert!(server.clone().status(context::current()).await.unwrap().database.is_some());
+
+    // Sessions don't belong to a connection, so another one can use it.
+    let other = Server::new(db, shared);
+    let row = Row(vec![DbValue::Int(1)]);
+    other.clone().insert_row(context::current(), session, "t".to_string(), row.clone()).await.unwrap();
+    assert_eq!(rows(session).await, Ok(vec![row]));
+
+    // Without a configured token any token and any session will do.
+    let server = new_server(&ServerConfig::default());
+    server.clone().authenticate(context::current(), String::new()).await.unwrap();
+    assert_eq!(server.clone().get_name(context::current(), SessionId::NONE).await, Err(DbRpcError::NoDatabaseOpen));
+}
+
+#[tokio::test]
+async fn session_expiry() {
+    let config = ServerConfig {
+        auth_token: Some("secret".to_string()),
+        session_ttl: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    let client = connect(new_server(&config));
+    let session = client.authenticate(context::current(), "secret".to_string()).await.unwrap().unwrap();
+    // Every call starts the lifetime over.
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let name = client.get_name(context::current(), session).await.unwrap();
+        assert_eq!(name, Err(DbRpcError::NoDatabaseOpen));
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(client.get_name(context::current(), session).await.unwrap(), Err(DbRpcError::Unauthorized));
+
+    let session = client.authenticate(context::current(), "secret".to_string()).await.unwrap().unwrap();
+    assert_eq!(client.get_name(context::current(), session).await.unwrap(), Err(DbRpcError::NoDatabaseOpen));
+}
+
+#[tokio::test]
+async fn startup_database() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let start = |args: &[&str]| {
+        let config = ServerConfig::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let server = new_server(&config);
+        async move { open_startup_db(&config, server.clone()).await.map(|()| server) }
+    };
+
+    let error = start(&["--db", &path]).await.err().unwrap();
+    assert_eq!(error.to_string(), format!("cannot open database {path}"));
+    let server = start(&["--create", "made", &path]).await.unwrap();
+    server.clone().save(context::current(), SessionId::NONE).await.unwrap();
+    drop(server);
+    let server = start(&["--db", &path]).await.unwrap();
+    assert_eq!(server.clone().get_name(context::current(), SessionId::NONE).await, Ok("made".to_string()));
+    drop(server);
+
+    // Without a flag the first call has to open one.
+    let client = connect(start(&[]).await.unwrap());
+    assert_eq!(client.get_name(context::current(), SessionId::NONE).await.unwrap(), Err(DbRpcError::NoDatabaseOpen));
+    client.open(context::current(), SessionId::NONE, path, false).await.unwrap().unwrap();
+    assert_eq!(client.get_name(context::current(), SessionId::NONE).await.unwrap(), Ok("made".to_string()));
+
+    let config = ServerConfig::from_args(["--create", "a", "b"].map(String::from)).unwrap();
+    assert_eq!(config.startup, Some(Startup::Create { name: "a".to_string(), path: "b".to_string() }));
+    assert!(ServerConfig::from_args(["--create", "a"].map(String::from)).is_err());
+    assert!(ServerConfig::from_args(["--db", "a", "--create", "b", "c"].map(String::from)).is_err());
+}
+
+#[tokio::test]
+async fn encrypted_databases() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let config = ServerConfig { passphrase: Some("hunter2".to_string()), ..ServerConfig::default() };
+    let server = new_server(&config);
+
+    server.clone().create(context::current(), SessionId::NONE, "db".to_string(), path.clone(), Format::Json, false).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "plaintext marker".to_string(), vec![DbType::Int]).await.unwrap();
+    server.clone().save(context::current(), SessionId::NONE).await.unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    assert!(!bytes.windows(16).any(|window| window == b"plaintext marker"));
+
+    server.clone().open(context::current(), SessionId::NONE, path, false).await.unwrap();
+    let names = server.clone().get_table_names(context::current(), SessionId::NONE).await;
+    assert_eq!(names, Ok(vec!["plaintext marker".to_string()]));
+}
+
+#[tokio::test]
+async fn export_import_table() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let file = dir.path().join("t.table").to_str().unwrap().to_string();
+    let server = new_server(&ServerConfig::default());
+    let error = server.clone().export_table(context::current(), SessionId::NONE, "t".to_string(), file.clone()).await;
+    assert_eq!(error, Err(DbRpcError::NoDatabaseOpen));
+
+    server.clone().create(context::current(), SessionId::NONE, "db".to_string(), path, Format::Bincode, false).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::Int]).await.unwrap();
+    server.clone().insert_row(context::current(), SessionId::NONE, "t".to_string(), Row(vec![DbValue::Int(1)])).await.unwrap();
+    server.clone().export_table(context::current(), SessionId::NONE, "t".to_string(), file.clone()).await.unwrap();
+    let error = server.clone().import_table(context::current(), SessionId::NONE, file.clone(), None).await.unwrap_err();
+    assert_eq!(error, DbRpcError::TableIsAlreadyPresent("t".to_string()));
+    let name = server.clone().import_table(context::current(), SessionId::NONE, file, Some("copy".to_string())).await.unwrap();
+    assert_eq!(name, "copy");
+    let rows = server.clone().get_rows(context::current(), SessionId::NONE, "copy".to_string()).await.unwrap();
+    assert_eq!(rows, [Row(vec![DbValue::Int(1)])]);
+}
+
+#[tokio::test]
+async fn poll_changes() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let server = new_server(&ServerConfig::default());
+    assert!(server.clone().poll_changes(context::current(), SessionId::NONE, 0).await.unwrap().is_empty());
+
+    server.clone().create(context::current(), SessionId::NONE, "db".to_string(), path.clone(), Format::Bincode, false).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::Int]).await.unwrap();
+    server.clone().insert_row(context::current(), SessionId::NONE, "t".to_string(), Row(vec![DbValue::Int(1)])).await.unwrap();
+    let stats = server.clone().get_stats(context::current(), SessionId::NONE).await.unwrap();
+    assert_eq!(stats, DbStats { table_count: 1, row_count: 1, dirty: true });
+    let summary = server.clone().save(context::current(), SessionId::NONE).await.unwrap();
+    assert_eq!(summary.written, ["t"]);
+    assert!(!server.clone().get_stats(context::current(), SessionId::NONE).await.unwrap().dirty);
+
+    let changes = server.clone().poll_changes(context::current(), SessionId::NONE, 0).await.unwrap();
+    assert_eq!(changes.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert!(matches!(&changes[0].1, ChangeEvent::Mutation(TxOp::CreateTable { name, .. }) if name == "t"));
+    assert!(matches!(&changes[1].1, ChangeEvent::Mutation(TxOp::InsertRow { .. })));
+    assert!(matches!(&changes[2].1, ChangeEvent::Saved { path: saved } if *saved == path));
+
+    let missing = dir.path().join("missing").to_str().unwrap().to_string();
+    let error = server.clone().open(context::current(), SessionId::NONE, missing.clone(), false).await.unwrap_err();
+    assert_eq!(error, DbRpcError::FileNotFound(missing));
+
+    // Reopening continues the sequence.
+    server.clone().open(context::current(), SessionId::NONE, path, false).await.unwrap();
+    server.clone().remove_table(context::current(), SessionId::NONE, "t".to_string()).await.unwrap();
+    let changes = server.clone().poll_changes(context::current(), SessionId::NONE, 3).await.unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].0, 3);
+}
+
+#[tokio::test]
+async fn current_table_is_per_connection() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let db = DbSlot::default();
+    let shared = Arc::new(Shared::new(&ServerConfig::default()));
+    let server = Server::new(db.clone(), shared.clone());
+    let other = Server::new(db, shared);
+
+    server.clone().create(context::current(), SessionId::NONE, "db".to_string(), path, Format::Bincode, false).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "a".to_string(), vec![DbType::Int]).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "b".to_string(), vec![DbType::String]).await.unwrap();
+    server.clone().insert_row(context::current(), SessionId::NONE, "a".to_string(), Row(vec![DbValue::Int(1)])).await.unwrap();
+    assert_eq!(server.clone().get_rows_current(context::current(), SessionId::NONE).await, Err(DbRpcError::NoTableSelected));
+
+    server.clone().use_table(context::current(), SessionId::NONE, "a".to_string()).await.unwrap();
+    server.clone().insert_row_current(context::current(), SessionId::NONE, Row(vec![DbValue::Int(2)])).await.unwrap();
+    assert_eq!(
+        server.clone().get_rows_current(context::current(), SessionId::NONE).await,
+        Ok(vec![Row(vec![DbValue::Int(1)]), Row(vec![DbValue::Int(2)])])
+    );
+    assert_eq!(server.clone().get_table_schema_current(context::current(), SessionId::NONE).await, Ok(vec![DbType::Int]));
+
+    other.clone().use_table(context::current(), SessionId::NONE, "b".to_string()).await.unwrap();
+    assert_eq!(other.clone().get_rows_current(context::current(), SessionId::NONE).await, Ok(vec![]));
+    assert_eq!(server.clone().get_table_schema_current(context::current(), SessionId::NONE).await, Ok(vec![DbType::Int]));
+}
+
+#[tokio::test]
+async fn get_rows_multi() {
+    let dir = tempdir().unwrap();
+    let config = ServerConfig { max_result_rows: 2, ..ServerConfig::default() };
+    let server = server_with_db(&dir, &config).await;
+    server.clone().create_table(context::current(), SessionId::NONE, "a".to_string(), vec![DbType::Int]).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "b".to_string(), vec![DbType::String]).await.unwrap();
+    server.clone().insert_row(context::current(), SessionId::NONE, "a".to_string(), Row(vec![DbValue::Int(1)])).await.unwrap();
+    server.clone().insert_row(context::current(), SessionId::NONE, "b".to_string(), Row(vec![DbValue::String("x".to_string())])).await.unwrap();
+
+    let tables = vec!["a".to_string(), "b".to_string(), "missing".to_string()];
+    let rows = server.clone().get_rows_multi(context::current(), SessionId::NONE, tables.clone()).await.unwrap();
+    let expected = HashMap::from([
+        ("a".to_string(), Some(vec![Row(vec![DbValue::Int(1)])])),
+        ("b".to_string(), Some(vec![Row(vec![DbValue::String("x".to_string())])])),
+        ("missing".to_string(), None),
+    ]);
+    assert_eq!(rows, expected);
+
+    server.clone().insert_row(context::current(), SessionId::NONE, "b".to_string(), Row(vec![DbValue::String("y".to_string())])).await.unwrap();
+    let too_large = server.clone().get_rows_multi(context::current(), SessionId::NONE, tables).await;
+    assert_eq!(too_large, Err(DbRpcError::ResultTooLarge { limit: 2 }));
+}
+
+#[tokio::test]
+async fn result_size_limit() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let config = ServerConfig { max_result_rows: 2, ..ServerConfig::default() };
+    let server = new_server(&config);
+    assert_eq!(server.clone().get_rows(context::current(), SessionId::NONE, "t".to_string()).await, Err(DbRpcError::NoDatabaseOpen));
+
+    server.clone().create(context::current(), SessionId::NONE, "db".to_string(), path, Format::Bincode, false).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "t".to_string(), vec![DbType::Int]).await.unwrap();
+    for value in 0..3 {
+        server.clone().insert_row(context::current(), SessionId::NONE, "t".to_string(), Row(vec![DbValue::Int(value)])).await.unwrap();
+    }
+    let too_large = Err(DbRpcError::ResultTooLarge { limit: 2 });
+    assert_eq!(server.clone().get_rows(context::current(), SessionId::NONE, "t".to_string()).await, too_large);
+    let query = Query::new("t".to_string());
+    assert_eq!(server.clone().run_query(context::current(), SessionId::NONE, query.clone()).await, too_large);
+    assert_eq!(server.clone().run_query(context::current(), SessionId::NONE, query.limit(2)).await.unwrap().len(), 2);
+    let sql = |query: &str| server.clone().execute_sql(context::current(), SessionId::NONE, query.to_string());
+    assert!(matches!(sql("SELECT * FROM t").await, Err(DbRpcError::ResultTooLarge { limit: 2 })));
+    assert!(sql("SELECT * FROM t LIMIT 2").await.is_ok());
+
+    assert_eq!(server.clone().get_row(context::current(), SessionId::NONE, "t".to_string(), 2).await, Ok(Row(vec![DbValue::Int(2)])));
+    assert_eq!(server.clone().get_row(context::current(), SessionId::NONE, "t".to_string(), 3).await, Err(DbRpcError::RowIndexOutOfRange(3)));
+    let missing = DbRpcError::TableIsMissing("missing".to_string());
+    assert_eq!(server.clone().get_row(context::current(), SessionId::NONE, "missing".to_string(), 0).await, Err(missing.clone()));
+
+    server.clone().remove_row(context::current(), SessionId::NONE, "t".to_string(), 0).await.unwrap();
+    assert_eq!(server.clone().get_rows(context::current(), SessionId::NONE, "t".to_string()).await.unwrap().len(), 2);
+    assert_eq!(server.clone().get_rows(context::current(), SessionId::NONE, "missing".to_string()).await, Err(missing));
+    assert_eq!(server.clone().snapshot(context::current(), SessionId::NONE).await.unwrap().tables.len(), 1);
+
+    // Rows from every table count together.
+    server.clone().create_table(context::current(), SessionId::NONE, "u".to_string(), vec![DbType::Int]).await.unwrap();
+    server.clone().insert_row(context::current(), SessionId::NONE, "u".to_string(), Row(vec![DbValue::Int(2)])).await.unwrap();
+    assert_eq!(server.clone().snapshot(context::current(), SessionId::NONE).await, Err(DbRpcError::ResultTooLarge { limit: 2 }));
+    let search = |value| server.clone().search(context::current(), SessionId::NONE, DbValue::Int(value), false);
+    assert_eq!(search(2).await.unwrap().len(), 2);
+    server.clone().insert_row(context::current(), SessionId::NONE, "u".to_string(), Row(vec![DbValue::Int(2)])).await.unwrap();
+    assert_eq!(search(2).await, Err(DbRpcError::ResultTooLarge { limit: 2 }));
+}
+
+#[test]
+fn listen_addresses() {
+    assert_eq!(parse_addr("127.0.0.1:9000").unwrap(), "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(parse_addr("[::1]:9000").unwrap(), std::net::SocketAddr::new(std::net::Ipv6Addr::LOCALHOST.into(), 9000));
+    assert!(parse_addr("::1:9000").is_err());
+    assert!(parse_addr("127.0.0.1").is_err());
+
+    let args = ["--listen", "0.0.0.0:9000", "--listen", "[::]:9000", "--http", "127.0.0.1:9001"];
+    let config = ServerConfig::from_args(args.map(String::from)).unwrap();
+    assert_eq!(config.listen, vec![parse_addr("0.0.0.0:9000").unwrap(), parse_addr("[::]:9000").unwrap()]);
+    assert_eq!(config.http, parse_addr("127.0.0.1:9001").unwrap());
+    assert!(ServerConfig::from_args(["--listen".to_string()]).is_err());
+    assert_eq!(ServerConfig::from_args(Vec::new()).unwrap(), ServerConfig::default());
+}
+
+#[test]
+fn host_and_port() {
+    let config = ServerConfig::from_args(["--host", "0.0.0.0", "--port", "0"].map(String::from)).unwrap();
+    assert_eq!(config.listen, vec![parse_addr("0.0.0.0:0").unwrap()]);
+    let config = ServerConfig::from_args(["--port", "9000"].map(String::from)).unwrap();
+    assert_eq!(config.listen, vec![parse_addr("[::1]:9000").unwrap()]);
+    let config = ServerConfig::from_args(["--host", "[::]"].map(String::from)).unwrap();
+    assert_eq!(config.listen, vec![parse_addr("[::]:8080").unwrap()]);
+    assert!(ServerConfig::from_args(["--host", "localhost"].map(String::from)).is_err());
+    assert!(ServerConfig::from_args(["--port", "65536"].map(String::from)).is_err());
+    assert!(ServerConfig::from_args(["--listen", "[::1]:9000", "--port", "9001"].map(String::from)).is_err());
+    // The environment only applies without address flags.
+    let config = ServerConfig::from_args_or_addr(Vec::new(), Some("127.0.0.1:7000")).unwrap();
+    assert_eq!(config.listen, vec![parse_addr("127.0.0.1:7000").unwrap()]);
+    let config = ServerConfig::from_args_or_addr(["--port", "9000"].map(String::from), Some("127.0.0.1:7000")).unwrap();
+    assert_eq!(config.listen, vec![parse_addr("[::1]:9000").unwrap()]);
+    assert!(ServerConfig::from_args_or_addr(Vec::new(), Some("nowhere")).is_err());
+}
+
+#[test]
+fn wal_flag() {
+    assert_eq!(ServerConfig::default().wal, None);
+    let config = ServerConfig::from_args(["--wal", "never"].map(String::from)).unwrap();
+    assert_eq!(config.wal, Some(FsyncPolicy::Never));
+    assert!(ServerConfig::from_args(["--wal", "sometimes"].map(String::from)).is_err());
+}
+
+#[test]
+fn connection_flags() {
+    let config = ServerConfig::from_args(["--max-frame-length", "1024"].map(String::from)).unwrap();
+    assert_eq!(config.max_frame_length, 1024);
+    let config = ServerConfig::from_args(["--max-channels-per-ip", "2", "--max-connections", "4"].map(String::from)).unwrap();
+    assert_eq!((config.max_channels_per_ip, config.max_connections), (2, 4));
+    // Without a single connection allowed the server would serve nobody.
+    assert!(ServerConfig::from_args(["--max-channels-per-ip", "0"].map(String::from)).is_err());
+    assert!(ServerConfig::from_args(["--max-connections", "0"].map(String::from)).is_err());
+    let config = ServerConfig::from_args(["--handshake-timeout-secs", "3"].map(String::from)).unwrap();
+    assert_eq!(config.handshake_timeout, Duration::from_secs(3));
+    assert!(ServerConfig::from_args(["--handshake-timeout-secs", "0"].map(String::from)).is_err());
+}
+
+#[test]
+fn autosave_and_log_flags() {
+    let config = ServerConfig::from_args(["--autosave-secs", "30"].map(String::from)).unwrap();
+    assert_eq!(config.autosave_secs, Some(30));
+    assert!(ServerConfig::from_args(["--autosave-secs", "0"].map(String::from)).is_err());
+    let config = ServerConfig::from_args(["--log-level", "warn,db_server=debug"].map(String::from)).unwrap();
+    assert_eq!(config.log_level.as_deref(), Some("warn,db_server=debug"));
+    assert!(ServerConfig::from_args(["--log-level", "db_server=loud"].map(String::from)).is_err());
+}
+
+#[test]
+fn auth_flags() {
+    let dir = tempdir().unwrap();
+    let token_file = dir.path().join("token");
+    std::fs::write(&token_file, "secret\n").unwrap();
+    let args = ["--auth-token-file", token_file.to_str().unwrap(), "--session-ttl-secs", "60"].map(String::from);
+    let config = ServerConfig::from_args(args).unwrap();
+    assert_eq!((config.auth_token.as_deref(), config.session_ttl), (Some("secret"), Duration::from_secs(60)));
+    let config = ServerConfig::from_args(["--auth-token", "secret"].map(String::from)).unwrap();
+    assert_eq!(config.auth_token.as_deref(), Some("secret"));
+    assert!(ServerConfig::from_args(["--session-ttl-secs", "0"].map(String::from)).is_err());
+}
+
+#[test]
+fn transport_flags() {
+    let config = ServerConfig::from_args(["--tls-cert", "cert.pem", "--tls-key", "key.pem"].map(String::from)).unwrap();
+    let tls = config.tls.unwrap();
+    assert_eq!((tls.cert.to_str(), tls.key.to_str()), (Some("cert.pem"), Some("key.pem")));
+    assert!(ServerConfig::from_args(["--tls-cert", "cert.pem"].map(String::from)).is_err());
+    let config = ServerConfig::from_args(["--wire-format", "bincode"].map(String::from)).unwrap();
+    assert_eq!(config.wire_format, WireFormat::Bincode);
+    assert!(ServerConfig::from_args(["--wire-format", "xml"].map(String::from)).is_err());
+}
+
+#[test]
+fn usage_lists_every_flag() {
+    let usage = crate::config::usage();
+    let flags: Vec<Vec<&str>> = usage
+        .lines()
+        .filter_map(|line| line.strip_prefix("  --"))
+        .map(|line| line.split("  ").next().unwrap().split(' ').collect())
+        .collect();
+    assert!(flags.len() > 20);
+    for flag in flags.into_iter().filter(|flag| flag[0] != "help") {
+        // Each listed flag is read, whether or not `1` is a valid value for it.
+        let args = std::iter::once(format!("--{}", flag[0])).chain(flag[1..].iter().map(|_| "1".to_string()));
+        let _ = ServerConfig::from_args(args);
+    }
+    let error = ServerConfig::from_args(["--bogus", "1"].map(String::from)).unwrap_err();
+    assert_eq!(error.to_string(), "unknown argument --bogus, see --help");
+}
+
+#[tokio::test]
+async fn savepoints() {
+    let dir = tempdir().unwrap();
+    let path = db_path(&dir);
+    let config = ServerConfig { max_savepoints: 2, ..ServerConfig::default() };
+    let server = new_server(&config);
+    assert_eq!(server.clone().create_savepoint(context::current(), SessionId::NONE).await, Err(DbRpcError::NoDatabaseOpen));
+
+    server.clone().create(context::current(), SessionId::NONE, "db".to_string(), path, Format::Bincode, false).await.unwrap();
+    let empty = server.clone().create_savepoint(context::current(), SessionId::NONE).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "a".to_string(), vec![DbType::Int]).await.unwrap();
+    let one = server.clone().create_savepoint(context::current(), SessionId::NONE).await.unwrap();
+    server.clone().create_table(context::current(), SessionId::NONE, "b".to_string(), vec![DbType::Int]).await.unwrap();
+    let two = server.clone().create_savepoint(context::current(), SessionId::NONE).await.unwrap();
+
+    // Only the two most recent savepoints are kept.
+    let evicted = server.clone().restore_savepoint(context::current(), SessionId::NONE, empty).await;
+    assert_eq!(evicted, Err(DbRpcError::UnknownSavepoint(empty)));
+    server.clone().restore_savepoint(context::current(), SessionId::NONE, one).await.unwrap();
+    assert_eq!(server.clone().table_count(context::current(), SessionId::NONE).await, Ok(1));
+    server.clone().restore_savepoint(context::current(), SessionId::NONE, two).await.unwrap();
+    assert_eq!(server.clone().table_count(context::current(), SessionId::NONE).await, Ok(2));
+
+    // Savepoints of one database can't be restored into the next one.
+    let other = dir.path().join("other").to_str().unwrap().to_string();
+    server.clone().create(context::current(), SessionId::NONE, "other".to_string(), other, Format::Bincode, true).await.unwrap();
+    let restored = server.clone().restore_savepoint(context::current(), SessionId::NONE, two).await;
+    assert_eq!(restored, Err(DbRpcError::UnknownSavepoint(two)));
+    assert_eq!(server.clone().table_count(context::current(), SessionId::NONE).await, Ok(0));
+    let three = server.clone().create_savepoint(context::current(), SessionId::NONE).await.unwrap();
+    assert!(three > two);
+    server.clone().close(context::current(), SessionId::NONE, false).await.unwrap();
+    let restored = server.clone().restore_savepoint(context::current(), SessionId::NONE, three).await;
+    assert_eq!(restored, Err(DbRpcError::UnknownSavepoint(three)));
+}
+
+#[test]
+fn channel_key_falls_back_on_error() {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    let v4 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    assert_eq!(channel_key(Ok(parse_addr("127.0.0.1:5000").unwrap())), v4);
+    assert_eq!(channel_key(Ok(parse_addr("[::ffff:127.0.0.1]:5000").unwrap())), v4);
+    assert_eq!(channel_key(Ok(parse_addr("[::1]:5000").unwrap())), IpAddr::V6(Ipv6Addr::LOCALHOST));
+    let closed = std::io::Error::from(std::io::ErrorKind::NotConnected);
+    assert_eq!(channel_key(Err(closed)), IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+}
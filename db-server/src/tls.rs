@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsFiles;
+
+/// Accepts TLS connections with the certificate chain and key of `files`, both PEM.
+pub fn acceptor(files: &TlsFiles) -> anyhow::Result<TlsAcceptor> {
+    let cert_path = files.cert.display();
+    let certs = rustls_pemfile::certs(&mut reader(&files.cert)?).with_context(|| format!("invalid certificate {cert_path}"))?;
+    if certs.is_empty() {
+        bail!("no certificate in {cert_path}");
+    }
+    let key_path = files.key.display();
+    let key = rustls_pemfile::read_all(&mut reader(&files.key)?)
+        .with_context(|| format!("invalid private key {key_path}"))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .with_context(|| format!("no private key in {key_path}"))?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+        .with_context(|| format!("cannot use certificate {cert_path} with key {key_path}"))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn reader(path: &std::path::Path) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("cannot read {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
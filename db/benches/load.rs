@@ -0,0 +1,33 @@
+//! Smoke benchmark of loading a large database with serial and parallel row validation:
+//! `cargo bench -p db --bench load`.
+use db::{DbType, DbValue, LoadOptions, Row, SavedDatabase};
+use std::time::Instant;
+
+const TABLES: usize = 8;
+const ROWS: i64 = 200_000;
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("bench".to_string(), path.clone()).unwrap();
+    for t in 0..TABLES {
+        let name = format!("t{t}");
+        db.create_table(name.clone(), vec![DbType::Int, DbType::String]).unwrap();
+        for i in 0..ROWS {
+            db.insert_row(name.clone(), Row(vec![DbValue::Int(i), DbValue::String(i.to_string())])).unwrap();
+        }
+    }
+    db.save().unwrap();
+    drop(db);
+
+    let runs = [
+        ("unvalidated", LoadOptions { validate: false, parallel: false, ..LoadOptions::default() }),
+        ("serial", LoadOptions { validate: true, parallel: false, ..LoadOptions::default() }),
+        ("parallel", LoadOptions { validate: true, parallel: true, ..LoadOptions::default() }),
+    ];
+    for (label, options) in runs {
+        let start = Instant::now();
+        let db = SavedDatabase::load_from_disk_with(&path, options).unwrap();
+        println!("{label:>12}: {:?} for {} tables", start.elapsed(), db.table_count());
+    }
+}
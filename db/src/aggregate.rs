@@ -0,0 +1,103 @@
+use crate::table::Table;
+use crate::types::{DbError, DbType, DbValue};
+use serde::{Deserialize, Serialize};
+
+/// Statistic over a numeric column, see `Table::aggregate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregate {
+    /// Of the column's own type.
+    Sum,
+    Avg,
+    /// Population variance, so a single row has variance 0.
+    Variance,
+    StdDev,
+}
+
+/// Running mean and sum of squared deviations, updated with Welford's algorithm so that
+/// large values with a small spread keep their precision.
+#[derive(Default)]
+struct Moments {
+    count: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    fn push(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn variance(&self) -> f64 {
+        self.m2 / self.count as f64
+    }
+}
+
+impl Table {
+    /// Computes `aggregate` over an `Int`, `UInt` or `Real` column in a single pass.
+    /// `Avg`, `Variance` and `StdDev` are `Real` and `None` for an empty table; `Sum`
+    /// is 0 then and fails with `SumOverflow` if it doesn't fit the column's type.
+    pub fn aggregate(&self, column: usize, aggregate: Aggregate) -> Result<Option<DbValue>, DbError> {
+        let r#type = *self.schema().get(column).ok_or(DbError::ColumnOutOfRange(column))?;
+        if !matches!(r#type, DbType::Int | DbType::UInt | DbType::Real) {
+            return Err(DbError::ColumnTypeMismatch {
+                column,
+                expected: DbType::Real,
+                got: r#type,
+            });
+        }
+        let values = self.rows().iter().map(|row| &row.0[column]);
+        if aggregate == Aggregate::Sum {
+            return sum(r#type, values).map(Some).ok_or(DbError::SumOverflow(column));
+        }
+
+        let mut moments = Moments::default();
+        for value in values {
+            moments.push(match *value {
+                DbValue::Int(value) => value as f64,
+                DbValue::UInt(value) => value as f64,
+                DbValue::Real(value) => value,
+                _ => unreachable!("rows match the schema"),
+            });
+        }
+        if moments.count == 0 {
+            return Ok(None);
+        }
+        Ok(Some(DbValue::Real(match aggregate {
+            Aggregate::Sum => unreachable!("handled above"),
+            Aggregate::Avg => moments.mean,
+            Aggregate::Variance => moments.variance(),
+            Aggregate::StdDev => moments.variance().sqrt(),
+        })))
+    }
+}
+
+/// `None` on overflow.
+fn sum<'a>(r#type: DbType, values: impl Iterator<Item = &'a DbValue>) -> Option<DbValue> {
+    match r#type {
+        DbType::Int => values
+            .map(|value| match value {
+                DbValue::Int(value) => *value,
+                _ => unreachable!("rows match the schema"),
+            })
+            .try_fold(0i64, i64::checked_add)
+            .map(DbValue::Int),
+        DbType::UInt => values
+            .map(|value| match value {
+                DbValue::UInt(value) => *value,
+                _ => unreachable!("rows match the schema"),
+            })
+            .try_fold(0u64, u64::checked_add)
+            .map(DbValue::UInt),
+        _ => Some(DbValue::Real(
+            values
+                .map(|value| match value {
+                    DbValue::Real(value) => *value,
+                    _ => unreachable!("rows match the schema"),
+                })
+                .sum(),
+        )),
+    }
+}
@@ -0,0 +1,74 @@
+use crate::database::SavedDatabase;
+use crate::types::DbError;
+use serde::{Deserialize, Serialize};
+
+/// When a database saves itself without an explicit `save`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AutosavePolicy {
+    /// Save unsaved changes when the database is dropped.
+    OnDrop,
+    /// Save after this many successful mutations since the last save.
+    EveryNMutations(usize),
+}
+
+/// Clones of the database start without a policy, so that dropping a clone never
+/// overwrites the original's file.
+#[derive(Debug, Default)]
+pub(crate) struct Autosave {
+    policy: Option<AutosavePolicy>,
+    mutations: usize,
+}
+
+impl Clone for Autosave {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Autosave {
+    pub(crate) fn reset(&mut self) {
+        self.mutations = 0;
+    }
+
+    /// Counts a mutation and tells whether it is time to save.
+    pub(crate) fn count(&mut self) -> bool {
+        self.mutations += 1;
+        matches!(self.policy, Some(AutosavePolicy::EveryNMutations(n)) if self.mutations >= n)
+    }
+}
+
+impl SavedDatabase {
+    /// Opts into saving automatically. A failed save after a mutation is returned by that
+    /// mutation, which stays applied. A failed save on drop can only be logged, so call
+    /// `close` to get the error instead.
+    pub fn autosave(&mut self, policy: AutosavePolicy) {
+        self.autosave = Autosave { policy: Some(policy), mutations: 0 };
+    }
+
+    /// Drops the database, first saving unsaved changes under `AutosavePolicy::OnDrop` and
+    /// returning the error if that fails.
+    pub fn close(mut self) -> Result<(), DbError> {
+        let policy = self.autosave.policy.take();
+        if policy == Some(AutosavePolicy::OnDrop) && self.is_dirty() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn autosave_after_mutation(&mut self) -> Result<(), DbError> {
+        if self.autosave.count() {
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SavedDatabase {
+    fn drop(&mut self) {
+        if self.autosave.policy == Some(AutosavePolicy::OnDrop) && self.is_dirty() {
+            if let Err(error) = self.save() {
+                tracing::error!(path = %self.path().display(), %error, "autosave on drop failed");
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::database::{DbSnapshot, SavedDatabase};
+use crate::format::{self, StorageOptions};
+use crate::layout;
+use crate::migrations;
+use crate::types::DbError;
+use chrono::Utc;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const BACKUP_EXTENSION: &str = "dbbak";
+
+impl SavedDatabase {
+    /// Keeps at most `max` backups per directory, deleting the oldest after each new one.
+    pub fn set_max_backups(&mut self, max: Option<usize>) {
+        self.max_backups = max;
+    }
+
+    /// Directory backups go to when none is given: the one holding the database.
+    fn backup_dir(&self) -> PathBuf {
+        self.path()
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf()
+    }
+
+    /// Prefix of backup file names, the database name with anything but letters, digits,
+    /// `-` and `_` replaced.
+    fn backup_prefix(&self) -> String {
+        let name: String = self
+            .get_name()
+            .chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        format!("{name}-")
+    }
+
+    /// Writes the in-memory state, saved or not, to `<name>-YYYYMMDD-HHMMSS.dbbak` in
+    /// `dir` or next to the database, using UTC. Further backups within the same second
+    /// get `-1`, `-2`, ... appended to the time instead of replacing it.
+    pub fn backup(&self, dir: Option<&Path>) -> Result<PathBuf, DbError> {
+        let dir = dir.map_or_else(|| self.backup_dir(), Path::to_path_buf);
+        fs::create_dir_all(&dir)?;
+        let stamp = format!("{}{}", self.backup_prefix(), Utc::now().format("%Y%m%d-%H%M%S"));
+        let mut path = dir.join(format!("{stamp}.{BACKUP_EXTENSION}"));
+        for counter in 1.. {
+            if !path.exists() {
+                break;
+            }
+            path = dir.join(format!("{stamp}-{counter}.{BACKUP_EXTENSION}"));
+        }
+        let options = StorageOptions { format: self.format(), compression: self.compression() };
+        layout::write_atomic(&path, &format::encode(&*self.complete_db()?, options, self.encryption.as_ref())?)?;
+
+        if let Some(max) = self.max_backups {
+            let backups = self.list_backups(Some(&dir))?;
+            for old in &backups[..backups.len().saturating_sub(max)] {
+                fs::remove_file(old)?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Backups of this database in `dir` or next to the database, oldest first. Only
+    /// names `backup` writes count, so backups of a database whose name extends this
+    /// one's, like `db-x` for `db`, are left out.
+    pub fn list_backups(&self, dir: Option<&Path>) -> Result<Vec<PathBuf>, DbError> {
+        let dir = dir.map_or_else(|| self.backup_dir(), Path::to_path_buf);
+        let prefix = self.backup_prefix();
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let order = path.file_name().and_then(|name| name.to_str()).and_then(|name| backup_order(name, &prefix));
+            if let Some(order) = order {
+                backups.push((order, path));
+            }
+        }
+        backups.sort();
+        Ok(backups.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Replaces the in-memory state with the backup at `path`, like `restore`. The
+    /// database's own file is only changed by the next save.
+    pub fn restore_backup(&mut self, path: impl AsRef<Path>) -> Result<(), DbError> {
+        self.check_mutable()?;
+        let path = path.as_ref();
+        let decoded = migrations::migrate(&fs::read(path)?, self.unlock(), path)?;
+        for table in decoded.value.tables.values() {
+            table.validate_rows()?;
+        }
+        self.restore(DbSnapshot(decoded.value))
+    }
+}
+
+/// Time stamp and counter of `name` if it is `<prefix>YYYYMMDD-HHMMSS[-N].dbbak`, which
+/// sort chronologically.
+fn backup_order(name: &str, prefix: &str) -> Option<(String, u64)> {
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+    let rest = name.strip_prefix(prefix)?.strip_suffix(BACKUP_EXTENSION)?.strip_suffix('.')?;
+    let (stamp, counter) = match rest.get(15..)? {
+        "" => (rest, 0),
+        counter => (&rest[..15], counter.strip_prefix('-').filter(|counter| digits(counter))?.parse().ok()?),
+    };
+    let (date, time) = stamp.split_once('-')?;
+    (date.len() == 8 && time.len() == 6 && digits(date) && digits(time)).then(|| (stamp.to_string(), counter))
+}
@@ -1,78 +1,726 @@
-use crate::{Row, table::Table, types::{DbError, DbType}};
+use crate::{Row, autosave::Autosave, migrations, encryption::{Encryption, Unlock}, events::{ChangeEvent, Subscribers}, format::{self, Compression, Decoded, Format, StorageOptions}, layout::{self, Layout}, lock::DbLock, table::{CheckConstraint, ColumnDefault, Table, MAX_ROWS}, types::{DbError, DbType, DbValue}, wal::{self, FsyncPolicy, TxOp}};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::{Entry, HashMap};
-use std::fs::{create_dir_all, read, File};
-use std::io::Write;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::hash_map::{DefaultHasher, Entry, HashMap};
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::fs::{create_dir_all, metadata, read, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "mmap")]
+use crate::mmap::MappedTables;
 
 #[derive(Debug, Clone)]
 pub struct SavedDatabase {
-    db: Database,
-    path: String,
+    pub(crate) db: Database,
+    path: PathBuf,
+    wal: Option<FsyncPolicy>,
+    layout: Layout,
+    storage: StorageOptions,
+    pub(crate) encryption: Option<Encryption>,
+    report: LoadReport,
+    /// Set by every mutation and cleared by saving. Tables track their own changes too,
+    /// this also covers removed tables.
+    dirty: bool,
+    pub(crate) autosave: Autosave,
+    pub(crate) max_backups: Option<usize>,
+    /// Shared by clones, which may save to the same file.
+    lock: Option<Arc<DbLock>>,
+    pub(crate) events: Subscribers,
+    /// Hash of the unencrypted content the last save wrote to `path`, so that saving the
+    /// same content again is skipped.
+    saved_hash: Option<u64>,
+    /// Set by `open_mmap`.
+    #[cfg(feature = "mmap")]
+    pub(crate) mapped: Option<Arc<MappedTables>>,
 }
 
+/// Tables a save wrote, sorted by name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SaveSummary {
+    /// Whether anything was written; unset when the content on disk was already up to
+    /// date.
+    pub wrote: bool,
+    pub written: Vec<String>,
+}
+
+/// How `load_from_disk_with` locks and checks what it reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadOptions {
+    /// Check that every row fits the schema of its table. Only files this library wrote
+    /// itself are safe to load without.
+    pub validate: bool,
+    /// Validate tables, and chunks of rows of large ones, on rayon's thread pool. On by
+    /// default with the `parallel` feature, without which validation is serial regardless.
+    pub parallel: bool,
+    /// Load without the lock instead of failing with `DatabaseLocked` when another
+    /// database holds it, e.g. a hung process or a filesystem keeping the lock of a dead
+    /// one. Saves then race with the holder's, and a corrupt write-ahead log isn't
+    /// repaired.
+    pub ignore_lock: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self { validate: true, parallel: cfg!(feature = "parallel"), ignore_lock: false }
+    }
+}
+
+/// What `load_from_disk` noticed about the files it read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Some file carried no checksum, so bit rot in it could go unnoticed. That is the
+    /// case for plain JSON and for files written before checksums were added.
+    pub missing_checksum: bool,
+    /// Operations replayed from the write-ahead log.
+    pub replayed: usize,
+    /// Byte offset in the write-ahead log of a torn or corrupt record. It and everything
+    /// after it was not replayed, and was cut off the log if the database was loaded for
+    /// writing.
+    pub corrupt_wal_record: Option<u64>,
+}
+
+/// Native serde representation of a whole database, tables sorted by name.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DatabaseSnapshot {
+    pub name: String,
+    pub tables: Vec<(String, Vec<DbType>, Vec<Row>)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TableInfo {
+    pub name: String,
+    pub schema: Vec<DbType>,
+    pub row_count: usize,
+    pub materialized: Option<MaterializedInfo>,
+}
+
+/// Overview of a whole database, e.g. for a status bar.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DbStats {
+    pub table_count: usize,
+    pub row_count: usize,
+    /// There are unsaved changes.
+    pub dirty: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MaterializedInfo {
+    pub source: String,
+    pub stale: bool,
+    pub orphaned: bool,
+}
+
+/// In-memory copy of a database taken by `SavedDatabase::savepoint`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Database {
-    name: String,
-    tables: HashMap<String, Table>,
+pub struct DbSnapshot(pub(crate) Database);
+
+/// How a materialized projection was derived and which source version it reflects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Materialization {
+    pub(crate) source: String,
+    pub(crate) columns: Vec<bool>,
+    pub(crate) source_version: u64,
+    pub(crate) orphaned: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Database {
+    pub(crate) name: String,
+    pub(crate) tables: HashMap<String, Table>,
+    pub(crate) materialized: HashMap<String, Materialization>,
 }
 
 impl SavedDatabase {
-    pub fn create(name: String, path: String) -> Result<Self, DbError> {
+    pub fn create(name: String, path: impl Into<PathBuf>) -> Result<Self, DbError> {
+        Self::create_with(name, path, StorageOptions::default())
+    }
+
+    /// Creates a database saved with `options`, which may also be just a `Format` or `Compression`.
+    /// Like `load_from_disk`, this locks `path` for as long as the database is alive.
+    pub fn create_with(name: String, path: impl Into<PathBuf>, options: impl Into<StorageOptions>) -> Result<Self, DbError> {
+        let path = path.into();
+        let lock = DbLock::exclusive(&path)?;
+        let mut pinned_db = Self::create_with_layout(name, path, Layout::File);
+        pinned_db.lock = Some(Arc::new(lock));
+        pinned_db.storage = options.into();
+        pinned_db.save()?;
+
+        Ok(pinned_db)
+    }
+
+    /// Creates a database whose file is encrypted with a key derived from `passphrase`,
+    /// which `load_from_disk_encrypted` needs to open it again. Backups and the records
+    /// of the write-ahead log are encrypted too.
+    pub fn create_encrypted(name: String, path: impl Into<PathBuf>, passphrase: &str) -> Result<Self, DbError> {
+        let path = path.into();
+        let lock = DbLock::exclusive(&path)?;
+        let mut db = Self::create_with_layout(name, path, Layout::File);
+        db.lock = Some(Arc::new(lock));
+        db.encryption = Some(Encryption::new(passphrase));
+        db.save()?;
+
+        Ok(db)
+    }
+
+    pub(crate) fn create_with_layout(name: String, path: PathBuf, layout: Layout) -> Self {
         let db = Database {
             name,
             tables: HashMap::new(),
+            materialized: HashMap::new(),
         };
-        let pinned_db = Self { db, path };
-        pinned_db.save()?;
+        Self { db, path, wal: None, layout, storage: StorageOptions::default(), encryption: None, report: LoadReport::default(), dirty: true, autosave: Autosave::default(), max_backups: None, lock: None, events: Subscribers::default(), saved_hash: None, #[cfg(feature = "mmap")] mapped: None }
+    }
 
-        Ok(pinned_db)
+    /// In the directory layout only tables changed since the last save are rewritten; a
+    /// single file always holds every table, but isn't written when nothing changed since
+    /// the last save or when it would get the same content again.
+    pub fn save(&mut self) -> Result<SaveSummary, DbError> {
+        self.save_tables(false)
+    }
+
+    /// Rewrites every table, whether it changed or not.
+    pub fn save_full(&mut self) -> Result<SaveSummary, DbError> {
+        self.save_tables(true)
+    }
+
+    fn save_tables(&mut self, all: bool) -> Result<SaveSummary, DbError> {
+        self.check_writable()?;
+        // Without a hash, the file at `path` may not be what this database last wrote.
+        let (summary, hash) = if all || self.is_dirty() || self.saved_hash.is_none() {
+            self.write_to(&self.path, all, if all { None } else { self.saved_hash })?
+        } else {
+            (SaveSummary::default(), self.saved_hash)
+        };
+        self.saved_hash = hash;
+        self.mark_clean();
+        if self.wal.is_some() {
+            wal::truncate(&self.path)?;
+        }
+        if summary.wrote {
+            self.events.emit(ChangeEvent::Saved { path: self.path.clone() });
+        }
+
+        Ok(summary)
+    }
+
+    /// Writes the database to `new_path`, which must not exist unless `overwrite` is set.
+    /// With `switch` later saves go to `new_path` and the original file is left as it was.
+    /// Tables of a database opened with `open_mmap` are decoded first.
+    pub fn save_as(&mut self, new_path: impl Into<PathBuf>, switch: bool, overwrite: bool) -> Result<(), DbError> {
+        let new_path = new_path.into();
+        if !overwrite && new_path.exists() {
+            return Err(DbError::FileExists(new_path.display().to_string()));
+        }
+        if new_path == self.path {
+            self.check_writable()?;
+        }
+        let lock = if switch && new_path != self.path {
+            Some(DbLock::exclusive(&new_path)?)
+        } else {
+            None
+        };
+        let (_, hash) = self.write_to(&new_path, true, None)?;
+        self.events.emit(ChangeEvent::Saved { path: new_path.clone() });
+        if switch {
+            self.saved_hash = hash;
+            if let Some(lock) = lock {
+                self.lock = Some(Arc::new(lock));
+            }
+            self.path = new_path;
+            self.mark_clean();
+            if self.wal.is_some() {
+                wal::truncate(&self.path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// Switches to a key derived from `new`, which the next save encrypts with. Fails with
+    /// `BadPassphrase` unless `old` is the current passphrase. With the write-ahead log
+    /// enabled the database is saved right away, so that the log never holds records
+    /// sealed with a key the file can't be opened with.
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), DbError> {
+        let encryption = self.encryption.as_ref().ok_or(DbError::NotEncrypted)?;
+        if !encryption.matches(old) {
+            return Err(DbError::BadPassphrase);
+        }
+        let previous = self.encryption.replace(Encryption::new(new));
+        self.saved_hash = None;
+        self.mark_dirty();
+        if self.wal.is_none() {
+            return self.autosave_after_mutation();
+        }
+        if let Err(error) = self.save() {
+            self.encryption = previous;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unlock(&self) -> Unlock<'_> {
+        self.encryption.as_ref().map_or(Unlock::Nothing, Unlock::Key)
+    }
+
+    pub(crate) fn set_lock(&mut self, lock: DbLock) {
+        self.lock = Some(Arc::new(lock));
+    }
+
+    /// Fails with `MemoryMapped` for databases opened with `open_mmap`.
+    pub(crate) fn check_mutable(&self) -> Result<(), DbError> {
+        #[cfg(feature = "mmap")]
+        if self.mapped.is_some() {
+            return Err(DbError::MemoryMapped);
+        }
+        Ok(())
     }
 
-    pub fn save(&self) -> Result<(), DbError> {
-        let path = Path::new(&self.path);
-        if let Some(prefix) = path.parent() {
-            create_dir_all(prefix).unwrap();
+    fn check_writable(&self) -> Result<(), DbError> {
+        if self.lock.as_ref().is_some_and(|lock| lock.shared) {
+            return Err(DbError::ReadOnly);
         }
-        let mut file = File::create(path)?;
-        let content = bincode::serialize(&self.db)?;
-        file.write_all(&content)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> Format {
+        self.storage.format
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.storage.compression
+    }
+
+    /// Saves in `format`, which later saves keep using.
+    pub fn save_in(&mut self, format: Format) -> Result<SaveSummary, DbError> {
+        self.set_format(format);
+        self.save()
+    }
+
+    /// The next save writes the whole database in `format`.
+    pub fn set_format(&mut self, format: Format) {
+        self.storage.format = format;
+        self.mark_dirty();
+    }
+
+    /// The next save writes the whole database with `compression`.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.storage.compression = compression;
+        self.mark_dirty();
+    }
 
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+        for table in self.db.tables.values_mut() {
+            table.mark_dirty();
+        }
+    }
+
+    /// The database with every table, those `open_mmap` hasn't decoded yet included.
+    pub(crate) fn complete_db(&self) -> Result<Cow<'_, Database>, DbError> {
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = self.mapped.as_ref().filter(|mapped| mapped.len() > 0) {
+            let mut db = self.db.clone();
+            for name in mapped.names() {
+                let table = mapped.table(name).expect("name is mapped")?;
+                db.tables.insert(name.clone(), table.clone());
+            }
+            return Ok(Cow::Owned(db));
+        }
+        Ok(Cow::Borrowed(&self.db))
+    }
+
+    /// Every table sorted by name, those `open_mmap` hasn't decoded yet included.
+    pub(crate) fn sorted_tables(&self) -> Result<Vec<(&String, &Table)>, DbError> {
+        let mut tables: Vec<(&String, &Table)> = self.db.tables.iter().collect();
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &self.mapped {
+            for name in mapped.names() {
+                tables.push((name, mapped.table(name).expect("name is mapped")?));
+            }
+        }
+        tables.sort_by(|a, b| a.0.cmp(b.0));
+        Ok(tables)
+    }
+
+    /// Writes the database to `path`. A single file is not written if its content hashes
+    /// to `unchanged`; returns the hash of the content for the next save to compare.
+    fn write_to(&self, path: &Path, all: bool, unchanged: Option<u64>) -> Result<(SaveSummary, Option<u64>), DbError> {
+        let db = self.complete_db()?;
+        let (mut written, hash) = if self.layout == Layout::Directory {
+            (layout::write_dir(&db, path, all, self.storage)?, None)
+        } else {
+            // Renaming over a directory fails with an error that doesn't name the path.
+            if path.is_dir() {
+                return Err(DbError::SavePathIsADirectory(path.display().to_string()));
+            }
+            if let Some(prefix) = path.parent() {
+                create_dir_all(prefix)?;
+            }
+            // Encrypting uses a fresh nonce every time, so the content before encryption
+            // is what's compared.
+            let content = format::encode(&*db, self.storage, None)?;
+            let mut hasher = DefaultHasher::new();
+            content.hash(&mut hasher);
+            let hash = hasher.finish();
+            if unchanged == Some(hash) {
+                return Ok((SaveSummary::default(), unchanged));
+            }
+            let content = match &self.encryption {
+                Some(encryption) => format::encode(&*db, self.storage, Some(encryption))?,
+                None => content,
+            };
+            layout::write_atomic(path, &content)?;
+            (self.get_table_names(), Some(hash))
+        };
+        written.sort();
+        Ok((SaveSummary { wrote: true, written }, hash))
+    }
+
+    fn mark_clean(&mut self) {
+        self.dirty = false;
+        self.autosave.reset();
+        for table in self.db.tables.values_mut() {
+            table.mark_clean();
+        }
+    }
+
+    /// Loads the file or directory at `path` and replays any write-ahead log left next to it,
+    /// in which case logging stays enabled. Fails with `DatabaseLocked` while another
+    /// database, in this or another process, has `path` open.
+    pub fn load_from_disk(path: impl Into<PathBuf>) -> Result<Self, DbError> {
+        Self::load_from_disk_with(path, LoadOptions::default())
+    }
+
+    /// `load_from_disk` with control over locking and how rows are validated.
+    pub fn load_from_disk_with(path: impl Into<PathBuf>, options: LoadOptions) -> Result<Self, DbError> {
+        let path = path.into();
+        Self::check_loadable(&path)?;
+        let lock = match DbLock::exclusive(&path) {
+            Ok(lock) => Some(Arc::new(lock)),
+            Err(DbError::DatabaseLocked { .. }) if options.ignore_lock => None,
+            Err(error) => return Err(error),
+        };
+        let mut db = Self::load_unlocked_with(path, Unlock::Nothing, options)?;
+        db.lock = lock;
+        db.repair_wal()?;
+        Ok(db)
+    }
+
+    /// Loads a database created by `create_encrypted`, failing with `BadPassphrase` if
+    /// `passphrase` is wrong and with `NotEncrypted` if the file isn't encrypted. Saves
+    /// keep encrypting with the same key.
+    pub fn load_from_disk_encrypted(path: impl Into<PathBuf>, passphrase: &str) -> Result<Self, DbError> {
+        let path = path.into();
+        Self::check_loadable(&path)?;
+        let lock = DbLock::exclusive(&path)?;
+        let mut db = Self::load_unlocked_with(path, Unlock::Passphrase(passphrase), LoadOptions::default())?;
+        if db.encryption.is_none() {
+            return Err(DbError::NotEncrypted);
+        }
+        db.lock = Some(Arc::new(lock));
+        db.repair_wal()?;
+        Ok(db)
+    }
+
+    /// Loads `path` like `load_from_disk`, but only prevents others from opening it for
+    /// writing. Saving fails with `ReadOnly`, and a corrupt write-ahead log is replayed up
+    /// to the corrupt record but left as it is.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self, DbError> {
+        let path = path.into();
+        Self::check_loadable(&path)?;
+        let lock = DbLock::shared(&path)?;
+        let mut db = Self::load_unlocked(path)?;
+        db.lock = Some(Arc::new(lock));
+        Ok(db)
+    }
+
+    /// Cuts a corrupt record found by the last load off the write-ahead log, which only
+    /// a database holding the exclusive lock may do.
+    fn repair_wal(&self) -> Result<(), DbError> {
+        let exclusive = self.lock.as_ref().is_some_and(|lock| !lock.shared);
+        match self.report.corrupt_wal_record {
+            Some(valid) if exclusive => wal::repair(&self.path, valid),
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads `path` without taking its lock, e.g. to compare against it. The write-ahead
+    /// log is replayed but never repaired.
+    pub(crate) fn load_unlocked(path: impl Into<PathBuf>) -> Result<Self, DbError> {
+        Self::load_unlocked_with(path.into(), Unlock::Nothing, LoadOptions::default())
+    }
+
+    /// Fails with `FileNotFound` or `IsADirectory` before anything, the lock file
+    /// included, is created next to `path`.
+    pub(crate) fn check_loadable(path: &Path) -> Result<(), DbError> {
+        match metadata(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(DbError::FileNotFound(path.display().to_string())),
+            Err(e) => Err(e.into()),
+            Ok(metadata) if metadata.is_dir() && !layout::is_database_dir(path) => {
+                Err(DbError::IsADirectory(path.display().to_string()))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn load_unlocked_with(path: PathBuf, unlock: Unlock<'_>, options: LoadOptions) -> Result<Self, DbError> {
+        Self::check_loadable(&path)?;
+        let mut db = if path.is_dir() {
+            let loaded = layout::read_dir(&path)?;
+            Self::from_decoded(loaded, path, Layout::Directory, options)?
+        } else {
+            Self::read_from(File::open(&path)?, path, unlock, options)?
+        };
+        let log = wal::read(&db.path, db.encryption.as_ref())?;
+        if !log.ops.is_empty() {
+            db.wal = Some(FsyncPolicy::default());
+            db.dirty = true;
+        }
+        db.report.replayed = log.ops.len();
+        db.report.corrupt_wal_record = log.corrupt_at;
+        // Only operations that succeeded are logged, so one failing means the log doesn't
+        // belong to the saved data.
+        for (index, op) in log.ops.into_iter().enumerate() {
+            db.apply(op).map_err(|source| DbError::WalReplayFailed { index, source: Box::new(source) })?;
+        }
+        Ok(db)
+    }
+
+    /// Replaces the in-memory state with the contents of the file at the current path.
+    pub fn reload(&mut self) -> Result<(), DbError> {
+        self.check_mutable()?;
+        let mut loaded = Self::load_unlocked_with(self.path.clone(), self.unlock(), LoadOptions::default())?;
+        std::mem::swap(&mut self.db, &mut loaded.db);
+        // The file may have been changed by someone else since the last save.
+        self.saved_hash = None;
+        self.wal = self.wal.or(loaded.wal);
+        self.report = loaded.report;
+        self.dirty = loaded.dirty;
+        self.repair_wal()?;
+        self.events.emit(ChangeEvent::Reloaded);
         Ok(())
     }
 
-    pub fn load_from_disk(path: String) -> Result<Self, DbError> {
-        let content = read(&path)?;
-        let db: Database = bincode::deserialize(&content)?;
-        for table in db.tables.values() {
-            table.validate_rows()?;
+    /// Appends every following mutation to `<path>.wal` once it is applied, so that
+    /// changes made after the last `save` survive a crash. `save` empties the log.
+    pub fn enable_wal(&mut self, sync: FsyncPolicy) {
+        self.wal = Some(sync);
+    }
+
+    /// Serializes the whole database, header and checksum included, as a save to a
+    /// single file would. Doesn't mark it clean.
+    pub fn save_to<W: Write>(&self, mut w: W) -> Result<(), DbError> {
+        w.write_all(&format::encode(&self.db, self.storage, self.encryption.as_ref())?)?;
+        Ok(())
+    }
+
+    /// Reads `r` to the end and deserializes and validates a database from it, stored as
+    /// its header says; later saves go to `path_for_future_saves` stored the same way.
+    pub fn load_from<R: Read>(r: R, path_for_future_saves: impl Into<PathBuf>) -> Result<Self, DbError> {
+        Self::read_from(r, path_for_future_saves.into(), Unlock::Nothing, LoadOptions::default())
+    }
+
+    fn read_from<R: Read>(mut r: R, path: PathBuf, unlock: Unlock<'_>, options: LoadOptions) -> Result<Self, DbError> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let decoded = migrations::migrate(&bytes, unlock, &path)?;
+        Self::from_decoded(decoded, path, Layout::File, options)
+    }
+
+    /// `load_from` for bytes already in memory.
+    pub fn load_from_bytes(bytes: &[u8], path: impl Into<PathBuf>) -> Result<Self, DbError> {
+        Self::load_from(bytes, path)
+    }
+
+    pub(crate) fn from_decoded(decoded: Decoded<Database>, path: PathBuf, layout: Layout, options: LoadOptions) -> Result<Self, DbError> {
+        let Decoded { value: mut db, options: storage, checksummed, encryption } = decoded;
+        check_counts(&db.tables)?;
+        if options.validate {
+            validate_tables(&db.tables, options.parallel)?;
+        }
+        for table in db.tables.values_mut() {
+            table.assign_missing_ids();
         }
 
-        Ok(Self { db, path })
+        let report = LoadReport { missing_checksum: !checksummed, ..LoadReport::default() };
+        Ok(Self { db, path, wal: None, layout, storage, encryption, report, dirty: false, autosave: Autosave::default(), max_backups: None, lock: None, events: Subscribers::default(), saved_hash: None, #[cfg(feature = "mmap")] mapped: None })
+    }
+
+    /// Checks the header and checksum of the file, or of every file of the directory, at
+    /// `path` without deserializing the database. Files without a checksum pass.
+    pub fn verify_file(path: impl AsRef<Path>) -> Result<(), DbError> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return layout::verify_dir(path);
+        }
+        format::verify(&read(path)?, path).map(drop)
+    }
+
+    /// Whether there are changes a save would write.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || self.db.tables.values().any(Table::is_dirty)
+    }
+
+    pub fn load_report(&self) -> &LoadReport {
+        &self.report
     }
 
     pub fn create_table(&mut self, name: String, schema: Vec<DbType>) -> Result<(), DbError> {
-        match self.db.tables.entry(name.clone()) {
-            Entry::Vacant(entry) => {
-                entry.insert(Table::new(name.clone(), schema));
-                Ok(())
+        self.execute(TxOp::CreateTable { name, schema })
+    }
+
+    /// Creates every table of `template`, skipping the ones already present if
+    /// `skip_existing` and otherwise failing with `TableIsAlreadyPresent`, as when a name
+    /// is listed twice. Creates none of them if any fails.
+    pub fn apply_template(&mut self, template: Vec<(String, Vec<DbType>)>, skip_existing: bool) -> Result<(), DbError> {
+        self.check_mutable()?;
+        let mut names = HashSet::new();
+        let mut missing = Vec::new();
+        for (name, schema) in template {
+            if !names.insert(name.clone()) {
+                return Err(DbError::TableIsAlreadyPresent(name));
+            }
+            match self.get_table(name.clone()) {
+                Ok(_) if skip_existing => {}
+                Ok(_) => return Err(DbError::TableIsAlreadyPresent(name)),
+                Err(_) => missing.push((name, schema)),
             }
-            Entry::Occupied(_) => return Err(DbError::TableIsAlreadyPresent(name)),
         }
+        let mut created = Vec::new();
+        for (name, schema) in missing {
+            if let Err(error) = self.create_table(name.clone(), schema) {
+                for name in created.into_iter().rev() {
+                    // The tables were just created, so removing them again can't fail.
+                    let _ = self.remove_table(name);
+                }
+                return Err(error);
+            }
+            created.push(name);
+        }
+        Ok(())
+    }
+
+    /// Renames the table `old` to `new`. Materialized projections keep refreshing from it,
+    /// and one renamed stays materialized. Fails with `TableIsAlreadyPresent` if `new` is
+    /// taken.
+    pub fn rename_table(&mut self, old: String, new: String) -> Result<(), DbError> {
+        self.execute(TxOp::RenameTable { old, new })
+    }
+
+    /// Creates `target` with the schema and checks of `source`, and its rows if
+    /// `with_rows`. A copy of a materialized projection is a plain table.
+    pub fn copy_table(&mut self, source: String, target: String, with_rows: bool) -> Result<(), DbError> {
+        self.execute(TxOp::CopyTable { source, target, with_rows })
+    }
+
+    pub(crate) fn insert_table(&mut self, table: Table) -> Result<(), DbError> {
+        self.execute(TxOp::InsertTable { table: Box::new(table) })
+    }
+
+    /// Columns `row` leaves out are filled in from their defaults before the insert is
+    /// logged, so that replaying it stores the same row.
+    pub fn insert_row(&mut self, table: String, row: Row) -> Result<(), DbError> {
+        self.check_mutable()?;
+        let row = match self.get_table(table.clone()) {
+            Ok(stored) => stored.complete_row(row)?,
+            Err(_) => row,
+        };
+        self.execute(TxOp::InsertRow { table, row })
+    }
+
+    /// Returns whether the row changed. Writing back the stored row is not logged or
+    /// reported as a change and doesn't make the database dirty.
+    pub fn update_row(&mut self, table: String, index: usize, row: Row) -> Result<bool, DbError> {
+        self.check_mutable()?;
+        if self.get_table(table.clone())?.row_at(index).is_some_and(|stored| stored.is_identical(&row)) {
+            return Ok(false);
+        }
+        self.execute(TxOp::UpdateRow { table, index, row })?;
+        Ok(true)
+    }
+
+    pub fn remove_row(&mut self, table: String, index: usize) -> Result<(), DbError> {
+        self.execute(TxOp::RemoveRow { table, index })
+    }
+
+    pub fn add_check(&mut self, table: String, check: CheckConstraint) -> Result<(), DbError> {
+        self.execute(TxOp::AddCheck { table, check })
+    }
+
+    /// See `Table::set_autoincrement`.
+    pub fn set_autoincrement(&mut self, table: String, column: usize) -> Result<(), DbError> {
+        self.execute(TxOp::SetAutoIncrement { table, column })
+    }
+
+    /// See `Table::set_default`.
+    pub fn set_default(&mut self, table: String, column: usize, default: Option<ColumnDefault>) -> Result<(), DbError> {
+        self.execute(TxOp::SetDefault { table, column, default })
+    }
+
+    /// See `Table::set_max_blob_len`.
+    pub fn set_max_blob_len(&mut self, table: String, max: usize) -> Result<(), DbError> {
+        self.execute(TxOp::SetMaxBlobLen { table, max })
+    }
+
+    /// See `Table::set_char_validator`. The validator is neither saved nor logged, so it
+    /// has to be set again after every load.
+    pub fn set_char_validator(&mut self, table: String, validator: fn(char) -> bool) -> Result<(), DbError> {
+        self.get_table_mut(table)?.set_char_validator(validator)
+    }
+
+    pub fn table_count(&self) -> usize {
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &self.mapped {
+            return self.db.tables.len() + mapped.len();
+        }
+        self.db.tables.len()
+    }
+
+    /// Decodes every table first if the database was opened with `open_mmap`.
+    pub fn stats(&self) -> Result<DbStats, DbError> {
+        Ok(DbStats {
+            table_count: self.table_count(),
+            row_count: self.sorted_tables()?.iter().map(|(_, table)| table.rows().len()).sum(),
+            dirty: self.is_dirty(),
+        })
     }
 
     pub fn get_table_names(&self) -> Vec<String> {
-        self.db.tables.keys().cloned().collect()
+        let names = self.db.tables.keys().cloned();
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &self.mapped {
+            return names.chain(mapped.names().cloned()).collect();
+        }
+        names.collect()
     }
 
-    pub fn get_table_mut(&mut self, name: String) -> Result<&mut Table, DbError> {
+    /// Changes made through the table skip the write-ahead log, subscribers and autosave,
+    /// which is why only `apply` and tests use it.
+    pub(crate) fn get_table_mut(&mut self, name: String) -> Result<&mut Table, DbError> {
+        self.check_mutable()?;
         self.db
             .tables
             .get_mut(&name)
             .ok_or(DbError::TableIsMissing(name))
     }
 
+    /// Decodes the table first if the database was opened with `open_mmap`.
     pub fn get_table(&self, name: String) -> Result<&Table, DbError> {
+        #[cfg(feature = "mmap")]
+        if let Some(table) = self.mapped.as_ref().and_then(|mapped| mapped.table(&name)) {
+            return table;
+        }
         self.db
             .tables
             .get(&name)
@@ -80,40 +728,351 @@ impl SavedDatabase {
     }
 
     pub fn remove_table(&mut self, name: String) -> Result<(), DbError> {
+        self.execute(TxOp::RemoveTable { name })
+    }
+
+    /// Builds a read-only table describing every column of every table,
+    /// with columns (table_name, column_index, column_name, column_type, row_count).
+    /// Decodes every table first if the database was opened with `open_mmap`.
+    pub fn catalog(&self) -> Result<Table, DbError> {
+        let mut catalog = Table::new(
+            "catalog".to_string(),
+            vec![DbType::String, DbType::Int, DbType::String, DbType::String, DbType::Int],
+        );
+        for (name, table) in self.sorted_tables()? {
+            for (index, r#type) in table.schema().iter().enumerate() {
+                let row = Row(vec![
+                    DbValue::String(name.clone()),
+                    DbValue::Int(index as i64),
+                    DbValue::String(format!("col{index}")),
+                    DbValue::String(r#type.to_string()),
+                    DbValue::Int(table.rows().len() as i64),
+                ]);
+                catalog.insert_row(row).expect("catalog row fits catalog schema");
+            }
+        }
+        Ok(catalog)
+    }
+
+    /// Decodes every table first if the database was opened with `open_mmap`.
+    pub fn snapshot(&self) -> Result<DatabaseSnapshot, DbError> {
+        let tables = self
+            .sorted_tables()?
+            .into_iter()
+            .map(|(name, table)| (name.clone(), table.schema().to_vec(), table.rows().to_vec()))
+            .collect();
+        Ok(DatabaseSnapshot {
+            name: self.db.name.clone(),
+            tables,
+        })
+    }
+
+    /// Captures the whole in-memory state without touching disk. Decodes every table
+    /// first if the database was opened with `open_mmap`.
+    pub fn savepoint(&self) -> Result<DbSnapshot, DbError> {
+        Ok(DbSnapshot(self.complete_db()?.into_owned()))
+    }
+
+    /// Rolls back to `snap`; the next save rewrites every table. The whole snapshot is
+    /// logged when the write-ahead log is enabled, so that replaying the log after a crash
+    /// doesn't apply the mutations before it to the restored state. It counts as a
+    /// mutation for autosaving. The snapshot stays restored if logging or autosaving
+    /// fails, and the error is returned.
+    pub fn restore(&mut self, snap: DbSnapshot) -> Result<(), DbError> {
+        self.check_mutable()?;
+        let logged = self.wal.map(|sync| (TxOp::Restore { snapshot: Box::new(snap.clone()) }, sync));
+        self.apply_restore(snap);
+        if let Some((op, sync)) = logged {
+            wal::append(&self.path, &op, sync, self.encryption.as_ref())?;
+        }
+        self.events.emit(ChangeEvent::Restored);
+        self.autosave_after_mutation()
+    }
+
+    fn apply_restore(&mut self, snap: DbSnapshot) {
+        self.db = snap.0;
+        self.mark_dirty();
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.db.name.as_str()
+    }
+
+    /// Creates `new_name` from the columns of `table_name` selected by `rows`. With
+    /// `schema`, the new table has that schema instead of the selected columns' and every
+    /// value is coerced into it by `DbValue::coerce`, failing with `CannotCoerce` if one
+    /// can't be.
+    pub fn projection(&mut self, table_name: String, rows: Vec<bool>, new_name: String, schema: Option<Vec<DbType>>) -> Result<(), DbError> {
+        self.execute(match schema {
+            None => TxOp::Projection { table: table_name, columns: rows, new_table: new_name },
+            Some(schema) => TxOp::CoercedProjection { table: table_name, columns: rows, new_table: new_name, schema },
+        })
+    }
+
+    /// Runs every `(table, columns, new_table)` projection, or none of them if one fails.
+    /// The batch is logged, reported and counted towards autosaving as one mutation.
+    pub fn project_many(&mut self, specs: Vec<(String, Vec<bool>, String)>) -> Result<(), DbError> {
+        self.execute(TxOp::ProjectMany { specs })
+    }
+
+    /// Applies `op`, then logs it when the write-ahead log is enabled and notifies
+    /// subscribers, so that only operations that succeeded get replayed. If logging fails
+    /// the change stays applied, unsaved and unlogged, and the error is returned.
+    fn execute(&mut self, op: TxOp) -> Result<(), DbError> {
+        self.check_mutable()?;
+        let logged = self.wal.map(|sync| (op.clone(), sync));
+        let event = self.events.is_active().then(|| op.clone());
+        self.apply(op)?;
+        self.dirty = true;
+        if let Some((op, sync)) = logged {
+            wal::append(&self.path, &op, sync, self.encryption.as_ref())?;
+        }
+        if let Some(op) = event {
+            self.events.emit(ChangeEvent::Mutation(op));
+        }
+        self.autosave_after_mutation()
+    }
+
+    fn apply(&mut self, op: TxOp) -> Result<(), DbError> {
+        match op {
+            TxOp::CreateTable { name, schema } => self.apply_insert_table(Table::new(name, schema)),
+            TxOp::InsertTable { table } => self.apply_insert_table(*table),
+            TxOp::RemoveTable { name } => self.apply_remove_table(name),
+            TxOp::InsertRow { table, row } => self.get_table_mut(table)?.insert_row(row),
+            TxOp::UpdateRow { table, index, row } => self.get_table_mut(table)?.update_row(index, row).map(drop),
+            TxOp::RemoveRow { table, index } => {
+                self.get_table_mut(table)?.remove_row(index);
+                Ok(())
+            }
+            TxOp::AddCheck { table, check } => self.get_table_mut(table)?.add_check(check),
+            TxOp::Projection { table, columns, new_table } => self.apply_projection(table, columns, new_table, None),
+            TxOp::CoercedProjection { table, columns, new_table, schema } => {
+                self.apply_projection(table, columns, new_table, Some(schema))
+            }
+            TxOp::CreateMaterialized { source, columns, name } => {
+                let source_version = self.get_table(source.clone())?.version();
+                self.apply_projection(source.clone(), columns.clone(), name.clone(), None)?;
+                self.db.materialized.insert(name, Materialization {
+                    source,
+                    columns,
+                    source_version,
+                    orphaned: false,
+                });
+                Ok(())
+            }
+            TxOp::RefreshMaterialized { name } => self.apply_refresh_materialized(name),
+            TxOp::SetAutoIncrement { table, column } => self.get_table_mut(table)?.set_autoincrement(column),
+            TxOp::RenameTable { old, new } => self.apply_rename_table(old, new),
+            TxOp::CopyTable { source, target, with_rows } => {
+                let copy = self.get_table(source)?.copy(target, with_rows);
+                self.apply_insert_table(copy)
+            }
+            TxOp::SetDefault { table, column, default } => self.get_table_mut(table)?.set_default(column, default),
+            TxOp::SetMaxBlobLen { table, max } => self.get_table_mut(table)?.set_max_blob_len(max),
+            TxOp::ProjectMany { specs } => self.apply_project_many(specs),
+            TxOp::Restore { snapshot } => {
+                self.apply_restore(*snapshot);
+                Ok(())
+            }
+        }
+    }
+
+    /// A projection only adds its table once it succeeds, so removing the tables added
+    /// before a failing one undoes the batch.
+    fn apply_project_many(&mut self, specs: Vec<(String, Vec<bool>, String)>) -> Result<(), DbError> {
+        let mut added = Vec::new();
+        for (table, columns, new_table) in specs {
+            if let Err(error) = self.apply_projection(table, columns, new_table.clone(), None) {
+                for name in added {
+                    self.db.tables.remove(&name);
+                }
+                return Err(error);
+            }
+            added.push(new_table);
+        }
+        Ok(())
+    }
+
+    fn apply_insert_table(&mut self, table: Table) -> Result<(), DbError> {
+        match self.db.tables.entry(table.name().to_string()) {
+            Entry::Vacant(entry) => {
+                entry.insert(table);
+                Ok(())
+            }
+            Entry::Occupied(entry) => Err(DbError::TableIsAlreadyPresent(entry.key().clone())),
+        }
+    }
+
+    fn apply_remove_table(&mut self, name: String) -> Result<(), DbError> {
         match self.db.tables.entry(name.clone()) {
             Entry::Occupied(entry) => {
                 entry.remove();
+                self.db.materialized.remove(&name);
+                for materialization in self.db.materialized.values_mut() {
+                    if materialization.source == name {
+                        materialization.orphaned = true;
+                    }
+                }
                 Ok(())
             }
-            Entry::Vacant(_) => return Err(DbError::TableIsMissing(name)),
+            Entry::Vacant(_) => Err(DbError::TableIsMissing(name)),
         }
     }
 
-    pub fn get_name(&self) -> &str {
-        self.db.name.as_str()
+    fn apply_rename_table(&mut self, old: String, new: String) -> Result<(), DbError> {
+        if self.db.tables.contains_key(&new) {
+            return Err(DbError::TableIsAlreadyPresent(new));
+        }
+        let mut table = self.db.tables.remove(&old).ok_or_else(|| DbError::TableIsMissing(old.clone()))?;
+        table.set_name(new.clone());
+        self.db.tables.insert(new.clone(), table);
+        if let Some(materialization) = self.db.materialized.remove(&old) {
+            self.db.materialized.insert(new.clone(), materialization);
+        }
+        for materialization in self.db.materialized.values_mut() {
+            if materialization.source == old {
+                materialization.source = new.clone();
+            }
+        }
+        Ok(())
     }
 
-    pub fn projection(&mut self, table_name: String, rows: Vec<bool>, new_name: String) -> Result<(), DbError> {
+    fn apply_projection(&mut self, table_name: String, rows: Vec<bool>, new_name: String, schema: Option<Vec<DbType>>) -> Result<(), DbError> {
         let table = self.get_table(table_name)?;
         if table.schema().len() != rows.len() {
             return Err(DbError::IncorrectRow);
         }
-        let new_schema = table.schema().iter().enumerate().filter(|(index, _)| rows[*index])
-            .map(|(_, r#type)| r#type.clone()).collect();
+        // Rows are indexed by the mask below, so one not matching the schema would panic.
+        if table.rows().iter().any(|row| row.0.len() != rows.len()) {
+            return Err(DbError::InvalidTableState(table.name().to_string()));
+        }
+        let mut new_schema: Vec<DbType> = table.schema().iter().enumerate().filter(|(index, _)| rows[*index])
+            .map(|(_, r#type)| *r#type).collect();
+        if let Some(schema) = schema {
+            if schema.len() != new_schema.len() {
+                return Err(DbError::RowLengthMismatch { expected: new_schema.len(), got: schema.len() });
+            }
+            new_schema = schema;
+        }
         let mut new_rows = vec![];
         for row in table.rows() {
             let mut new_row = Vec::new();
             for (index, value) in row.0.iter().enumerate() {
                 if rows[index]  {
-                    new_row.push(value.clone());
+                    let column = new_row.len();
+                    let to = new_schema[column];
+                    let coerced = value.clone().coerce(to)
+                        .ok_or_else(|| DbError::CannotCoerce { column, value: value.to_string(), to })?;
+                    new_row.push(coerced);
                 }
             }
             new_rows.push(new_row);
         }
-        self.create_table(new_name.clone(), new_schema)?;
+        let mut new_table = Table::new(new_name, new_schema);
         for row in new_rows {
-            self.get_table_mut(new_name.clone())?.insert_row(Row(row))?;
+            new_table.insert_row(Row(row))?;
         }
+        self.apply_insert_table(new_table)
+    }
+
+    fn apply_refresh_materialized(&mut self, name: String) -> Result<(), DbError> {
+        let materialization = self
+            .db
+            .materialized
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| DbError::NotMaterialized(name.clone()))?;
+        if materialization.orphaned {
+            return Err(DbError::TableIsMissing(materialization.source));
+        }
+        let source_version = self.get_table(materialization.source.clone())?.version();
+        let previous = self.db.tables.remove(&name);
+        if let Err(e) = self.apply_projection(materialization.source.clone(), materialization.columns.clone(), name.clone(), None) {
+            if let Some(previous) = previous {
+                self.db.tables.insert(name, previous);
+            }
+            return Err(e);
+        }
+        self.db.materialized.insert(name, Materialization {
+            source_version,
+            ..materialization
+        });
         Ok(())
     }
 }
+
+impl SavedDatabase {
+    /// Creates a projection that remembers its source; it becomes stale when the source
+    /// changes and is brought up to date by `refresh_materialized`.
+    pub fn create_materialized_projection(&mut self, source: String, columns: Vec<bool>, name: String) -> Result<(), DbError> {
+        self.execute(TxOp::CreateMaterialized { source, columns, name })
+    }
+
+    pub fn refresh_materialized(&mut self, name: String) -> Result<(), DbError> {
+        self.execute(TxOp::RefreshMaterialized { name })
+    }
+
+    /// Orphaned projections, whose source was dropped, are always stale.
+    pub fn is_stale(&self, name: String) -> Result<bool, DbError> {
+        let materialization = self
+            .db
+            .materialized
+            .get(&name)
+            .ok_or_else(|| DbError::NotMaterialized(name.clone()))?;
+        Ok(materialization.orphaned
+            || self.get_table(materialization.source.clone())?.version() != materialization.source_version)
+    }
+
+    pub fn table_info(&self, name: String) -> Result<TableInfo, DbError> {
+        let table = self.get_table(name.clone())?;
+        let materialized = match self.db.materialized.get(&name) {
+            Some(materialization) => Some(MaterializedInfo {
+                source: materialization.source.clone(),
+                stale: self.is_stale(name.clone())?,
+                orphaned: materialization.orphaned,
+            }),
+            None => None,
+        };
+        Ok(TableInfo {
+            name,
+            schema: table.schema().to_vec(),
+            row_count: table.rows().len(),
+            materialized,
+        })
+    }
+}
+
+/// Most tables a loaded database may have, past which the file is taken to be corrupt.
+const MAX_TABLES: usize = 1 << 16;
+
+/// Rejects decoded databases with more tables, or tables with more rows, than any
+/// database this crate writes would plausibly have.
+fn check_counts(tables: &HashMap<String, Table>) -> Result<(), DbError> {
+    if tables.len() > MAX_TABLES {
+        return Err(DbError::LimitExceeded { what: "tables", count: tables.len(), max: MAX_TABLES });
+    }
+    match tables.values().map(|table| table.rows().len()).max() {
+        Some(count) if count > MAX_ROWS => Err(DbError::LimitExceeded { what: "rows in a table", count, max: MAX_ROWS }),
+        _ => Ok(()),
+    }
+}
+
+/// Validates every table, reporting the one with the smallest name if several fail so
+/// that the error doesn't depend on the order of the map or of threads.
+fn validate_tables(tables: &HashMap<String, Table>, parallel: bool) -> Result<(), DbError> {
+    #[cfg(feature = "parallel")]
+    if parallel {
+        use rayon::prelude::*;
+        let failed = tables
+            .par_iter()
+            .filter_map(|(name, table)| table.validate_rows_parallel().err().map(|error| (name, error)))
+            .min_by(|a, b| a.0.cmp(b.0));
+        return failed.map_or(Ok(()), |(_, error)| Err(error));
+    }
+    #[cfg(not(feature = "parallel"))]
+    let _ = parallel;
+    for (_, table) in tables.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+        table.validate_rows()?;
+    }
+    Ok(())
+}
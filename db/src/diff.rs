@@ -0,0 +1,127 @@
+use crate::{DbError, DbType, Row, SavedDatabase, Table};
+use itertools::{EitherOrBoth, Itertools};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DatabaseDiff {
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+    pub schema_changes: Vec<SchemaDiff>,
+    /// Tables with equal schemas whose rows differ.
+    pub row_changes: Vec<TableDiff>,
+}
+
+impl DatabaseDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty()
+            && self.only_in_b.is_empty()
+            && self.schema_changes.is_empty()
+            && self.row_changes.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SchemaDiff {
+    pub table: String,
+    pub columns: Vec<ColumnDiff>,
+}
+
+/// A column whose type differs; `None` means the column does not exist on that side.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ColumnDiff {
+    pub column: usize,
+    pub a: Option<DbType>,
+    pub b: Option<DbType>,
+}
+
+/// Rows present in b but not in a are added, the reverse are removed, counting duplicates.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TableDiff {
+    pub table: String,
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Rows are compared by their encoding instead of `==`, which can't be hashed: rows are
+/// the same exactly when they are saved the same, so NaN matches NaN, while `0.0` and
+/// `-0.0`, or one instant in two offsets, differ.
+fn row_key(row: &Row) -> Vec<u8> {
+    bincode::serialize(row).expect("rows are always serializable")
+}
+
+pub fn diff_tables(a: &Table, b: &Table) -> TableDiff {
+    let mut counts: HashMap<Vec<u8>, isize> = HashMap::new();
+    for row in b.rows() {
+        *counts.entry(row_key(row)).or_default() += 1;
+    }
+    for row in a.rows() {
+        *counts.entry(row_key(row)).or_default() -= 1;
+    }
+    TableDiff {
+        table: a.name().to_string(),
+        added: counts.values().filter(|c| **c > 0).sum::<isize>() as usize,
+        removed: -counts.values().filter(|c| **c < 0).sum::<isize>() as usize,
+    }
+}
+
+fn diff_schemas(a: &[DbType], b: &[DbType]) -> Vec<ColumnDiff> {
+    (0..a.len().max(b.len()))
+        .map(|column| ColumnDiff {
+            column,
+            a: a.get(column).copied(),
+            b: b.get(column).copied(),
+        })
+        .filter(|diff| diff.a != diff.b)
+        .collect()
+}
+
+pub(crate) fn diff_databases(a: &SavedDatabase, b: &SavedDatabase) -> Result<DatabaseDiff, DbError> {
+    let mut diff = DatabaseDiff::default();
+    let tables = a.sorted_tables()?.into_iter().merge_join_by(b.sorted_tables()?, |a, b| a.0.cmp(b.0));
+    for tables in tables {
+        let ((name, table_a), (_, table_b)) = match tables {
+            EitherOrBoth::Left((name, _)) => {
+                diff.only_in_a.push(name.clone());
+                continue;
+            }
+            EitherOrBoth::Right((name, _)) => {
+                diff.only_in_b.push(name.clone());
+                continue;
+            }
+            EitherOrBoth::Both(a, b) => (a, b),
+        };
+        let columns = diff_schemas(table_a.schema(), table_b.schema());
+        if !columns.is_empty() {
+            diff.schema_changes.push(SchemaDiff { table: name.clone(), columns });
+            continue;
+        }
+        let table_diff = diff_tables(table_a, table_b);
+        if table_diff.added > 0 || table_diff.removed > 0 {
+            diff.row_changes.push(table_diff);
+        }
+    }
+    Ok(diff)
+}
+
+/// Loads both files and reports how `b` differs from `a`.
+pub fn diff_files(a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<DatabaseDiff, DbError> {
+    let a = SavedDatabase::load_unlocked(a.as_ref())?;
+    let b = SavedDatabase::load_unlocked(b.as_ref())?;
+    diff_databases(&a, &b)
+}
+
+impl SavedDatabase {
+    /// Reports how `other` differs from this database; "only in a" means only in `self`.
+    /// Decodes every table of either database first if it was opened with `open_mmap`.
+    pub fn diff(&self, other: &SavedDatabase) -> Result<DatabaseDiff, DbError> {
+        diff_databases(self, other)
+    }
+
+    /// Reports how the file at `path` differs from the in-memory state.
+    pub fn diff_against(&self, path: impl AsRef<Path>) -> Result<DatabaseDiff, DbError> {
+        let other = SavedDatabase::load_unlocked(path.as_ref())?;
+        self.diff(&other)
+    }
+}
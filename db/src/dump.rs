@@ -0,0 +1,135 @@
+use crate::{DbError, DbType, DbValue, SavedDatabase};
+use chrono::SecondsFormat;
+use itertools::Itertools;
+use std::io::Write;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SqlDialect {
+    Postgres,
+    Sqlite,
+}
+
+impl SqlDialect {
+    fn type_name(self, r#type: DbType) -> &'static str {
+        match (self, r#type) {
+            (Self::Postgres, DbType::Int) => "BIGINT",
+            (Self::Postgres, DbType::UInt) => "NUMERIC(20)",
+            (Self::Postgres, DbType::Real) => "DOUBLE PRECISION",
+            (Self::Postgres, DbType::Char) => "CHAR(1)",
+            (Self::Postgres, DbType::String) => "TEXT",
+            (Self::Postgres, DbType::Time) => "TIMESTAMPTZ",
+            (Self::Postgres, DbType::Blob) => "BYTEA",
+            // SQLite stores values above i64::MAX in such columns as REAL.
+            (Self::Sqlite, DbType::Int | DbType::UInt) => "INTEGER",
+            (Self::Sqlite, DbType::Real) => "REAL",
+            (Self::Sqlite, DbType::Char | DbType::String | DbType::Time) => "TEXT",
+            (Self::Sqlite, DbType::Blob) => "BLOB",
+        }
+    }
+
+    fn identifier(self, name: &str) -> String {
+        match self {
+            Self::Postgres => format!("\"{}\"", name.replace('"', "\"\"")),
+            Self::Sqlite => format!("`{}`", name.replace('`', "``")),
+        }
+    }
+
+    fn literal(self, value: &DbValue) -> String {
+        match (self, value) {
+            (_, DbValue::Int(x)) => x.to_string(),
+            (_, DbValue::UInt(x)) => x.to_string(),
+            (Self::Postgres, DbValue::Real(x)) if x.is_nan() => "'NaN'::double precision".to_string(),
+            (Self::Postgres, DbValue::Real(x)) if x.is_infinite() => {
+                format!("'{}Infinity'::double precision", if *x < 0.0 { "-" } else { "" })
+            }
+            (Self::Sqlite, DbValue::Real(x)) if x.is_nan() => "NULL".to_string(),
+            (Self::Sqlite, DbValue::Real(x)) if x.is_infinite() => {
+                format!("{}9e999", if *x < 0.0 { "-" } else { "" })
+            }
+            (_, DbValue::Real(x)) => format!("{x:?}"),
+            (_, DbValue::Char(x)) => string_literal(&x.to_string()),
+            (_, DbValue::String(x)) => string_literal(x),
+            (Self::Postgres, DbValue::Time(x)) => {
+                format!("{}::timestamptz", string_literal(&x.to_rfc3339_opts(SecondsFormat::AutoSi, false)))
+            }
+            (Self::Sqlite, DbValue::Time(x)) => string_literal(&x.to_rfc3339_opts(SecondsFormat::AutoSi, false)),
+            (Self::Postgres, DbValue::Blob(x)) => format!("'\\x{}'::bytea", hex(x)),
+            (Self::Sqlite, DbValue::Blob(x)) => format!("X'{}'", hex(x)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn string_literal(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+/// Leaves plain identifiers bare and double-quotes anything else.
+fn ddl_identifier(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+impl SavedDatabase {
+    /// Describes every table, in sorted order, as `CREATE TABLE name (col0 int, ...);`
+    /// using the native type names, one statement per line. Decodes every table first if
+    /// the database was opened with `open_mmap`.
+    pub fn to_ddl(&self) -> Result<String, DbError> {
+        Ok(self
+            .sorted_tables()?
+            .into_iter()
+            .map(|(name, table)| {
+                let columns = table
+                    .schema()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, r#type)| format!("col{index} {type}"))
+                    .join(", ");
+                format!("CREATE TABLE {} ({columns});\n", ddl_identifier(name))
+            })
+            .collect())
+    }
+
+    /// Writes `CREATE TABLE` and `INSERT` statements for every table in sorted order.
+    /// Columns are named `col0`, `col1`, ...; inserts are split every `batch_size` rows
+    /// when given, otherwise each table gets a single `INSERT`.
+    pub fn export_sql<W: Write>(&self, mut w: W, dialect: SqlDialect, batch_size: Option<usize>) -> Result<(), DbError> {
+        let mut names = self.get_table_names();
+        names.sort();
+        for name in names {
+            let table = self.get_table(name.clone())?;
+            let table_name = dialect.identifier(&name);
+            let columns = table
+                .schema()
+                .iter()
+                .enumerate()
+                .map(|(index, r#type)| format!("{} {}", dialect.identifier(&format!("col{index}")), dialect.type_name(*r#type)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(w, "CREATE TABLE {table_name} ({columns});")?;
+
+            let batch_size = batch_size.unwrap_or(table.rows().len()).max(1);
+            for batch in table.rows().chunks(batch_size) {
+                let values = batch
+                    .iter()
+                    .map(|row| {
+                        let values = row.0.iter().map(|value| dialect.literal(value)).collect::<Vec<_>>();
+                        format!("({})", values.join(", "))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",\n    ");
+                writeln!(w, "INSERT INTO {table_name} VALUES\n    {values};")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use crate::database::{SaveSummary, SavedDatabase};
+use crate::query::Query;
+use crate::types::{DbError, DbType, Row};
+use std::path::PathBuf;
+
+/// A database used directly from the embedding program, without the RPC server. It
+/// only bundles the `SavedDatabase` calls most programs need behind chainable methods;
+/// `database` and `database_mut` reach everything else.
+///
+/// ```
+/// use db::{CompareOp, Condition, DbType, DbValue, Embedded, Row};
+///
+/// # let dir = tempfile::tempdir().unwrap();
+/// let mut db = Embedded::open_or_create("shop", dir.path().join("shop.db"))?;
+/// db.ensure_table("items", vec![DbType::String, DbType::Int])?
+///     .insert("items", Row(vec![DbValue::String("tea".into()), DbValue::Int(4)]))?
+///     .insert("items", Row(vec![DbValue::String("cake".into()), DbValue::Int(12)]))?;
+/// let cheap = db.select("items", |query| query.filter(Condition::compare(1, CompareOp::Lt, DbValue::Int(10))))?;
+/// assert_eq!(cheap, [Row(vec![DbValue::String("tea".into()), DbValue::Int(4)])]);
+/// db.save()?;
+/// # Ok::<(), db::DbError>(())
+/// ```
+#[derive(Debug)]
+pub struct Embedded {
+    db: SavedDatabase,
+}
+
+impl Embedded {
+    /// Loads the database at `path` like `SavedDatabase::load_from_disk`, or creates one
+    /// called `name` there if nothing exists at `path` yet.
+    pub fn open_or_create(name: &str, path: impl Into<PathBuf>) -> Result<Self, DbError> {
+        let path = path.into();
+        let db = if path.exists() {
+            SavedDatabase::load_from_disk(path)?
+        } else {
+            SavedDatabase::create(name.to_string(), path)?
+        };
+        Ok(Self { db })
+    }
+
+    /// Creates the table `name` unless it is there already. Fails with
+    /// `TableIsAlreadyPresent` if it is, but with another schema.
+    pub fn ensure_table(&mut self, name: &str, schema: Vec<DbType>) -> Result<&mut Self, DbError> {
+        match self.db.get_table(name.to_string()) {
+            Ok(table) if table.schema() == schema => {}
+            Ok(_) => return Err(DbError::TableIsAlreadyPresent(name.to_string())),
+            Err(DbError::TableIsMissing(_)) => self.db.create_table(name.to_string(), schema)?,
+            Err(error) => return Err(error),
+        }
+        Ok(self)
+    }
+
+    pub fn insert(&mut self, table: &str, row: Row) -> Result<&mut Self, DbError> {
+        self.db.insert_row(table.to_string(), row)?;
+        Ok(self)
+    }
+
+    /// Rows of `table` the query built by `build` selects, e.g.
+    /// `db.select("t", |query| query.limit(10))`.
+    pub fn select(&self, table: &str, build: impl FnOnce(Query) -> Query) -> Result<Vec<Row>, DbError> {
+        build(self.db.query(table)).rows(&self.db)
+    }
+
+    pub fn save(&mut self) -> Result<SaveSummary, DbError> {
+        self.db.save()
+    }
+
+    pub fn database(&self) -> &SavedDatabase {
+        &self.db
+    }
+
+    pub fn database_mut(&mut self) -> &mut SavedDatabase {
+        &mut self.db
+    }
+
+    pub fn into_inner(self) -> SavedDatabase {
+        self.db
+    }
+}
@@ -0,0 +1,104 @@
+use crate::types::DbError;
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::fmt;
+
+const SALT_LEN: usize = 16;
+const CHECK_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+
+/// A key derived from a passphrase with Argon2 and the salt it was derived with.
+#[derive(Clone)]
+pub(crate) struct Encryption {
+    salt: [u8; SALT_LEN],
+    /// Derived along with the key and stored next to the salt, so that a wrong passphrase
+    /// is told apart from a modified file.
+    check: [u8; CHECK_LEN],
+    key: Key,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption").finish_non_exhaustive()
+    }
+}
+
+impl Encryption {
+    /// Derives a key from `passphrase` with a fresh random salt.
+    pub(crate) fn new(passphrase: &str) -> Self {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(passphrase, salt)
+    }
+
+    fn derive(passphrase: &str, salt: [u8; SALT_LEN]) -> Self {
+        let mut output = [0; KEY_LEN + CHECK_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut output)
+            .expect("salt and output lengths are valid for Argon2");
+        let (key, check) = output.split_at(KEY_LEN);
+        Self {
+            salt,
+            check: check.try_into().unwrap(),
+            key: *Key::from_slice(key),
+        }
+    }
+
+    pub(crate) fn matches(&self, passphrase: &str) -> bool {
+        Self::derive(passphrase, self.salt).check == self.check
+    }
+
+    /// Encrypts `payload` under a fresh nonce and prefixes it with the salt, the check
+    /// and the nonce.
+    pub(crate) fn seal(&self, payload: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.key)
+            .encrypt(&nonce, payload)
+            .expect("payload is within the cipher's size limit");
+        let mut sealed = Vec::with_capacity(SALT_LEN + CHECK_LEN + NONCE_LEN + ciphertext.len());
+        sealed.extend(self.salt);
+        sealed.extend(self.check);
+        sealed.extend(nonce);
+        sealed.extend(ciphertext);
+        sealed
+    }
+}
+
+/// What encrypted input is decrypted with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Unlock<'a> {
+    Nothing,
+    Passphrase(&'a str),
+    /// The key of an open database, which fits what it saved since its passphrase was
+    /// last changed.
+    Key(&'a Encryption),
+}
+
+/// Decrypts the output of `seal`, failing with `BadPassphrase` if `unlock` doesn't fit
+/// and with `CorruptPayload` if the ciphertext was modified. Also returns the key, for
+/// saving again.
+pub(crate) fn open(sealed: &[u8], unlock: Unlock<'_>) -> Result<(Vec<u8>, Encryption), DbError> {
+    if sealed.len() < SALT_LEN + CHECK_LEN + NONCE_LEN {
+        return Err(DbError::CorruptPayload("encrypted payload is truncated".to_string()));
+    }
+    let (salt, rest) = sealed.split_at(SALT_LEN);
+    let (check, rest) = rest.split_at(CHECK_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let salt: [u8; SALT_LEN] = salt.try_into().unwrap();
+    let encryption = match unlock {
+        Unlock::Nothing => return Err(DbError::PassphraseRequired),
+        Unlock::Passphrase(passphrase) => Encryption::derive(passphrase, salt),
+        Unlock::Key(encryption) if encryption.salt == salt => encryption.clone(),
+        Unlock::Key(_) => return Err(DbError::BadPassphrase),
+    };
+    if encryption.check != check {
+        return Err(DbError::BadPassphrase);
+    }
+    let payload = XChaCha20Poly1305::new(&encryption.key)
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| DbError::CorruptPayload("encrypted payload was modified".to_string()))?;
+    Ok((payload, encryption))
+}
@@ -0,0 +1,52 @@
+use crate::database::SavedDatabase;
+use crate::wal::TxOp;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// Events buffered per subscriber; a subscriber falling further behind sees `RecvError::Lagged`.
+pub(crate) const CHANGE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChangeEvent {
+    /// A mutation that was applied successfully.
+    Mutation(TxOp),
+    Saved { path: PathBuf },
+    Reloaded,
+    /// The database was rolled back to a savepoint.
+    Restored,
+}
+
+/// Sender shared by all subscribers of one database. Clones of the database start
+/// without subscribers instead of reporting their changes to the original's.
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers(Option<Sender<ChangeEvent>>);
+
+impl Clone for Subscribers {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+impl Subscribers {
+    pub(crate) fn is_active(&self) -> bool {
+        self.0.as_ref().is_some_and(|sender| sender.receiver_count() > 0)
+    }
+
+    /// Never blocks: slow subscribers lose their oldest events instead.
+    pub(crate) fn emit(&self, event: ChangeEvent) {
+        if let Some(sender) = &self.0 {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+impl SavedDatabase {
+    /// Streams every successful mutation and save made through this database's methods.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        self.events
+            .0
+            .get_or_insert_with(|| broadcast::channel(CHANGE_CAPACITY).0)
+            .subscribe()
+    }
+}
@@ -0,0 +1,81 @@
+use crate::database::SavedDatabase;
+use crate::format::{self, Decoded, StorageOptions};
+use crate::layout;
+use crate::migrations::{TableV0, TableV2, TableV3, TableV4};
+use crate::table::Table;
+use crate::types::DbError;
+use serde::{Deserialize, Serialize};
+use std::fs::read;
+use std::path::Path;
+
+/// Version of `TableFile`, bumped whenever it changes incompatibly.
+const TABLE_FILE_VERSION: u32 = 1;
+
+/// A single table stored on its own, with its schema and rows. Files of older format
+/// versions hold the table structure of theirs.
+#[derive(Serialize, Deserialize)]
+struct TableFile<T = Table> {
+    format_version: u32,
+    table: T,
+}
+
+impl<T: Into<Table>> TableFile<T> {
+    fn upgrade(self) -> TableFile {
+        TableFile { format_version: self.format_version, table: self.table.into() }
+    }
+}
+
+fn read_table_file(path: &Path) -> Result<Table, DbError> {
+    let bytes = read(path)?;
+    let Decoded { value: file, .. } = match format::file_version(&bytes, path)? {
+        0 => format::decode::<TableFile<TableV0>>(&bytes, path)?.map(TableFile::upgrade),
+        1 | 2 => format::decode::<TableFile<TableV2>>(&bytes, path)?.map(TableFile::upgrade),
+        3 => format::decode::<TableFile<TableV3>>(&bytes, path)?.map(TableFile::upgrade),
+        4 => format::decode::<TableFile<TableV4>>(&bytes, path)?.map(TableFile::upgrade),
+        _ => format::decode::<TableFile>(&bytes, path)?,
+    };
+    if file.format_version != TABLE_FILE_VERSION {
+        return Err(DbError::UnsupportedVersion {
+            found: file.format_version,
+            supported: TABLE_FILE_VERSION,
+        });
+    }
+    Ok(file.table)
+}
+
+impl SavedDatabase {
+    /// Writes the table `name` to a standalone file at `path`, in this database's format
+    /// and compression. The file is never encrypted, so it can be handed to someone who
+    /// doesn't know the passphrase.
+    pub fn export_table(&self, name: &str, path: &Path) -> Result<(), DbError> {
+        let file = TableFile {
+            format_version: TABLE_FILE_VERSION,
+            table: self.get_table(name.to_string())?.clone(),
+        };
+        let options = StorageOptions { format: self.format(), compression: self.compression() };
+        layout::write_atomic(path, &format::encode(&file, options, None)?)
+    }
+
+    /// Adds the table stored by `export_table` at `path`, under `rename_to` if given.
+    /// Fails with `TableIsAlreadyPresent` if the name is taken and with
+    /// `InvalidTableState` if a row doesn't fit the stored schema. Returns the name of
+    /// the new table.
+    pub fn import_table(&mut self, path: &Path, rename_to: Option<String>) -> Result<String, DbError> {
+        let mut table = read_table_file(path)?;
+        table.validate_rows()?;
+        table.assign_missing_ids();
+        table.mark_dirty();
+        if let Some(name) = rename_to {
+            table.set_name(name);
+        }
+        let name = table.name().to_string();
+        self.insert_table(table)?;
+        Ok(name)
+    }
+
+    /// Name of the table stored by `export_table` at `path`, which `import_table` uses
+    /// unless renaming it.
+    pub fn table_file_name(path: &Path) -> Result<String, DbError> {
+        Ok(read_table_file(path)?.name().to_string())
+    }
+}
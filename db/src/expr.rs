@@ -0,0 +1,161 @@
+use crate::database::SavedDatabase;
+use crate::table::Table;
+use crate::types::{DbError, DbType, DbValue, Row};
+use serde::{Deserialize, Serialize};
+use std::ops;
+
+/// Expression computing a column of `SavedDatabase::projection_computed` from a row.
+/// `Add`, `Sub` and `Mul` take `Int`, `UInt` or `Real` operands, mixed ones only with
+/// `Real`, which the result then is. `Add` also concatenates two `String`s. The
+/// operators build these, e.g. `Expr::Column(0) + Expr::Column(1)`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Expr {
+    Column(usize),
+    Literal(DbValue),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+}
+
+impl ops::Add for Expr {
+    type Output = Expr;
+
+    fn add(self, rhs: Expr) -> Expr {
+        Expr::Add(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::Sub for Expr {
+    type Output = Expr;
+
+    fn sub(self, rhs: Expr) -> Expr {
+        Expr::Sub(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::Mul for Expr {
+    type Output = Expr;
+
+    fn mul(self, rhs: Expr) -> Expr {
+        Expr::Mul(Box::new(self), Box::new(rhs))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+}
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Sub => "subtract",
+            Op::Mul => "multiply",
+        }
+    }
+
+    fn result_type(self, lhs: DbType, rhs: DbType) -> Result<DbType, DbError> {
+        use DbType::*;
+        match (self, lhs, rhs) {
+            (_, Int, Int) => Ok(Int),
+            (_, UInt, UInt) => Ok(UInt),
+            (_, Int | UInt | Real, Int | UInt | Real) if lhs == Real || rhs == Real => Ok(Real),
+            (Op::Add, String, String) => Ok(String),
+            _ => Err(DbError::InvalidExpression(format!("cannot {} {lhs:?} and {rhs:?}", self.name()))),
+        }
+    }
+
+    /// `None` on overflow. The operands have the types `result_type` accepted.
+    fn apply(self, lhs: DbValue, rhs: DbValue) -> Option<DbValue> {
+        use DbValue::*;
+        Some(match (lhs, rhs) {
+            (Int(lhs), Int(rhs)) => Int(match self {
+                Op::Add => lhs.checked_add(rhs)?,
+                Op::Sub => lhs.checked_sub(rhs)?,
+                Op::Mul => lhs.checked_mul(rhs)?,
+            }),
+            (UInt(lhs), UInt(rhs)) => UInt(match self {
+                Op::Add => lhs.checked_add(rhs)?,
+                Op::Sub => lhs.checked_sub(rhs)?,
+                Op::Mul => lhs.checked_mul(rhs)?,
+            }),
+            (String(lhs), String(rhs)) => String(lhs + &rhs),
+            (lhs, rhs) => {
+                let (lhs, rhs) = (as_real(&lhs), as_real(&rhs));
+                Real(match self {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                })
+            }
+        })
+    }
+}
+
+fn as_real(value: &DbValue) -> f64 {
+    match *value {
+        DbValue::Int(value) => value as f64,
+        DbValue::UInt(value) => value as f64,
+        DbValue::Real(value) => value,
+        _ => unreachable!("operands are numeric"),
+    }
+}
+
+impl Expr {
+    fn split(&self) -> Option<(Op, &Expr, &Expr)> {
+        match self {
+            Expr::Add(lhs, rhs) => Some((Op::Add, lhs, rhs)),
+            Expr::Sub(lhs, rhs) => Some((Op::Sub, lhs, rhs)),
+            Expr::Mul(lhs, rhs) => Some((Op::Mul, lhs, rhs)),
+            Expr::Column(_) | Expr::Literal(_) => None,
+        }
+    }
+
+    /// Type of the values the expression computes from rows of `schema`, failing if
+    /// they can't be computed.
+    pub fn result_type(&self, schema: &[DbType]) -> Result<DbType, DbError> {
+        match self {
+            Expr::Column(column) => schema.get(*column).copied().ok_or(DbError::ColumnOutOfRange(*column)),
+            Expr::Literal(value) => Ok(value.get_type()),
+            _ => {
+                let (op, lhs, rhs) = self.split().expect("is an operation");
+                op.result_type(lhs.result_type(schema)?, rhs.result_type(schema)?)
+            }
+        }
+    }
+
+    /// Computes the value for `row`, which must fit the schema `result_type` accepted.
+    /// Integer overflow fails with `ArithmeticOverflow`.
+    fn eval(&self, row: &Row, index: usize) -> Result<DbValue, DbError> {
+        match self {
+            Expr::Column(column) => Ok(row.0[*column].clone()),
+            Expr::Literal(value) => Ok(value.clone()),
+            _ => {
+                let (op, lhs, rhs) = self.split().expect("is an operation");
+                op.apply(lhs.eval(row, index)?, rhs.eval(row, index)?)
+                    .ok_or(DbError::ArithmeticOverflow { row: index })
+            }
+        }
+    }
+}
+
+impl SavedDatabase {
+    /// Creates `new_table` with a column computed by each of `exprs` from every row of
+    /// `table`, its types derived from the expressions.
+    pub fn projection_computed(&mut self, table: String, exprs: Vec<Expr>, new_table: String) -> Result<(), DbError> {
+        let source = self.get_table(table)?;
+        let schema = exprs
+            .iter()
+            .map(|expr| expr.result_type(source.schema()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut computed = Table::new(new_table, schema);
+        for (index, row) in source.rows().iter().enumerate() {
+            let values = exprs.iter().map(|expr| expr.eval(row, index)).collect::<Result<_, _>>()?;
+            computed.insert_row(Row(values))?;
+        }
+        self.insert_table(computed)
+    }
+}
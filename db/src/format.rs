@@ -0,0 +1,373 @@
+use crate::encryption::{self, Encryption, Unlock};
+use crate::types::DbError;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Marks files carrying a header; files without it are legacy bincode.
+const MAGIC: &[u8; 4] = b"ITDB";
+/// Version of the serialized structures, bumped whenever they change incompatibly.
+/// Version 2 added the payload checksum to the header, version 3 row ids to tables,
+/// version 4 auto-increment columns and version 5 column defaults and blob limits, see
+/// `migrations` for reading older files.
+pub(crate) const FORMAT_VERSION: u16 = 5;
+/// Oldest version still read; its header has no checksum.
+const MIN_FORMAT_VERSION: u16 = 1;
+/// Version of legacy bincode files, written before the header existed.
+const LEGACY_FORMAT_VERSION: u16 = 0;
+const HEADER_LEN_V1: usize = MAGIC.len() + 4;
+const HEADER_LEN: usize = HEADER_LEN_V1 + 4;
+/// Most bytes a compressed payload may decompress to, so that a small corrupt or
+/// hostile file can't make a load allocate without bound.
+pub(crate) const MAX_DECOMPRESSED_LEN: u64 = 1 << 32;
+/// Header flag marking a zstd-compressed payload.
+const FLAG_ZSTD: u8 = 1;
+/// Header flag marking an encrypted payload, see `Encryption::seal`. Compression comes
+/// first.
+const FLAG_ENCRYPTED: u8 = 2;
+/// Header flags describing how bincode payloads encode integers: big-endian and
+/// variable-length respectively. Files are always written with neither, the encoding of
+/// `bincode_options`, which stores `usize` as 8 bytes on every platform; these exist so
+/// that a file written otherwise is rejected as `ForeignEncoding` instead of misread.
+const FLAG_BIG_ENDIAN: u8 = 4;
+const FLAG_VARINT: u8 = 8;
+
+/// Serialization used for the payload of database files. JSON is meant for debugging,
+/// bincode for production.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Format {
+    #[default]
+    Bincode,
+    Json,
+    MessagePack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Requires the `zstd` feature.
+    Zstd,
+}
+
+/// How a database is written to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct StorageOptions {
+    pub format: Format,
+    pub compression: Compression,
+}
+
+impl From<Format> for StorageOptions {
+    fn from(format: Format) -> Self {
+        Self { format, ..Self::default() }
+    }
+}
+
+impl From<Compression> for StorageOptions {
+    fn from(compression: Compression) -> Self {
+        Self { compression, ..Self::default() }
+    }
+}
+
+impl Compression {
+    fn flags(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => FLAG_ZSTD,
+        }
+    }
+
+    fn compress(self, bytes: Vec<u8>) -> Result<Vec<u8>, DbError> {
+        match self {
+            Compression::None => Ok(bytes),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::encode_all(bytes.as_slice(), 0)?),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(DbError::CompressionUnsupported),
+        }
+    }
+
+    fn decompress(self, bytes: &[u8]) -> Result<std::borrow::Cow<'_, [u8]>, DbError> {
+        self.decompress_within(bytes, MAX_DECOMPRESSED_LEN)
+    }
+
+    /// Fails with `PayloadTooLarge` instead of decompressing past `limit` bytes.
+    pub(crate) fn decompress_within(self, bytes: &[u8], limit: u64) -> Result<std::borrow::Cow<'_, [u8]>, DbError> {
+        match self {
+            Compression::None => Ok(bytes.into()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                use std::io::Read;
+                let corrupt = |e: std::io::Error| DbError::CorruptPayload(e.to_string());
+                let mut decompressed = Vec::new();
+                zstd::Decoder::new(bytes)
+                    .map_err(corrupt)?
+                    .take(limit + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(corrupt)?;
+                if decompressed.len() as u64 > limit {
+                    return Err(DbError::PayloadTooLarge { max: limit });
+                }
+                Ok(decompressed.into())
+            }
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => {
+                let _ = limit;
+                Err(DbError::CompressionUnsupported)
+            }
+        }
+    }
+}
+
+impl Format {
+    fn tag(self) -> u8 {
+        match self {
+            Format::Bincode => 0,
+            Format::Json => 1,
+            Format::MessagePack => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, DbError> {
+        match tag {
+            0 => Ok(Format::Bincode),
+            1 => Ok(Format::Json),
+            2 => Ok(Format::MessagePack),
+            _ => Err(DbError::UnknownFormat(tag)),
+        }
+    }
+
+    fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, DbError> {
+        Ok(match self {
+            Format::Bincode => bincode_options().serialize(value)?,
+            Format::Json => serde_json::to_vec(value)?,
+            Format::MessagePack => rmp_serde::to_vec(value)?,
+        })
+    }
+
+    /// Fails with the offset into `bytes` the decoder gave up at.
+    fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, u64> {
+        let mut rest = bytes;
+        let consumed = |rest: &[u8]| (bytes.len() - rest.len()) as u64;
+        match self {
+            Format::Bincode => bincode_options()
+                // Reading from a stream, bincode would allocate whatever a corrupt length
+                // prefix asks for. With the limit, a string or collection declared longer
+                // than the rest of the input fails before anything is allocated for it.
+                .with_limit(bytes.len() as u64)
+                .deserialize_from(&mut rest)
+                .map_err(|_| consumed(rest)),
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| json_offset(bytes, &e)),
+            Format::MessagePack => rmp_serde::from_read(&mut rest).map_err(|_| consumed(rest)),
+        }
+    }
+}
+
+/// Options of every bincode payload, the same as `bincode::serialize` uses so that
+/// files written with it keep loading. Decoding adds a limit, see `Format::deserialize`.
+pub(crate) fn bincode_options() -> impl Options + Copy {
+    bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes()
+}
+
+/// Turns the 1-based line and column of a JSON error into a byte offset.
+fn json_offset(bytes: &[u8], error: &serde_json::Error) -> u64 {
+    let line_start: usize = bytes
+        .split(|&byte| byte == b'\n')
+        .take(error.line().saturating_sub(1))
+        .map(|line| line.len() + 1)
+        .sum();
+    (line_start + error.column().saturating_sub(1)) as u64
+}
+
+/// JSON files carry their version in the document instead of the binary header, so
+/// they stay plain JSON that can be read and diffed.
+#[derive(Serialize, Deserialize)]
+struct JsonFile<T> {
+    format_version: u16,
+    data: T,
+}
+
+/// A decoded file and how it was stored.
+pub(crate) struct Decoded<T> {
+    pub(crate) value: T,
+    pub(crate) options: StorageOptions,
+    /// Whether the file carried a checksum, which plain JSON and files written before
+    /// version 2 don't.
+    pub(crate) checksummed: bool,
+    /// The key an encrypted file was decrypted with.
+    pub(crate) encryption: Option<Encryption>,
+}
+
+impl<T> Decoded<T> {
+    pub(crate) fn map<U>(self, f: impl FnOnce(T) -> U) -> Decoded<U> {
+        Decoded {
+            value: f(self.value),
+            options: self.options,
+            checksummed: self.checksummed,
+            encryption: self.encryption,
+        }
+    }
+}
+
+struct Header {
+    options: StorageOptions,
+    checksummed: bool,
+    encrypted: bool,
+}
+
+/// Other files are prefixed with a header: magic, little-endian u16 format version,
+/// format tag, flags for the compression, encryption and bincode integer encoding, and
+/// the little-endian CRC32 of the stored payload. Encrypted files always have the header.
+pub(crate) fn encode<T: Serialize>(
+    value: &T,
+    options: StorageOptions,
+    encryption: Option<&Encryption>,
+) -> Result<Vec<u8>, DbError> {
+    let StorageOptions { format, compression } = options;
+    if format == Format::Json && compression == Compression::None && encryption.is_none() {
+        let file = JsonFile { format_version: FORMAT_VERSION, data: value };
+        return Ok(serde_json::to_vec_pretty(&file)?);
+    }
+    let mut payload = compression.compress(format.serialize(value)?)?;
+    let mut flags = compression.flags();
+    if let Some(encryption) = encryption {
+        payload = encryption.seal(&payload);
+        flags |= FLAG_ENCRYPTED;
+    }
+    let mut bytes = Vec::from(*MAGIC);
+    bytes.extend(FORMAT_VERSION.to_le_bytes());
+    bytes.extend([format.tag(), flags]);
+    bytes.extend(crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend(payload);
+    Ok(bytes)
+}
+
+fn check_version(version: u16) -> Result<(), DbError> {
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(DbError::UnsupportedVersion {
+            found: version.into(),
+            supported: FORMAT_VERSION.into(),
+        });
+    }
+    Ok(())
+}
+
+/// Parses the header of `bytes`, which must start with the magic, and verifies the
+/// checksum if there is one. Returns the header and the stored payload.
+fn split_header<'a>(bytes: &'a [u8], path: &Path) -> Result<(Header, &'a [u8]), DbError> {
+    if bytes.len() < HEADER_LEN_V1 {
+        return Err(DbError::NotADatabaseFile(path.display().to_string()));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    check_version(version)?;
+    let format = Format::from_tag(bytes[6])?;
+    let flags = bytes[7];
+    if format == Format::Bincode && flags & (FLAG_BIG_ENDIAN | FLAG_VARINT) != 0 {
+        return Err(DbError::ForeignEncoding {
+            big_endian: flags & FLAG_BIG_ENDIAN != 0,
+            varint: flags & FLAG_VARINT != 0,
+        });
+    }
+    if flags & !(FLAG_ZSTD | FLAG_ENCRYPTED) != 0 || (version < 2 && flags & FLAG_ENCRYPTED != 0) {
+        return Err(DbError::UnknownFlags(flags));
+    }
+    let compression = if flags & FLAG_ZSTD != 0 { Compression::Zstd } else { Compression::None };
+    let options = StorageOptions { format, compression };
+    let encrypted = flags & FLAG_ENCRYPTED != 0;
+    if version < 2 {
+        return Ok((Header { options, checksummed: false, encrypted }, &bytes[HEADER_LEN_V1..]));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(DbError::NotADatabaseFile(path.display().to_string()));
+    }
+    let expected = u32::from_le_bytes(bytes[HEADER_LEN_V1..HEADER_LEN].try_into().unwrap());
+    let payload = &bytes[HEADER_LEN..];
+    let actual = crc32fast::hash(payload);
+    if actual != expected {
+        return Err(DbError::ChecksumMismatch { expected, actual });
+    }
+    Ok((Header { options, checksummed: true, encrypted }, payload))
+}
+
+/// Format version `bytes`, read from `path`, were written with, which decides how
+/// `migrations` reads them. Only the header, or for plain JSON the version field, is
+/// looked at. Legacy bincode files predate versioning and are reported as version
+/// `LEGACY_FORMAT_VERSION`.
+pub(crate) fn file_version(bytes: &[u8], path: &Path) -> Result<u16, DbError> {
+    #[derive(Deserialize)]
+    struct JsonVersion {
+        format_version: u16,
+    }
+
+    if bytes.starts_with(MAGIC) && bytes.len() >= HEADER_LEN_V1 {
+        return Ok(u16::from_le_bytes([bytes[4], bytes[5]]));
+    }
+    if bytes.trim_ascii_start().starts_with(b"{") {
+        if let Ok(json) = Format::Json.deserialize::<JsonVersion>(bytes) {
+            return Ok(json.format_version);
+        }
+    }
+    if bytes.is_empty() {
+        return Err(DbError::EmptyFile(path.display().to_string()));
+    }
+    Ok(LEGACY_FORMAT_VERSION)
+}
+
+/// Checks the header and checksum of `bytes`, read from `path`, without deserializing
+/// the payload. Returns whether there was a checksum to verify.
+pub(crate) fn verify(bytes: &[u8], path: &Path) -> Result<bool, DbError> {
+    if !bytes.starts_with(MAGIC) {
+        return Ok(false);
+    }
+    split_header(bytes, path).map(|(header, _)| header.checksummed)
+}
+
+/// Decodes input that isn't encrypted, see `decode_with`.
+pub(crate) fn decode<T: DeserializeOwned>(bytes: &[u8], path: &Path) -> Result<Decoded<T>, DbError> {
+    decode_with(bytes, Unlock::Nothing, path)
+}
+
+/// Decodes `bytes`, read from `path`, which errors name. Input without the magic is
+/// tried as JSON when it starts with `{`, then as legacy bincode, and is rejected as
+/// `NotADatabaseFile` if neither deserializes, or as `CorruptData` if it looked like
+/// JSON. Encrypted input is decrypted with `unlock`.
+pub(crate) fn decode_with<T: DeserializeOwned>(bytes: &[u8], unlock: Unlock<'_>, path: &Path) -> Result<Decoded<T>, DbError> {
+    if bytes.is_empty() {
+        return Err(DbError::EmptyFile(path.display().to_string()));
+    }
+    if !bytes.starts_with(MAGIC) {
+        let mut json_error = None;
+        if bytes.trim_ascii_start().starts_with(b"{") {
+            match Format::Json.deserialize::<JsonFile<T>>(bytes) {
+                Ok(file) => {
+                    check_version(file.format_version)?;
+                    return Ok(Decoded { value: file.data, options: Format::Json.into(), checksummed: false, encryption: None });
+                }
+                Err(offset) => json_error = Some(offset),
+            }
+        }
+        let Ok(value) = Format::Bincode.deserialize(bytes) else {
+            return Err(match json_error {
+                Some(offset) => DbError::CorruptData { path: path.display().to_string(), offset: Some(offset) },
+                None => DbError::NotADatabaseFile(path.display().to_string()),
+            });
+        };
+        return Ok(Decoded { value, options: Format::Bincode.into(), checksummed: false, encryption: None });
+    }
+    let (Header { options, checksummed, encrypted }, payload) = split_header(bytes, path)?;
+    let header_len = bytes.len() - payload.len();
+    let (decrypted, encryption) = if encrypted {
+        let (decrypted, encryption) = encryption::open(payload, unlock)?;
+        (Some(decrypted), Some(encryption))
+    } else {
+        (None, None)
+    };
+    let payload = options.compression.decompress(decrypted.as_deref().unwrap_or(payload))?;
+    let value = options.format.deserialize(&payload).map_err(|offset| DbError::CorruptData {
+        path: path.display().to_string(),
+        // Offsets into a decompressed or decrypted payload don't point into the file.
+        offset: (options.compression == Compression::None && !encrypted).then_some(header_len as u64 + offset),
+    })?;
+    Ok(Decoded { value, options, checksummed, encryption })
+}
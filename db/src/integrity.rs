@@ -0,0 +1,107 @@
+use crate::{DbError, DbValue, SavedDatabase, Table};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum IntegrityFinding {
+    /// The table is stored under a key that differs from its own name.
+    TableNameMismatch { key: String, name: String },
+    RowSchemaMismatch { table: String, row: usize },
+    MissingDerivedTable { name: String },
+    /// The source of a materialized projection is gone but it was not flagged orphaned.
+    MissingSource { name: String, source: String },
+    DerivationWidthMismatch { name: String, expected: usize, got: usize },
+    /// Row ids or insertion times are not kept for exactly the rows of the table.
+    RowMetadataMismatch { table: String, rows: usize, ids: usize, created_at: usize },
+    /// Another row of the table already has the id of this one.
+    DuplicateRowId { table: String, row: usize, id: u64 },
+    CheckViolation { table: String, row: usize, column: usize },
+    /// A `Blob` or `String` value is longer than the table accepts.
+    ValueTooLong { table: String, row: usize, column: usize },
+    /// Another row of the table already has the auto-increment value of this one.
+    DuplicateAutoIncrement { table: String, row: usize, column: usize },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct IntegrityReport {
+    pub findings: Vec<IntegrityFinding>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+impl SavedDatabase {
+    /// Checks every internal invariant and collects all violations: tables, their rows and
+    /// row ids against the schema, constraints and limits, and materialized projections.
+    /// Rows not fitting the schema are not checked further. Decodes every table first if
+    /// the database was opened with `open_mmap`.
+    pub fn check_integrity(&self) -> Result<IntegrityReport, DbError> {
+        let tables: BTreeMap<&String, &Table> = self.sorted_tables()?.into_iter().collect();
+        let mut findings = Vec::new();
+        for (&key, table) in &tables {
+            if key != table.name() {
+                findings.push(IntegrityFinding::TableNameMismatch {
+                    key: key.clone(),
+                    name: table.name().to_string(),
+                });
+            }
+            let (rows, ids, created_at) = (table.rows().len(), table.row_ids().len(), table.created_at().len());
+            if ids != rows || created_at != rows {
+                findings.push(IntegrityFinding::RowMetadataMismatch { table: key.clone(), rows, ids, created_at });
+            }
+            let mut seen_ids = HashSet::new();
+            for (row, &id) in table.row_ids().iter().enumerate() {
+                if !seen_ids.insert(id) {
+                    findings.push(IntegrityFinding::DuplicateRowId { table: key.clone(), row, id });
+                }
+            }
+            let mut seen_sequence = HashSet::new();
+            for (row, values) in table.rows().iter().enumerate() {
+                if values.schema() != table.schema() {
+                    findings.push(IntegrityFinding::RowSchemaMismatch { table: key.clone(), row });
+                    continue;
+                }
+                for check in table.checks().iter().filter(|check| check.check(values).is_err()) {
+                    findings.push(IntegrityFinding::CheckViolation { table: key.clone(), row, column: check.column });
+                }
+                if let Err(DbError::BlobTooLarge { column, .. } | DbError::StringTooLong { column, .. }) = table.check_lengths(values) {
+                    findings.push(IntegrityFinding::ValueTooLong { table: key.clone(), row, column });
+                }
+                let sequence = table.autoincrement().and_then(|auto| match values.0[auto.column] {
+                    DbValue::Int(value) => Some((auto.column, i128::from(value))),
+                    DbValue::UInt(value) => Some((auto.column, i128::from(value))),
+                    _ => None,
+                });
+                if let Some((column, value)) = sequence {
+                    if !seen_sequence.insert(value) {
+                        findings.push(IntegrityFinding::DuplicateAutoIncrement { table: key.clone(), row, column });
+                    }
+                }
+            }
+        }
+        for (name, materialization) in self.db.materialized.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            if !tables.contains_key(name) {
+                findings.push(IntegrityFinding::MissingDerivedTable { name: name.clone() });
+            }
+            match tables.get(&materialization.source) {
+                None if !materialization.orphaned => findings.push(IntegrityFinding::MissingSource {
+                    name: name.clone(),
+                    source: materialization.source.clone(),
+                }),
+                Some(source) if source.schema().len() != materialization.columns.len() => {
+                    findings.push(IntegrityFinding::DerivationWidthMismatch {
+                        name: name.clone(),
+                        expected: source.schema().len(),
+                        got: materialization.columns.len(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(IntegrityReport { findings })
+    }
+}
@@ -0,0 +1,179 @@
+use crate::types::base64;
+use crate::{DbError, DbType, DbValue, Row, SavedDatabase, Table};
+use chrono::{DateTime, SecondsFormat};
+use serde_json::{json, Map, Value};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+fn value_to_json(value: &DbValue) -> Value {
+    match value {
+        DbValue::Int(x) => json!(x),
+        DbValue::UInt(x) => json!(x),
+        DbValue::Real(x) if x.is_nan() => json!("NaN"),
+        DbValue::Real(x) if x.is_infinite() => json!(if *x > 0.0 { "inf" } else { "-inf" }),
+        DbValue::Real(x) => json!(x),
+        DbValue::Char(x) => json!(x.to_string()),
+        DbValue::String(x) => json!(x),
+        DbValue::Time(x) => json!(x.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        DbValue::Blob(x) => json!(base64::encode(x)),
+    }
+}
+
+fn value_from_json(r#type: DbType, value: &Value) -> Result<DbValue, String> {
+    let mismatch = || format!("expected {:?}, got {}", r#type, value);
+    match r#type {
+        DbType::Int => value.as_i64().map(DbValue::Int).ok_or_else(mismatch),
+        DbType::UInt => value.as_u64().map(DbValue::UInt).ok_or_else(mismatch),
+        DbType::Real => match value.as_str() {
+            Some("NaN") => Ok(DbValue::Real(f64::NAN)),
+            Some("inf") => Ok(DbValue::Real(f64::INFINITY)),
+            Some("-inf") => Ok(DbValue::Real(f64::NEG_INFINITY)),
+            _ => value.as_f64().map(DbValue::Real).ok_or_else(mismatch),
+        },
+        DbType::Char => {
+            let mut chars = value.as_str().ok_or_else(mismatch)?.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(DbValue::Char(c)),
+                _ => Err(mismatch()),
+            }
+        }
+        DbType::String => value
+            .as_str()
+            .map(|x| DbValue::String(x.to_string()))
+            .ok_or_else(mismatch),
+        DbType::Time => {
+            let text = value.as_str().ok_or_else(mismatch)?;
+            DateTime::parse_from_rfc3339(text)
+                .map(DbValue::Time)
+                .map_err(|e| format!("invalid RFC3339 time {text:?}: {e}"))
+        }
+        DbType::Blob => {
+            let text = value.as_str().ok_or_else(mismatch)?;
+            base64::decode(text)
+                .map(DbValue::Blob)
+                .ok_or_else(|| format!("invalid base64 blob {text:?}"))
+        }
+    }
+}
+
+fn as_object<'a>(value: &'a Value, what: &str, allowed: &[&str], lenient: bool) -> Result<&'a Map<String, Value>, DbError> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| DbError::InvalidJsonExport(format!("{what} must be an object")))?;
+    if !lenient {
+        if let Some(key) = object.keys().find(|key| !allowed.contains(&key.as_str())) {
+            return Err(DbError::InvalidJsonExport(format!("unknown key {key:?} in {what}")));
+        }
+    }
+    Ok(object)
+}
+
+fn field<'a>(object: &'a Map<String, Value>, what: &str, key: &str) -> Result<&'a Value, DbError> {
+    object
+        .get(key)
+        .ok_or_else(|| DbError::InvalidJsonExport(format!("missing key {key:?} in {what}")))
+}
+
+fn table_from_json(name: String, value: &Value, lenient: bool) -> Result<Table, DbError> {
+    let what = format!("table {name}");
+    let object = as_object(value, &what, &["schema", "rows"], lenient)?;
+    let schema: Vec<DbType> = serde_json::from_value(field(object, &what, "schema")?.clone())?;
+    let rows = field(object, &what, "rows")?
+        .as_array()
+        .ok_or_else(|| DbError::InvalidJsonExport(format!("rows of {what} must be an array")))?;
+
+    let mut table = Table::new(name.clone(), schema.clone());
+    for (row_index, row) in rows.iter().enumerate() {
+        let mismatch = |column: usize, reason: String| DbError::JsonValueMismatch {
+            table: name.clone(),
+            row: row_index,
+            column,
+            reason,
+        };
+        let values = row
+            .as_array()
+            .ok_or_else(|| mismatch(0, "row must be an array".to_string()))?;
+        if values.len() != schema.len() {
+            let reason = format!("expected {} values, got {}", schema.len(), values.len());
+            return Err(mismatch(schema.len().min(values.len()), reason));
+        }
+        let mut new_row = Vec::with_capacity(values.len());
+        for (column, (r#type, value)) in schema.iter().zip(values).enumerate() {
+            new_row.push(value_from_json(*r#type, value).map_err(|reason| mismatch(column, reason))?);
+        }
+        table.insert_row(Row(new_row))?;
+    }
+    Ok(table)
+}
+
+fn table_to_json(table: &Table) -> Result<Value, DbError> {
+    let rows: Vec<Value> = table
+        .rows()
+        .iter()
+        .map(|row| Value::Array(row.0.iter().map(value_to_json).collect()))
+        .collect();
+    Ok(json!({
+        "schema": serde_json::to_value(table.schema())?,
+        "rows": rows,
+    }))
+}
+
+fn write_json<W: Write>(w: W, value: &Value, pretty: bool) -> Result<(), DbError> {
+    if pretty {
+        serde_json::to_writer_pretty(w, value)?;
+    } else {
+        serde_json::to_writer(w, value)?;
+    }
+    Ok(())
+}
+
+impl SavedDatabase {
+    /// Writes the whole database as JSON. Tables are keyed by name in sorted order,
+    /// times are RFC3339 strings, blobs base64 and non-finite reals are written as "NaN", "inf" or "-inf".
+    pub fn export_json<W: Write>(&self, w: W, pretty: bool) -> Result<(), DbError> {
+        let mut tables = Map::new();
+        for name in self.get_table_names() {
+            let table = self.get_table(name.clone())?;
+            tables.insert(name, table_to_json(table)?);
+        }
+        let value = json!({
+            "name": self.get_name(),
+            "tables": tables,
+        });
+        write_json(w, &value, pretty)
+    }
+
+    pub fn export_table_json<W: Write>(&self, name: String, w: W) -> Result<(), DbError> {
+        let value = table_to_json(self.get_table(name)?)?;
+        write_json(w, &value, false)
+    }
+
+    /// Creates a new database at `path_for_new_db` from the `export_json` format.
+    /// Unknown keys are rejected unless `lenient` is set.
+    pub fn import_json<R: Read>(path_for_new_db: impl Into<PathBuf>, r: R, lenient: bool) -> Result<SavedDatabase, DbError> {
+        let value: Value = serde_json::from_reader(r)?;
+        let object = as_object(&value, "database", &["name", "tables"], lenient)?;
+        let name = field(object, "database", "name")?
+            .as_str()
+            .ok_or_else(|| DbError::InvalidJsonExport("database name must be a string".to_string()))?;
+        let tables = as_object(field(object, "database", "tables")?, "tables", &[], true)?;
+        let tables = tables
+            .iter()
+            .map(|(name, table)| table_from_json(name.clone(), table, lenient))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut db = SavedDatabase::create(name.to_string(), path_for_new_db)?;
+        for table in tables {
+            db.insert_table(table)?;
+        }
+        db.save()?;
+        Ok(db)
+    }
+
+    /// Adds a single table in the `export_table_json` format under `name`.
+    pub fn import_table_json<R: Read>(&mut self, name: String, r: R, lenient: bool) -> Result<(), DbError> {
+        let value: Value = serde_json::from_reader(r)?;
+        let table = table_from_json(name, &value, lenient)?;
+        self.insert_table(table)
+    }
+}
@@ -0,0 +1,179 @@
+use crate::database::{Database, Materialization, SavedDatabase};
+use crate::format::{self, Decoded, StorageOptions};
+use crate::lock::DbLock;
+use crate::migrations;
+use crate::types::DbError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, read, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest";
+const TABLE_EXTENSION: &str = "table";
+const MANIFEST_VERSION: u32 = 1;
+
+/// Where a database keeps its data: a single file, or a directory holding a manifest plus
+/// one file per table so that saving only rewrites the tables that changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Layout {
+    File,
+    Directory,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    format_version: u32,
+    pub(crate) name: String,
+    pub(crate) tables: Vec<String>,
+    pub(crate) materialized: HashMap<String, Materialization>,
+}
+
+/// `path` with `suffix` appended to its last component, e.g. `db.wal` for `db`.
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Replaces `path` with `bytes` through a synced `<path>.tmp` renamed over it, so a failed
+/// write leaves the previous contents in place.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), DbError> {
+    let tmp = with_suffix(path, ".tmp");
+
+    let written = File::create(&tmp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+
+    // Make the rename itself durable.
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Table names may contain anything, so files are named after their hex encoding.
+fn table_file_name(name: &str) -> String {
+    let hex: String = name.bytes().map(|b| format!("{b:02x}")).collect();
+    format!("{hex}.{TABLE_EXTENSION}")
+}
+
+/// Writes the manifest and every table file, or only those of dirty tables unless `all`,
+/// then deletes files of tables that are no longer part of the database. Returns the
+/// names of the tables written.
+pub(crate) fn write_dir(db: &Database, dir: &Path, all: bool, options: StorageOptions) -> Result<Vec<String>, DbError> {
+    create_dir_all(dir)?;
+    let mut written = Vec::new();
+    for (name, table) in &db.tables {
+        if all || table.is_dirty() {
+            write_atomic(&dir.join(table_file_name(name)), &format::encode(table, options, None)?)?;
+            written.push(name.clone());
+        }
+    }
+
+    let mut tables: Vec<String> = db.tables.keys().cloned().collect();
+    tables.sort();
+    let manifest = Manifest {
+        format_version: MANIFEST_VERSION,
+        name: db.name.clone(),
+        tables,
+        materialized: db.materialized.clone(),
+    };
+    write_atomic(&dir.join(MANIFEST), &format::encode(&manifest, options, None)?)?;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let is_table_file = path.extension().is_some_and(|extension| extension == TABLE_EXTENSION);
+        if is_table_file && !manifest.tables.iter().any(|name| table_file_name(name) == file_name) {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(written)
+}
+
+/// Whether `dir` holds a database in the directory layout.
+pub(crate) fn is_database_dir(dir: &Path) -> bool {
+    dir.join(MANIFEST).is_file()
+}
+
+pub(crate) fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST)
+}
+
+pub(crate) fn read_manifest(dir: &Path) -> Result<Decoded<Manifest>, DbError> {
+    let path = manifest_path(dir);
+    let decoded: Decoded<Manifest> = format::decode(&read(&path)?, &path)?;
+    let manifest = &decoded.value;
+    if manifest.format_version != MANIFEST_VERSION {
+        return Err(DbError::UnsupportedVersion {
+            found: manifest.format_version,
+            supported: MANIFEST_VERSION,
+        });
+    }
+    Ok(decoded)
+}
+
+pub(crate) fn table_path(dir: &Path, name: &str) -> Result<PathBuf, DbError> {
+    let path = dir.join(table_file_name(name));
+    if !path.exists() {
+        return Err(DbError::MissingTableFile {
+            table: name.to_string(),
+            path: path.display().to_string(),
+        });
+    }
+    Ok(path)
+}
+
+/// The options are those of the manifest, which later saves keep using; the database
+/// counts as checksummed only if every file was.
+pub(crate) fn read_dir(dir: &Path) -> Result<Decoded<Database>, DbError> {
+    let Decoded { value: manifest, options, mut checksummed, .. } = read_manifest(dir)?;
+    let mut tables = HashMap::new();
+    for name in manifest.tables {
+        let path = table_path(dir, &name)?;
+        let decoded = migrations::migrate_table(&read(&path)?, &path)?;
+        checksummed &= decoded.checksummed;
+        tables.insert(name, decoded.value);
+    }
+
+    let value = Database {
+        name: manifest.name,
+        tables,
+        materialized: manifest.materialized,
+    };
+    Ok(Decoded { value, options, checksummed, encryption: None })
+}
+
+/// Verifies the checksums of the manifest and every table file it lists.
+pub(crate) fn verify_dir(dir: &Path) -> Result<(), DbError> {
+    let manifest = read_manifest(dir)?.value;
+    for name in &manifest.tables {
+        let path = table_path(dir, name)?;
+        format::verify(&read(&path)?, &path)?;
+    }
+    Ok(())
+}
+
+impl SavedDatabase {
+    /// Creates a database stored as a directory with one file per table at `dir`.
+    pub fn create_dir_layout(name: String, dir: impl Into<PathBuf>) -> Result<Self, DbError> {
+        let dir = dir.into();
+        let lock = DbLock::exclusive(&dir)?;
+        let mut db = Self::create_with_layout(name, dir, Layout::Directory);
+        db.set_lock(lock);
+        db.save()?;
+
+        Ok(db)
+    }
+}
@@ -1,10 +1,50 @@
+mod aggregate;
+mod autosave;
+mod backup;
 mod database;
+pub mod diff;
+mod dump;
+mod embedded;
+mod encryption;
+mod events;
+mod export;
+mod expr;
+mod format;
+mod integrity;
+mod json;
+mod layout;
+mod lock;
+mod manager;
+mod migrations;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod query;
+mod schema;
+mod search;
+mod shared;
+mod sql;
 pub mod rpc;
 mod table;
 #[cfg(test)]
 mod tests;
 mod types;
+mod wal;
 
-pub use database::SavedDatabase;
-pub use table::Table;
+pub use aggregate::Aggregate;
+pub use autosave::AutosavePolicy;
+pub use database::{DatabaseSnapshot, DbSnapshot, DbStats, LoadOptions, LoadReport, MaterializedInfo, SaveSummary, SavedDatabase, TableInfo};
+pub use dump::SqlDialect;
+pub use embedded::Embedded;
+pub use events::ChangeEvent;
+pub use expr::Expr;
+pub use format::{Compression, Format, StorageOptions};
+pub use integrity::{IntegrityFinding, IntegrityReport};
+pub use manager::DatabaseManager;
+pub use query::{CompareOp, Condition, Query};
+pub use schema::SchemaBuilder;
+pub use search::SearchHit;
+pub use shared::SharedDatabase;
+pub use sql::{sql_operation, QueryResult};
+pub use table::{AutoIncrement, CheckConstraint, ColumnDefault, Table, TableStats, TimeBounds, DEFAULT_MAX_BLOB_LEN, MAX_STRING_LEN};
 pub use types::{DbError, DbType, DbValue, Row};
+pub use wal::{FsyncPolicy, TxOp};
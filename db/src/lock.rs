@@ -0,0 +1,70 @@
+use crate::layout::with_suffix;
+use crate::types::DbError;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Advisory lock on `<path>.lock`, released when the last clone of the database holding
+/// it is dropped. The OS drops the lock of a process that died, so a lock file left
+/// behind by a crash is simply taken over; it only still names the dead holder.
+#[derive(Debug)]
+pub(crate) struct DbLock {
+    file: File,
+    pub(crate) shared: bool,
+}
+
+pub(crate) fn lock_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".lock")
+}
+
+impl DbLock {
+    /// Taken by databases that may save; the lock file records the holder's PID.
+    pub(crate) fn exclusive(path: &Path) -> Result<Self, DbError> {
+        let mut file = open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(locked(path, &mut file)),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(Self { file, shared: false })
+    }
+
+    /// Taken by read-only databases, any number of which may be open at once.
+    pub(crate) fn shared(path: &Path) -> Result<Self, DbError> {
+        let mut file = open(path)?;
+        match file.try_lock_shared() {
+            Ok(()) => Ok(Self { file, shared: true }),
+            Err(TryLockError::WouldBlock) => Err(locked(path, &mut file)),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for DbLock {
+    /// Clears the PID while still holding the lock, so that later shared holders aren't
+    /// reported as this process.
+    fn drop(&mut self) {
+        if !self.shared {
+            let _ = self.file.set_len(0);
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File, DbError> {
+    let lock_path = lock_path(path);
+    if let Some(parent) = lock_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(lock_path)?)
+}
+
+/// The PID is missing while the lock is only held shared.
+fn locked(path: &Path, file: &mut File) -> DbError {
+    let mut content = String::new();
+    let holder_pid = file.read_to_string(&mut content).ok().and_then(|_| content.trim().parse().ok());
+    DbError::DatabaseLocked { path: path.display().to_string(), holder_pid }
+}
@@ -0,0 +1,62 @@
+use crate::database::SavedDatabase;
+use crate::types::DbError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const EXTENSION: &str = "db";
+
+/// A directory of databases, each stored as `<name>.db` and addressed by its name.
+#[derive(Debug, Clone)]
+pub struct DatabaseManager {
+    dir: PathBuf,
+}
+
+impl DatabaseManager {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Names of the databases in the directory, sorted. Names that aren't UTF-8 are
+    /// skipped.
+    pub fn list(&self) -> Result<Vec<String>, DbError> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Where the database called `name` is stored. Names that would point outside the
+    /// directory are rejected.
+    pub fn path_of(&self, name: &str) -> Result<PathBuf, DbError> {
+        let invalid = name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']);
+        if invalid {
+            return Err(DbError::InvalidDatabaseName(name.to_string()));
+        }
+        Ok(self.dir.join(format!("{name}.{EXTENSION}")))
+    }
+
+    /// Opens the database called `name` like `SavedDatabase::load_from_disk`.
+    pub fn open(&self, name: &str) -> Result<SavedDatabase, DbError> {
+        SavedDatabase::load_from_disk(self.path_of(name)?)
+    }
+
+    /// Creates a database called `name` in the directory.
+    pub fn create(&self, name: &str) -> Result<SavedDatabase, DbError> {
+        let path = self.path_of(name)?;
+        if path.exists() {
+            return Err(DbError::FileExists(path.display().to_string()));
+        }
+        SavedDatabase::create(name.to_string(), path)
+    }
+}
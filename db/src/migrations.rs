@@ -0,0 +1,188 @@
+use crate::database::{Database, Materialization, SavedDatabase};
+use crate::encryption::Unlock;
+use crate::format::{self, Decoded};
+use crate::layout::{self, with_suffix};
+use crate::table::{AutoIncrement, CheckConstraint, Table};
+use crate::types::{DbError, DbType, Row};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, read};
+use std::path::{Path, PathBuf};
+
+/// Tables of files written before the header existed, which only had rows and a schema.
+#[derive(Deserialize)]
+pub(crate) struct TableV0 {
+    name: String,
+    rows: Vec<Row>,
+    schema: Vec<DbType>,
+}
+
+/// Databases of files written before the header existed, before materialized projections.
+#[derive(Deserialize)]
+struct DatabaseV0 {
+    name: String,
+    tables: HashMap<String, TableV0>,
+}
+
+/// Tables of versions 1 and 2, before rows had ids.
+#[derive(Deserialize)]
+pub(crate) struct TableV2 {
+    name: String,
+    rows: Vec<Row>,
+    schema: Vec<DbType>,
+    version: u64,
+    checks: Vec<CheckConstraint>,
+    #[serde(default)]
+    created_at: Vec<DateTime<Utc>>,
+}
+
+/// Tables of version 3, before auto-increment columns.
+#[derive(Deserialize)]
+pub(crate) struct TableV3 {
+    name: String,
+    rows: Vec<Row>,
+    schema: Vec<DbType>,
+    version: u64,
+    checks: Vec<CheckConstraint>,
+    #[serde(default)]
+    created_at: Vec<DateTime<Utc>>,
+    #[serde(default)]
+    ids: Vec<u64>,
+    #[serde(default)]
+    next_id: u64,
+}
+
+/// Tables of version 4, before column defaults and blob limits were saved.
+#[derive(Deserialize)]
+pub(crate) struct TableV4 {
+    name: String,
+    rows: Vec<Row>,
+    schema: Vec<DbType>,
+    version: u64,
+    checks: Vec<CheckConstraint>,
+    #[serde(default)]
+    created_at: Vec<DateTime<Utc>>,
+    #[serde(default)]
+    ids: Vec<u64>,
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default)]
+    autoincrement: Option<AutoIncrement>,
+}
+
+/// Databases of versions 1 to 4, the same as today's but for the tables.
+#[derive(Deserialize)]
+struct LegacyDatabase<T> {
+    name: String,
+    tables: HashMap<String, T>,
+    materialized: HashMap<String, Materialization>,
+}
+
+impl From<TableV0> for Table {
+    fn from(table: TableV0) -> Self {
+        let TableV0 { name, rows, schema } = table;
+        Table::from_legacy(name, rows, schema, 0, Vec::new(), Vec::new(), (Vec::new(), 0, None))
+    }
+}
+
+impl From<TableV2> for Table {
+    fn from(table: TableV2) -> Self {
+        let TableV2 { name, rows, schema, version, checks, created_at } = table;
+        Table::from_legacy(name, rows, schema, version, checks, created_at, (Vec::new(), 0, None))
+    }
+}
+
+impl From<TableV3> for Table {
+    fn from(table: TableV3) -> Self {
+        let TableV3 { name, rows, schema, version, checks, created_at, ids, next_id } = table;
+        Table::from_legacy(name, rows, schema, version, checks, created_at, (ids, next_id, None))
+    }
+}
+
+impl From<TableV4> for Table {
+    fn from(table: TableV4) -> Self {
+        let TableV4 { name, rows, schema, version, checks, created_at, ids, next_id, autoincrement } = table;
+        Table::from_legacy(name, rows, schema, version, checks, created_at, (ids, next_id, autoincrement))
+    }
+}
+
+impl From<DatabaseV0> for Database {
+    fn from(db: DatabaseV0) -> Self {
+        Database {
+            name: db.name,
+            tables: db.tables.into_iter().map(|(name, table)| (name, table.into())).collect(),
+            materialized: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Into<Table>> From<LegacyDatabase<T>> for Database {
+    fn from(db: LegacyDatabase<T>) -> Self {
+        Database {
+            name: db.name,
+            tables: db.tables.into_iter().map(|(name, table)| (name, table.into())).collect(),
+            materialized: db.materialized,
+        }
+    }
+}
+
+/// Decodes a database file of any supported version, see `format::decode_with`. Each
+/// version whose structures differ from the current ones has a private copy of them
+/// above, converted to the current ones after decoding; saving always writes
+/// `FORMAT_VERSION`.
+pub(crate) fn migrate(bytes: &[u8], unlock: Unlock<'_>, path: &Path) -> Result<Decoded<Database>, DbError> {
+    match format::file_version(bytes, path)? {
+        0 => Ok(format::decode_with::<DatabaseV0>(bytes, unlock, path)?.map(Database::from)),
+        1 | 2 => Ok(format::decode_with::<LegacyDatabase<TableV2>>(bytes, unlock, path)?.map(Database::from)),
+        3 => Ok(format::decode_with::<LegacyDatabase<TableV3>>(bytes, unlock, path)?.map(Database::from)),
+        4 => Ok(format::decode_with::<LegacyDatabase<TableV4>>(bytes, unlock, path)?.map(Database::from)),
+        _ => format::decode_with(bytes, unlock, path),
+    }
+}
+
+/// Decodes a table file of the directory layout of any supported version.
+pub(crate) fn migrate_table(bytes: &[u8], path: &Path) -> Result<Decoded<Table>, DbError> {
+    match format::file_version(bytes, path)? {
+        0 => Ok(format::decode::<TableV0>(bytes, path)?.map(Table::from)),
+        1 | 2 => Ok(format::decode::<TableV2>(bytes, path)?.map(Table::from)),
+        3 => Ok(format::decode::<TableV3>(bytes, path)?.map(Table::from)),
+        4 => Ok(format::decode::<TableV4>(bytes, path)?.map(Table::from)),
+        _ => format::decode(bytes, path),
+    }
+}
+
+impl SavedDatabase {
+    /// Format version of the file at `path`, or of the manifest for the directory
+    /// layout, without decoding it. Older versions still load and are written in the
+    /// current one by the next save.
+    pub fn file_version(path: impl AsRef<Path>) -> Result<u16, DbError> {
+        let path = path.as_ref();
+        Self::check_loadable(path)?;
+        let path = if path.is_dir() { layout::manifest_path(path) } else { path.to_path_buf() };
+        format::file_version(&read(&path)?, &path)
+    }
+
+    /// Rewrites the database at `path` in the current format version, after copying the
+    /// original to `<path>.bak`, which is returned. Fails with `FileExists` if that copy
+    /// is already there, so an earlier original is never replaced.
+    pub fn migrate(path: impl Into<PathBuf>) -> Result<PathBuf, DbError> {
+        let path = path.into();
+        let mut db = Self::load_from_disk(&path)?;
+        let backup = with_suffix(&path, ".bak");
+        if backup.exists() {
+            return Err(DbError::FileExists(backup.display().to_string()));
+        }
+        if path.is_dir() {
+            fs::create_dir(&backup)?;
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                fs::copy(entry.path(), backup.join(entry.file_name()))?;
+            }
+        } else {
+            fs::copy(&path, &backup)?;
+        }
+        db.save_full()?;
+        Ok(backup)
+    }
+}
@@ -0,0 +1,122 @@
+use crate::database::{Database, LoadOptions, SavedDatabase};
+use crate::encryption::Unlock;
+use crate::format::Decoded;
+use crate::layout::{self, Layout};
+use crate::lock::DbLock;
+use crate::migrations;
+use crate::table::Table;
+use crate::types::DbError;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Table files of a database opened with `open_mmap`, each decoded the first time it is
+/// accessed. Empty for the single file layout, which is decoded right away.
+#[derive(Debug, Default)]
+pub(crate) struct MappedTables {
+    tables: HashMap<String, MappedTable>,
+    /// Number of tables decoded so far.
+    decoded: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct MappedTable {
+    path: PathBuf,
+    map: Mmap,
+    table: OnceLock<Table>,
+}
+
+impl MappedTables {
+    pub(crate) fn names(&self) -> impl Iterator<Item = &String> {
+        self.tables.keys()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Decodes the table `name` unless that already happened; `None` if there is no such
+    /// table.
+    pub(crate) fn table(&self, name: &str) -> Option<Result<&Table, DbError>> {
+        let mapped = self.tables.get(name)?;
+        if let Some(table) = mapped.table.get() {
+            return Some(Ok(table));
+        }
+        Some(decode_table(&mapped.map, &mapped.path).map(|table| {
+            // Another thread may have decoded it meanwhile, both results are the same.
+            if mapped.table.set(table).is_ok() {
+                self.decoded.fetch_add(1, Ordering::Relaxed);
+            }
+            mapped.table.get().expect("table was just set")
+        }))
+    }
+}
+
+/// Maps `path`, which stays mapped as long as the database is open.
+fn map(path: &Path) -> Result<Mmap, DbError> {
+    let file = File::open(path)?;
+    // SAFETY: the shared lock taken by `open_mmap` keeps other databases from saving
+    // over the file while it is mapped; other programs modifying it are not guarded
+    // against, like with any memory-mapped file.
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+fn decode_table(bytes: &[u8], path: &Path) -> Result<Table, DbError> {
+    let mut table = migrations::migrate_table(bytes, path)?.value;
+    table.validate_rows()?;
+    table.assign_missing_ids();
+    Ok(table)
+}
+
+impl SavedDatabase {
+    /// Opens `path` read-only for analysis of large databases without reading it into a
+    /// buffer first: the file is memory-mapped and deserialized from the mapping, and in
+    /// the directory layout each table is only decoded when `get_table` first asks for it.
+    /// Methods going over every table, like `search`, `snapshot` or `stats`, decode the
+    /// rest first and fail if one of them can't be. Mutations fail with `MemoryMapped` and
+    /// a write-ahead log is not replayed.
+    pub fn open_mmap(path: impl Into<PathBuf>) -> Result<Self, DbError> {
+        let path = path.into();
+        Self::check_loadable(&path)?;
+        let lock = DbLock::shared(&path)?;
+        let mut mapped = MappedTables::default();
+        let mut db = if path.is_dir() {
+            let Decoded { value: manifest, options, checksummed, .. } = layout::read_manifest(&path)?;
+            for name in manifest.tables {
+                let table_path = layout::table_path(&path, &name)?;
+                let map = map(&table_path)?;
+                mapped.tables.insert(name, MappedTable { path: table_path, map, table: OnceLock::new() });
+            }
+            let db = Database { name: manifest.name, tables: HashMap::new(), materialized: manifest.materialized };
+            let decoded = Decoded { value: db, options, checksummed, encryption: None };
+            Self::from_decoded(decoded, path, Layout::Directory, LoadOptions::default())?
+        } else {
+            let decoded = migrations::migrate(&map(&path)?, Unlock::Nothing, &path)?;
+            Self::from_decoded(decoded, path, Layout::File, LoadOptions::default())?
+        };
+        db.set_lock(lock);
+        db.mapped = Some(Arc::new(mapped));
+        Ok(db)
+    }
+
+    /// Decodes every table of a database opened with `open_mmap` and unmaps the files.
+    /// The result can be changed like any database opened with `open_read_only`.
+    pub fn materialize(mut self) -> Result<Self, DbError> {
+        if let Some(mapped) = self.mapped.take() {
+            for name in mapped.names() {
+                let table = mapped.table(name).expect("name is mapped")?;
+                self.db.tables.insert(name.clone(), table.clone());
+            }
+        }
+        Ok(self)
+    }
+
+    /// Number of tables `open_mmap` decoded so far.
+    #[cfg(test)]
+    pub(crate) fn decoded_tables(&self) -> usize {
+        self.mapped.as_ref().map_or(0, |mapped| mapped.decoded.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{DbError, DbValue, Row, SavedDatabase, Table};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    /// Values of different types never match.
+    pub fn matches(self, left: &DbValue, right: &DbValue) -> bool {
+        if left.get_type() != right.get_type() {
+            return false;
+        }
+        let Some(ordering) = left.partial_cmp(right) else {
+            return self == Self::Ne;
+        };
+        match self {
+            Self::Eq => ordering == Ordering::Equal,
+            Self::Ne => ordering != Ordering::Equal,
+            Self::Lt => ordering == Ordering::Less,
+            Self::Le => ordering != Ordering::Greater,
+            Self::Gt => ordering == Ordering::Greater,
+            Self::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Condition {
+    Compare {
+        column: usize,
+        op: CompareOp,
+        value: DbValue,
+    },
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    pub fn compare(column: usize, op: CompareOp, value: DbValue) -> Self {
+        Self::Compare { column, op, value }
+    }
+
+    pub fn matches(&self, row: &Row) -> bool {
+        match self {
+            Self::Compare { column, op, value } => op.matches(&row.0[*column], value),
+            Self::And(conditions) => conditions.iter().all(|c| c.matches(row)),
+            Self::Or(conditions) => conditions.iter().any(|c| c.matches(row)),
+            Self::Not(condition) => !condition.matches(row),
+        }
+    }
+
+    fn validate(&self, width: usize) -> Result<(), DbError> {
+        match self {
+            Self::Compare { column, .. } if *column >= width => Err(DbError::ColumnOutOfRange(*column)),
+            Self::Compare { .. } => Ok(()),
+            Self::And(conditions) | Self::Or(conditions) => {
+                conditions.iter().try_for_each(|c| c.validate(width))
+            }
+            Self::Not(condition) => condition.validate(width),
+        }
+    }
+}
+
+/// A serializable selection over one table. All column indexes refer to the source table.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Query {
+    table: String,
+    columns: Option<Vec<usize>>,
+    filters: Vec<Condition>,
+    order_by: Option<(usize, bool)>,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+impl Query {
+    pub fn new(table: String) -> Self {
+        Self {
+            table,
+            columns: None,
+            filters: Vec::new(),
+            order_by: None,
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    pub fn select_columns(mut self, columns: &[usize]) -> Self {
+        self.columns = Some(columns.to_vec());
+        self
+    }
+
+    /// Conditions added by repeated calls must all hold.
+    pub fn filter(mut self, condition: Condition) -> Self {
+        self.filters.push(condition);
+        self
+    }
+
+    pub fn order_by(mut self, column: usize, desc: bool) -> Self {
+        self.order_by = Some((column, desc));
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    fn validate(&self, table: &Table) -> Result<(), DbError> {
+        let width = table.schema().len();
+        let columns = self.columns.iter().flatten();
+        if let Some(column) = columns.chain(self.order_by.iter().map(|(c, _)| c)).find(|c| **c >= width) {
+            return Err(DbError::ColumnOutOfRange(*column));
+        }
+        self.filters.iter().try_for_each(|c| c.validate(width))
+    }
+
+    /// The rows passing the filters in order, at most `max_rows` of them.
+    fn matching<'a>(&self, table: &'a Table, max_rows: usize) -> Result<Vec<&'a Row>, DbError> {
+        self.validate(table)?;
+        let mut rows: Vec<&Row> = table
+            .rows()
+            .iter()
+            .filter(|row| self.filters.iter().all(|c| c.matches(row)))
+            .collect();
+        if let Some((column, desc)) = self.order_by {
+            rows.sort_by(|a, b| {
+                let ordering = a.0[column].sort_cmp(&b.0[column]);
+                if desc { ordering.reverse() } else { ordering }
+            });
+        }
+        let limit = self.limit.map_or(max_rows, |limit| limit.min(max_rows));
+        Ok(rows.into_iter().skip(self.offset).take(limit).collect())
+    }
+
+    fn project(&self, row: &Row) -> Row {
+        match &self.columns {
+            Some(columns) => Row(columns.iter().map(|c| row.0[*c].clone()).collect()),
+            None => row.clone(),
+        }
+    }
+
+    pub fn rows(&self, db: &SavedDatabase) -> Result<Vec<Row>, DbError> {
+        self.rows_within(db, usize::MAX)
+    }
+
+    /// Like `rows`, but stops after `max_rows` rows as if the query had that `limit`, so
+    /// that a caller capping results never builds more than it sends.
+    pub fn rows_within(&self, db: &SavedDatabase, max_rows: usize) -> Result<Vec<Row>, DbError> {
+        let table = db.get_table(self.table.clone())?;
+        Ok(self.matching(table, max_rows)?.into_iter().map(|row| self.project(row)).collect())
+    }
+
+    pub fn count(&self, db: &SavedDatabase) -> Result<usize, DbError> {
+        let table = db.get_table(self.table.clone())?;
+        Ok(self.matching(table, usize::MAX)?.len())
+    }
+
+    /// Stores the result as a new table named `name`.
+    pub fn into_table(&self, db: &mut SavedDatabase, name: String) -> Result<(), DbError> {
+        let table = db.get_table(self.table.clone())?;
+        let schema = match &self.columns {
+            Some(columns) => {
+                self.validate(table)?;
+                columns.iter().map(|c| table.schema()[*c]).collect()
+            }
+            None => table.schema().to_vec(),
+        };
+        let rows = self.rows(db)?;
+        let mut new_table = Table::new(name, schema);
+        for row in rows {
+            new_table.insert_row(row)?;
+        }
+        db.insert_table(new_table)
+    }
+}
+
+impl SavedDatabase {
+    pub fn query(&self, table: &str) -> Query {
+        Query::new(table.to_string())
+    }
+}
@@ -1,17 +1,316 @@
-use crate::{DbType, Row};
+use crate::diff::DatabaseDiff;
+use crate::types::DbError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::{ChangeEvent, CheckConstraint, DatabaseSnapshot, DbStats, DbType, DbValue, Format, IntegrityReport, Query, QueryResult, Row, SaveSummary, SearchHit, TableInfo, TableStats};
+
+/// Version of the `Service` protocol, bumped whenever a call changes incompatibly.
+/// 2: every call returns a `Result`.
+/// 3: `insert_row` and `insert_row_current` return the stored row.
+/// 4: `create` and `open` take `force`.
+/// 5: `ServerStatus` has `last_autosave`.
+/// 6: `authenticate` returns a session, which every call but `protocol_version` and
+/// `status` takes.
+/// 7: connections start with the preamble of their `WireFormat`.
+/// 8: `TxOp::AddCheck` follows `RefreshMaterialized`, changing the tags in
+/// `ChangeEvent::Mutation`.
+/// 9: `import_json` takes `force`.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// How the messages of a connection are serialized. Before them, each side sends the
+/// other a byte naming its format, so that a client and server disagreeing on it fail
+/// right away instead of misreading each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    /// Smaller and faster to encode than JSON, especially for rows with times.
+    Bincode,
+}
+
+impl WireFormat {
+    fn preamble(self) -> u8 {
+        match self {
+            Self::Json => b'j',
+            Self::Bincode => b'b',
+        }
+    }
+
+    /// Sends this format's preamble over `stream` and reads the peer's, failing with
+    /// `InvalidData` if it names another format.
+    pub async fn handshake(self, stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> io::Result<()> {
+        stream.write_all(&[self.preamble()]).await?;
+        stream.flush().await?;
+        let peer = stream.read_u8().await?;
+        if peer == self.preamble() {
+            return Ok(());
+        }
+        let peer = [Self::Json, Self::Bincode]
+            .into_iter()
+            .find(|format| format.preamble() == peer)
+            .map_or_else(|| format!("an unknown wire format ({peer:#04x})"), |format| format!("{format}"));
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("peer speaks {peer}, expected {self}")))
+    }
+}
+
+impl Display for WireFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Json => "json",
+            Self::Bincode => "bincode",
+        })
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            _ => Err(format!("unknown wire format {s:?}, expected json or bincode")),
+        }
+    }
+}
+
+/// Session `authenticate` started, which calls pass to show they may be made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionId(pub u128);
+
+impl SessionId {
+    /// What clients pass to a server without an auth token, which takes every session.
+    pub const NONE: Self = Self(0);
+}
+
+/// Kind of change a table's ACL can allow, see `Service::set_table_acl`. Reading is always
+/// allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TableOperation {
+    Insert,
+    Update,
+    Delete,
+    /// Creating, dropping or replacing the table, or adding checks to it.
+    Alter,
+}
+
+/// Part of a table's rows returned by `Service::get_rows_page`: `rows` starts at row
+/// `offset` of the `total` rows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowsPage {
+    pub rows: Vec<Row>,
+    pub total: usize,
+    pub offset: usize,
+}
+
+/// What `Service::status` reports about the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    /// The open database, if any.
+    pub database: Option<DbStatus>,
+    pub uptime_secs: u64,
+    /// Version of the server package.
+    pub version: String,
+    /// When the server last saved the open database by itself, see its `--autosave-secs`.
+    pub last_autosave: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbStatus {
+    pub name: String,
+    /// Where the database is saved, with anything not UTF-8 replaced.
+    pub path: String,
+    pub table_count: usize,
+    pub total_rows: usize,
+    /// There are unsaved changes.
+    pub dirty: bool,
+}
+
+/// Failure of a call. Errors of the database clients are likely to act on are mirrored
+/// with their fields, the rest arrive as `Db` with their message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum DbRpcError {
+    #[error("No database is open")]
+    NoDatabaseOpen,
+    #[error("No table is selected")]
+    NoTableSelected,
+    #[error("The open database has unsaved changes")]
+    UnsavedChanges,
+    #[error("Result has more than the allowed {limit} rows")]
+    ResultTooLarge { limit: usize },
+    #[error("No savepoint has id {0}")]
+    UnknownSavepoint(u64),
+    #[error("Table {table} does not allow {operation:?}")]
+    Forbidden { table: String, operation: TableOperation },
+    #[error("Not authorized")]
+    Unauthorized,
+    #[error("Path {0} is not valid UTF-8")]
+    PathNotUtf8(String),
+    #[error("Unknown type {0}")]
+    UnknownType(String),
+    #[error("Row has {got} values, expected {expected}")]
+    RowLengthMismatch { expected: usize, got: usize },
+    #[error("Column {column} expects {expected:?}, got {got:?}")]
+    ColumnTypeMismatch { column: usize, expected: DbType, got: DbType },
+    #[error("Blob of {len} bytes in column {column} exceeds the limit of {max} bytes")]
+    BlobTooLarge { column: usize, len: usize, max: usize },
+    #[error("Check on column {column} failed: {reason}")]
+    CheckViolation { column: usize, reason: String },
+    #[error("File {0} already exists")]
+    FileExists(String),
+    #[error("{0} does not exist")]
+    FileNotFound(String),
+    #[error("Invalid database name {0:?}")]
+    InvalidDatabaseName(String),
+    #[error("Table {0} is already present")]
+    TableIsAlreadyPresent(String),
+    #[error("Table {0} is missing")]
+    TableIsMissing(String),
+    #[error("Row {0} is out of range")]
+    RowIndexOutOfRange(usize),
+    #[error("Rows {0:?} are out of range")]
+    RowIndicesOutOfRange(Vec<usize>),
+    #[error("No row has id {0}")]
+    RowIdNotFound(u64),
+    #[error("Column {0} is out of range")]
+    ColumnOutOfRange(usize),
+    #[error("Table {0} is not a materialized projection")]
+    NotMaterialized(String),
+    #[error("Database {path} is locked{}", holder_pid.map(|pid| format!(" by process {pid}")).unwrap_or_default())]
+    DatabaseLocked { path: String, holder_pid: Option<u32> },
+    #[error("Database was opened read-only")]
+    ReadOnly,
+    #[error("Wrong passphrase")]
+    BadPassphrase,
+    #[error("Database is encrypted, a passphrase is required")]
+    PassphraseRequired,
+    #[error("SQL syntax error at byte {offset}: {message}")]
+    SqlSyntax { offset: usize, message: String },
+    #[error("{0}")]
+    Db(String),
+}
+
+impl From<DbError> for DbRpcError {
+    fn from(error: DbError) -> Self {
+        match error {
+            DbError::UnknownType(name) => Self::UnknownType(name),
+            DbError::RowLengthMismatch { expected, got } => Self::RowLengthMismatch { expected, got },
+            DbError::ColumnTypeMismatch { column, expected, got } => Self::ColumnTypeMismatch { column, expected, got },
+            DbError::BlobTooLarge { column, len, max } => Self::BlobTooLarge { column, len, max },
+            DbError::CheckViolation { column, reason } => Self::CheckViolation { column, reason },
+            DbError::FileExists(path) => Self::FileExists(path),
+            DbError::FileNotFound(path) => Self::FileNotFound(path),
+            DbError::InvalidDatabaseName(name) => Self::InvalidDatabaseName(name),
+            DbError::TableIsAlreadyPresent(name) => Self::TableIsAlreadyPresent(name),
+            DbError::TableIsMissing(name) => Self::TableIsMissing(name),
+            DbError::RowIndexOutOfRange(index) => Self::RowIndexOutOfRange(index),
+            DbError::RowIndicesOutOfRange(indices) => Self::RowIndicesOutOfRange(indices),
+            DbError::RowIdNotFound(id) => Self::RowIdNotFound(id),
+            DbError::ColumnOutOfRange(column) => Self::ColumnOutOfRange(column),
+            DbError::NotMaterialized(name) => Self::NotMaterialized(name),
+            DbError::DatabaseLocked { path, holder_pid } => Self::DatabaseLocked { path, holder_pid },
+            DbError::ReadOnly => Self::ReadOnly,
+            DbError::BadPassphrase => Self::BadPassphrase,
+            DbError::PassphraseRequired => Self::PassphraseRequired,
+            DbError::SqlSyntax { offset, message } => Self::SqlSyntax { offset, message },
+            error => Self::Db(error.to_string()),
+        }
+    }
+}
 
 #[tarpc::service]
 pub trait Service {
-    async fn create(name: String, path: String);
-    async fn open(path: String);
-    async fn get_name() -> Option<String>;
-    async fn get_table_names() -> Option<Vec<String>>;
-    async fn save();
-    async fn remove_table(name: String);
-    async fn create_table(name: String, schema: Vec<DbType>);
-    async fn remove_row(table: String, index: usize);
-    async fn insert_row(table: String, row: Row);
-    async fn get_table_schema(table: String) -> Option<Vec<DbType>>;
-    async fn get_rows(table: String) -> Option<Vec<Row>>;
-    async fn table_projection(table: String, rows: Vec<bool>, new_table: String);
-}
\ No newline at end of file
+    /// `PROTOCOL_VERSION` of the server, for clients to check before other calls.
+    async fn protocol_version() -> Result<u32, DbRpcError>;
+    /// Works without an open database too.
+    async fn status() -> Result<ServerStatus, DbRpcError>;
+    /// Starts a session if `token` is the server's auth token, or fails with
+    /// `Unauthorized`. If the server has a token, every other call but `protocol_version`
+    /// and `status` fails with `Unauthorized` unless given a session that was used within
+    /// the server's session lifetime.
+    async fn authenticate(token: String) -> Result<SessionId, DbRpcError>;
+    /// Fails with `UnsavedChanges` instead of replacing an open database with unsaved
+    /// changes, unless `force`.
+    async fn create(session: SessionId, name: String, path: String, format: Format, force: bool) -> Result<(), DbRpcError>;
+    /// Fails with `UnsavedChanges` like `create`.
+    async fn open(session: SessionId, path: String, force: bool) -> Result<(), DbRpcError>;
+    /// Closes the open database, saving it first if `save`. Until the next `open` or
+    /// `create`, calls on it fail with `NoDatabaseOpen`.
+    async fn close(session: SessionId, save: bool) -> Result<(), DbRpcError>;
+    async fn get_name(session: SessionId) -> Result<String, DbRpcError>;
+    async fn get_table_names(session: SessionId) -> Result<Vec<String>, DbRpcError>;
+    async fn table_count(session: SessionId) -> Result<usize, DbRpcError>;
+    async fn get_stats(session: SessionId) -> Result<DbStats, DbRpcError>;
+    async fn save(session: SessionId) -> Result<SaveSummary, DbRpcError>;
+    async fn save_as(session: SessionId, path: String, switch: bool, overwrite: bool) -> Result<(), DbRpcError>;
+    async fn reload(session: SessionId) -> Result<(), DbRpcError>;
+    async fn remove_table(session: SessionId, name: String) -> Result<(), DbRpcError>;
+    async fn create_table(session: SessionId, name: String, schema: Vec<DbType>) -> Result<(), DbRpcError>;
+    /// Fails with `TableIsMissing` or `TableIsAlreadyPresent` like `create_table`. Needs
+    /// `Alter` on both names.
+    async fn rename_table(session: SessionId, old: String, new: String) -> Result<(), DbRpcError>;
+    /// Creates `dst` with the schema of `src`, and its rows if `with_rows`.
+    async fn copy_table(session: SessionId, src: String, dst: String, with_rows: bool) -> Result<(), DbRpcError>;
+    async fn remove_row(session: SessionId, table: String, index: usize) -> Result<(), DbRpcError>;
+    /// Returns the row as stored, with the columns it left out filled in from their
+    /// defaults.
+    async fn insert_row(session: SessionId, table: String, row: Row) -> Result<Row, DbRpcError>;
+    /// Replaces the row at `index` in place, keeping its position and id.
+    async fn update_row(session: SessionId, table: String, index: usize, row: Row) -> Result<(), DbRpcError>;
+    /// Replaces the value in column `col` of the row at `row`, checked like `update_row`.
+    async fn update_cell(session: SessionId, table: String, row: usize, col: usize, value: DbValue) -> Result<(), DbRpcError>;
+    async fn validate_row(session: SessionId, table: String, row: Row) -> Result<bool, DbRpcError>;
+    async fn use_table(session: SessionId, name: String) -> Result<(), DbRpcError>;
+    async fn get_rows_current(session: SessionId) -> Result<Vec<Row>, DbRpcError>;
+    async fn get_table_schema_current(session: SessionId) -> Result<Vec<DbType>, DbRpcError>;
+    async fn insert_row_current(session: SessionId, row: Row) -> Result<Row, DbRpcError>;
+    async fn add_check(session: SessionId, table: String, constraint: CheckConstraint) -> Result<(), DbRpcError>;
+    async fn get_table_schema(session: SessionId, table: String) -> Result<Vec<DbType>, DbRpcError>;
+    async fn get_rows(session: SessionId, table: String) -> Result<Vec<Row>, DbRpcError>;
+    /// Up to `limit` rows starting at `offset`, fewer at the end of the table or if
+    /// `limit` exceeds the server's page size. An `offset` past the end returns no rows.
+    async fn get_rows_page(session: SessionId, table: String, offset: usize, limit: usize) -> Result<RowsPage, DbRpcError>;
+    /// `get_rows_page` counting from the last row: up to `limit` rows, newest first, after
+    /// skipping the `offset` newest ones.
+    async fn get_rows_desc(session: SessionId, table: String, offset: usize, limit: usize) -> Result<RowsPage, DbRpcError>;
+    async fn get_rows_multi(session: SessionId, tables: Vec<String>) -> Result<HashMap<String, Option<Vec<Row>>>, DbRpcError>;
+    async fn get_row(session: SessionId, table: String, index: usize) -> Result<Row, DbRpcError>;
+    /// Rows at `indices`, in that order, or `RowIndicesOutOfRange` listing those past the
+    /// end.
+    async fn get_rows_by_indices(session: SessionId, table: String, indices: Vec<usize>) -> Result<Vec<Row>, DbRpcError>;
+    /// Whether `table` has a row equal to `row`, see `Table::contains_row`.
+    async fn contains_row(session: SessionId, table: String, row: Row) -> Result<bool, DbRpcError>;
+    async fn table_projection(session: SessionId, table: String, rows: Vec<bool>, new_table: String) -> Result<(), DbRpcError>;
+    async fn project_many(session: SessionId, specs: Vec<(String, Vec<bool>, String)>) -> Result<(), DbRpcError>;
+    async fn create_materialized_projection(session: SessionId, table: String, rows: Vec<bool>, new_table: String) -> Result<(), DbRpcError>;
+    async fn refresh_materialized(session: SessionId, table: String) -> Result<(), DbRpcError>;
+    async fn get_table_info(session: SessionId, table: String) -> Result<TableInfo, DbRpcError>;
+    async fn table_stats(session: SessionId, table: String) -> Result<TableStats, DbRpcError>;
+    async fn get_catalog(session: SessionId) -> Result<Vec<Row>, DbRpcError>;
+    async fn check_integrity(session: SessionId) -> Result<IntegrityReport, DbRpcError>;
+    async fn diff_database(session: SessionId, path: String) -> Result<DatabaseDiff, DbRpcError>;
+    async fn run_query(session: SessionId, query: Query) -> Result<Vec<Row>, DbRpcError>;
+    async fn execute_sql(session: SessionId, query: String) -> Result<QueryResult, DbRpcError>;
+    async fn search(session: SessionId, value: DbValue, contains: bool) -> Result<Vec<(SearchHit, Row)>, DbRpcError>;
+    async fn poll_changes(session: SessionId, since_seq: u64) -> Result<Vec<(u64, ChangeEvent)>, DbRpcError>;
+    async fn create_savepoint(session: SessionId) -> Result<u64, DbRpcError>;
+    async fn restore_savepoint(session: SessionId, id: u64) -> Result<(), DbRpcError>;
+    async fn backup(session: SessionId, dir: Option<String>) -> Result<String, DbRpcError>;
+    async fn list_backups(session: SessionId) -> Result<Vec<String>, DbRpcError>;
+    async fn restore_backup(session: SessionId, path: String) -> Result<(), DbRpcError>;
+    async fn export_json(session: SessionId, path: String, pretty: bool) -> Result<(), DbRpcError>;
+    async fn snapshot(session: SessionId) -> Result<DatabaseSnapshot, DbRpcError>;
+    /// Opens the database exported to `json_path` as a new one at `path`. Fails with
+    /// `UnsavedChanges` like `create`.
+    async fn import_json(session: SessionId, json_path: String, path: String, force: bool) -> Result<(), DbRpcError>;
+    async fn export_table(session: SessionId, name: String, path: String) -> Result<(), DbRpcError>;
+    async fn import_table(session: SessionId, path: String, rename: Option<String>) -> Result<String, DbRpcError>;
+    /// Restricts the changes clients may make to `table` to `operations`, or lifts the
+    /// restriction if `None`. Requires the server's admin token.
+    async fn set_table_acl(session: SessionId, token: String, table: String, operations: Option<HashSet<TableOperation>>) -> Result<(), DbRpcError>;
+}
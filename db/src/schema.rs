@@ -0,0 +1,57 @@
+use crate::types::DbType;
+
+/// Builds the schema passed to `SavedDatabase::create_table` column by column.
+///
+/// Tables don't store column names yet, so the names only document the schema and are
+/// available through `names`.
+#[derive(Debug, Clone, Default)]
+pub struct SchemaBuilder {
+    columns: Vec<(String, DbType)>,
+}
+
+impl SchemaBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn column(mut self, name: impl Into<String>, r#type: DbType) -> Self {
+        self.columns.push((name.into(), r#type));
+        self
+    }
+
+    pub fn int(self, name: impl Into<String>) -> Self {
+        self.column(name, DbType::Int)
+    }
+
+    pub fn uint(self, name: impl Into<String>) -> Self {
+        self.column(name, DbType::UInt)
+    }
+
+    pub fn real(self, name: impl Into<String>) -> Self {
+        self.column(name, DbType::Real)
+    }
+
+    pub fn char(self, name: impl Into<String>) -> Self {
+        self.column(name, DbType::Char)
+    }
+
+    pub fn string(self, name: impl Into<String>) -> Self {
+        self.column(name, DbType::String)
+    }
+
+    pub fn time(self, name: impl Into<String>) -> Self {
+        self.column(name, DbType::Time)
+    }
+
+    pub fn blob(self, name: impl Into<String>) -> Self {
+        self.column(name, DbType::Blob)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.columns.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn build(&self) -> Vec<DbType> {
+        self.columns.iter().map(|(_, r#type)| *r#type).collect()
+    }
+}
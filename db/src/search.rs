@@ -0,0 +1,49 @@
+use crate::database::SavedDatabase;
+use crate::types::{DbError, DbValue};
+use serde::{Deserialize, Serialize};
+
+/// Location of a single matching cell.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SearchHit {
+    pub table: String,
+    pub row: usize,
+    pub column: usize,
+}
+
+impl SavedDatabase {
+    /// Finds every cell equal to `value`, ordered by table name, row and column. With
+    /// `string_contains` a String probe matches String cells containing it, ignoring case.
+    /// Decodes every table first if the database was opened with `open_mmap`.
+    pub fn search(&self, value: &DbValue, string_contains: bool) -> Result<Vec<SearchHit>, DbError> {
+        let needle = match value {
+            DbValue::String(s) if string_contains => Some(s.to_lowercase()),
+            _ => None,
+        };
+        let mut hits = Vec::new();
+        for (name, table) in self.sorted_tables()? {
+            let columns: Vec<usize> = table
+                .schema()
+                .iter()
+                .enumerate()
+                .filter(|(_, r#type)| **r#type == value.get_type())
+                .map(|(column, _)| column)
+                .collect();
+            for (row_index, row) in table.rows().iter().enumerate() {
+                for &column in &columns {
+                    let found = match (&needle, &row.0[column]) {
+                        (Some(needle), DbValue::String(cell)) => cell.to_lowercase().contains(needle),
+                        (_, cell) => cell == value,
+                    };
+                    if found {
+                        hits.push(SearchHit {
+                            table: name.clone(),
+                            row: row_index,
+                            column,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(hits)
+    }
+}
@@ -0,0 +1,36 @@
+use crate::database::{SavedDatabase, TableInfo};
+use crate::types::{DbError, Row};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Cheaply clonable handle letting many threads read a database at once while
+/// writers take turns.
+///
+/// A closure that panics does not make the database unusable for the other handles.
+#[derive(Debug, Clone)]
+pub struct SharedDatabase(Arc<RwLock<SavedDatabase>>);
+
+impl SharedDatabase {
+    pub fn new(db: SavedDatabase) -> Self {
+        Self(Arc::new(RwLock::new(db)))
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&SavedDatabase) -> R) -> R {
+        f(&self.0.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn write<R>(&self, f: impl FnOnce(&mut SavedDatabase) -> R) -> R {
+        f(&mut self.0.write().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn get_rows(&self, table: String) -> Result<Vec<Row>, DbError> {
+        self.read(|db| Ok(db.get_table(table)?.rows().to_vec()))
+    }
+
+    pub fn insert_row(&self, table: String, row: Row) -> Result<(), DbError> {
+        self.write(|db| db.insert_row(table, row))
+    }
+
+    pub fn table_info(&self, name: String) -> Result<TableInfo, DbError> {
+        self.read(|db| db.table_info(name))
+    }
+}
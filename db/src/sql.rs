@@ -0,0 +1,555 @@
+use crate::{CompareOp, Condition, DbError, DbType, DbValue, Query, Row, SavedDatabase};
+use crate::rpc::TableOperation;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Outcome of `SavedDatabase::execute_sql`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum QueryResult {
+    Affected(usize),
+    Rows { schema: Vec<DbType>, rows: Vec<Row> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    /// A double-quoted identifier, never taken as a keyword.
+    Quoted(String),
+    Number(String),
+    Str(String),
+    Symbol(&'static str),
+}
+
+fn syntax(offset: usize, message: impl Into<String>) -> DbError {
+    DbError::SqlSyntax {
+        offset,
+        message: message.into(),
+    }
+}
+
+fn tokenize(sql: &str) -> Result<Vec<(Token, usize)>, DbError> {
+    const SYMBOLS: [&str; 12] = ["<=", ">=", "<>", "!=", "=", "<", ">", "(", ")", ",", "*", ";"];
+    let mut tokens = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&(_, c)) = chars.peek().filter(|(_, c)| c.is_alphanumeric() || *c == '_') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push((Token::Word(word), start));
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let mut number = String::new();
+            number.push(c);
+            chars.next();
+            while let Some(&(_, c)) = chars.peek() {
+                let exponent_sign = (c == '-' || c == '+') && number.ends_with(['e', 'E']);
+                if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+                    number.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push((Token::Number(number), start));
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, q)) if q == c && chars.peek().map(|(_, c)| *c) == Some(c) => {
+                        chars.next();
+                        text.push(c);
+                    }
+                    Some((_, q)) if q == c => break,
+                    Some((_, c)) => text.push(c),
+                    None if c == '"' => return Err(syntax(start, "unterminated quoted identifier")),
+                    None => return Err(syntax(start, "unterminated string literal")),
+                }
+            }
+            tokens.push((if c == '"' { Token::Quoted(text) } else { Token::Str(text) }, start));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| sql[start..].starts_with(**symbol))
+                .ok_or_else(|| syntax(start, format!("unexpected character {c:?}")))?;
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((Token::Symbol(symbol), start));
+        }
+    }
+    Ok(tokens)
+}
+
+enum Literal {
+    Number(String),
+    Str(String),
+}
+
+enum Statement {
+    Select {
+        table: String,
+        columns: Option<Vec<(String, usize)>>,
+        filter: Option<RawCondition>,
+        order_by: Option<((String, usize), bool)>,
+        limit: Option<usize>,
+    },
+    Insert {
+        table: String,
+        values: Vec<(Literal, usize)>,
+    },
+    Delete {
+        table: String,
+        filter: Option<RawCondition>,
+    },
+    CreateTable {
+        table: String,
+        schema: Vec<DbType>,
+    },
+    DropTable {
+        table: String,
+    },
+}
+
+/// A predicate whose column names and literals are not yet resolved against a schema.
+enum RawCondition {
+    Compare {
+        column: (String, usize),
+        op: CompareOp,
+        value: (Literal, usize),
+    },
+    And(Box<RawCondition>, Box<RawCondition>),
+    Or(Box<RawCondition>, Box<RawCondition>),
+    Not(Box<RawCondition>),
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end, |(_, offset)| *offset)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), DbError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(syntax(self.offset(), format!("expected {keyword}")))
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), DbError> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(syntax(self.offset(), format!("expected '{symbol}'")))
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, DbError> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Word(word) | Token::Quoted(word)) => Ok(word),
+            _ => Err(syntax(offset, "expected identifier")),
+        }
+    }
+
+    fn column(&mut self) -> Result<(String, usize), DbError> {
+        let offset = self.offset();
+        Ok((self.identifier()?, offset))
+    }
+
+    fn literal(&mut self) -> Result<(Literal, usize), DbError> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Number(number)) => Ok((Literal::Number(number), offset)),
+            Some(Token::Str(text)) => Ok((Literal::Str(text), offset)),
+            _ => Err(syntax(offset, "expected literal")),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, DbError> {
+        let offset = self.offset();
+        let statement = if self.eat_keyword("SELECT") {
+            self.select()?
+        } else if self.eat_keyword("INSERT") {
+            self.expect_keyword("INTO")?;
+            let table = self.identifier()?;
+            self.expect_keyword("VALUES")?;
+            self.expect_symbol("(")?;
+            let mut values = vec![self.literal()?];
+            while self.eat_symbol(",") {
+                values.push(self.literal()?);
+            }
+            self.expect_symbol(")")?;
+            Statement::Insert { table, values }
+        } else if self.eat_keyword("DELETE") {
+            self.expect_keyword("FROM")?;
+            let table = self.identifier()?;
+            let filter = if self.eat_keyword("WHERE") { Some(self.or()?) } else { None };
+            Statement::Delete { table, filter }
+        } else if self.eat_keyword("CREATE") {
+            self.create_table()?
+        } else if self.eat_keyword("DROP") {
+            self.expect_keyword("TABLE")?;
+            Statement::DropTable {
+                table: self.identifier()?,
+            }
+        } else {
+            return Err(syntax(offset, "expected SELECT, INSERT, DELETE, CREATE or DROP"));
+        };
+        self.eat_symbol(";");
+        if self.position < self.tokens.len() {
+            return Err(syntax(self.offset(), "unexpected input after statement"));
+        }
+        Ok(statement)
+    }
+
+    /// Parses the rest of a `CREATE TABLE` statement after `CREATE`.
+    fn create_table(&mut self) -> Result<Statement, DbError> {
+        self.expect_keyword("TABLE")?;
+        let table = self.identifier()?;
+        self.expect_symbol("(")?;
+        let mut schema = Vec::new();
+        if !self.eat_symbol(")") {
+            loop {
+                self.identifier()?;
+                let offset = self.offset();
+                let name = self.identifier()?;
+                schema.push(name.parse().map_err(|_| syntax(offset, format!("unknown type {name}")))?);
+                if self.eat_symbol(")") {
+                    break;
+                }
+                self.expect_symbol(",")?;
+            }
+        }
+        Ok(Statement::CreateTable { table, schema })
+    }
+
+    /// Parses `CREATE TABLE` statements separated by `;`, the last one optionally too.
+    fn ddl(&mut self) -> Result<Vec<(String, Vec<DbType>)>, DbError> {
+        let mut tables = Vec::new();
+        while self.position < self.tokens.len() {
+            if !self.eat_keyword("CREATE") {
+                return Err(syntax(self.offset(), "expected CREATE"));
+            }
+            let Statement::CreateTable { table, schema } = self.create_table()? else {
+                unreachable!("create_table parses CREATE TABLE");
+            };
+            tables.push((table, schema));
+            if !self.eat_symbol(";") && self.position < self.tokens.len() {
+                return Err(syntax(self.offset(), "expected ;"));
+            }
+        }
+        Ok(tables)
+    }
+
+    fn select(&mut self) -> Result<Statement, DbError> {
+        let columns = if self.eat_symbol("*") {
+            None
+        } else {
+            let mut columns = vec![self.column()?];
+            while self.eat_symbol(",") {
+                columns.push(self.column()?);
+            }
+            Some(columns)
+        };
+        self.expect_keyword("FROM")?;
+        let table = self.identifier()?;
+        let filter = if self.eat_keyword("WHERE") { Some(self.or()?) } else { None };
+        let order_by = if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            let column = self.column()?;
+            let desc = self.eat_keyword("DESC");
+            if !desc {
+                self.eat_keyword("ASC");
+            }
+            Some((column, desc))
+        } else {
+            None
+        };
+        let limit = if self.eat_keyword("LIMIT") {
+            let offset = self.offset();
+            match self.next() {
+                Some(Token::Number(number)) => Some(number.parse().map_err(|_| syntax(offset, "invalid LIMIT"))?),
+                _ => return Err(syntax(offset, "expected LIMIT count")),
+            }
+        } else {
+            None
+        };
+        Ok(Statement::Select {
+            table,
+            columns,
+            filter,
+            order_by,
+            limit,
+        })
+    }
+
+    fn or(&mut self) -> Result<RawCondition, DbError> {
+        let mut condition = self.and()?;
+        while self.eat_keyword("OR") {
+            condition = RawCondition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<RawCondition, DbError> {
+        let mut condition = self.not()?;
+        while self.eat_keyword("AND") {
+            condition = RawCondition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<RawCondition, DbError> {
+        if self.eat_keyword("NOT") {
+            return Ok(RawCondition::Not(Box::new(self.not()?)));
+        }
+        if self.eat_symbol("(") {
+            let condition = self.or()?;
+            self.expect_symbol(")")?;
+            return Ok(condition);
+        }
+        let column = self.column()?;
+        let offset = self.offset();
+        let op = match self.next() {
+            Some(Token::Symbol("=")) => CompareOp::Eq,
+            Some(Token::Symbol("<>" | "!=")) => CompareOp::Ne,
+            Some(Token::Symbol("<")) => CompareOp::Lt,
+            Some(Token::Symbol("<=")) => CompareOp::Le,
+            Some(Token::Symbol(">")) => CompareOp::Gt,
+            Some(Token::Symbol(">=")) => CompareOp::Ge,
+            _ => return Err(syntax(offset, "expected comparison operator")),
+        };
+        Ok(RawCondition::Compare {
+            column,
+            op,
+            value: self.literal()?,
+        })
+    }
+}
+
+/// Columns are addressed as `col0`, `col1`, ... since tables have unnamed columns.
+fn column_index(schema: &[DbType], (name, offset): &(String, usize)) -> Result<usize, DbError> {
+    name.to_ascii_lowercase()
+        .strip_prefix("col")
+        .and_then(|index| index.parse().ok())
+        .filter(|index: &usize| *index < schema.len())
+        .ok_or_else(|| syntax(*offset, format!("unknown column {name}")))
+}
+
+fn value(r#type: DbType, (literal, offset): &(Literal, usize)) -> Result<DbValue, DbError> {
+    let invalid = || syntax(*offset, format!("literal does not fit column type {:?}", r#type));
+    match (r#type, literal) {
+        (DbType::Int | DbType::UInt | DbType::Real, Literal::Number(text))
+        | (DbType::Char | DbType::String | DbType::Time | DbType::Blob, Literal::Str(text)) => {
+            DbValue::parse(r#type, text).map_err(|_| invalid())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn condition(schema: &[DbType], raw: &RawCondition) -> Result<Condition, DbError> {
+    Ok(match raw {
+        RawCondition::Compare { column, op, value: literal } => {
+            let column = column_index(schema, column)?;
+            Condition::compare(column, *op, value(schema[column], literal)?)
+        }
+        RawCondition::And(a, b) => Condition::And(vec![condition(schema, a)?, condition(schema, b)?]),
+        RawCondition::Or(a, b) => Condition::Or(vec![condition(schema, a)?, condition(schema, b)?]),
+        RawCondition::Not(a) => Condition::Not(Box::new(condition(schema, a)?)),
+    })
+}
+
+/// Table the statement `sql` for `execute_sql` changes and how, without running it;
+/// `None` for `SELECT`.
+pub fn sql_operation(sql: &str) -> Result<Option<(String, TableOperation)>, DbError> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        position: 0,
+        end: sql.len(),
+    };
+    Ok(match parser.statement()? {
+        Statement::Select { .. } => None,
+        Statement::Insert { table, .. } => Some((table, TableOperation::Insert)),
+        Statement::Delete { table, .. } => Some((table, TableOperation::Delete)),
+        Statement::CreateTable { table, .. } | Statement::DropTable { table } => Some((table, TableOperation::Alter)),
+    })
+}
+
+impl SavedDatabase {
+    /// Runs a single statement of a small SQL subset: `SELECT cols|* FROM t [WHERE ...]
+    /// [ORDER BY col [ASC|DESC]] [LIMIT n]`, `INSERT INTO t VALUES (...)`,
+    /// `DELETE FROM t [WHERE ...]`, `CREATE TABLE t (name type, ...)` and `DROP TABLE t`.
+    /// Column names given to `CREATE TABLE` are not stored.
+    pub fn execute_sql(&mut self, sql: &str) -> Result<QueryResult, DbError> {
+        let mut parser = Parser {
+            tokens: tokenize(sql)?,
+            position: 0,
+            end: sql.len(),
+        };
+        match parser.statement()? {
+            Statement::Select { table, columns, filter, order_by, limit } => {
+                self.select(table, columns, filter, order_by, limit)
+            }
+            Statement::Insert { table, values } => {
+                let target = self.get_table(table.clone())?;
+                if values.len() != target.schema().len() {
+                    return Err(DbError::RowLengthMismatch {
+                        expected: target.schema().len(),
+                        got: values.len(),
+                    });
+                }
+                let row = target
+                    .schema()
+                    .iter()
+                    .zip(&values)
+                    .map(|(r#type, literal)| value(*r#type, literal))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.insert_row(table, Row(row))?;
+                Ok(QueryResult::Affected(1))
+            }
+            Statement::Delete { table, filter } => {
+                let target = self.get_table(table.clone())?;
+                let filter = filter.map(|f| condition(target.schema(), &f)).transpose()?;
+                let matching: Vec<usize> = target
+                    .rows()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, row)| filter.as_ref().is_none_or(|f| f.matches(row)))
+                    .map(|(index, _)| index)
+                    .collect();
+                for index in matching.iter().rev() {
+                    self.remove_row(table.clone(), *index)?;
+                }
+                Ok(QueryResult::Affected(matching.len()))
+            }
+            Statement::CreateTable { table, schema } => {
+                self.create_table(table, schema)?;
+                Ok(QueryResult::Affected(0))
+            }
+            Statement::DropTable { table } => {
+                self.remove_table(table)?;
+                Ok(QueryResult::Affected(0))
+            }
+        }
+    }
+
+    /// Runs `sql` like `execute_sql` if it is a `SELECT`, which doesn't need the database
+    /// to be mutable; other statements fail with `SqlSyntax`.
+    pub fn query_sql(&self, sql: &str) -> Result<QueryResult, DbError> {
+        self.query_sql_within(sql, usize::MAX)
+    }
+
+    /// Runs `sql` like `query_sql`, but stops after `max_rows` rows as if the statement
+    /// had that `LIMIT`, so that a caller capping results never builds more than it sends.
+    pub fn query_sql_within(&self, sql: &str, max_rows: usize) -> Result<QueryResult, DbError> {
+        let mut parser = Parser {
+            tokens: tokenize(sql)?,
+            position: 0,
+            end: sql.len(),
+        };
+        match parser.statement()? {
+            Statement::Select { table, columns, filter, order_by, limit } => {
+                let limit = limit.map_or(max_rows, |limit| limit.min(max_rows));
+                self.select(table, columns, filter, order_by, Some(limit))
+            }
+            _ => Err(syntax(0, "expected a SELECT statement")),
+        }
+    }
+
+    fn select(
+        &self,
+        table: String,
+        columns: Option<Vec<(String, usize)>>,
+        filter: Option<RawCondition>,
+        order_by: Option<((String, usize), bool)>,
+        limit: Option<usize>,
+    ) -> Result<QueryResult, DbError> {
+        let schema = self.get_table(table.clone())?.schema().to_vec();
+        let mut query = Query::new(table);
+        let mut result_schema = schema.clone();
+        if let Some(columns) = columns {
+            let columns = columns
+                .iter()
+                .map(|column| column_index(&schema, column))
+                .collect::<Result<Vec<_>, _>>()?;
+            result_schema = columns.iter().map(|c| schema[*c]).collect();
+            query = query.select_columns(&columns);
+        }
+        if let Some(filter) = filter {
+            query = query.filter(condition(&schema, &filter)?);
+        }
+        if let Some((column, desc)) = order_by {
+            query = query.order_by(column_index(&schema, &column)?, desc);
+        }
+        if let Some(limit) = limit {
+            query = query.limit(limit);
+        }
+        Ok(QueryResult::Rows {
+            schema: result_schema,
+            rows: query.rows(self)?,
+        })
+    }
+
+    /// Creates the tables of `CREATE TABLE t (name type, ...)` statements separated by
+    /// `;`, as written by `to_ddl`. Creates none of them if any is malformed or already
+    /// exists.
+    pub fn execute_ddl(&mut self, ddl: &str) -> Result<(), DbError> {
+        let mut parser = Parser {
+            tokens: tokenize(ddl)?,
+            position: 0,
+            end: ddl.len(),
+        };
+        let tables = parser.ddl()?;
+        let mut names = HashSet::new();
+        for (table, _) in &tables {
+            if self.get_table(table.clone()).is_ok() || !names.insert(table) {
+                return Err(DbError::TableIsAlreadyPresent(table.clone()));
+            }
+        }
+        for (table, schema) in tables {
+            self.create_table(table, schema)?;
+        }
+        Ok(())
+    }
+}
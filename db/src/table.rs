@@ -1,12 +1,118 @@
+use crate::query::CompareOp;
 use crate::types::{DbError, DbType, DbValue, Row};
-use itertools::Itertools;
+use chrono::{DateTime, FixedOffset, Utc};
 use serde::{Deserialize, Serialize};
 
+/// Earliest and latest timestamp of a column.
+pub type TimeBounds = (DateTime<FixedOffset>, DateTime<FixedOffset>);
+
+/// Summary of a table computed by `Table::stats`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TableStats {
+    pub row_count: usize,
+    /// Smallest and largest value of each column; `None` for columns that aren't `Int`,
+    /// `UInt` or `Real` and when there is no value. NaN reals are skipped.
+    pub bounds: Vec<Option<(DbValue, DbValue)>>,
+}
+
+/// Requires `row[column] <op> value` for every row of a table.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CheckConstraint {
+    pub column: usize,
+    pub op: CompareOp,
+    pub value: DbValue,
+}
+
+impl CheckConstraint {
+    pub(crate) fn check(&self, row: &Row) -> Result<(), DbError> {
+        if self.op.matches(&row.0[self.column], &self.value) {
+            Ok(())
+        } else {
+            Err(DbError::CheckViolation {
+                column: self.column,
+                reason: format!("{} is not {:?} {}", row.0[self.column], self.op, self.value),
+            })
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Table {
     name: String,
     rows: Vec<Row>,
     schema: Vec<DbType>,
+    version: u64,
+    checks: Vec<CheckConstraint>,
+    /// When each row was inserted, parallel to `rows`.
+    #[serde(default)]
+    created_at: Vec<DateTime<Utc>>,
+    /// Id of each row, parallel to `rows`. Ids are never reused, so unlike indices they
+    /// survive removals of other rows.
+    #[serde(default)]
+    ids: Vec<u64>,
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default)]
+    autoincrement: Option<AutoIncrement>,
+    /// Set by every change since the table was loaded or last saved.
+    #[serde(skip)]
+    dirty: bool,
+    /// Accepts the values of `Char` columns; not saved, so it has to be set again after
+    /// loading.
+    #[serde(skip)]
+    char_validator: Option<fn(char) -> bool>,
+    /// Longest `Blob` value inserts and updates accept.
+    #[serde(default = "default_max_blob_len")]
+    max_blob_len: usize,
+    /// Default of each column, see `set_default`.
+    #[serde(default)]
+    defaults: Vec<Option<ColumnDefault>>,
+}
+
+/// Column whose values `Table::insert_row` takes from a sequence, see
+/// `Table::set_autoincrement`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoIncrement {
+    pub column: usize,
+    /// Value the next insert gets.
+    pub next: u64,
+}
+
+/// Value filled in for a column an inserted row leaves out, see `Table::set_default`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ColumnDefault {
+    Value(DbValue),
+    /// The time of the insert, for `Time` columns.
+    Now,
+}
+
+/// Moves the sequence past the value `row` has in its column, so it's never handed out
+/// again.
+fn advance_sequence(autoincrement: &mut Option<AutoIncrement>, row: &Row) {
+    if let Some(auto) = autoincrement {
+        let value = match row.0[auto.column] {
+            DbValue::Int(value) => u64::try_from(value).ok(),
+            DbValue::UInt(value) => Some(value),
+            _ => None,
+        };
+        if let Some(value) = value {
+            auto.next = auto.next.max(value.saturating_add(1));
+        }
+    }
+}
+
+/// Blobs are meant for small payloads like thumbnails, larger files belong next to the
+/// database.
+pub const DEFAULT_MAX_BLOB_LEN: usize = 1 << 20;
+
+/// Longest `String` value, in bytes, a table stores.
+pub const MAX_STRING_LEN: usize = 1 << 24;
+
+/// Most rows a loaded table may have, past which the file is taken to be corrupt.
+pub(crate) const MAX_ROWS: usize = u32::MAX as usize;
+
+fn default_max_blob_len() -> usize {
+    DEFAULT_MAX_BLOB_LEN
 }
 
 impl Table {
@@ -15,42 +121,387 @@ impl Table {
             name,
             rows: Vec::new(),
             schema,
+            version: 0,
+            checks: Vec::new(),
+            created_at: Vec::new(),
+            ids: Vec::new(),
+            next_id: 0,
+            autoincrement: None,
+            dirty: true,
+            char_validator: None,
+            max_blob_len: DEFAULT_MAX_BLOB_LEN,
+            defaults: Vec::new(),
         }
     }
 
-    pub fn insert_row(&mut self, row: Row) -> Result<(), DbError> {
-        let row_schema = row.schema();
-        if row_schema == self.schema {
-            self.rows.push(row);
-            Ok(())
-        } else {
-            Err(DbError::IncorrectRow)
+    /// A table read from a file of an older version. Rows of files written before rows
+    /// had ids come without `ids` and are numbered in row order, see
+    /// `assign_missing_ids`. Dirty, so that the next save writes it in the current format.
+    pub(crate) fn from_legacy(
+        name: String,
+        rows: Vec<Row>,
+        schema: Vec<DbType>,
+        version: u64,
+        checks: Vec<CheckConstraint>,
+        created_at: Vec<DateTime<Utc>>,
+        (ids, next_id, autoincrement): (Vec<u64>, u64, Option<AutoIncrement>),
+    ) -> Self {
+        let new = Self::new(String::new(), Vec::new());
+        let mut table = Self { name, rows, schema, version, checks, created_at, ids, next_id, autoincrement, ..new };
+        table.assign_missing_ids();
+        table
+    }
+
+    fn check_schema(&self, row: &Row) -> Result<(), DbError> {
+        if row.0.len() != self.schema.len() {
+            return Err(DbError::RowLengthMismatch {
+                expected: self.schema.len(),
+                got: row.0.len(),
+            });
+        }
+        for (column, (expected, value)) in self.schema.iter().zip(&row.0).enumerate() {
+            if value.get_type() != *expected {
+                return Err(DbError::ColumnTypeMismatch {
+                    column,
+                    expected: *expected,
+                    got: value.get_type(),
+                });
+            }
         }
+        Ok(())
     }
 
-    pub fn update_row(&mut self, idx: usize, row: Row) -> Result<(), DbError> {
-        let row_schema = row.schema();
-        if row_schema == self.schema {
-            self.rows[idx] = row;
-            Ok(())
-        } else {
-            Err(DbError::IncorrectRow)
+    fn check_chars(&self, row: &Row) -> Result<(), DbError> {
+        let Some(validator) = self.char_validator else {
+            return Ok(());
+        };
+        for (column, value) in row.0.iter().enumerate() {
+            if let DbValue::Char(c) = value {
+                if !validator(*c) {
+                    return Err(DbError::CheckViolation {
+                        column,
+                        reason: format!("{c:?} is not an allowed char"),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_lengths(&self, row: &Row) -> Result<(), DbError> {
+        for (column, value) in row.0.iter().enumerate() {
+            match value {
+                DbValue::Blob(bytes) if bytes.len() > self.max_blob_len => {
+                    return Err(DbError::BlobTooLarge { column, len: bytes.len(), max: self.max_blob_len });
+                }
+                DbValue::String(string) if string.len() > MAX_STRING_LEN => {
+                    return Err(DbError::StringTooLong { column, len: string.len(), max: MAX_STRING_LEN });
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the validation `insert_row` performs, without touching the table.
+    pub fn check_row(&self, row: &Row) -> Result<(), DbError> {
+        self.check_schema(row)?;
+        self.check_chars(row)?;
+        self.check_lengths(row)?;
+        self.checks.iter().try_for_each(|check| check.check(row))
+    }
+
+    /// Restricts the values of `Char` columns on every insert and update, e.g. to reject
+    /// control characters. Fails if a stored row is already rejected.
+    pub fn set_char_validator(&mut self, validator: fn(char) -> bool) -> Result<(), DbError> {
+        let previous = self.char_validator.replace(validator);
+        if let Err(error) = self.rows.iter().try_for_each(|row| self.check_chars(row)) {
+            self.char_validator = previous;
+            return Err(error);
         }
+        Ok(())
+    }
+
+    /// Limits the length of `Blob` values on every insert and update,
+    /// `DEFAULT_MAX_BLOB_LEN` unless set. Fails if a stored row is already rejected. The
+    /// limit is saved with the table.
+    pub fn set_max_blob_len(&mut self, max: usize) -> Result<(), DbError> {
+        let previous = std::mem::replace(&mut self.max_blob_len, max);
+        if let Err(error) = self.rows.iter().try_for_each(|row| self.check_lengths(row)) {
+            self.max_blob_len = previous;
+            return Err(error);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn max_blob_len(&self) -> usize {
+        self.max_blob_len
+    }
+
+    pub fn row_fits(&self, row: &Row) -> bool {
+        self.check_row(row).is_ok()
+    }
+
+    /// Adds a constraint enforced, together with the existing ones, on every insert and update.
+    /// Fails if a stored row already violates it.
+    pub fn add_check(&mut self, check: CheckConstraint) -> Result<(), DbError> {
+        let expected = *self.schema.get(check.column).ok_or(DbError::ColumnOutOfRange(check.column))?;
+        if check.value.get_type() != expected {
+            return Err(DbError::ColumnTypeMismatch {
+                column: check.column,
+                expected,
+                got: check.value.get_type(),
+            });
+        }
+        self.rows.iter().try_for_each(|row| check.check(row))?;
+        self.checks.push(check);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn checks(&self) -> &[CheckConstraint] {
+        &self.checks
+    }
+
+    /// Sets the value `insert_row` fills in for `column` when a row ends before it, or
+    /// removes it if `None`. A row can only leave out trailing columns that all have a
+    /// default. Defaults are saved with the table.
+    pub fn set_default(&mut self, column: usize, default: Option<ColumnDefault>) -> Result<(), DbError> {
+        let expected = *self.schema.get(column).ok_or(DbError::ColumnOutOfRange(column))?;
+        let got = match &default {
+            Some(ColumnDefault::Value(value)) => value.get_type(),
+            Some(ColumnDefault::Now) => DbType::Time,
+            None => expected,
+        };
+        if got != expected {
+            return Err(DbError::ColumnTypeMismatch { column, expected, got });
+        }
+        self.defaults.resize(self.schema.len(), None);
+        self.defaults[column] = default;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Default of `column`, `None` if it has none or doesn't exist.
+    pub fn default_of(&self, column: usize) -> Option<&ColumnDefault> {
+        self.defaults.get(column)?.as_ref()
+    }
+
+    /// Makes `column`, which has to be `Int` or `UInt`, take its values from a sequence
+    /// starting past the largest value stored so far, or at 1. Inserts giving 0 there, or
+    /// leaving it out as a trailing column, get the next value of the sequence; other
+    /// values are kept and move the sequence past them. The sequence is saved with the
+    /// table, and ends before `u64::MAX` in `UInt` columns and after `i64::MAX` in `Int`
+    /// ones.
+    pub fn set_autoincrement(&mut self, column: usize) -> Result<(), DbError> {
+        let got = *self.schema.get(column).ok_or(DbError::ColumnOutOfRange(column))?;
+        if !matches!(got, DbType::Int | DbType::UInt) {
+            return Err(DbError::ColumnTypeMismatch { column, expected: DbType::Int, got });
+        }
+        self.autoincrement = Some(AutoIncrement { column, next: 1 });
+        for row in &self.rows {
+            advance_sequence(&mut self.autoincrement, row);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn autoincrement(&self) -> Option<AutoIncrement> {
+        self.autoincrement
+    }
+
+    /// `row` with the columns it leaves out filled in from their defaults, as far as they
+    /// have one, and the next value of the auto-increment sequence if it asks for one.
+    /// Fails with `AutoIncrementExhausted` if the sequence has ended.
+    pub fn complete_row(&self, mut row: Row) -> Result<Row, DbError> {
+        loop {
+            let column = row.0.len();
+            let value = match (self.autoincrement, self.defaults.get(column)) {
+                (Some(auto), _) if auto.column == column => DbValue::UInt(0),
+                (_, Some(Some(ColumnDefault::Value(value)))) => value.clone(),
+                (_, Some(Some(ColumnDefault::Now))) => DbValue::Time(Utc::now().fixed_offset()),
+                _ => break,
+            };
+            row.0.push(value);
+        }
+        if let Some(AutoIncrement { column, next }) = self.autoincrement {
+            if let Some(value @ (DbValue::Int(0) | DbValue::UInt(0))) = row.0.get_mut(column) {
+                let exhausted = DbError::AutoIncrementExhausted { column };
+                *value = match self.schema[column] {
+                    DbType::Int => DbValue::Int(i64::try_from(next).map_err(|_| exhausted)?),
+                    // A sequence moved past `u64::MAX` stays there.
+                    _ if next == u64::MAX => return Err(exhausted),
+                    _ => DbValue::UInt(next),
+                };
+            }
+        }
+        Ok(row)
+    }
+
+    pub fn insert_row(&mut self, row: Row) -> Result<(), DbError> {
+        let row = self.complete_row(row)?;
+        self.check_row(&row)?;
+        advance_sequence(&mut self.autoincrement, &row);
+        self.rows.push(row);
+        self.created_at.push(Utc::now());
+        self.ids.push(self.next_id);
+        self.next_id += 1;
+        self.version += 1;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Replaces row `idx`, returning whether it changed; a row equal to the stored one
+    /// leaves the table untouched, including its version and dirty flag.
+    pub fn update_row(&mut self, idx: usize, row: Row) -> Result<bool, DbError> {
+        self.check_row(&row)?;
+        let slot = self.rows.get_mut(idx).ok_or(DbError::RowIndexOutOfRange(idx))?;
+        if slot.is_identical(&row) {
+            return Ok(false);
+        }
+        advance_sequence(&mut self.autoincrement, &row);
+        *slot = row;
+        self.version += 1;
+        self.dirty = true;
+        Ok(true)
     }
 
     pub fn remove_row(&mut self, idx: usize) {
         if self.rows.len() > idx {
             self.rows.remove(idx);
+            if idx < self.created_at.len() {
+                self.created_at.remove(idx);
+            }
+            if idx < self.ids.len() {
+                self.ids.remove(idx);
+            }
+            self.version += 1;
+            self.dirty = true;
         }
     }
 
+    /// Index of the row with `id`; ids only grow, so `ids` stays sorted.
+    fn index_of(&self, id: u64) -> Option<usize> {
+        self.ids.binary_search(&id).ok()
+    }
+
+    pub fn row_by_id(&self, id: u64) -> Option<&Row> {
+        self.index_of(id).and_then(|idx| self.rows.get(idx))
+    }
+
+    pub fn update_by_id(&mut self, id: u64, row: Row) -> Result<bool, DbError> {
+        let idx = self.index_of(id).ok_or(DbError::RowIdNotFound(id))?;
+        self.update_row(idx, row)
+    }
+
+    pub fn remove_by_id(&mut self, id: u64) {
+        if let Some(idx) = self.index_of(id) {
+            self.remove_row(idx);
+        }
+    }
+
+    /// Ids of the rows, in the order of `rows`.
+    pub fn row_ids(&self) -> &[u64] {
+        &self.ids
+    }
+
+    /// Insertion times of the rows, in the order of `rows`.
+    pub(crate) fn created_at(&self) -> &[DateTime<Utc>] {
+        &self.created_at
+    }
+
+    /// Numbers the rows of tables saved before rows had ids, and dates rows saved before
+    /// insertion times were kept to the Unix epoch, so that both stay parallel to `rows`.
+    pub(crate) fn assign_missing_ids(&mut self) {
+        while self.ids.len() < self.rows.len() {
+            self.ids.push(self.next_id);
+            self.next_id += 1;
+        }
+        self.created_at.resize(self.rows.len(), DateTime::UNIX_EPOCH);
+    }
+
+    /// Checks that every row fits the schema and the limits on `Blob` and `String`
+    /// lengths, failing for the first row that doesn't.
     pub fn validate_rows(&self) -> Result<(), DbError> {
+        self.rows.iter().try_for_each(|row| self.validate_row(row))
+    }
+
+    /// `validate_rows` for large tables, checking chunks of rows on rayon's thread pool.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn validate_rows_parallel(&self) -> Result<(), DbError> {
+        use rayon::prelude::*;
+        const CHUNK_ROWS: usize = 16 * 1024;
+        match self.rows.par_iter().with_min_len(CHUNK_ROWS).find_map_first(|row| self.validate_row(row).err()) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_row(&self, row: &Row) -> Result<(), DbError> {
+        if row.schema() != self.schema {
+            return Err(DbError::InvalidTableState(self.name.clone()));
+        }
+        self.check_lengths(row)
+    }
+
+    /// Earliest and latest timestamp of a Time column, `None` when the table is empty.
+    pub fn time_bounds(&self, column: usize) -> Result<Option<TimeBounds>, DbError> {
+        let r#type = *self.schema.get(column).ok_or(DbError::ColumnOutOfRange(column))?;
+        if r#type != DbType::Time {
+            return Err(DbError::ColumnTypeMismatch {
+                column,
+                expected: DbType::Time,
+                got: r#type,
+            });
+        }
+        Ok(self.rows.iter().fold(None, |bounds, row| {
+            let DbValue::Time(time) = row.0[column] else {
+                unreachable!("rows match the schema");
+            };
+            match bounds {
+                None => Some((time, time)),
+                Some((min, max)) => Some((min.min(time), max.max(time))),
+            }
+        }))
+    }
+
+    /// Row count and bounds of the numeric columns, in a single pass over the rows.
+    pub fn stats(&self) -> TableStats {
+        let numeric: Vec<bool> = self
+            .schema
+            .iter()
+            .map(|r#type| matches!(r#type, DbType::Int | DbType::UInt | DbType::Real))
+            .collect();
+        let mut bounds: Vec<Option<(DbValue, DbValue)>> = vec![None; self.schema.len()];
         for row in &self.rows {
-            if row.schema() != self.schema {
-                return Err(DbError::InvalidTableState(self.name.clone()));
+            for (column, value) in row.0.iter().enumerate() {
+                if !numeric[column] || matches!(value, DbValue::Real(x) if x.is_nan()) {
+                    continue;
+                }
+                match &mut bounds[column] {
+                    None => bounds[column] = Some((value.clone(), value.clone())),
+                    Some((min, max)) => {
+                        if value < min {
+                            *min = value.clone();
+                        }
+                        if value > max {
+                            *max = value.clone();
+                        }
+                    }
+                }
             }
         }
-        Ok(())
+        TableStats { row_count: self.rows.len(), bounds }
+    }
+
+    /// Insertion time of row `idx`; updating a row keeps it.
+    pub fn row_created_at(&self, idx: usize) -> Option<DateTime<Utc>> {
+        self.created_at.get(idx).copied()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
     pub fn schema(&self) -> &[DbType] {
@@ -60,4 +511,75 @@ impl Table {
     pub fn rows(&self) -> &[Row] {
         &self.rows
     }
+
+    /// The rows from the last inserted to the first.
+    pub fn rows_rev(&self) -> impl Iterator<Item = &Row> {
+        self.rows.iter().rev()
+    }
+
+    pub fn row_at(&self, idx: usize) -> Option<&Row> {
+        self.rows.get(idx)
+    }
+
+    /// Rows at `indices`, in that order, failing with `RowIndicesOutOfRange` listing every
+    /// index past the end.
+    pub fn rows_at(&self, indices: &[usize]) -> Result<Vec<&Row>, DbError> {
+        let missing: Vec<usize> = indices.iter().copied().filter(|&idx| idx >= self.rows.len()).collect();
+        if !missing.is_empty() {
+            return Err(DbError::RowIndicesOutOfRange(missing));
+        }
+        Ok(indices.iter().map(|&idx| &self.rows[idx]).collect())
+    }
+
+    /// Whether a row equal to `row` is stored. Rows holding a NaN never match, NaN not
+    /// being equal to itself.
+    pub fn contains_row(&self, row: &Row) -> bool {
+        self.rows.contains(row)
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    pub(crate) fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn rows_mut(&mut self) -> &mut Vec<Row> {
+        self.dirty = true;
+        &mut self.rows
+    }
+
+    /// A copy named `name`, with the schema, checks and settings of this table, but
+    /// without rows and with a fresh auto-increment sequence unless `with_rows`.
+    pub(crate) fn copy(&self, name: String, with_rows: bool) -> Table {
+        let mut copy = self.clone();
+        copy.set_name(name);
+        if !with_rows {
+            copy.rows.clear();
+            copy.created_at.clear();
+            copy.ids.clear();
+            copy.next_id = 0;
+            copy.version = 0;
+            if let Some(auto) = &mut copy.autoincrement {
+                auto.next = 1;
+            }
+        }
+        copy
+    }
+
+    pub(crate) fn set_name(&mut self, name: String) {
+        self.name = name;
+        self.dirty = true;
+    }
+
+    /// Counter bumped by every mutation of the rows.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
 }
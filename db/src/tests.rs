@@ -2,16 +2,24 @@ use chrono::DateTime;
 use crate::*;
 use tempfile::tempdir;
 use crate::database::SavedDatabase;
+use crate::events::CHANGE_CAPACITY;
+use crate::layout::{self, Layout};
+use std::path::{Path, PathBuf};
 use chrono::prelude::*;
 
+/// Loads `path` while a database of the test may still hold its lock.
+fn load_ignoring_lock(path: impl Into<PathBuf>) -> Result<SavedDatabase, DbError> {
+    SavedDatabase::load_from_disk_with(path, LoadOptions { ignore_lock: true, ..LoadOptions::default() })
+}
+
 #[test]
 fn save_load() {
     let dir = tempdir().unwrap();
     let path = dir.path().join("db");
     std::fs::File::create(&path).unwrap();
-    let db = SavedDatabase::create("db".to_string(), path.to_str().unwrap().to_string()).unwrap();
+    SavedDatabase::create("db".to_string(), &path).unwrap();
 
-    let db = SavedDatabase::load_from_disk(path.to_str().unwrap().to_string()).unwrap();
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
     assert_eq!(db.get_name(), "db");
 }
 
@@ -21,7 +29,7 @@ fn test_table_crud() {
     let path = dir.path().join("db");
     std::fs::File::create(&path).unwrap();
     let mut db =
-        SavedDatabase::create("db".to_string(), path.to_str().unwrap().to_string()).unwrap();
+        SavedDatabase::create("db".to_string(), &path).unwrap();
 
     db.create_table("table".to_string(), vec![DbType::Int])
         .unwrap();
@@ -49,7 +57,7 @@ fn table_projection() {
     let path = dir.path().join("db");
     std::fs::File::create(&path).unwrap();
     let mut db =
-        SavedDatabase::create("db".to_string(), path.to_str().unwrap().to_string()).unwrap();
+        SavedDatabase::create("db".to_string(), &path).unwrap();
 
     db.create_table(
         "table".to_string(),
@@ -64,12 +72,12 @@ fn table_projection() {
     ]);
     let row2 = Row(vec![
         DbValue::String("C".to_string()),
-        DbValue::Time(DateTime::<Utc>::from_utc(NaiveDate::from_ymd(2016, 7, 8).and_hms(9, 10, 11), Utc)),
+        DbValue::Time(Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset()),
     ]);
     table.insert_row(row1.clone()).unwrap();
     table.insert_row(row2.clone()).unwrap();
 
-    db.projection("table".to_string(), vec![true, false], "projection".to_string()).unwrap();
+    db.projection("table".to_string(), vec![true, false], "projection".to_string(), None).unwrap();
 
     let projection_table = db.get_table("projection".to_string()).unwrap();
     assert_eq!(projection_table.schema(), vec![DbType::String]);
@@ -78,3 +86,2878 @@ fn table_projection() {
     assert_eq!(iter.next().unwrap().clone(), Row(vec![DbValue::String("C".to_string())]));
     assert_eq!(iter.next(), None);
 }
+
+#[test]
+fn time_bounds() {
+    let mut table = Table::new("table".to_string(), vec![DbType::String, DbType::Time]);
+    assert_eq!(table.time_bounds(1).unwrap(), None);
+
+    let latest = Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset();
+    let middle = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap().fixed_offset();
+    for (name, time) in [("B", middle), ("C", latest), ("D", DateTime::default())] {
+        table.insert_row(Row(vec![DbValue::String(name.to_string()), DbValue::Time(time)])).unwrap();
+    }
+    assert_eq!(table.time_bounds(1).unwrap(), Some((DateTime::default(), latest)));
+
+    assert!(matches!(
+        table.time_bounds(0),
+        Err(DbError::ColumnTypeMismatch { column: 0, expected: DbType::Time, got: DbType::String })
+    ));
+    assert!(matches!(table.time_bounds(2), Err(DbError::ColumnOutOfRange(2))));
+}
+
+#[test]
+fn catalog() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+
+    db.create_table("b".to_string(), vec![DbType::Int, DbType::Time]).unwrap();
+    db.create_table("a".to_string(), vec![DbType::String]).unwrap();
+    db.get_table_mut("a".to_string()).unwrap()
+        .insert_row(Row(vec![DbValue::String("x".to_string())])).unwrap();
+
+    let catalog = db.catalog().unwrap();
+    assert_eq!(catalog.schema(), vec![DbType::String, DbType::Int, DbType::String, DbType::String, DbType::Int]);
+    let row = |table: &str, index: i64, r#type: &str, count: i64| Row(vec![
+        DbValue::String(table.to_string()),
+        DbValue::Int(index),
+        DbValue::String(format!("col{index}")),
+        DbValue::String(r#type.to_string()),
+        DbValue::Int(count),
+    ]);
+    assert_eq!(catalog.rows(), vec![
+        row("a", 0, "string", 1),
+        row("b", 0, "int", 0),
+        row("b", 1, "time", 0),
+    ]);
+
+    db.remove_table("b".to_string()).unwrap();
+    assert_eq!(db.catalog().unwrap().rows(), vec![row("a", 0, "string", 1)]);
+}
+
+#[test]
+fn export_json() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+
+    db.create_table(
+        "t".to_string(),
+        vec![DbType::Int, DbType::Real, DbType::Char, DbType::String, DbType::Time],
+    )
+    .unwrap();
+    db.create_table("empty".to_string(), vec![]).unwrap();
+    db.get_table_mut("t".to_string()).unwrap()
+        .insert_row(Row(vec![
+            DbValue::Int(-7),
+            DbValue::Real(0.1 + 0.2),
+            DbValue::Char('ж'),
+            DbValue::String("a \"b\"".to_string()),
+            DbValue::Time(Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset()),
+        ]))
+        .unwrap();
+
+    let mut buffer = Vec::new();
+    db.export_json(&mut buffer, true).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+
+    assert_eq!(value["name"], "db");
+    let names: Vec<_> = value["tables"].as_object().unwrap().keys().cloned().collect();
+    assert_eq!(names, vec!["empty", "t"]);
+    let table = &value["tables"]["t"];
+    assert_eq!(table["schema"], serde_json::json!(["Int", "Real", "Char", "String", "Time"]));
+    let row = &table["rows"][0];
+    assert_eq!(row[0], -7);
+    assert_eq!(row[1].as_f64().unwrap(), 0.1 + 0.2);
+    assert_eq!(row[2], "ж");
+    assert_eq!(row[3], "a \"b\"");
+    assert_eq!(row[4], "2016-07-08T09:10:11Z");
+
+    let mut buffer = Vec::new();
+    db.export_table_json("t".to_string(), &mut buffer).unwrap();
+    let single: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+    assert_eq!(&single, table);
+}
+
+#[test]
+fn snapshot_serde_round_trip() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+
+    db.create_table("b".to_string(), vec![DbType::Time]).unwrap();
+    db.create_table("a".to_string(), vec![DbType::Int, DbType::Char]).unwrap();
+    db.get_table_mut("a".to_string()).unwrap()
+        .insert_row(Row(vec![DbValue::Int(1), DbValue::Char('c')])).unwrap();
+
+    let snapshot = db.snapshot().unwrap();
+    assert_eq!(snapshot.name, "db");
+    assert_eq!(snapshot.tables, vec![
+        ("a".to_string(), vec![DbType::Int, DbType::Char], vec![Row(vec![DbValue::Int(1), DbValue::Char('c')])]),
+        ("b".to_string(), vec![DbType::Time], vec![]),
+    ]);
+
+    let bytes = bincode::serialize(&snapshot).unwrap();
+    assert_eq!(bincode::deserialize::<DatabaseSnapshot>(&bytes).unwrap(), snapshot);
+    let json = serde_json::to_string(&snapshot).unwrap();
+    assert_eq!(serde_json::from_str::<DatabaseSnapshot>(&json).unwrap(), snapshot);
+}
+
+#[test]
+fn import_json_round_trip() {
+    let dir = tempdir().unwrap();
+    let mut db =
+        SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+
+    db.create_table(
+        "t".to_string(),
+        vec![DbType::Int, DbType::Real, DbType::Char, DbType::String, DbType::Time],
+    )
+    .unwrap();
+    db.create_table("other".to_string(), vec![DbType::Int]).unwrap();
+    let table = db.get_table_mut("t".to_string()).unwrap();
+    for real in [0.1 + 0.2, f64::INFINITY, -1e300] {
+        table.insert_row(Row(vec![
+            DbValue::Int(i64::MIN),
+            DbValue::Real(real),
+            DbValue::Char('"'),
+            DbValue::String("multi\nline".to_string()),
+            DbValue::Time(Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset()),
+        ])).unwrap();
+    }
+
+    let mut buffer = Vec::new();
+    db.export_json(&mut buffer, false).unwrap();
+    let imported_path = dir.path().join("imported");
+    let imported = SavedDatabase::import_json(imported_path.clone(), buffer.as_slice(), false).unwrap();
+    assert_eq!(imported.snapshot().unwrap(), db.snapshot().unwrap());
+    drop(imported);
+    let mut merged = SavedDatabase::load_from_disk(imported_path).unwrap();
+    assert_eq!(merged.snapshot().unwrap(), db.snapshot().unwrap());
+
+    let mut buffer = Vec::new();
+    db.export_table_json("other".to_string(), &mut buffer).unwrap();
+    merged.import_table_json("copy".to_string(), buffer.as_slice(), false).unwrap();
+    assert_eq!(merged.get_table("copy".to_string()).unwrap().schema(), vec![DbType::Int]);
+    assert!(matches!(
+        merged.import_table_json("copy".to_string(), buffer.as_slice(), false),
+        Err(DbError::TableIsAlreadyPresent(_))
+    ));
+}
+
+#[test]
+fn import_json_errors() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+
+    let json = r#"{"name": "db", "tables": {"t": {"schema": ["Int", "Time"], "rows": [[1, "2016-07-08T09:10:11Z"], [2, "yesterday"]]}}}"#;
+    match SavedDatabase::import_json(path.clone(), json.as_bytes(), false) {
+        Err(DbError::JsonValueMismatch { table, row, column, .. }) => {
+            assert_eq!((table.as_str(), row, column), ("t", 1, 1));
+        }
+        other => panic!("unexpected result {other:?}"),
+    }
+
+    let json = r#"{"name": "db", "tables": {"t": {"schema": ["Char"], "rows": [["ab"]]}}}"#;
+    assert!(matches!(
+        SavedDatabase::import_json(path.clone(), json.as_bytes(), false),
+        Err(DbError::JsonValueMismatch { column: 0, .. })
+    ));
+
+    let json = r#"{"name": "db", "tables": {"t": {"schema": [], "rows": [], "rowz": []}}}"#;
+    assert!(matches!(
+        SavedDatabase::import_json(path.clone(), json.as_bytes(), false),
+        Err(DbError::InvalidJsonExport(_))
+    ));
+    let db = SavedDatabase::import_json(&path, json.as_bytes(), true).unwrap();
+    assert_eq!(db.get_table_names(), vec!["t".to_string()]);
+}
+
+#[test]
+fn save_to_and_load_from_streams() {
+    let mut db = SavedDatabase::create_with_layout("db".to_string(), PathBuf::new(), Layout::File);
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::String]).unwrap();
+    db.get_table_mut("t".to_string())
+        .unwrap()
+        .insert_row(Row(vec![DbValue::Int(5), DbValue::String("five".to_string())]))
+        .unwrap();
+
+    let mut bytes = Vec::new();
+    db.save_to(&mut bytes).unwrap();
+    assert!(bytes.starts_with(b"ITDB"));
+    let loaded = SavedDatabase::load_from(bytes.as_slice(), "later".to_string()).unwrap();
+    assert_eq!(loaded.snapshot().unwrap(), db.snapshot().unwrap());
+    assert_eq!(loaded.path(), "later");
+    assert!(!loaded.load_report().missing_checksum);
+
+    db.set_format(Format::Json);
+    let mut cursor = std::io::Cursor::new(Vec::new());
+    db.save_to(&mut cursor).unwrap();
+    cursor.set_position(0);
+    let loaded = SavedDatabase::load_from(&mut cursor, String::new()).unwrap();
+    assert_eq!(loaded.snapshot().unwrap(), db.snapshot().unwrap());
+    assert_eq!(loaded.format(), Format::Json);
+
+    bytes[20] ^= 0xff;
+    assert!(matches!(
+        SavedDatabase::load_from(std::io::Cursor::new(bytes), String::new()),
+        Err(DbError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn encryption() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_encrypted("db".to_string(), path.clone(), "hunter2").unwrap();
+    db.create_table("secrets".to_string(), vec![DbType::String]).unwrap();
+    db.get_table_mut("secrets".to_string())
+        .unwrap()
+        .insert_row(Row(vec![DbValue::String("plaintext marker".to_string())]))
+        .unwrap();
+    db.save().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    assert!(!bytes.windows(16).any(|window| window == b"plaintext marker"));
+    SavedDatabase::verify_file(&path).unwrap();
+    let snapshot = db.snapshot().unwrap();
+    drop(db);
+
+    assert!(matches!(SavedDatabase::load_from_disk(path.clone()), Err(DbError::PassphraseRequired)));
+    assert!(matches!(SavedDatabase::load_from_disk_encrypted(path.clone(), "hunter3"), Err(DbError::BadPassphrase)));
+    let mut db = SavedDatabase::load_from_disk_encrypted(path.clone(), "hunter2").unwrap();
+    assert_eq!(db.snapshot().unwrap(), snapshot);
+    assert!(db.is_encrypted());
+
+    assert!(matches!(db.change_passphrase("wrong", "new"), Err(DbError::BadPassphrase)));
+    db.change_passphrase("hunter2", "correct horse").unwrap();
+    db.save().unwrap();
+    drop(db);
+    assert!(matches!(SavedDatabase::load_from_disk_encrypted(path.clone(), "hunter2"), Err(DbError::BadPassphrase)));
+    SavedDatabase::load_from_disk_encrypted(path.clone(), "correct horse").unwrap();
+
+    // A flipped ciphertext byte behind a fixed-up checksum still fails authentication.
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    assert!(matches!(
+        SavedDatabase::load_from_bytes(&bytes, String::new()),
+        Err(DbError::ChecksumMismatch { .. })
+    ));
+    let crc = crc32fast::hash(&bytes[12..]);
+    bytes[8..12].copy_from_slice(&crc.to_le_bytes());
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(matches!(
+        SavedDatabase::load_from_disk_encrypted(path.clone(), "correct horse"),
+        Err(DbError::CorruptPayload(_))
+    ));
+
+    let plain = dir.path().join("plain");
+    let mut db = SavedDatabase::create("plain".to_string(), plain.clone()).unwrap();
+    assert!(matches!(db.change_passphrase("", "new"), Err(DbError::NotEncrypted)));
+    drop(db);
+    assert!(matches!(SavedDatabase::load_from_disk_encrypted(plain, "new"), Err(DbError::NotEncrypted)));
+}
+
+#[test]
+fn encrypted_wal() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_encrypted("db".to_string(), path.clone(), "hunter2").unwrap();
+    db.enable_wal(FsyncPolicy::Always);
+    db.create_table("secrets".to_string(), vec![DbType::String]).unwrap();
+    db.insert_row("secrets".to_string(), Row(vec![DbValue::String("plaintext marker".to_string())])).unwrap();
+    let log = std::fs::read(path.with_extension("wal")).unwrap();
+    assert!(!log.is_empty());
+    assert!(!log.windows(16).any(|window| window == b"plaintext marker"));
+
+    // Changing the passphrase saves, so the log never mixes keys.
+    db.change_passphrase("hunter2", "correct horse").unwrap();
+    assert_eq!(std::fs::metadata(path.with_extension("wal")).unwrap().len(), 0);
+    db.insert_row("secrets".to_string(), Row(vec![DbValue::String("second".to_string())])).unwrap();
+    let snapshot = db.snapshot().unwrap();
+    drop(db);
+
+    let db = SavedDatabase::load_from_disk_encrypted(&path, "correct horse").unwrap();
+    assert_eq!(db.load_report().replayed, 1);
+    assert_eq!(db.snapshot().unwrap(), snapshot);
+    drop(db);
+
+    // Records sealed with another key are rejected rather than taken as a torn tail.
+    let other = dir.path().join("other");
+    let mut db = SavedDatabase::create_encrypted("db".to_string(), other.clone(), "other").unwrap();
+    db.enable_wal(FsyncPolicy::Always);
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    drop(db);
+    std::fs::copy(other.with_extension("wal"), path.with_extension("wal")).unwrap();
+    assert!(matches!(SavedDatabase::load_from_disk_encrypted(&path, "correct horse"), Err(DbError::CorruptPayload(_))));
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_paths() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join(std::ffi::OsStr::from_bytes(b"db-\xff"));
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.enable_wal(FsyncPolicy::Never);
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    assert_eq!(db.path(), path);
+    drop(db);
+
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.load_report().replayed, 2);
+    assert_eq!(db.get_table("t".to_string()).unwrap().rows().len(), 1);
+}
+
+#[test]
+fn load_from_bytes() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.get_table_mut("t".to_string()).unwrap().insert_row(Row(vec![DbValue::Int(5)])).unwrap();
+    db.save().unwrap();
+
+    let bytes = std::fs::read(&path).unwrap();
+    let new_path = dir.path().join("copy");
+    let mut loaded = SavedDatabase::load_from_bytes(&bytes, new_path.clone()).unwrap();
+    assert_eq!(loaded.snapshot().unwrap(), db.snapshot().unwrap());
+
+    loaded.save().unwrap();
+    assert_eq!(std::fs::read(new_path).unwrap(), bytes);
+    assert!(SavedDatabase::load_from_bytes(&bytes[..bytes.len() - 1], String::new()).is_err());
+}
+
+#[test]
+fn table_count() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    assert_eq!(db.table_count(), 0);
+
+    for name in ["a", "b", "c"] {
+        db.create_table(name.to_string(), vec![DbType::Int]).unwrap();
+    }
+    assert_eq!(db.table_count(), 3);
+
+    db.remove_table("b".to_string()).unwrap();
+    assert_eq!(db.table_count(), 2);
+}
+
+fn sql_dump_database(dir: &tempfile::TempDir) -> SavedDatabase {
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table(
+        "it's".to_string(),
+        vec![DbType::Int, DbType::Real, DbType::Char, DbType::String, DbType::Time],
+    )
+    .unwrap();
+    let table = db.get_table_mut("it's".to_string()).unwrap();
+    table.insert_row(Row(vec![
+        DbValue::Int(1),
+        DbValue::Real(0.5),
+        DbValue::Char('\''),
+        DbValue::String("O'Brien".to_string()),
+        DbValue::Time(Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset()),
+    ])).unwrap();
+    table.insert_row(Row(vec![
+        DbValue::Int(2),
+        DbValue::Real(f64::INFINITY),
+        DbValue::Char('x'),
+        DbValue::String(String::new()),
+        DbValue::Time(DateTime::default()),
+    ])).unwrap();
+    table.insert_row(Row(vec![
+        DbValue::Int(3),
+        DbValue::Real(-2.0),
+        DbValue::Char('y'),
+        DbValue::String("z".to_string()),
+        DbValue::Time(DateTime::default()),
+    ])).unwrap();
+    db
+}
+
+#[test]
+fn export_sql_postgres() {
+    let dir = tempdir().unwrap();
+    let db = sql_dump_database(&dir);
+
+    let mut buffer = Vec::new();
+    db.export_sql(&mut buffer, SqlDialect::Postgres, Some(2)).unwrap();
+    assert_eq!(String::from_utf8(buffer).unwrap(), "\
+CREATE TABLE \"it's\" (\"col0\" BIGINT, \"col1\" DOUBLE PRECISION, \"col2\" CHAR(1), \"col3\" TEXT, \"col4\" TIMESTAMPTZ);
+INSERT INTO \"it's\" VALUES
+    (1, 0.5, '''', 'O''Brien', '2016-07-08T09:10:11+00:00'::timestamptz),
+    (2, 'Infinity'::double precision, 'x', '', '1970-01-01T00:00:00+00:00'::timestamptz);
+INSERT INTO \"it's\" VALUES
+    (3, -2.0, 'y', 'z', '1970-01-01T00:00:00+00:00'::timestamptz);
+");
+}
+
+#[test]
+fn export_sql_sqlite() {
+    let dir = tempdir().unwrap();
+    let db = sql_dump_database(&dir);
+
+    let mut buffer = Vec::new();
+    db.export_sql(&mut buffer, SqlDialect::Sqlite, None).unwrap();
+    let sql = String::from_utf8(buffer).unwrap();
+    assert_eq!(sql.matches("INSERT").count(), 1);
+
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    connection.execute_batch(&sql).unwrap();
+    let mut statement = connection
+        .prepare("SELECT col0, col1, col2, col3, col4 FROM `it's` ORDER BY col0")
+        .unwrap();
+    let rows: Vec<(i64, f64, String, String, String)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0], (1, 0.5, "'".to_string(), "O'Brien".to_string(), "2016-07-08T09:10:11+00:00".to_string()));
+    assert_eq!(rows[1].1, f64::INFINITY);
+    assert_eq!(rows[2].1, -2.0);
+}
+
+#[test]
+fn query_builder() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("orders".to_string(), vec![DbType::Int, DbType::String, DbType::Real]).unwrap();
+    let table = db.get_table_mut("orders".to_string()).unwrap();
+    for (id, customer, total) in [(1, "ann", 10.0), (2, "bob", 25.5), (3, "ann", 7.25), (4, "cid", 99.0), (5, "ann", 30.0), (6, "bob", 1.0)] {
+        table.insert_row(Row(vec![DbValue::Int(id), DbValue::String(customer.to_string()), DbValue::Real(total)])).unwrap();
+    }
+
+    let query = db
+        .query("orders")
+        .filter(Condition::compare(2, CompareOp::Ge, DbValue::Real(5.0)))
+        .filter(Condition::Not(Box::new(Condition::compare(1, CompareOp::Eq, DbValue::String("cid".to_string())))))
+        .order_by(2, true)
+        .offset(1)
+        .limit(2)
+        .select_columns(&[0, 2]);
+    // Matching totals descending: 30.0 (5), 25.5 (2), 10.0 (1), 7.25 (3); skip one, take two.
+    let expected = vec![
+        Row(vec![DbValue::Int(2), DbValue::Real(25.5)]),
+        Row(vec![DbValue::Int(1), DbValue::Real(10.0)]),
+    ];
+    assert_eq!(query.rows(&db).unwrap(), expected);
+    assert_eq!(query.count(&db).unwrap(), 2);
+
+    let query: Query = serde_json::from_str(&serde_json::to_string(&query).unwrap()).unwrap();
+    query.into_table(&mut db, "top".to_string()).unwrap();
+    let top = db.get_table("top".to_string()).unwrap();
+    assert_eq!(top.schema(), vec![DbType::Int, DbType::Real]);
+    assert_eq!(top.rows(), expected);
+
+    assert!(matches!(db.query("orders").order_by(3, false).rows(&db), Err(DbError::ColumnOutOfRange(3))));
+    assert!(matches!(db.query("missing").count(&db), Err(DbError::TableIsMissing(_))));
+}
+
+#[test]
+fn order_by_nan() {
+    let dir = tempdir().unwrap();
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Real]).unwrap();
+    for value in [2.0, f64::NAN, -1.0, f64::NAN, 0.5, f64::NEG_INFINITY] {
+        db.insert_row("t".to_string(), Row(vec![DbValue::Real(value)])).unwrap();
+    }
+    let values = |rows: Vec<Row>| rows.iter().map(|row| row.0[0].to_string()).collect::<Vec<_>>();
+
+    // NaN sorts after every number, so it can't scramble the others.
+    let ascending = ["-inf", "-1", "0.5", "2", "NaN", "NaN"];
+    assert_eq!(values(db.query("t").order_by(0, false).rows(&db).unwrap()), ascending);
+    let QueryResult::Rows { rows, .. } = db.execute_sql("SELECT * FROM t ORDER BY col0 DESC").unwrap() else {
+        panic!("expected rows");
+    };
+    assert_eq!(values(rows), ascending.into_iter().rev().collect::<Vec<_>>());
+}
+
+#[test]
+fn insert_row_errors() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int, DbType::String]);
+
+    assert!(matches!(
+        table.insert_row(Row(vec![DbValue::Int(1)])),
+        Err(DbError::RowLengthMismatch { expected: 2, got: 1 })
+    ));
+    assert!(matches!(
+        table.insert_row(Row(vec![DbValue::Int(1), DbValue::Char('x')])),
+        Err(DbError::ColumnTypeMismatch { column: 1, expected: DbType::String, got: DbType::Char })
+    ));
+    assert!(table.rows().is_empty());
+
+    table.insert_row(Row(vec![DbValue::Int(1), DbValue::String("x".to_string())])).unwrap();
+    assert!(matches!(
+        table.update_row(0, Row(vec![DbValue::Real(1.0), DbValue::String("x".to_string())])),
+        Err(DbError::ColumnTypeMismatch { column: 0, expected: DbType::Int, got: DbType::Real })
+    ));
+}
+
+#[test]
+fn validate_row() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int, DbType::String]);
+    table.add_check(CheckConstraint { column: 0, op: CompareOp::Ge, value: DbValue::Int(0) }).unwrap();
+
+    let fitting = Row(vec![DbValue::Int(1), DbValue::String("x".to_string())]);
+    assert!(table.row_fits(&fitting));
+    assert!(table.check_row(&fitting).is_ok());
+
+    let negative = Row(vec![DbValue::Int(-1), DbValue::String("x".to_string())]);
+    assert!(!table.row_fits(&negative));
+    assert!(matches!(table.check_row(&negative), Err(DbError::CheckViolation { column: 0, .. })));
+    assert!(matches!(
+        table.check_row(&Row(vec![DbValue::Int(1)])),
+        Err(DbError::RowLengthMismatch { expected: 2, got: 1 })
+    ));
+
+    assert!(table.rows().is_empty());
+    assert_eq!(table.version(), 0);
+}
+
+#[test]
+fn execute_sql() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+
+    assert_eq!(
+        db.execute_sql("CREATE TABLE people (id int, name string, initial char, born time, score real);").unwrap(),
+        QueryResult::Affected(0)
+    );
+    assert_eq!(db.get_table("people".to_string()).unwrap().schema(),
+        vec![DbType::Int, DbType::String, DbType::Char, DbType::Time, DbType::Real]);
+
+    db.execute_sql("INSERT INTO people VALUES (1, 'O''Brien', 'o', '2016-07-08T09:10:11Z', 1.5)").unwrap();
+    db.execute_sql("insert into people values (2, 'Ann', 'a', '2000-01-01T00:00:00+02:00', -2e1)").unwrap();
+    db.execute_sql("INSERT INTO people VALUES (3, 'Bob', 'b', '1999-12-31T23:59:59Z', 3)").unwrap();
+
+    let result = db.execute_sql("SELECT col1, col0 FROM people WHERE col4 > -100.0 AND NOT (col0 = 3) ORDER BY col1 DESC LIMIT 5").unwrap();
+    assert_eq!(result, QueryResult::Rows {
+        schema: vec![DbType::String, DbType::Int],
+        rows: vec![
+            Row(vec![DbValue::String("O'Brien".to_string()), DbValue::Int(1)]),
+            Row(vec![DbValue::String("Ann".to_string()), DbValue::Int(2)]),
+        ],
+    });
+    let QueryResult::Rows { rows, .. } = db.execute_sql("SELECT * FROM people WHERE col3 < '2000-01-01T00:00:00Z' OR col2 = 'o' ORDER BY col3 LIMIT 1").unwrap() else {
+        panic!("expected rows");
+    };
+    assert_eq!(rows[0].get(3), DbValue::Time(Utc.with_ymd_and_hms(1999, 12, 31, 22, 0, 0).unwrap().fixed_offset()));
+    let select = "SELECT col1 FROM people WHERE col0 = 3";
+    assert_eq!(db.query_sql(select).unwrap(), db.execute_sql(select).unwrap());
+    let rows = |result| match result {
+        QueryResult::Rows { rows, .. } => rows.len(),
+        QueryResult::Affected(_) => panic!("expected rows"),
+    };
+    assert_eq!(rows(db.query_sql_within("SELECT * FROM people", 2).unwrap()), 2);
+    assert_eq!(rows(db.query_sql_within("SELECT * FROM people LIMIT 1", 2).unwrap()), 1);
+    assert!(matches!(db.query_sql("DELETE FROM people"), Err(DbError::SqlSyntax { offset: 0, .. })));
+
+    assert_eq!(db.execute_sql("DELETE FROM people WHERE col0 >= 2").unwrap(), QueryResult::Affected(2));
+    assert_eq!(db.get_table("people".to_string()).unwrap().rows().len(), 1);
+    assert_eq!(db.execute_sql("DROP TABLE people").unwrap(), QueryResult::Affected(0));
+    assert!(db.get_table_names().is_empty());
+}
+
+#[test]
+fn execute_sql_syntax_errors() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.execute_sql("CREATE TABLE t (a int, b string)").unwrap();
+
+    let mut offset = |sql: &str| match db.execute_sql(sql) {
+        Err(DbError::SqlSyntax { offset, .. }) => offset,
+        other => panic!("unexpected result {other:?}"),
+    };
+    assert_eq!(offset("SELEC * FROM t"), 0);
+    assert_eq!(offset("SELECT * FROM t WHERE col0 == 1"), 28);
+    assert_eq!(offset("INSERT INTO t VALUES (1, 'unterminated)"), 25);
+    assert_eq!(offset("INSERT INTO t VALUES (1, 2)"), 25);
+    assert_eq!(offset("SELECT col7 FROM t"), 7);
+    assert_eq!(offset("CREATE TABLE u (a float)"), 18);
+    assert_eq!(offset("DROP TABLE t extra"), 13);
+}
+
+#[test]
+fn reload_discards_unsaved_changes() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+
+    db.get_table_mut("t".to_string()).unwrap().insert_row(Row(vec![DbValue::Int(1)])).unwrap();
+    db.reload().unwrap();
+    assert!(db.get_table("t".to_string()).unwrap().rows().is_empty());
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(db.reload(), Err(DbError::FileNotFound(_))));
+    assert_eq!(db.get_table_names(), vec!["t".to_string()]);
+}
+
+#[test]
+fn materialized_projection() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("source".to_string(), vec![DbType::Int, DbType::String]).unwrap();
+    db.get_table_mut("source".to_string()).unwrap()
+        .insert_row(Row(vec![DbValue::Int(1), DbValue::String("a".to_string())])).unwrap();
+
+    db.create_materialized_projection("source".to_string(), vec![false, true], "names".to_string()).unwrap();
+    assert!(!db.is_stale("names".to_string()).unwrap());
+    assert!(matches!(db.is_stale("source".to_string()), Err(DbError::NotMaterialized(_))));
+
+    db.get_table_mut("source".to_string()).unwrap()
+        .insert_row(Row(vec![DbValue::Int(2), DbValue::String("b".to_string())])).unwrap();
+    assert!(db.is_stale("names".to_string()).unwrap());
+    assert_eq!(db.get_table("names".to_string()).unwrap().rows().len(), 1);
+
+    db.refresh_materialized("names".to_string()).unwrap();
+    assert!(!db.is_stale("names".to_string()).unwrap());
+    assert_eq!(db.get_table("names".to_string()).unwrap().rows(), vec![
+        Row(vec![DbValue::String("a".to_string())]),
+        Row(vec![DbValue::String("b".to_string())]),
+    ]);
+
+    db.save().unwrap();
+    drop(db);
+    let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert!(!db.is_stale("names".to_string()).unwrap());
+
+    db.remove_table("source".to_string()).unwrap();
+    let info = db.table_info("names".to_string()).unwrap();
+    assert_eq!(info.row_count, 2);
+    assert_eq!(info.materialized, Some(MaterializedInfo {
+        source: "source".to_string(),
+        stale: true,
+        orphaned: true,
+    }));
+    assert!(matches!(db.refresh_materialized("names".to_string()), Err(DbError::TableIsMissing(_))));
+    assert_eq!(db.get_table("names".to_string()).unwrap().rows().len(), 2);
+}
+
+#[test]
+fn wal_logs_table_settings() {
+    use bincode::Options;
+    // Variants keep the tags logs written before the later ones were added.
+    let tagged = |op: &TxOp| format::bincode_options().serialize(op).unwrap()[..4].to_vec();
+    assert_eq!(tagged(&TxOp::RefreshMaterialized { name: String::new() }), 8u32.to_le_bytes());
+    assert_eq!(tagged(&TxOp::SetMaxBlobLen { table: String::new(), max: 0 }), 15u32.to_le_bytes());
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.enable_wal(FsyncPolicy::Always);
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::Blob]).unwrap();
+    db.set_default("t".to_string(), 1, Some(ColumnDefault::Value(DbValue::Blob(vec![1])))).unwrap();
+    db.set_max_blob_len("t".to_string(), 4).unwrap();
+    assert!(db.set_default("t".to_string(), 1, Some(ColumnDefault::Now)).is_err());
+    drop(db);
+
+    let mut db = SavedDatabase::load_from_disk(path).unwrap();
+    assert_eq!(db.load_report().replayed, 3);
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    assert_eq!(db.get_table("t".to_string()).unwrap().rows()[0], Row(vec![DbValue::Int(1), DbValue::Blob(vec![1])]));
+    assert!(matches!(
+        db.insert_row("t".to_string(), Row(vec![DbValue::Int(2), DbValue::Blob(vec![0; 5])])),
+        Err(DbError::BlobTooLarge { len: 5, max: 4, .. })
+    ));
+}
+
+#[test]
+fn wal_logs_restore() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+    db.enable_wal(FsyncPolicy::Always);
+    let savepoint = db.savepoint().unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    db.create_table("u".to_string(), vec![DbType::Int]).unwrap();
+    db.restore(savepoint).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(2)])).unwrap();
+    let expected = db.snapshot().unwrap();
+    drop(db);
+
+    // Replaying restores the savepoint too, dropping the mutations logged before it.
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.load_report().replayed, 4);
+    assert_eq!(db.snapshot().unwrap(), expected);
+}
+
+#[test]
+fn wal_replay_after_crash() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.enable_wal(FsyncPolicy::Always);
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(2)])).unwrap();
+    db.update_row("t".to_string(), 0, Row(vec![DbValue::Int(10)])).unwrap();
+    assert!(db.insert_row("t".to_string(), Row(vec![DbValue::Char('x')])).is_err());
+    db.projection("t".to_string(), vec![true], "p".to_string(), None).unwrap();
+    db.remove_row("t".to_string(), 1).unwrap();
+    let expected = db.snapshot().unwrap();
+    drop(db);
+
+    let mut db = SavedDatabase::load_from_disk(path.clone()).unwrap();
+    assert_eq!(db.snapshot().unwrap(), expected);
+    assert_eq!(db.get_table("p".to_string()).unwrap().rows().len(), 2);
+    // The failed insert is not logged.
+    assert_eq!(db.load_report().replayed, 6);
+    assert_eq!(db.load_report().corrupt_wal_record, None);
+
+    db.save().unwrap();
+    assert_eq!(std::fs::metadata(path.with_extension("wal")).unwrap().len(), 0);
+    db.remove_table("p".to_string()).unwrap();
+    drop(db);
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.get_table_names(), vec!["t".to_string()]);
+}
+
+#[test]
+fn wal_handcrafted_and_corrupt() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+
+    // The log a crashed process would have left behind.
+    let record = |op: &TxOp| {
+        let payload = bincode::serialize(op).unwrap();
+        let mut record = (payload.len() as u32).to_le_bytes().to_vec();
+        record.extend(crc32fast::hash(&payload).to_le_bytes());
+        record.extend(payload);
+        record
+    };
+    let insert = |value| TxOp::InsertRow { table: "t".to_string(), row: Row(vec![DbValue::Int(value)]) };
+    let mut log = record(&insert(1));
+    log.extend(record(&insert(2)));
+    let valid_len = log.len() as u64;
+    let mut corrupt = record(&insert(3));
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+    log.extend(corrupt);
+    log.extend(record(&insert(4)));
+    std::fs::write(path.with_extension("wal"), &log).unwrap();
+    drop(db);
+
+    // Loads that don't take the database for writing replay the valid records only.
+    let read_only = SavedDatabase::open_read_only(&path).unwrap();
+    assert_eq!(read_only.load_report().replayed, 2);
+    assert_eq!(read_only.load_report().corrupt_wal_record, Some(valid_len));
+    let ignoring_lock = load_ignoring_lock(&path).unwrap();
+    assert_eq!(ignoring_lock.load_report().replayed, 2);
+    assert_eq!(std::fs::read(path.with_extension("wal")).unwrap(), log);
+    drop((read_only, ignoring_lock));
+
+    let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.get_table("t".to_string()).unwrap().rows(), [Row(vec![DbValue::Int(1)]), Row(vec![DbValue::Int(2)])]);
+    assert_eq!(db.load_report().replayed, 2);
+    assert_eq!(db.load_report().corrupt_wal_record, Some(valid_len));
+    assert!(db.is_dirty());
+
+    // The corrupt tail is cut off, so records appended later are found again.
+    assert_eq!(std::fs::metadata(path.with_extension("wal")).unwrap().len(), valid_len);
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(5)])).unwrap();
+    drop(db);
+    let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.load_report().replayed, 3);
+    assert_eq!(db.load_report().corrupt_wal_record, None);
+
+    // A torn record at the end is tolerated the same way.
+    db.save().unwrap();
+    assert_eq!(std::fs::metadata(path.with_extension("wal")).unwrap().len(), 0);
+    std::fs::write(path.with_extension("wal"), &record(&insert(6))[..5]).unwrap();
+    drop(db);
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.get_table("t".to_string()).unwrap().rows().len(), 3);
+    assert_eq!(db.load_report().corrupt_wal_record, Some(0));
+    drop(db);
+
+    // A record that doesn't apply means the log is not the one of this file.
+    let mut log = record(&insert(6));
+    log.extend(record(&TxOp::RemoveRow { table: "missing".to_string(), index: 0 }));
+    std::fs::write(path.with_extension("wal"), &log).unwrap();
+    assert!(matches!(
+        SavedDatabase::load_from_disk(&path),
+        Err(DbError::WalReplayFailed { index: 1, source }) if matches!(*source, DbError::TableIsMissing(_))
+    ));
+}
+
+#[test]
+fn project_many() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.enable_wal(FsyncPolicy::Never);
+    db.create_table("a".to_string(), vec![DbType::Int, DbType::String]).unwrap();
+    db.create_table("b".to_string(), vec![DbType::Int, DbType::Char]).unwrap();
+    db.insert_row("a".to_string(), Row(vec![DbValue::Int(1), DbValue::String("x".to_string())])).unwrap();
+    db.save().unwrap();
+
+    let spec = |table: &str, columns: Vec<bool>, new_table: &str| (table.to_string(), columns, new_table.to_string());
+    let failing = vec![spec("a", vec![false, true], "a_names"), spec("missing", vec![true], "m")];
+    assert!(matches!(db.project_many(failing), Err(DbError::TableIsMissing(_))));
+    assert!(db.get_table("a_names".to_string()).is_err());
+    assert!(!db.is_dirty());
+    assert_eq!(std::fs::metadata(path.with_extension("wal")).unwrap().len(), 0);
+
+    // A later projection whose table is taken undoes the earlier ones too.
+    let taken = vec![spec("a", vec![false, true], "a_names"), spec("b", vec![true, false], "a_names")];
+    assert!(matches!(db.project_many(taken), Err(DbError::TableIsAlreadyPresent(_))));
+    assert_eq!(db.table_count(), 2);
+
+    db.project_many(vec![spec("a", vec![false, true], "a_names"), spec("b", vec![true, false], "b_ids")]).unwrap();
+    assert_eq!(db.get_table("a_names".to_string()).unwrap().rows(), [Row(vec![DbValue::String("x".to_string())])]);
+    assert_eq!(db.get_table("b_ids".to_string()).unwrap().schema(), [DbType::Int]);
+
+    // The batch is a single record of the log.
+    let snapshot = db.snapshot().unwrap();
+    drop(db);
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.load_report().replayed, 1);
+    assert_eq!(db.snapshot().unwrap(), snapshot);
+}
+
+#[test]
+fn backups() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    let saved = std::fs::read(&path).unwrap();
+
+    // Unsaved changes are part of the backup, the database file stays as it was.
+    let backup = db.backup(None).unwrap();
+    assert_eq!(backup.parent(), Some(dir.path()));
+    let name = backup.file_name().unwrap().to_str().unwrap();
+    assert!(name.starts_with("db-") && name.ends_with(".dbbak") && name.len() == "db-YYYYMMDD-HHMMSS.dbbak".len());
+    assert_eq!(std::fs::read(&path).unwrap(), saved);
+    assert_eq!(db.list_backups(None).unwrap(), vec![backup.clone()]);
+
+    let expected = db.snapshot().unwrap();
+    db.remove_table("t".to_string()).unwrap();
+    db.restore_backup(&backup).unwrap();
+    assert_eq!(db.snapshot().unwrap(), expected);
+    assert_eq!(db.path(), path);
+    assert!(db.is_dirty());
+    assert_eq!(std::fs::read(&path).unwrap(), saved);
+    db.save().unwrap();
+    assert_eq!(load_ignoring_lock(&path).unwrap().snapshot().unwrap(), expected);
+
+    // Older backups are pruned once there are more than allowed.
+    let other = dir.path().join("backups");
+    std::fs::create_dir(&other).unwrap();
+    for stamp in ["20200101-000000", "20210101-000000", "20220101-000000"] {
+        std::fs::write(other.join(format!("db-{stamp}.dbbak")), b"").unwrap();
+    }
+    std::fs::write(other.join("another-20200101-000000.dbbak"), b"").unwrap();
+    // Backups of a database named `db-x`, and files that only look like backups.
+    for other_file in ["db-x-20200101-000000.dbbak", "db-notes.dbbak", "db-20200101-000000-x.dbbak"] {
+        std::fs::write(other.join(other_file), b"").unwrap();
+    }
+    db.set_max_backups(Some(2));
+    let newest = db.backup(Some(&other)).unwrap();
+    assert_eq!(db.list_backups(Some(&other)).unwrap(), [other.join("db-20220101-000000.dbbak"), newest]);
+    for other_file in ["another-20200101-000000.dbbak", "db-x-20200101-000000.dbbak", "db-notes.dbbak", "db-20200101-000000-x.dbbak"] {
+        assert!(other.join(other_file).exists(), "{other_file}");
+    }
+
+    // Backups within the same second are numbered instead of replacing each other.
+    let same = dir.path().join("same");
+    db.set_max_backups(None);
+    let backups: Vec<PathBuf> = (0..3).map(|_| db.backup(Some(&same)).unwrap()).collect();
+    let names = db.list_backups(Some(&same)).unwrap();
+    assert_eq!(names.len(), 3);
+    assert!(names.iter().all(|name| backups.contains(name)));
+    std::fs::create_dir_all(dir.path().join("numbered")).unwrap();
+    for name in ["db-20200101-000000-2.dbbak", "db-20200101-000000-10.dbbak", "db-20200101-000000.dbbak", "db-20200101-000001.dbbak"] {
+        std::fs::write(dir.path().join("numbered").join(name), b"").unwrap();
+    }
+    let sorted = db.list_backups(Some(&dir.path().join("numbered"))).unwrap();
+    let sorted: Vec<_> = sorted.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+    assert_eq!(sorted, ["db-20200101-000000.dbbak", "db-20200101-000000-2.dbbak", "db-20200101-000000-10.dbbak", "db-20200101-000001.dbbak"]);
+}
+
+#[test]
+fn file_locks() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    let pid = std::process::id();
+    match SavedDatabase::load_from_disk(path.clone()) {
+        Err(DbError::DatabaseLocked { path: locked, holder_pid }) => {
+            assert_eq!(locked, path);
+            assert_eq!(holder_pid, Some(pid));
+        }
+        other => panic!("expected the database to be locked, got {other:?}"),
+    }
+    assert!(matches!(SavedDatabase::open_read_only(path.clone()), Err(DbError::DatabaseLocked { .. })));
+    assert!(matches!(SavedDatabase::create("db".to_string(), path.clone()), Err(DbError::DatabaseLocked { .. })));
+
+    // Clones share the lock, so it is released with the last of them.
+    let clone = db.clone();
+    drop(db);
+    assert!(SavedDatabase::load_from_disk(path.clone()).is_err());
+    drop(clone);
+    let mut db = SavedDatabase::load_from_disk(path.clone()).unwrap();
+    db.reload().unwrap();
+    drop(db);
+
+    // Read-only opens share the lock with each other.
+    let mut reader = SavedDatabase::open_read_only(path.clone()).unwrap();
+    let other_reader = SavedDatabase::open_read_only(path.clone()).unwrap();
+    assert!(matches!(
+        SavedDatabase::load_from_disk(path.clone()),
+        Err(DbError::DatabaseLocked { holder_pid: None, .. })
+    ));
+    assert!(matches!(reader.save(), Err(DbError::ReadOnly)));
+    drop((reader, other_reader));
+
+    // A lock file left by a process that died holds no lock and is taken over.
+    std::fs::write(path.with_extension("lock"), "4294967295\n").unwrap();
+    let mut db = SavedDatabase::load_from_disk(path.clone()).unwrap();
+    assert_eq!(std::fs::read_to_string(path.with_extension("lock")).unwrap(), format!("{pid}\n"));
+
+    // Switching to another file moves the lock.
+    let new_path = dir.path().join("new");
+    db.save_as(new_path.clone(), true, false).unwrap();
+    SavedDatabase::load_from_disk(&path).unwrap();
+    assert!(matches!(SavedDatabase::load_from_disk(&new_path), Err(DbError::DatabaseLocked { .. })));
+
+    // Ignoring the lock loads the database anyway, without taking the lock over.
+    let ignoring = load_ignoring_lock(&new_path).unwrap();
+    assert_eq!(ignoring.snapshot().unwrap(), db.snapshot().unwrap());
+    drop(db);
+    SavedDatabase::load_from_disk(&new_path).unwrap();
+    drop(ignoring);
+}
+
+#[test]
+fn check_integrity() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db =
+        SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("a".to_string(), vec![DbType::Int]).unwrap();
+    db.create_table("b".to_string(), vec![DbType::String]).unwrap();
+    db.insert_row("a".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    db.insert_row("a".to_string(), Row(vec![DbValue::Int(2)])).unwrap();
+    db.create_materialized_projection("a".to_string(), vec![true], "m".to_string()).unwrap();
+    assert!(db.check_integrity().unwrap().is_ok());
+
+    db.get_table_mut("a".to_string()).unwrap().rows_mut()[1] = Row(vec![DbValue::Real(2.0)]);
+    db.get_table_mut("b".to_string()).unwrap().set_name("c".to_string());
+    db.db.tables.remove("m");
+
+    assert_eq!(db.check_integrity().unwrap().findings, vec![
+        IntegrityFinding::RowSchemaMismatch { table: "a".to_string(), row: 1 },
+        IntegrityFinding::TableNameMismatch { key: "b".to_string(), name: "c".to_string() },
+        IntegrityFinding::MissingDerivedTable { name: "m".to_string() },
+    ]);
+}
+
+#[test]
+fn check_integrity_of_rows() {
+    let dir = tempdir().unwrap();
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::Blob]).unwrap();
+    db.set_autoincrement("t".to_string(), 0).unwrap();
+    db.add_check("t".to_string(), CheckConstraint { column: 0, op: CompareOp::Lt, value: DbValue::Int(10) }).unwrap();
+    db.set_max_blob_len("t".to_string(), 2).unwrap();
+    for value in [1, 2, 3] {
+        db.insert_row("t".to_string(), Row(vec![DbValue::Int(value), DbValue::Blob(vec![])])).unwrap();
+    }
+    assert!(db.check_integrity().unwrap().is_ok());
+
+    let rows = db.get_table_mut("t".to_string()).unwrap().rows_mut();
+    rows[0] = Row(vec![DbValue::Int(20), DbValue::Blob(vec![0; 3])]);
+    rows[2].0[0] = DbValue::Int(2);
+    let mut table = serde_json::to_value(db.get_table("t".to_string()).unwrap()).unwrap();
+    table["ids"] = serde_json::json!([0, 1, 0]);
+    table["created_at"].as_array_mut().unwrap().pop();
+    db.db.tables.insert("t".to_string(), serde_json::from_value(table).unwrap());
+
+    let t = || "t".to_string();
+    assert_eq!(db.check_integrity().unwrap().findings, vec![
+        IntegrityFinding::RowMetadataMismatch { table: t(), rows: 3, ids: 3, created_at: 2 },
+        IntegrityFinding::DuplicateRowId { table: t(), row: 2, id: 0 },
+        IntegrityFinding::CheckViolation { table: t(), row: 0, column: 0 },
+        IntegrityFinding::ValueTooLong { table: t(), row: 0, column: 1 },
+        IntegrityFinding::DuplicateAutoIncrement { table: t(), row: 2, column: 0 },
+    ]);
+}
+
+#[test]
+fn check_constraints() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int, DbType::String]);
+    table.add_check(CheckConstraint { column: 0, op: CompareOp::Ge, value: DbValue::Int(0) }).unwrap();
+    table.add_check(CheckConstraint { column: 0, op: CompareOp::Lt, value: DbValue::Int(100) }).unwrap();
+
+    table.insert_row(Row(vec![DbValue::Int(5), DbValue::String("ok".to_string())])).unwrap();
+    assert!(matches!(
+        table.insert_row(Row(vec![DbValue::Int(-1), DbValue::String("negative".to_string())])),
+        Err(DbError::CheckViolation { column: 0, .. })
+    ));
+    assert!(matches!(
+        table.update_row(0, Row(vec![DbValue::Int(100), DbValue::String("too big".to_string())])),
+        Err(DbError::CheckViolation { column: 0, .. })
+    ));
+    assert_eq!(table.rows(), vec![Row(vec![DbValue::Int(5), DbValue::String("ok".to_string())])]);
+
+    assert!(matches!(
+        table.add_check(CheckConstraint { column: 1, op: CompareOp::Eq, value: DbValue::Int(0) }),
+        Err(DbError::ColumnTypeMismatch { column: 1, .. })
+    ));
+    assert!(matches!(
+        table.add_check(CheckConstraint { column: 0, op: CompareOp::Gt, value: DbValue::Int(5) }),
+        Err(DbError::CheckViolation { .. })
+    ));
+    assert_eq!(table.checks().len(), 2);
+}
+
+#[test]
+fn db_type_display_from_str() {
+    for r#type in [DbType::Int, DbType::Real, DbType::Char, DbType::String, DbType::Time] {
+        assert_eq!(r#type.to_string().parse::<DbType>().unwrap(), r#type);
+    }
+    assert_eq!("STRING".parse::<DbType>().unwrap(), DbType::String);
+    assert!(matches!("float".parse::<DbType>(), Err(DbError::UnknownType(name)) if name == "float"));
+}
+
+#[test]
+fn parse_db_value() {
+    assert_eq!(DbValue::parse(DbType::Int, "-42").unwrap(), DbValue::Int(-42));
+    assert_eq!(DbValue::parse(DbType::Real, "2.5e3").unwrap(), DbValue::Real(2500.0));
+    assert_eq!(DbValue::parse(DbType::Char, "ж").unwrap(), DbValue::Char('ж'));
+    assert_eq!(DbValue::parse(DbType::String, " as is ").unwrap(), DbValue::String(" as is ".to_string()));
+    assert_eq!(
+        DbValue::parse(DbType::Time, "2016-07-08T11:10:11+02:00").unwrap(),
+        DbValue::Time(Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset())
+    );
+
+    for (r#type, input) in [
+        (DbType::Int, "1.5"),
+        (DbType::Real, "one"),
+        (DbType::Char, "ab"),
+        (DbType::Char, ""),
+        (DbType::Time, "2016-07-08"),
+    ] {
+        assert!(matches!(
+            DbValue::parse(r#type, input),
+            Err(DbError::ParseError { expected, .. }) if expected == r#type
+        ));
+    }
+}
+
+#[test]
+fn save_as() {
+    let dir = tempdir().unwrap();
+    let original = dir.path().join("db");
+    let copy = dir.path().join("copy");
+    let moved = dir.path().join("nested/moved");
+    let mut db = SavedDatabase::create("db".to_string(), original.clone()).unwrap();
+    let original_bytes = std::fs::read(&original).unwrap();
+
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.save_as(copy.clone(), false, false).unwrap();
+    assert_eq!(db.path(), original);
+    assert_eq!(SavedDatabase::load_from_disk(copy.clone()).unwrap().get_table_names(), vec!["t".to_string()]);
+
+    db.remove_table("t".to_string()).unwrap();
+    assert!(matches!(db.save_as(copy.clone(), false, false), Err(DbError::FileExists(_))));
+    assert_eq!(SavedDatabase::load_from_disk(copy.clone()).unwrap().table_count(), 1);
+    db.save_as(copy.clone(), false, true).unwrap();
+    assert_eq!(SavedDatabase::load_from_disk(copy).unwrap().table_count(), 0);
+
+    db.create_table("u".to_string(), vec![DbType::Int]).unwrap();
+    db.save_as(moved.clone(), true, false).unwrap();
+    assert_eq!(db.path(), moved);
+    db.create_table("v".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+    drop(db);
+    assert_eq!(SavedDatabase::load_from_disk(moved).unwrap().table_count(), 2);
+    assert_eq!(std::fs::read(&original).unwrap(), original_bytes);
+}
+
+#[test]
+fn diff_database_files() {
+    use crate::diff::{diff_files, ColumnDiff, SchemaDiff, TableDiff};
+
+    let dir = tempdir().unwrap();
+    let path_a = dir.path().join("a");
+    let path_b = dir.path().join("b");
+    let mut a = SavedDatabase::create("db".to_string(), path_a.clone()).unwrap();
+    a.create_table("only_a".to_string(), vec![DbType::Int]).unwrap();
+    a.create_table("schema".to_string(), vec![DbType::Int, DbType::String]).unwrap();
+    a.create_table("rows".to_string(), vec![DbType::Int]).unwrap();
+    a.create_table("same".to_string(), vec![DbType::Char]).unwrap();
+    for value in [1, 2, 2, 3] {
+        a.insert_row("rows".to_string(), Row(vec![DbValue::Int(value)])).unwrap();
+    }
+    a.insert_row("same".to_string(), Row(vec![DbValue::Char('s')])).unwrap();
+    a.save().unwrap();
+
+    let mut b = a.clone();
+    b.save_as(path_b.clone(), true, false).unwrap();
+    assert!(diff_files(&path_a, &path_b).unwrap().is_empty());
+
+    b.remove_table("only_a".to_string()).unwrap();
+    b.create_table("only_b".to_string(), vec![DbType::Time]).unwrap();
+    b.remove_table("schema".to_string()).unwrap();
+    b.create_table("schema".to_string(), vec![DbType::Real, DbType::String, DbType::Int]).unwrap();
+    b.remove_row("rows".to_string(), 1).unwrap();
+    b.insert_row("rows".to_string(), Row(vec![DbValue::Int(4)])).unwrap();
+    b.insert_row("rows".to_string(), Row(vec![DbValue::Int(5)])).unwrap();
+    b.save().unwrap();
+
+    let diff = diff_files(&path_a, &path_b).unwrap();
+    assert_eq!(diff.only_in_a, vec!["only_a".to_string()]);
+    assert_eq!(diff.only_in_b, vec!["only_b".to_string()]);
+    assert_eq!(diff.schema_changes, vec![SchemaDiff {
+        table: "schema".to_string(),
+        columns: vec![
+            ColumnDiff { column: 0, a: Some(DbType::Int), b: Some(DbType::Real) },
+            ColumnDiff { column: 2, a: None, b: Some(DbType::Int) },
+        ],
+    }]);
+    assert_eq!(diff.row_changes, vec![TableDiff { table: "rows".to_string(), added: 2, removed: 1 }]);
+    assert_eq!(a.diff_against(&path_b).unwrap(), diff);
+}
+
+fn table_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "table"))
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn dir_layout_round_trip() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), &path).unwrap();
+    assert!(path.is_dir());
+    db.create_table("a".to_string(), vec![DbType::Int]).unwrap();
+    db.create_table("b/c".to_string(), vec![DbType::String]).unwrap();
+    db.insert_row("a".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    db.insert_row("b/c".to_string(), Row(vec![DbValue::String("x".to_string())])).unwrap();
+    db.create_materialized_projection("a".to_string(), vec![true], "m".to_string()).unwrap();
+    db.save().unwrap();
+    assert_eq!(table_files(&path).len(), 3);
+
+    let expected = db.snapshot().unwrap();
+    drop(db);
+    let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.snapshot().unwrap(), expected);
+    assert!(db.table_info("m".to_string()).unwrap().materialized.is_some());
+
+    db.remove_table("b/c".to_string()).unwrap();
+    db.save().unwrap();
+    assert_eq!(table_files(&path).len(), 2);
+    let expected = db.snapshot().unwrap();
+    drop(db);
+    assert_eq!(SavedDatabase::load_from_disk(&path).unwrap().snapshot().unwrap(), expected);
+}
+
+#[test]
+fn save_skips_unchanged_content() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    let mut events = db.subscribe();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    let summary = db.save().unwrap();
+    assert!(summary.wrote);
+    assert_eq!(summary.written, ["t"]);
+
+    // A file removed behind the database's back shows whether a save wrote.
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(db.save().unwrap(), SaveSummary::default());
+    assert!(!path.exists());
+    // Changes that leave the content as it was don't cause a write either.
+    db.set_format(Format::Bincode);
+    assert!(db.is_dirty());
+    assert!(!db.save().unwrap().wrote);
+    assert!(!path.exists());
+
+    let mut saved = 0;
+    while let Ok(event) = events.try_recv() {
+        saved += matches!(event, ChangeEvent::Saved { .. }) as usize;
+    }
+    assert_eq!(saved, 1);
+    assert!(db.save_full().unwrap().wrote);
+    assert!(path.exists());
+    db.set_format(Format::Json);
+    assert!(db.save().unwrap().wrote);
+}
+
+#[test]
+fn dir_layout_partial_save() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), &path).unwrap();
+    db.create_table("a".to_string(), vec![DbType::Int]).unwrap();
+    db.create_table("b".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+
+    // Files of clean tables are left alone, so a marker written into one survives the save.
+    let files = table_files(&path);
+    let untouched = files.iter().find(|file| file.file_name().unwrap() == "62.table").unwrap();
+    std::fs::write(untouched, b"marker").unwrap();
+    db.insert_row("a".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    assert_eq!(db.save().unwrap().written, ["a"]);
+    assert_eq!(std::fs::read(untouched).unwrap(), b"marker");
+    assert!(db.save().unwrap().written.is_empty());
+    db.update_row("a".to_string(), 0, Row(vec![DbValue::Int(2)])).unwrap();
+    assert_eq!(db.save().unwrap().written, ["a"]);
+    assert_eq!(std::fs::read(untouched).unwrap(), b"marker");
+
+    assert_eq!(db.save_full().unwrap().written, ["a", "b"]);
+    assert_ne!(std::fs::read(untouched).unwrap(), b"marker");
+    std::fs::write(untouched, b"marker").unwrap();
+    db.remove_row("a".to_string(), 0).unwrap();
+    assert_eq!(db.save().unwrap().written, ["a"]);
+    assert_eq!(std::fs::read(untouched).unwrap(), b"marker");
+
+    std::fs::remove_file(untouched).unwrap();
+    drop(db);
+    assert!(matches!(
+        SavedDatabase::load_from_disk(&path),
+        Err(DbError::MissingTableFile { table, .. }) if table == "b"
+    ));
+}
+
+fn every_type_db(path: PathBuf, format: Format) -> SavedDatabase {
+    let mut db = SavedDatabase::create_with("db".to_string(), &path, format).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::Real, DbType::Char, DbType::String, DbType::Time]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![
+        DbValue::Int(-7),
+        DbValue::Real(0.1),
+        DbValue::Char('ж'),
+        DbValue::String("a \"quoted\" string".to_string()),
+        DbValue::Time(Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset()),
+    ])).unwrap();
+    db.save().unwrap();
+    db
+}
+
+#[test]
+fn projection_computed() {
+    let dir = tempdir().unwrap();
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::Int, DbType::Real, DbType::String, DbType::UInt]).unwrap();
+    for (a, b, c, s, u) in [(1, 2, 0.5, "x", 3), (-4, 10, 1.5, "y", 0)] {
+        let row = vec![DbValue::Int(a), DbValue::Int(b), DbValue::Real(c), DbValue::String(s.to_string()), DbValue::UInt(u)];
+        db.insert_row("t".to_string(), Row(row)).unwrap();
+    }
+
+    let exprs = vec![
+        Expr::Column(0) + Expr::Column(1),
+        (Expr::Column(1) - Expr::Column(0)) * Expr::Column(2),
+        Expr::Column(3) + Expr::Literal(DbValue::String("!".to_string())),
+        Expr::Column(4),
+    ];
+    db.projection_computed("t".to_string(), exprs, "computed".to_string()).unwrap();
+    let computed = db.get_table("computed".to_string()).unwrap();
+    assert_eq!(computed.schema(), [DbType::Int, DbType::Real, DbType::String, DbType::UInt]);
+    assert_eq!(computed.rows(), [
+        Row(vec![DbValue::Int(3), DbValue::Real(0.5), DbValue::String("x!".to_string()), DbValue::UInt(3)]),
+        Row(vec![DbValue::Int(6), DbValue::Real(21.0), DbValue::String("y!".to_string()), DbValue::UInt(0)]),
+    ]);
+
+    let invalid = [
+        (Expr::Column(3) * Expr::Column(3), "Invalid expression: cannot multiply String and String"),
+        (Expr::Column(0) + Expr::Column(4), "Invalid expression: cannot add Int and UInt"),
+        (Expr::Column(5), "Column 5 is out of range"),
+        (Expr::Column(4) - Expr::Literal(DbValue::UInt(1)), "Expression overflows for row 1"),
+    ];
+    for (expr, message) in invalid {
+        let error = db.projection_computed("t".to_string(), vec![expr], "bad".to_string()).unwrap_err();
+        assert_eq!(error.to_string(), message);
+    }
+    assert!(db.get_table("bad".to_string()).is_err());
+}
+
+#[test]
+fn export_import_table() {
+    let dir = tempdir().unwrap();
+    let db = every_type_db(dir.path().join("db"), Format::Bincode);
+    let file = dir.path().join("t.table");
+    db.export_table("t", &file).unwrap();
+    assert!(matches!(db.export_table("missing", &file), Err(DbError::TableIsMissing(_))));
+
+    let mut other = SavedDatabase::create("other".to_string(), dir.path().join("other")).unwrap();
+    assert_eq!(other.import_table(&file, None).unwrap(), "t");
+    let (imported, original) = (other.get_table("t".to_string()).unwrap(), db.get_table("t".to_string()).unwrap());
+    assert_eq!(imported.schema(), original.schema());
+    assert_eq!(imported.rows(), original.rows());
+
+    assert!(matches!(other.import_table(&file, None), Err(DbError::TableIsAlreadyPresent(name)) if name == "t"));
+    assert_eq!(other.import_table(&file, Some("copy".to_string())).unwrap(), "copy");
+    assert_eq!(other.get_table("copy".to_string()).unwrap().rows(), original.rows());
+    other.save().unwrap();
+    drop(other);
+    let mut other = SavedDatabase::load_from_disk(dir.path().join("other")).unwrap();
+    let mut names = other.get_table_names();
+    names.sort();
+    assert_eq!(names, ["copy", "t"]);
+
+    // A row whose value doesn't match the schema is rejected even though it deserializes.
+    let db = every_type_db(dir.path().join("json"), Format::Json);
+    db.export_table("t", &file).unwrap();
+    let text = std::fs::read_to_string(&file).unwrap();
+    assert!(text.contains("\"Int\": -7"));
+    std::fs::write(&file, text.replace("\"Int\": -7", "\"UInt\": 7")).unwrap();
+    assert!(matches!(other.import_table(&file, Some("bad".to_string())), Err(DbError::InvalidTableState(_))));
+    assert!(other.get_table("bad".to_string()).is_err());
+}
+
+#[test]
+fn format_round_trip() {
+    let dir = tempdir().unwrap();
+    for format in [Format::Bincode, Format::Json, Format::MessagePack] {
+        let path = dir.path().join(format!("{format:?}"));
+        let expected = every_type_db(path.clone(), format).snapshot().unwrap();
+
+        let loaded = SavedDatabase::load_from_disk(path.clone()).unwrap();
+        assert_eq!(loaded.format(), format);
+        assert_eq!(loaded.snapshot().unwrap(), expected);
+    }
+
+    let json = std::fs::read(dir.path().join("Json")).unwrap();
+    assert!(String::from_utf8_lossy(&json).contains("a \\\"quoted\\\" string"));
+}
+
+#[test]
+fn set_format_and_legacy_files() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = every_type_db(path.clone(), Format::Bincode);
+    db.set_format(Format::Json);
+    db.save().unwrap();
+    drop(db);
+    let mut db = SavedDatabase::load_from_disk(path.clone()).unwrap();
+    assert_eq!(db.format(), Format::Json);
+
+    // Files written before the header existed are plain bincode of the structures back then.
+    let legacy = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v0-bincode.db")).unwrap();
+    let loaded = SavedDatabase::load_from_bytes(&legacy, String::new()).unwrap();
+    assert_eq!(loaded.format(), Format::Bincode);
+    assert_eq!(loaded.get_table("every_type".to_string()).unwrap().rows(), [Row(vec![
+        DbValue::Int(-7),
+        DbValue::Real(2.5),
+        DbValue::Char('x'),
+        DbValue::String("text".to_string()),
+        DbValue::Time(Utc.with_ymd_and_hms(2023, 11, 2, 11, 0, 0).unwrap().fixed_offset()),
+    ])]);
+
+    db.save_in(Format::MessagePack).unwrap();
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[6] = 9;
+    assert!(matches!(SavedDatabase::load_from_bytes(&bytes, path), Err(DbError::UnknownFormat(9))));
+}
+
+#[test]
+fn search() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("people".to_string(), vec![DbType::Int, DbType::String]).unwrap();
+    db.create_table("pets".to_string(), vec![DbType::String, DbType::Int, DbType::Time]).unwrap();
+    db.insert_row("people".to_string(), Row(vec![DbValue::Int(1), DbValue::String("Ann".to_string())])).unwrap();
+    db.insert_row("people".to_string(), Row(vec![DbValue::Int(2), DbValue::String("Joanna".to_string())])).unwrap();
+    let born = Utc.with_ymd_and_hms(2016, 7, 8, 9, 10, 11).unwrap().fixed_offset();
+    db.insert_row("pets".to_string(), Row(vec![DbValue::String("ann".to_string()), DbValue::Int(2), DbValue::Time(born)])).unwrap();
+
+    let hit = |table: &str, row, column| SearchHit { table: table.to_string(), row, column };
+    assert_eq!(db.search(&DbValue::Int(2), false).unwrap(), vec![hit("people", 1, 0), hit("pets", 0, 1)]);
+    assert_eq!(db.search(&DbValue::String("Ann".to_string()), false).unwrap(), vec![hit("people", 0, 1)]);
+    assert_eq!(
+        db.search(&DbValue::String("ANN".to_string()), true).unwrap(),
+        vec![hit("people", 0, 1), hit("people", 1, 1), hit("pets", 0, 0)]
+    );
+    // Contains mode only changes how String cells match.
+    assert_eq!(db.search(&DbValue::Int(2), true).unwrap(), db.search(&DbValue::Int(2), false).unwrap());
+
+    assert_eq!(db.search(&DbValue::Time(born), true).unwrap(), vec![hit("pets", 0, 2)]);
+    assert!(db.search(&DbValue::String(born.to_rfc3339()), true).unwrap().is_empty());
+}
+
+#[test]
+fn change_events() {
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    let mut events = db.subscribe();
+
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    assert!(db.insert_row("t".to_string(), Row(vec![DbValue::Char('x')])).is_err());
+    db.update_row("t".to_string(), 0, Row(vec![DbValue::Int(2)])).unwrap();
+    db.remove_row("t".to_string(), 0).unwrap();
+    db.save().unwrap();
+    db.reload().unwrap();
+    db.remove_table("t".to_string()).unwrap();
+
+    let mut received = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        received.push(event);
+    }
+    let received: Vec<String> = received
+        .iter()
+        .map(|event| match event {
+            ChangeEvent::Mutation(op) => format!("{op:?}").split_whitespace().next().unwrap().to_string(),
+            ChangeEvent::Saved { path } => format!("Saved {}", path.display()),
+            other => format!("{other:?}"),
+        })
+        .collect();
+    assert_eq!(received, vec![
+        "CreateTable".to_string(),
+        "InsertRow".to_string(),
+        "UpdateRow".to_string(),
+        "RemoveRow".to_string(),
+        format!("Saved {}", path.display()),
+        "Reloaded".to_string(),
+        "RemoveTable".to_string(),
+    ]);
+
+    // A subscriber that falls behind loses the oldest events without blocking mutations.
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    for value in 0..CHANGE_CAPACITY as i64 + 10 {
+        db.insert_row("t".to_string(), Row(vec![DbValue::Int(value)])).unwrap();
+    }
+    assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Lagged(11));
+    assert!(events.try_recv().is_ok());
+
+    // Clones don't share subscribers.
+    let mut clone = db.clone();
+    clone.remove_table("t".to_string()).unwrap();
+    let mut remaining = 0;
+    while events.try_recv().is_ok() {
+        remaining += 1;
+    }
+    assert_eq!(remaining, CHANGE_CAPACITY - 1);
+}
+
+#[test]
+fn shared_database_concurrent_readers() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SharedDatabase>();
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    let shared = SharedDatabase::new(db);
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                let mut seen = 0;
+                while seen < 100 {
+                    let rows = shared.get_rows("t".to_string()).unwrap();
+                    // Readers never observe a partially applied insert or rows going away.
+                    assert!(rows.len() >= seen);
+                    assert!(rows.iter().enumerate().all(|(i, row)| row.0 == vec![DbValue::Int(i as i64)]));
+                    seen = rows.len();
+                }
+            })
+        })
+        .collect();
+    for value in 0..100 {
+        shared.insert_row("t".to_string(), Row(vec![DbValue::Int(value)])).unwrap();
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    assert_eq!(shared.table_info("t".to_string()).unwrap().row_count, 100);
+    assert_eq!(shared.read(|db| db.get_table("t".to_string()).unwrap().version()), 100);
+    assert!(shared.write(|db| db.remove_table("t".to_string())).is_ok());
+}
+
+#[test]
+fn savepoint_restore() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), path.clone()).unwrap();
+    for name in ["a", "b", "c", "d"] {
+        db.create_table(name.to_string(), vec![DbType::Int]).unwrap();
+        db.insert_row(name.to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    }
+    db.save().unwrap();
+    let names = db.get_table_names();
+    let snapshot = db.snapshot().unwrap();
+    let savepoint = db.savepoint().unwrap();
+
+    db.remove_table("b".to_string()).unwrap();
+    db.insert_row("a".to_string(), Row(vec![DbValue::Int(2)])).unwrap();
+    db.create_table("e".to_string(), vec![DbType::String]).unwrap();
+    db.restore(savepoint.clone()).unwrap();
+    assert_eq!(db.get_table_names(), names);
+    assert_eq!(db.snapshot().unwrap(), snapshot);
+
+    // Restored tables are written by the next save although they were saved before.
+    db.insert_row("a".to_string(), Row(vec![DbValue::Int(3)])).unwrap();
+    db.save().unwrap();
+    db.restore(savepoint).unwrap();
+    db.save().unwrap();
+    drop(db);
+    assert_eq!(SavedDatabase::load_from_disk(&path).unwrap().snapshot().unwrap(), snapshot);
+}
+
+#[test]
+fn failed_save_keeps_original() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+    let saved = std::fs::read(&path).unwrap();
+
+    // The temporary file can't be created, so the save fails before touching the database file.
+    std::fs::create_dir(dir.path().join("db.tmp")).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    assert!(matches!(db.save(), Err(DbError::Io(_))));
+    assert_eq!(std::fs::read(&path).unwrap(), saved);
+
+    // A parent that is a file is reported instead of panicking.
+    let below_file = path.join("copy");
+    assert!(matches!(db.save_as(below_file, true, false), Err(DbError::Io(_))));
+    assert_eq!(db.path(), path);
+    assert_eq!(std::fs::read(&path).unwrap(), saved);
+
+    std::fs::remove_dir(dir.path().join("db.tmp")).unwrap();
+    db.save().unwrap();
+    assert!(!dir.path().join("db.tmp").exists());
+    let expected = db.snapshot().unwrap();
+    drop(db);
+    assert_eq!(SavedDatabase::load_from_disk(&path).unwrap().snapshot().unwrap(), expected);
+}
+
+#[test]
+fn database_manager() {
+    let dir = tempdir().unwrap();
+    let manager = DatabaseManager::new(dir.path());
+    assert!(manager.list().unwrap().is_empty());
+
+    let mut a = SavedDatabase::create("a".to_string(), dir.path().join("a.db")).unwrap();
+    a.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    a.save().unwrap();
+    drop(a);
+    manager.create("b").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "not a database").unwrap();
+    assert_eq!(manager.list().unwrap(), ["a", "b"]);
+
+    let a = manager.open("a").unwrap();
+    assert_eq!(a.get_table_names(), ["t"]);
+    assert!(matches!(manager.open("a"), Err(DbError::DatabaseLocked { .. })));
+    assert!(manager.open("b").unwrap().get_table_names().is_empty());
+    assert!(matches!(manager.create("a"), Err(DbError::FileExists(_))));
+    assert!(manager.open("missing").is_err());
+    for name in ["", "..", "../a", "x/y"] {
+        assert!(matches!(manager.open(name), Err(DbError::InvalidDatabaseName(_))), "{name}");
+    }
+}
+
+#[test]
+fn aggregates() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int, DbType::Real, DbType::String, DbType::UInt]);
+    assert_eq!(table.aggregate(0, Aggregate::Sum).unwrap(), Some(DbValue::Int(0)));
+    assert_eq!(table.aggregate(1, Aggregate::Variance).unwrap(), None);
+    for value in [2, 4, 4, 4, 5, 5, 7, 9] {
+        let row = vec![
+            DbValue::Int(value),
+            DbValue::Real(1e9 + value as f64),
+            DbValue::String(String::new()),
+            DbValue::UInt(u64::MAX / 4),
+        ];
+        table.insert_row(Row(row)).unwrap();
+    }
+
+    assert_eq!(table.aggregate(0, Aggregate::Sum).unwrap(), Some(DbValue::Int(40)));
+    assert_eq!(table.aggregate(0, Aggregate::Avg).unwrap(), Some(DbValue::Real(5.0)));
+    assert_eq!(table.aggregate(0, Aggregate::Variance).unwrap(), Some(DbValue::Real(4.0)));
+    assert_eq!(table.aggregate(0, Aggregate::StdDev).unwrap(), Some(DbValue::Real(2.0)));
+    // The offset would swamp the spread in a naive sum of squares.
+    let Some(DbValue::Real(variance)) = table.aggregate(1, Aggregate::Variance).unwrap() else {
+        panic!("expected a real");
+    };
+    assert!((variance - 4.0).abs() < 1e-6, "{variance}");
+
+    assert!(matches!(table.aggregate(3, Aggregate::Sum), Err(DbError::SumOverflow(3))));
+    assert!(matches!(table.aggregate(2, Aggregate::Avg), Err(DbError::ColumnTypeMismatch { column: 2, .. })));
+    assert!(matches!(table.aggregate(4, Aggregate::Avg), Err(DbError::ColumnOutOfRange(4))));
+
+    let mut single = Table::new("single".to_string(), vec![DbType::Real]);
+    single.insert_row(Row(vec![DbValue::Real(3.5)])).unwrap();
+    assert_eq!(single.aggregate(0, Aggregate::Variance).unwrap(), Some(DbValue::Real(0.0)));
+}
+
+#[test]
+fn row_ids() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    let table = db.get_table_mut("t".to_string()).unwrap();
+    for value in 0..3 {
+        table.insert_row(Row(vec![DbValue::Int(value)])).unwrap();
+    }
+    assert_eq!(table.row_ids(), [0, 1, 2]);
+
+    table.remove_by_id(1);
+    assert_eq!(table.row_ids(), [0, 2]);
+    assert_eq!(table.row_by_id(0), Some(&Row(vec![DbValue::Int(0)])));
+    assert_eq!(table.row_by_id(1), None);
+    assert_eq!(table.row_by_id(2), Some(&Row(vec![DbValue::Int(2)])));
+
+    table.update_by_id(2, Row(vec![DbValue::Int(20)])).unwrap();
+    assert_eq!(table.row_at(1), Some(&Row(vec![DbValue::Int(20)])));
+    assert!(matches!(table.update_by_id(1, Row(vec![DbValue::Int(1)])), Err(DbError::RowIdNotFound(1))));
+    table.remove_by_id(1);
+    assert_eq!(table.rows().len(), 2);
+
+    table.insert_row(Row(vec![DbValue::Int(3)])).unwrap();
+    assert_eq!(table.row_ids(), [0, 2, 3]);
+    db.save().unwrap();
+    drop(db);
+    let loaded = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(loaded.get_table("t".to_string()).unwrap().row_ids(), [0, 2, 3]);
+}
+
+#[test]
+fn row_created_at() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+
+    let before = Utc::now();
+    for value in 0..3 {
+        db.insert_row("t".to_string(), Row(vec![DbValue::Int(value)])).unwrap();
+    }
+    let after = Utc::now();
+    let table = db.get_table("t".to_string()).unwrap();
+    let times: Vec<_> = (0..3).map(|idx| table.row_created_at(idx).unwrap()).collect();
+    assert!(times.iter().all(|time| (before..=after).contains(time)));
+    assert_eq!(table.row_created_at(3), None);
+
+    db.update_row("t".to_string(), 0, Row(vec![DbValue::Int(10)])).unwrap();
+    db.remove_row("t".to_string(), 1).unwrap();
+    db.save().unwrap();
+    drop(db);
+
+    let loaded = SavedDatabase::load_from_disk(&path).unwrap();
+    let table = loaded.get_table("t".to_string()).unwrap();
+    assert_eq!(table.row_created_at(0), Some(times[0]));
+    assert_eq!(table.row_created_at(1), Some(times[2]));
+    assert_eq!(table.row_created_at(2), None);
+}
+
+#[test]
+fn save_path_is_a_directory() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    std::fs::create_dir(&path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    let error = db.save().unwrap_err();
+    assert!(matches!(&error, DbError::SavePathIsADirectory(p) if *p == path.display().to_string()));
+    assert_eq!(error.to_string(), format!("Save path {} is a directory, expected a file", path.display()));
+    assert!(matches!(db.save_as(dir.path(), false, true), Err(DbError::SavePathIsADirectory(_))));
+    assert!(!layout::with_suffix(&path, ".tmp").exists());
+
+    drop(db);
+    let error = SavedDatabase::load_from_disk(&path).unwrap_err();
+    assert_eq!(error.to_string(), format!("{} is a directory but not a database stored as one", path.display()));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn open_mmap() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), &path).unwrap();
+    for name in ["a", "b", "c"] {
+        db.create_table(name.to_string(), vec![DbType::String]).unwrap();
+        db.insert_row(name.to_string(), Row(vec![DbValue::String(name.to_string())])).unwrap();
+    }
+    db.save().unwrap();
+    let snapshot = db.snapshot().unwrap();
+    drop(db);
+
+    let mut mapped = SavedDatabase::open_mmap(&path).unwrap();
+    assert!(matches!(SavedDatabase::load_from_disk(&path), Err(DbError::DatabaseLocked { .. })));
+    assert_eq!(mapped.table_count(), 3);
+    assert_eq!(mapped.decoded_tables(), 0);
+    let rows = mapped.get_table("b".to_string()).unwrap().rows().to_vec();
+    assert_eq!(rows, [Row(vec![DbValue::String("b".to_string())])]);
+    mapped.get_table("b".to_string()).unwrap();
+    assert_eq!(mapped.decoded_tables(), 1);
+    assert!(matches!(mapped.get_table("missing".to_string()), Err(DbError::TableIsMissing(_))));
+
+    let row = Row(vec![DbValue::String("x".to_string())]);
+    assert!(matches!(mapped.insert_row("a".to_string(), row.clone()), Err(DbError::MemoryMapped)));
+    assert!(matches!(mapped.get_table_mut("a".to_string()), Err(DbError::MemoryMapped)));
+    assert!(matches!(mapped.remove_table("a".to_string()), Err(DbError::MemoryMapped)));
+    assert_eq!(mapped.decoded_tables(), 1);
+
+    let mut materialized = mapped.materialize().unwrap();
+    assert_eq!(materialized.snapshot().unwrap(), snapshot);
+    materialized.insert_row("a".to_string(), row.clone()).unwrap();
+    assert!(matches!(materialized.save(), Err(DbError::ReadOnly)));
+    drop(materialized);
+
+    let file = dir.path().join("file");
+    drop(every_type_db(file.clone(), Format::Bincode));
+    let mut mapped = SavedDatabase::open_mmap(&file).unwrap();
+    assert_eq!(mapped.get_table("t".to_string()).unwrap().rows().len(), 1);
+    assert!(matches!(mapped.create_table("u".to_string(), vec![]), Err(DbError::MemoryMapped)));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_sees_undecoded_tables() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), &path).unwrap();
+    for name in ["a", "b"] {
+        db.create_table(name.to_string(), vec![DbType::String]).unwrap();
+        db.insert_row(name.to_string(), Row(vec![DbValue::String(name.to_string())])).unwrap();
+    }
+    db.save().unwrap();
+    let snapshot = db.snapshot().unwrap();
+    let ddl = db.to_ddl().unwrap();
+    let mut json = Vec::new();
+    db.export_json(&mut json, false).unwrap();
+    drop(db);
+
+    let mut mapped = SavedDatabase::open_mmap(&path).unwrap();
+    let hits = mapped.search(&DbValue::String("b".to_string()), false).unwrap();
+    assert_eq!(hits, [SearchHit { table: "b".to_string(), row: 0, column: 0 }]);
+    assert_eq!(mapped.snapshot().unwrap(), snapshot);
+    assert_eq!(mapped.to_ddl().unwrap(), ddl);
+    let mut mapped_json = Vec::new();
+    mapped.export_json(&mut mapped_json, false).unwrap();
+    assert_eq!(mapped_json, json);
+
+    let backup = mapped.backup(Some(&dir.path().join("backups"))).unwrap();
+    let mut restored = SavedDatabase::create("db".to_string(), dir.path().join("restored")).unwrap();
+    restored.restore_backup(&backup).unwrap();
+    assert_eq!(restored.snapshot().unwrap(), snapshot);
+
+    let fresh = || SavedDatabase::open_mmap(&path).unwrap();
+    let stats = fresh().stats().unwrap();
+    assert_eq!((stats.table_count, stats.row_count), (2, 2));
+    assert_eq!(fresh().catalog().unwrap().rows().len(), 2);
+    assert!(fresh().check_integrity().unwrap().is_ok());
+    assert!(fresh().diff(&restored).unwrap().is_empty());
+    assert!(restored.diff(&fresh()).unwrap().is_empty());
+    let savepoint = fresh().savepoint().unwrap();
+    assert!(matches!(mapped.restore(savepoint.clone()), Err(DbError::MemoryMapped)));
+    assert!(matches!(mapped.restore_backup(&backup), Err(DbError::MemoryMapped)));
+    let mut restored = SavedDatabase::create("db".to_string(), dir.path().join("from savepoint")).unwrap();
+    restored.restore(savepoint).unwrap();
+    assert_eq!(restored.snapshot().unwrap(), snapshot);
+
+    // Saving over its own files would delete those of tables not decoded yet.
+    assert!(matches!(mapped.save_as(&path, false, true), Err(DbError::ReadOnly)));
+    let copy = dir.path().join("copy");
+    mapped.save_as(&copy, false, false).unwrap();
+    drop(mapped);
+    assert_eq!(SavedDatabase::load_from_disk(&copy).unwrap().snapshot().unwrap(), snapshot);
+    assert_eq!(SavedDatabase::load_from_disk(&path).unwrap().snapshot().unwrap(), snapshot);
+}
+
+#[test]
+fn load_errors() {
+    let dir = tempdir().unwrap();
+    let name = |path: &Path| path.display().to_string();
+
+    let missing = dir.path().join("missing");
+    assert!(matches!(SavedDatabase::load_from_disk(&missing), Err(DbError::FileNotFound(p)) if p == name(&missing)));
+    assert!(!missing.with_extension("lock").exists());
+    assert!(matches!(SavedDatabase::load_from_disk(dir.path()), Err(DbError::IsADirectory(p)) if p == name(dir.path())));
+
+    let empty = dir.path().join("empty");
+    std::fs::write(&empty, "").unwrap();
+    assert!(matches!(SavedDatabase::load_from_disk(&empty), Err(DbError::EmptyFile(p)) if p == name(&empty)));
+
+    let text = dir.path().join("text");
+    std::fs::write(&text, "hello\n").unwrap();
+    let error = SavedDatabase::load_from_disk(&text).unwrap_err();
+    assert_eq!(error.to_string(), format!("{} is not a database file", name(&text)));
+
+    // A payload cut short behind a valid checksum fails where the decoder ran out of data.
+    let path = dir.path().join("db");
+    drop(every_type_db(path.clone(), Format::Bincode));
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes.truncate(bytes.len() - 3);
+    let crc = crc32fast::hash(&bytes[12..]);
+    bytes[8..12].copy_from_slice(&crc.to_le_bytes());
+    std::fs::write(&path, &bytes).unwrap();
+    let error = SavedDatabase::load_from_disk(&path).unwrap_err();
+    let DbError::CorruptData { offset: Some(offset), .. } = error else {
+        panic!("unexpected error {error:?}");
+    };
+    // The value that is cut off starts within the last bytes.
+    assert!((bytes.len() as u64 - 8..bytes.len() as u64).contains(&offset), "{offset}");
+    assert_eq!(error.to_string(), format!("Corrupt data in {} at byte {offset}", name(&path)));
+
+    let json = dir.path().join("json");
+    std::fs::write(&json, "{\n  \"format_version\": 2,\n  \"data\": [}").unwrap();
+    assert!(matches!(SavedDatabase::load_from_disk(&json), Err(DbError::CorruptData { offset: Some(36), .. })));
+}
+
+#[test]
+fn hostile_length_prefixes() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let with_header = |format: u8, payload: &[u8]| {
+        let mut bytes = b"ITDB".to_vec();
+        bytes.extend(2u16.to_le_bytes());
+        bytes.extend([format, 0]);
+        bytes.extend(crc32fast::hash(payload).to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    };
+    let huge = 1u64 << 60;
+    let name = |rest: &[u8]| [&2u64.to_le_bytes()[..], b"db", rest].concat();
+    let payloads = [
+        // The database name, the table count and the row count of a table.
+        with_header(0, &huge.to_le_bytes()),
+        with_header(0, &name(&huge.to_le_bytes())),
+        with_header(0, &name(&[&1u64.to_le_bytes()[..], &1u64.to_le_bytes(), b"t", &1u64.to_le_bytes(), b"t", &huge.to_le_bytes()].concat())),
+        // Legacy bincode without a header.
+        [&huge.to_le_bytes()[..], b"db"].concat(),
+        // MessagePack arrays and strings with 32-bit lengths.
+        with_header(2, &[0xdd, 0xff, 0xff, 0xff, 0xff]),
+        with_header(2, &[0x93, 0xdb, 0xff, 0xff, 0xff, 0xff, b'd']),
+    ];
+    for payload in payloads {
+        std::fs::write(&path, &payload).unwrap();
+        let error = SavedDatabase::load_from_disk(&path).unwrap_err();
+        assert!(matches!(error, DbError::CorruptData { .. } | DbError::NotADatabaseFile(_)), "{error:?}");
+    }
+
+    // A write-ahead log record declaring a huge row is skipped like any corrupt record.
+    let db = every_type_db(path.clone(), Format::Bincode);
+    drop(db);
+    let mut op = bincode::serialize(&TxOp::InsertRow { table: "t".to_string(), row: Row(vec![]) }).unwrap();
+    let row_len_at = op.len() - 8;
+    op[row_len_at..].copy_from_slice(&huge.to_le_bytes());
+    let mut record = (op.len() as u32).to_le_bytes().to_vec();
+    record.extend(crc32fast::hash(&op).to_le_bytes());
+    record.extend(&op);
+    std::fs::write(path.with_extension("wal"), record).unwrap();
+    let loaded = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(loaded.load_report().replayed, 0);
+    assert_eq!(loaded.load_report().corrupt_wal_record, Some(0));
+}
+
+#[test]
+fn file_header() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    drop(every_type_db(path.clone(), Format::Bincode));
+    let bytes = std::fs::read(&path).unwrap();
+    assert_eq!(&bytes[..4], b"ITDB");
+    assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), crate::format::FORMAT_VERSION);
+
+    let legacy = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v0-empty.db")).unwrap();
+    let empty = DatabaseSnapshot { name: "empty".to_string(), tables: Vec::new() };
+    assert_eq!(SavedDatabase::load_from_bytes(&legacy, String::new()).unwrap().snapshot().unwrap(), empty);
+
+    let garbage = dir.path().join("garbage");
+    std::fs::write(&garbage, "just some text that is not a database").unwrap();
+    assert!(matches!(SavedDatabase::load_from_disk(&garbage), Err(DbError::NotADatabaseFile(p)) if p == garbage.display().to_string()));
+    assert!(matches!(SavedDatabase::load_from_bytes(b"ITDB\x01", String::new()), Err(DbError::NotADatabaseFile(_))));
+
+    let mut future = bytes.clone();
+    future[4..6].copy_from_slice(&(crate::format::FORMAT_VERSION + 1).to_le_bytes());
+    assert!(matches!(
+        SavedDatabase::load_from_bytes(&future, path),
+        Err(DbError::UnsupportedVersion { found, supported })
+            if found == u32::from(crate::format::FORMAT_VERSION) + 1 && supported == u32::from(crate::format::FORMAT_VERSION)
+    ));
+
+    // The flags record the integer encoding, which has to be the one this build uses.
+    assert_eq!(bytes[7], 0);
+    let mut foreign = bytes.clone();
+    foreign[7] = 4;
+    let error = SavedDatabase::load_from_bytes(&foreign, String::new()).unwrap_err();
+    assert!(matches!(error, DbError::ForeignEncoding { big_endian: true, varint: false }));
+    assert_eq!(error.to_string(), "Payload encodes integers big-endian and fixed-width, only little-endian fixed-width ones are supported");
+    foreign[7] = 8;
+    let foreign_path = dir.path().join("foreign");
+    std::fs::write(&foreign_path, &foreign).unwrap();
+    assert!(matches!(SavedDatabase::load_from_disk(&foreign_path), Err(DbError::ForeignEncoding { big_endian: false, varint: true })));
+    assert!(matches!(SavedDatabase::verify_file(&foreign_path), Err(DbError::ForeignEncoding { .. })));
+}
+
+#[test]
+fn checksums() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    drop(every_type_db(path.clone(), Format::Bincode));
+    SavedDatabase::verify_file(&path).unwrap();
+    assert!(!SavedDatabase::load_from_disk(path.clone()).unwrap().load_report().missing_checksum);
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(matches!(SavedDatabase::verify_file(&path), Err(DbError::ChecksumMismatch { .. })));
+    match SavedDatabase::load_from_disk(&path) {
+        Err(DbError::ChecksumMismatch { expected, actual }) => {
+            assert_eq!(expected, u32::from_le_bytes(bytes[8..12].try_into().unwrap()));
+            assert_eq!(actual, crc32fast::hash(&bytes[12..]));
+        }
+        other => panic!("expected a checksum mismatch, got {other:?}"),
+    }
+
+    // A version 1 header has no checksum.
+    let legacy = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v1-bincode.db")).unwrap();
+    let loaded = SavedDatabase::load_from_bytes(&legacy, String::new()).unwrap();
+    assert!(loaded.load_report().missing_checksum);
+    assert_eq!(loaded.get_name(), "legacy");
+    let headerless = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v0-bincode.db")).unwrap();
+    assert!(SavedDatabase::load_from_bytes(&headerless, String::new()).unwrap().load_report().missing_checksum);
+
+    let dir_path = dir.path().join("dir");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), dir_path.clone()).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    db.save().unwrap();
+    SavedDatabase::verify_file(&dir_path).unwrap();
+    let table_file = table_files(std::path::Path::new(&dir_path)).pop().unwrap();
+    let mut bytes = std::fs::read(&table_file).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    std::fs::write(&table_file, bytes).unwrap();
+    assert!(matches!(SavedDatabase::verify_file(&dir_path), Err(DbError::ChecksumMismatch { .. })));
+    drop(db);
+    assert!(matches!(SavedDatabase::load_from_disk(dir_path), Err(DbError::ChecksumMismatch { .. })));
+}
+
+#[test]
+fn diff_in_memory() {
+    use crate::diff::{DatabaseDiff, TableDiff};
+
+    let dir = tempdir().unwrap();
+    let mut a = SavedDatabase::create("db".to_string(), dir.path().join("a")).unwrap();
+    a.create_table("t".to_string(), vec![DbType::Int, DbType::String]).unwrap();
+    for value in 0..3 {
+        a.insert_row("t".to_string(), Row(vec![DbValue::Int(value), DbValue::String("x".to_string())])).unwrap();
+    }
+    let mut b = a.clone();
+    assert!(a.diff(&b).unwrap().is_empty());
+
+    b.create_table("added".to_string(), vec![DbType::Real]).unwrap();
+    b.update_row("t".to_string(), 1, Row(vec![DbValue::Int(1), DbValue::String("y".to_string())])).unwrap();
+    let diff = a.diff(&b).unwrap();
+    assert_eq!(diff.only_in_a, Vec::<String>::new());
+    assert_eq!(diff.only_in_b, vec!["added".to_string()]);
+    assert!(diff.schema_changes.is_empty());
+    assert_eq!(diff.row_changes, vec![TableDiff { table: "t".to_string(), added: 1, removed: 1 }]);
+
+    let reverse = b.diff(&a).unwrap();
+    assert_eq!(reverse.only_in_a, vec!["added".to_string()]);
+    let bytes = bincode::serialize(&diff).unwrap();
+    assert_eq!(bincode::deserialize::<DatabaseDiff>(&bytes).unwrap(), diff);
+
+    // Rows compare the way they are saved.
+    let mut c = SavedDatabase::create("db".to_string(), dir.path().join("c")).unwrap();
+    c.create_table("r".to_string(), vec![DbType::Real, DbType::Time]).unwrap();
+    let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00+00:00").unwrap();
+    c.insert_row("r".to_string(), Row(vec![DbValue::Real(f64::NAN), DbValue::Time(time)])).unwrap();
+    let mut d = c.clone();
+    assert!(c.diff(&d).unwrap().is_empty());
+    let moved = time.with_timezone(&FixedOffset::east_opt(5 * 3600).unwrap());
+    d.update_row("r".to_string(), 0, Row(vec![DbValue::Real(f64::NAN), DbValue::Time(moved)])).unwrap();
+    assert_eq!(c.diff(&d).unwrap().row_changes, vec![TableDiff { table: "r".to_string(), added: 1, removed: 1 }]);
+}
+
+#[test]
+fn json_format_round_trip() {
+    let dir = tempdir().unwrap();
+    for format in [Format::Bincode, Format::Json] {
+        let path = dir.path().join(format!("{format:?}"));
+        let mut db = every_type_db(path.clone(), format);
+        let reals = [f64::INFINITY, f64::NEG_INFINITY, f64::NAN, -0.0, f64::MIN_POSITIVE, 0.1 + 0.2, f64::MAX];
+        db.create_table("reals".to_string(), vec![DbType::Real]).unwrap();
+        for x in reals {
+            db.insert_row("reals".to_string(), Row(vec![DbValue::Real(x)])).unwrap();
+        }
+        db.save().unwrap();
+        let rows = db.get_table("t".to_string()).unwrap().rows().to_vec();
+        drop(db);
+
+        let loaded = SavedDatabase::load_from_disk(path.clone()).unwrap();
+        assert_eq!(loaded.format(), format);
+        assert_eq!(loaded.get_table("t".to_string()).unwrap().rows(), rows);
+        let loaded_reals: Vec<u64> = loaded
+            .get_table("reals".to_string())
+            .unwrap()
+            .rows()
+            .iter()
+            .map(|row| match row.0[0] {
+                DbValue::Real(x) => x.to_bits(),
+                _ => panic!("not a real"),
+            })
+            .collect();
+        assert_eq!(loaded_reals, reals.map(f64::to_bits));
+    }
+
+    // JSON files are plain JSON with readable times and named non-finite reals.
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.path().join("Json")).unwrap()).unwrap();
+    assert_eq!(json["format_version"], crate::format::FORMAT_VERSION);
+    let text = json.to_string();
+    assert!(text.contains("2016-07-08T09:10:11Z"));
+    assert!(text.contains(r#"{"Real":"-inf"}"#));
+    assert!(text.contains(r#"{"Real":"NaN"}"#));
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn compressed_files() {
+    let dir = tempdir().unwrap();
+    let plain_path = dir.path().join("plain");
+    let path = dir.path().join("db");
+    let mut db = every_type_db(plain_path.clone(), Format::Bincode);
+    for _ in 0..100 {
+        db.insert_row("t".to_string(), db.get_table("t".to_string()).unwrap().rows()[0].clone()).unwrap();
+    }
+    db.save().unwrap();
+    db.save_as(path.clone(), true, false).unwrap();
+    db.set_compression(Compression::Zstd);
+    db.save().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() < std::fs::metadata(&plain_path).unwrap().len() / 4);
+
+    let expected = db.snapshot().unwrap();
+    drop(db);
+    let loaded = SavedDatabase::load_from_disk(path.clone()).unwrap();
+    assert_eq!(loaded.compression(), Compression::Zstd);
+    assert_eq!(loaded.snapshot().unwrap(), expected);
+    assert_eq!(SavedDatabase::load_from_disk(plain_path).unwrap().compression(), Compression::None);
+
+    let options = StorageOptions { format: Format::Json, compression: Compression::Zstd };
+    let json_path = dir.path().join("json");
+    SavedDatabase::create_with("db".to_string(), json_path.clone(), options).unwrap();
+    assert_eq!(SavedDatabase::load_from_disk(json_path).unwrap().format(), Format::Json);
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    bytes.truncate(bytes.len() - 3);
+    let checksum = crc32fast::hash(&bytes[12..]);
+    bytes[8..12].copy_from_slice(&checksum.to_le_bytes());
+    assert!(matches!(SavedDatabase::load_from_bytes(&bytes, path), Err(DbError::CorruptPayload(_))));
+
+    // Decompression stops at the limit instead of inflating whatever the payload asks for.
+    let bomb = zstd::encode_all(vec![0; 1 << 16].as_slice(), 0).unwrap();
+    assert_eq!(Compression::Zstd.decompress_within(&bomb, 1 << 16).unwrap().len(), 1 << 16);
+    assert!(matches!(
+        Compression::Zstd.decompress_within(&bomb, (1 << 16) - 1),
+        Err(DbError::PayloadTooLarge { max }) if max == (1 << 16) - 1
+    ));
+}
+
+#[test]
+fn unsigned_integers() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.create_table("ids".to_string(), vec![DbType::UInt]).unwrap();
+    let big = i64::MAX as u64 + 1;
+    for id in [big, 7, u64::MAX] {
+        db.insert_row("ids".to_string(), Row(vec![DbValue::UInt(id)])).unwrap();
+    }
+    assert!(matches!(
+        db.insert_row("ids".to_string(), Row(vec![DbValue::Int(7)])),
+        Err(DbError::ColumnTypeMismatch { column: 0, expected: DbType::UInt, got: DbType::Int })
+    ));
+    db.save().unwrap();
+    drop(db);
+
+    let loaded = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(loaded.get_table("ids".to_string()).unwrap().rows()[0], Row(vec![DbValue::UInt(big)]));
+    let sorted = loaded
+        .query("ids")
+        .filter(Condition::compare(0, CompareOp::Gt, DbValue::UInt(7)))
+        .order_by(0, true)
+        .rows(&loaded)
+        .unwrap();
+    assert_eq!(sorted, vec![Row(vec![DbValue::UInt(u64::MAX)]), Row(vec![DbValue::UInt(big)])]);
+
+    assert_eq!(DbType::UInt.to_string().parse::<DbType>().unwrap(), DbType::UInt);
+    assert_eq!(DbValue::parse(DbType::UInt, "18446744073709551615").unwrap(), DbValue::UInt(u64::MAX));
+    assert!(DbValue::parse(DbType::UInt, "-1").is_err());
+    assert_eq!(DbValue::UInt(u64::MAX).to_string(), "18446744073709551615");
+
+    let mut db = loaded;
+    db.execute_sql("INSERT INTO ids VALUES (18446744073709551614)").unwrap();
+    assert_eq!(db.execute_sql("SELECT col0 FROM ids WHERE col0 = 18446744073709551614").unwrap(), QueryResult::Rows {
+        schema: vec![DbType::UInt],
+        rows: vec![Row(vec![DbValue::UInt(u64::MAX - 1)])],
+    });
+}
+
+#[test]
+fn row_at() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int]);
+    assert_eq!(table.row_at(0), None);
+    table.insert_row(Row(vec![DbValue::Int(1)])).unwrap();
+    table.insert_row(Row(vec![DbValue::Int(2)])).unwrap();
+    assert_eq!(table.row_at(1), Some(&Row(vec![DbValue::Int(2)])));
+    assert_eq!(table.row_at(2), None);
+}
+
+#[test]
+fn char_validator() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int, DbType::Char]);
+    table.insert_row(Row(vec![DbValue::Int(0), DbValue::Char('\n')])).unwrap();
+    assert!(matches!(table.set_char_validator(|c| !c.is_control()), Err(DbError::CheckViolation { column: 1, .. })));
+    table.remove_row(0);
+
+    table.set_char_validator(|c| !c.is_control()).unwrap();
+    table.insert_row(Row(vec![DbValue::Int(1), DbValue::Char('é')])).unwrap();
+    let rejected = Row(vec![DbValue::Int(2), DbValue::Char('\u{7}')]);
+    assert!(matches!(table.insert_row(rejected.clone()), Err(DbError::CheckViolation { column: 1, .. })));
+    assert!(matches!(table.update_row(0, rejected), Err(DbError::CheckViolation { column: 1, .. })));
+    assert_eq!(table.rows(), [Row(vec![DbValue::Int(1), DbValue::Char('é')])]);
+}
+
+#[test]
+fn to_ddl() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    assert_eq!(db.to_ddl().unwrap(), "");
+    db.create_table("people".to_string(), vec![DbType::Int, DbType::String, DbType::Time]).unwrap();
+    db.create_table("odd \"name\"".to_string(), vec![DbType::UInt]).unwrap();
+    db.create_table("empty".to_string(), vec![]).unwrap();
+    assert_eq!(
+        db.to_ddl().unwrap(),
+        "CREATE TABLE empty ();\n\
+         CREATE TABLE \"odd \"\"name\"\"\" (col0 uint);\n\
+         CREATE TABLE people (col0 int, col1 string, col2 time);\n"
+    );
+}
+
+#[test]
+fn execute_ddl() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.execute_ddl(
+        "CREATE TABLE people (id int, name string, born time);\n\
+         create table scores (person uint, score real, grade char)",
+    )
+    .unwrap();
+    let mut names = db.get_table_names();
+    names.sort();
+    assert_eq!(names, ["people", "scores"]);
+    assert_eq!(
+        db.get_table("scores".to_string()).unwrap().schema(),
+        [DbType::UInt, DbType::Real, DbType::Char]
+    );
+
+    let mut copy = SavedDatabase::create("copy".to_string(), dir.path().join("copy")).unwrap();
+    db.create_table("odd \"name\"".to_string(), vec![DbType::Int]).unwrap();
+    copy.execute_ddl(&db.to_ddl().unwrap()).unwrap();
+    assert_eq!(copy.to_ddl().unwrap(), db.to_ddl().unwrap());
+
+    for ddl in [
+        "CREATE TABLE a (x int); CREATE TABLE b (y float);",
+        "CREATE TABLE a (x int) CREATE TABLE b (y int)",
+        "CREATE TABLE a (x int); DROP TABLE people",
+        "CREATE TABLE a (x int, \"y int)",
+    ] {
+        assert!(matches!(copy.execute_ddl(ddl), Err(DbError::SqlSyntax { .. })), "{ddl}");
+    }
+    assert!(matches!(
+        copy.execute_ddl("CREATE TABLE a (x int); CREATE TABLE people (y int)"),
+        Err(DbError::TableIsAlreadyPresent(name)) if name == "people"
+    ));
+    assert!(copy.get_table("a".to_string()).is_err());
+}
+
+#[test]
+fn schema_builder() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    let schema = SchemaBuilder::new().int("id").string("name").time("deleted_at");
+    assert_eq!(schema.names().collect::<Vec<_>>(), ["id", "name", "deleted_at"]);
+    db.create_table("people".to_string(), schema.build()).unwrap();
+    assert_eq!(
+        db.get_table("people".to_string()).unwrap().schema(),
+        [DbType::Int, DbType::String, DbType::Time]
+    );
+}
+
+#[test]
+fn dirty_tracking() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    assert!(!db.is_dirty());
+
+    let steps: [fn(&mut SavedDatabase); 9] = [
+        |db| db.create_table("t".to_string(), vec![DbType::Int]).unwrap(),
+        |db| db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap(),
+        |db| assert!(db.update_row("t".to_string(), 0, Row(vec![DbValue::Int(2)])).unwrap()),
+        |db| db.add_check("t".to_string(), CheckConstraint { column: 0, op: CompareOp::Ge, value: DbValue::Int(0) }).unwrap(),
+        |db| db.projection("t".to_string(), vec![true], "p".to_string(), None).unwrap(),
+        |db| db.remove_row("t".to_string(), 0).unwrap(),
+        |db| db.remove_table("p".to_string()).unwrap(),
+        |db| db.get_table_mut("t".to_string()).unwrap().insert_row(Row(vec![DbValue::Int(3)])).unwrap(),
+        |db| db.set_format(Format::Json),
+    ];
+    for (i, step) in steps.iter().enumerate() {
+        step(&mut db);
+        assert!(db.is_dirty(), "step {i}");
+        db.save().unwrap();
+        assert!(!db.is_dirty(), "step {i}");
+    }
+
+    // Failed mutations and reads leave it clean.
+    assert!(db.insert_row("t".to_string(), Row(vec![])).is_err());
+    db.get_table("t".to_string()).unwrap();
+    assert!(!db.is_dirty());
+
+    let savepoint = db.savepoint().unwrap();
+    db.restore(savepoint).unwrap();
+    assert!(db.is_dirty());
+    db.reload().unwrap();
+    assert!(!db.is_dirty());
+    drop(db);
+    assert!(!SavedDatabase::load_from_disk(&path).unwrap().is_dirty());
+}
+
+#[test]
+fn autosave() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    db.autosave(AutosavePolicy::OnDrop);
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    // Dropping a clone doesn't save.
+    drop(db.clone());
+    assert_eq!(load_ignoring_lock(path.clone()).unwrap().table_count(), 0);
+    drop(db);
+    assert_eq!(SavedDatabase::load_from_disk(path.clone()).unwrap().table_count(), 1);
+
+    let mut db = SavedDatabase::load_from_disk(path.clone()).unwrap();
+    db.autosave(AutosavePolicy::EveryNMutations(3));
+    let saved_rows = |path: &Path| load_ignoring_lock(path).unwrap().get_table("t".to_string()).unwrap().rows().len();
+    let mut counts = Vec::new();
+    for value in 0..7 {
+        db.insert_row("t".to_string(), Row(vec![DbValue::Int(value)])).unwrap();
+        counts.push(saved_rows(&path));
+    }
+    assert_eq!(counts, [0, 0, 3, 3, 3, 6, 6]);
+    assert!(db.is_dirty());
+
+    // An explicit save restarts the count.
+    db.save().unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(7)])).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(8)])).unwrap();
+    assert_eq!(saved_rows(&path), 7);
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(9)])).unwrap();
+    assert_eq!(saved_rows(&path), 10);
+    // Without OnDrop, unsaved changes are lost.
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(10)])).unwrap();
+    drop(db);
+    assert_eq!(saved_rows(&path), 10);
+
+    // Restoring a savepoint counts as a mutation too.
+    let mut db = SavedDatabase::load_from_disk(path.clone()).unwrap();
+    db.autosave(AutosavePolicy::EveryNMutations(2));
+    let savepoint = db.savepoint().unwrap();
+    db.remove_row("t".to_string(), 0).unwrap();
+    db.restore(savepoint).unwrap();
+    assert!(!db.is_dirty());
+    assert_eq!(saved_rows(&path), 10);
+
+    // `close` returns the error of the save on drop, here failing to replace a directory.
+    let blocked = dir.path().join("blocked");
+    let mut db = SavedDatabase::create("db".to_string(), blocked.clone()).unwrap();
+    db.autosave(AutosavePolicy::OnDrop);
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    std::fs::remove_file(&blocked).unwrap();
+    std::fs::create_dir_all(blocked.join("child")).unwrap();
+    assert!(db.close().is_err());
+}
+
+#[test]
+fn migrate_fixtures() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let dir = tempdir().unwrap();
+    for (name, version, format) in [
+        ("v1-bincode.db", 1, Format::Bincode),
+        ("v2-bincode.db", 2, Format::Bincode),
+        ("v2-json.db", 2, Format::Json),
+        ("v2-messagepack.db", 2, Format::MessagePack),
+        ("v3-bincode.db", 3, Format::Bincode),
+        ("v4-bincode.db", 4, Format::Bincode),
+    ] {
+        let path = dir.path().join(name);
+        std::fs::copy(fixtures.join(name), &path).unwrap();
+        assert_eq!(SavedDatabase::file_version(&path).unwrap(), version, "{name}");
+
+        let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+        assert_eq!(db.get_name(), "legacy");
+        assert_eq!(db.format(), format);
+        let table = db.get_table("people".to_string()).unwrap();
+        assert_eq!(table.schema(), [DbType::String, DbType::Int]);
+        assert_eq!(table.rows(), [
+            Row(vec![DbValue::String("Ada".to_string()), DbValue::Int(36)]),
+            Row(vec![DbValue::String("Alan".to_string()), DbValue::Int(41)]),
+        ]);
+        assert_eq!(table.row_ids(), [0, 1]);
+        assert_eq!(table.row_created_at(1), Some(Utc.with_ymd_and_hms(2023, 11, 2, 11, 0, 0).unwrap()));
+        let autoincrement = (version == 4).then_some(AutoIncrement { column: 1, next: 42 });
+        assert_eq!(table.autoincrement(), autoincrement, "{name}");
+        assert_eq!(table.max_blob_len(), DEFAULT_MAX_BLOB_LEN);
+
+        db.insert_row("people".to_string(), Row(vec![DbValue::String("Grace".to_string()), DbValue::Int(45)])).unwrap();
+        db.save().unwrap();
+        assert_eq!(SavedDatabase::file_version(&path).unwrap(), crate::format::FORMAT_VERSION);
+        let snapshot = db.snapshot().unwrap();
+        drop(db);
+        let reloaded = SavedDatabase::load_from_disk(&path).unwrap();
+        assert_eq!(reloaded.snapshot().unwrap(), snapshot);
+        assert_eq!(reloaded.get_table("people".to_string()).unwrap().row_ids(), [0, 1, 2]);
+    }
+
+    // Files without a header hold neither checks nor creation times.
+    let path = dir.path().join("v0-bincode.db");
+    std::fs::copy(fixtures.join("v0-bincode.db"), &path).unwrap();
+    assert_eq!(SavedDatabase::file_version(&path).unwrap(), 0);
+    let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.get_name(), "legacy");
+    assert_eq!(db.get_table_names().len(), 2);
+    let table = db.get_table("people".to_string()).unwrap();
+    assert_eq!(table.rows(), [
+        Row(vec![DbValue::String("Ada".to_string()), DbValue::Int(36)]),
+        Row(vec![DbValue::String("Alan".to_string()), DbValue::Int(41)]),
+    ]);
+    assert_eq!(table.row_ids(), [0, 1]);
+    assert_eq!(table.row_created_at(1), Some(DateTime::UNIX_EPOCH));
+    let before = Utc::now();
+    db.insert_row("people".to_string(), Row(vec![DbValue::String("Grace".to_string()), DbValue::Int(45)])).unwrap();
+    db.remove_row("people".to_string(), 0).unwrap();
+    let table = db.get_table("people".to_string()).unwrap();
+    assert_eq!(table.row_created_at(0), Some(DateTime::UNIX_EPOCH));
+    assert!(table.row_created_at(1).unwrap() >= before);
+    db.save().unwrap();
+    assert_eq!(SavedDatabase::file_version(&path).unwrap(), crate::format::FORMAT_VERSION);
+    let snapshot = db.snapshot().unwrap();
+    drop(db);
+    assert_eq!(SavedDatabase::load_from_disk(&path).unwrap().snapshot().unwrap(), snapshot);
+
+    // The directory layout reads table files of older versions too.
+    let dir_path = dir.path().join("dir");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), &dir_path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+    drop(db);
+    let table_file = table_files(&dir_path).pop().unwrap();
+    // Laid out like `TableV2`, with the header of the version 2 fixture.
+    let rows = vec![Row(vec![DbValue::Int(1)]), Row(vec![DbValue::Int(2)])];
+    let payload = bincode::serialize(&("t", &rows, vec![DbType::Int], 2u64, Vec::<CheckConstraint>::new(), Vec::<DateTime<Utc>>::new())).unwrap();
+    let mut bytes = std::fs::read(fixtures.join("v2-bincode.db")).unwrap()[..12].to_vec();
+    bytes[8..12].copy_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend(payload);
+    std::fs::write(&table_file, bytes).unwrap();
+    assert_eq!(SavedDatabase::file_version(&dir_path).unwrap(), crate::format::FORMAT_VERSION);
+    let loaded = SavedDatabase::load_from_disk(&dir_path).unwrap();
+    let table = loaded.get_table("t".to_string()).unwrap();
+    assert_eq!(table.rows(), rows);
+    assert_eq!(table.row_ids(), [0, 1]);
+}
+
+#[test]
+fn migrate_in_place() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("legacy.db");
+    std::fs::copy(fixtures.join("v1-bincode.db"), &path).unwrap();
+    let snapshot = SavedDatabase::load_from_disk(&path).unwrap().snapshot().unwrap();
+
+    let backup = SavedDatabase::migrate(&path).unwrap();
+    assert_eq!(backup, dir.path().join("legacy.db.bak"));
+    assert_eq!(std::fs::read(&backup).unwrap(), std::fs::read(fixtures.join("v1-bincode.db")).unwrap());
+    assert_eq!(SavedDatabase::file_version(&path).unwrap(), crate::format::FORMAT_VERSION);
+    let migrated = SavedDatabase::load_from_disk(&path).unwrap();
+    assert!(!migrated.load_report().missing_checksum);
+    assert_eq!(migrated.snapshot().unwrap(), snapshot);
+    drop(migrated);
+    // The original is kept from the first migration.
+    assert!(matches!(SavedDatabase::migrate(&path), Err(DbError::FileExists(_))));
+
+    let dir_path = dir.path().join("dir");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), &dir_path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.save().unwrap();
+    drop(db);
+    let backup = SavedDatabase::migrate(&dir_path).unwrap();
+    assert_eq!(table_files(&backup).len(), 1);
+    assert_eq!(SavedDatabase::load_from_disk(&backup).unwrap().get_table_names(), ["t"]);
+}
+
+#[test]
+fn column_defaults() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.enable_wal(FsyncPolicy::Never);
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::String, DbType::Time]).unwrap();
+    let table = db.get_table_mut("t".to_string()).unwrap();
+    assert!(matches!(table.set_default(3, Some(ColumnDefault::Now)), Err(DbError::ColumnOutOfRange(3))));
+    assert!(matches!(
+        table.set_default(1, Some(ColumnDefault::Now)),
+        Err(DbError::ColumnTypeMismatch { column: 1, expected: DbType::String, got: DbType::Time })
+    ));
+    table.set_default(1, Some(ColumnDefault::Value(DbValue::String("none".to_string())))).unwrap();
+    // Without a default for the last column, rows still have to give it.
+    let short = db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)]));
+    assert!(matches!(short, Err(DbError::RowLengthMismatch { expected: 3, got: 2 })));
+
+    db.get_table_mut("t".to_string()).unwrap().set_default(2, Some(ColumnDefault::Now)).unwrap();
+    let before = Utc::now().fixed_offset();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    let time = DbValue::Time(Utc::now().fixed_offset());
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(2), DbValue::String("given".to_string())])).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(3), DbValue::String("all".to_string()), time.clone()])).unwrap();
+    let rows = db.get_table("t".to_string()).unwrap().rows().to_vec();
+    assert_eq!(rows[0].0[1], DbValue::String("none".to_string()));
+    assert!(matches!(rows[0].0[2], DbValue::Time(t) if t >= before && DbValue::Time(t) <= time));
+    assert_eq!(rows[1].0[1], DbValue::String("given".to_string()));
+    assert_eq!(rows[2].0[2], time);
+
+    // The log records the filled in values, so replaying it restores the same rows.
+    drop(db);
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.get_table("t".to_string()).unwrap().rows(), rows);
+
+    // Defaults and blob limits are saved with the table.
+    for format in [Format::Bincode, Format::Json, Format::MessagePack] {
+        let path = dir.path().join(format!("{format:?}"));
+        let mut db = SavedDatabase::create_with("db".to_string(), &path, format).unwrap();
+        db.create_table("t".to_string(), vec![DbType::Int, DbType::Blob]).unwrap();
+        db.set_default("t".to_string(), 1, Some(ColumnDefault::Value(DbValue::Blob(vec![7])))).unwrap();
+        db.set_max_blob_len("t".to_string(), 4).unwrap();
+        db.save().unwrap();
+        drop(db);
+
+        let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+        let table = db.get_table("t".to_string()).unwrap();
+        assert_eq!(table.default_of(1), Some(&ColumnDefault::Value(DbValue::Blob(vec![7]))), "{format:?}");
+        assert_eq!(table.max_blob_len(), 4, "{format:?}");
+        db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+        let too_large = db.insert_row("t".to_string(), Row(vec![DbValue::Int(2), DbValue::Blob(vec![0; 5])]));
+        assert!(matches!(too_large, Err(DbError::BlobTooLarge { len: 5, max: 4, .. })), "{format:?}");
+    }
+}
+
+#[test]
+fn autoincrement() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::String, DbType::UInt]).unwrap();
+    let set = db.set_autoincrement("t".to_string(), 0);
+    assert!(matches!(set, Err(DbError::ColumnTypeMismatch { column: 0, expected: DbType::Int, got: DbType::String })));
+    db.set_autoincrement("t".to_string(), 1).unwrap();
+    let insert = |db: &mut SavedDatabase, name: &str, id: Option<u64>| {
+        let mut row = vec![DbValue::String(name.to_string())];
+        row.extend(id.map(DbValue::UInt));
+        db.insert_row("t".to_string(), Row(row)).unwrap();
+    };
+    // 0 asks for the next value, as does leaving the column out.
+    insert(&mut db, "a", Some(0));
+    insert(&mut db, "b", None);
+    insert(&mut db, "c", Some(0));
+    let ids = |db: &SavedDatabase| -> Vec<DbValue> {
+        db.get_table("t".to_string()).unwrap().rows().iter().map(|row| row.0[1].clone()).collect()
+    };
+    assert_eq!(ids(&db), [1, 2, 3].map(DbValue::UInt));
+
+    db.save().unwrap();
+    drop(db);
+    let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.get_table("t".to_string()).unwrap().autoincrement(), Some(AutoIncrement { column: 1, next: 4 }));
+    insert(&mut db, "d", None);
+    // Given values are kept and move the sequence past them.
+    insert(&mut db, "e", Some(10));
+    insert(&mut db, "f", None);
+    assert_eq!(ids(&db), [1, 2, 3, 4, 10, 11].map(DbValue::UInt));
+
+    // Starting a sequence on a filled column continues after its largest value, and the
+    // write-ahead log records it.
+    db.enable_wal(FsyncPolicy::Never);
+    db.create_table("u".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("u".to_string(), Row(vec![DbValue::Int(-5)])).unwrap();
+    db.insert_row("u".to_string(), Row(vec![DbValue::Int(7)])).unwrap();
+    db.set_autoincrement("u".to_string(), 0).unwrap();
+    drop(db);
+    let mut db = SavedDatabase::load_from_disk(&path).unwrap();
+    db.insert_row("u".to_string(), Row(vec![])).unwrap();
+    let rows = db.get_table("u".to_string()).unwrap().rows().to_vec();
+    assert_eq!(rows, [-5, 7, 8].map(|i| Row(vec![DbValue::Int(i)])));
+
+    // A sequence past the largest value of its column fails instead of repeating it.
+    db.insert_row("u".to_string(), Row(vec![DbValue::Int(i64::MAX)])).unwrap();
+    assert!(matches!(db.insert_row("u".to_string(), Row(vec![])), Err(DbError::AutoIncrementExhausted { column: 0 })));
+    insert(&mut db, "g", Some(u64::MAX - 1));
+    let exhausted = db.insert_row("t".to_string(), Row(vec![DbValue::String("h".to_string())]));
+    assert!(matches!(exhausted, Err(DbError::AutoIncrementExhausted { column: 1 })));
+    assert_eq!(db.get_table("t".to_string()).unwrap().rows().len(), 4);
+}
+
+#[test]
+fn rename_and_copy_tables() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create_dir_layout("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::Int]).unwrap();
+    db.set_autoincrement("t".to_string(), 0).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(0), DbValue::Int(5)])).unwrap();
+    db.create_materialized_projection("t".to_string(), vec![false, true], "m".to_string()).unwrap();
+    db.save().unwrap();
+
+    assert!(matches!(db.rename_table("t".to_string(), "m".to_string()), Err(DbError::TableIsAlreadyPresent(_))));
+    assert!(matches!(db.rename_table("x".to_string(), "y".to_string()), Err(DbError::TableIsMissing(_))));
+    db.rename_table("t".to_string(), "source".to_string()).unwrap();
+    db.rename_table("m".to_string(), "view".to_string()).unwrap();
+    db.insert_row("source".to_string(), Row(vec![DbValue::Int(0), DbValue::Int(6)])).unwrap();
+    assert!(db.is_stale("view".to_string()).unwrap());
+    db.refresh_materialized("view".to_string()).unwrap();
+    assert_eq!(db.get_table("view".to_string()).unwrap().rows().len(), 2);
+
+    db.copy_table("source".to_string(), "full".to_string(), true).unwrap();
+    db.copy_table("source".to_string(), "empty".to_string(), false).unwrap();
+    assert!(matches!(db.copy_table("source".to_string(), "view".to_string(), true), Err(DbError::TableIsAlreadyPresent(_))));
+    assert!(matches!(db.is_stale("full".to_string()), Err(DbError::NotMaterialized(_))));
+    db.insert_row("empty".to_string(), Row(vec![DbValue::Int(0), DbValue::Int(7)])).unwrap();
+    db.save().unwrap();
+    let snapshot = db.snapshot().unwrap();
+    drop(db);
+
+    let db = SavedDatabase::load_from_disk(&path).unwrap();
+    assert_eq!(db.snapshot().unwrap(), snapshot);
+    let mut names = db.get_table_names();
+    names.sort();
+    assert_eq!(names, ["empty", "full", "source", "view"]);
+    assert_eq!(db.get_table("full".to_string()).unwrap().rows(), db.get_table("source".to_string()).unwrap().rows());
+    assert_eq!(db.get_table("empty".to_string()).unwrap().rows(), [Row(vec![DbValue::Int(1), DbValue::Int(7)])]);
+    assert_eq!(table_files(&path).len(), 4);
+}
+
+#[test]
+fn parallel_validation() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = SavedDatabase::create("db".to_string(), path.clone()).unwrap();
+    for name in ["a", "b", "c"] {
+        db.create_table(name.to_string(), vec![DbType::Int]).unwrap();
+        let table = db.get_table_mut(name.to_string()).unwrap();
+        let rows = if name == "a" { 10 } else { 50_000 };
+        for i in 0..rows {
+            table.insert_row(Row(vec![DbValue::Int(i)])).unwrap();
+        }
+        // Both large tables fail, in a chunk other than the first.
+        if name != "a" {
+            table.rows_mut()[40_000] = Row(vec![DbValue::String("bad".to_string())]);
+        }
+    }
+    db.save().unwrap();
+    drop(db);
+
+    for parallel in [false, true] {
+        let options = LoadOptions { validate: true, parallel, ..LoadOptions::default() };
+        for _ in 0..5 {
+            let error = SavedDatabase::load_from_disk_with(&path, options).unwrap_err();
+            assert!(matches!(&error, DbError::InvalidTableState(name) if name == "b"), "{error}");
+        }
+    }
+    assert!(matches!(SavedDatabase::load_from_disk(&path), Err(DbError::InvalidTableState(name)) if name == "b"));
+
+    let options = LoadOptions { validate: false, parallel: false, ..LoadOptions::default() };
+    let db = SavedDatabase::load_from_disk_with(&path, options).unwrap();
+    assert_eq!(db.get_table("c".to_string()).unwrap().rows().len(), 50_000);
+}
+
+#[test]
+fn blob_values() {
+    let dir = tempdir().unwrap();
+    let bytes: Vec<u8> = (0..=255).collect();
+    for format in [Format::Bincode, Format::Json, Format::MessagePack] {
+        let path = dir.path().join(format!("{format:?}"));
+        let mut db = SavedDatabase::create_with("db".to_string(), &path, format).unwrap();
+        db.create_table("t".to_string(), vec![DbType::Int, DbType::Blob]).unwrap();
+        for (i, blob) in [Vec::new(), vec![0xff], bytes.clone()].into_iter().enumerate() {
+            db.insert_row("t".to_string(), Row(vec![DbValue::Int(i as i64), DbValue::Blob(blob)])).unwrap();
+        }
+        db.save().unwrap();
+        drop(db);
+        let db = SavedDatabase::load_from_disk(&path).unwrap();
+        let table = db.get_table("t".to_string()).unwrap();
+        assert_eq!(table.schema(), [DbType::Int, DbType::Blob]);
+        assert_eq!(table.rows()[2].get(1), DbValue::Blob(bytes.clone()), "{format:?}");
+        assert_eq!(table.rows()[0].get(1), DbValue::Blob(Vec::new()));
+    }
+
+    assert_eq!(DbValue::Blob(vec![0x0a, 0xbc]).to_string(), "0x0abc");
+    assert_eq!(DbValue::Blob(Vec::new()).to_string(), "0x");
+    assert_eq!("Blob".parse::<DbType>().unwrap(), DbType::Blob);
+    assert_eq!(DbValue::parse(DbType::Blob, "aGk=").unwrap(), DbValue::Blob(b"hi".to_vec()));
+    assert!(DbValue::parse(DbType::Blob, "aGk").is_err());
+
+    // JSON exports write base64 and read it back.
+    let db = SavedDatabase::load_from_disk(dir.path().join("Bincode")).unwrap();
+    let mut json = Vec::new();
+    db.export_json(&mut json, false).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(value["tables"]["t"]["rows"][1], serde_json::json!([1, "/w=="]));
+    let imported = SavedDatabase::import_json(dir.path().join("imported"), json.as_slice(), false).unwrap();
+    assert_eq!(imported.get_table("t".to_string()).unwrap().rows(), db.get_table("t".to_string()).unwrap().rows());
+
+    // Values over the table's limit are rejected, as is a limit stored rows exceed.
+    let mut table = Table::new("t".to_string(), vec![DbType::Blob]);
+    let too_large = Row(vec![DbValue::Blob(vec![0; DEFAULT_MAX_BLOB_LEN + 1])]);
+    assert!(matches!(
+        table.insert_row(too_large),
+        Err(DbError::BlobTooLarge { column: 0, len, max: DEFAULT_MAX_BLOB_LEN }) if len == DEFAULT_MAX_BLOB_LEN + 1
+    ));
+    table.insert_row(Row(vec![DbValue::Blob(vec![0; 16])])).unwrap();
+    assert!(matches!(table.set_max_blob_len(8), Err(DbError::BlobTooLarge { len: 16, max: 8, .. })));
+    table.set_max_blob_len(16).unwrap();
+    assert!(table.update_row(0, Row(vec![DbValue::Blob(vec![0; 17])])).is_err());
+
+    let mut table = Table::new("t".to_string(), vec![DbType::String]);
+    let too_long = Row(vec![DbValue::String("x".repeat(MAX_STRING_LEN + 1))]);
+    assert!(matches!(table.insert_row(too_long.clone()), Err(DbError::StringTooLong { column: 0, .. })));
+
+    // Loading validates the lengths of stored values too.
+    let path = dir.path().join("too_long");
+    let mut db = SavedDatabase::create("db".to_string(), &path).unwrap();
+    db.create_table("t".to_string(), vec![DbType::String]).unwrap();
+    db.get_table_mut("t".to_string()).unwrap().rows_mut().push(too_long);
+    db.save().unwrap();
+    drop(db);
+    for parallel in [false, true] {
+        let options = LoadOptions { validate: true, parallel, ..LoadOptions::default() };
+        assert!(matches!(SavedDatabase::load_from_disk_with(&path, options), Err(DbError::StringTooLong { column: 0, .. })));
+    }
+}
+
+#[test]
+fn table_stats() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int, DbType::Real, DbType::String, DbType::UInt]);
+    assert_eq!(table.stats(), TableStats { row_count: 0, bounds: vec![None; 4] });
+
+    for (i, r, s, u) in [(3, 0.5, "b", 7), (-2, f64::NAN, "a", 9), (10, -1.5, "c", 8)] {
+        table.insert_row(Row(vec![DbValue::Int(i), DbValue::Real(r), DbValue::String(s.to_string()), DbValue::UInt(u)])).unwrap();
+    }
+    let stats = table.stats();
+    assert_eq!(stats.row_count, 3);
+    assert_eq!(stats.bounds, vec![
+        Some((DbValue::Int(-2), DbValue::Int(10))),
+        Some((DbValue::Real(-1.5), DbValue::Real(0.5))),
+        None,
+        Some((DbValue::UInt(7), DbValue::UInt(9))),
+    ]);
+}
+
+#[test]
+fn projection_of_inconsistent_rows() {
+    let dir = tempdir().unwrap();
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int, DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1), DbValue::Int(2)])).unwrap();
+    db.get_table_mut("t".to_string()).unwrap().rows_mut()[0].0.push(DbValue::Int(3));
+
+    let error = db.projection("t".to_string(), vec![true, false], "p".to_string(), None).unwrap_err();
+    assert!(matches!(error, DbError::InvalidTableState(name) if name == "t"));
+    assert!(db.get_table("p".to_string()).is_err());
+}
+
+#[test]
+fn projection_with_schema() {
+    let dir = tempdir().unwrap();
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    db.create_table("t".to_string(), vec![DbType::String, DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::String("a".to_string()), DbValue::Int(2)])).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::String("bc".to_string()), DbValue::Int(-3)])).unwrap();
+
+    db.projection("t".to_string(), vec![false, true], "p".to_string(), Some(vec![DbType::Real])).unwrap();
+    let projection = db.get_table("p".to_string()).unwrap();
+    assert_eq!(projection.schema(), vec![DbType::Real]);
+    assert_eq!(projection.rows(), [Row(vec![DbValue::Real(2.0)]), Row(vec![DbValue::Real(-3.0)])]);
+
+    let error = db.projection("t".to_string(), vec![true, true], "q".to_string(), Some(vec![DbType::Char, DbType::Int])).unwrap_err();
+    assert!(matches!(error, DbError::CannotCoerce { column: 0, value, to: DbType::Char } if value == "bc"));
+    let error = db.projection("t".to_string(), vec![false, true], "q".to_string(), Some(vec![DbType::UInt])).unwrap_err();
+    assert!(matches!(error, DbError::CannotCoerce { column: 0, to: DbType::UInt, .. }));
+    let error = db.projection("t".to_string(), vec![false, true], "q".to_string(), Some(vec![])).unwrap_err();
+    assert!(matches!(error, DbError::RowLengthMismatch { expected: 1, got: 0 }));
+    assert!(db.get_table("q".to_string()).is_err());
+
+    assert_eq!(DbValue::Real(4.0).coerce(DbType::Int), Some(DbValue::Int(4)));
+    assert_eq!(DbValue::Real(4.5).coerce(DbType::Int), None);
+    assert_eq!(DbValue::Char('x').coerce(DbType::String), Some(DbValue::String("x".to_string())));
+    assert_eq!(DbValue::Int(1).coerce(DbType::Time), None);
+}
+
+#[test]
+fn embedded_reopen() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let mut db = Embedded::open_or_create("db", &path).unwrap();
+    db.ensure_table("t", vec![DbType::Int]).unwrap().insert("t", Row(vec![DbValue::Int(1)])).unwrap();
+    db.save().unwrap();
+    drop(db);
+
+    let mut db = Embedded::open_or_create("other", &path).unwrap();
+    assert_eq!(db.database().get_name(), "db");
+    db.ensure_table("t", vec![DbType::Int]).unwrap();
+    assert_eq!(db.select("t", |query| query).unwrap(), [Row(vec![DbValue::Int(1)])]);
+    let mismatch = db.ensure_table("t", vec![DbType::String]);
+    assert!(matches!(mismatch, Err(DbError::TableIsAlreadyPresent(_))));
+}
+
+#[test]
+fn update_to_same_row() {
+    let dir = tempdir().unwrap();
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    db.create_table("t".to_string(), vec![DbType::Int]).unwrap();
+    db.insert_row("t".to_string(), Row(vec![DbValue::Int(1)])).unwrap();
+    db.save().unwrap();
+    let version = db.get_table("t".to_string()).unwrap().version();
+    let mut events = db.subscribe();
+
+    assert!(!db.update_row("t".to_string(), 0, Row(vec![DbValue::Int(1)])).unwrap());
+    assert!(!db.is_dirty());
+    assert_eq!(db.get_table("t".to_string()).unwrap().version(), version);
+    assert!(events.try_recv().is_err());
+    assert!(db.update_row("t".to_string(), 5, Row(vec![DbValue::Int(1)])).is_err());
+
+    assert!(db.update_row("t".to_string(), 0, Row(vec![DbValue::Int(2)])).unwrap());
+    assert!(db.is_dirty());
+    assert!(matches!(events.try_recv(), Ok(ChangeEvent::Mutation(TxOp::UpdateRow { .. }))));
+    let table = db.get_table_mut("t".to_string()).unwrap();
+    assert!(!table.update_row(0, Row(vec![DbValue::Int(2)])).unwrap());
+    assert_eq!(table.version(), version + 1);
+
+    // The same instant in another offset is saved and shown differently.
+    let time = |text| Row(vec![DbValue::Time(DateTime::parse_from_rfc3339(text).unwrap())]);
+    db.create_table("times".to_string(), vec![DbType::Time]).unwrap();
+    db.insert_row("times".to_string(), time("2024-01-01T00:00:00+00:00")).unwrap();
+    assert!(db.update_row("times".to_string(), 0, time("2024-01-01T05:00:00+05:00")).unwrap());
+    assert_eq!(db.get_table("times".to_string()).unwrap().rows()[0].to_string(), "2024-01-01 05:00:00 +05:00 ");
+    assert!(!db.update_row("times".to_string(), 0, time("2024-01-01T05:00:00+05:00")).unwrap());
+    let table = db.get_table_mut("times".to_string()).unwrap();
+    assert!(table.update_row(0, time("2024-01-01T00:00:00+00:00")).unwrap());
+}
+
+#[test]
+fn time_offsets() {
+    let dir = tempdir().unwrap();
+    let time = DateTime::parse_from_rfc3339("2023-06-01T12:30:00+02:00").unwrap();
+    assert_eq!(DbValue::Time(time).to_string(), "2023-06-01 12:30:00 +02:00");
+    assert_eq!(DbValue::parse(DbType::Time, "2023-06-01T12:30:00+02:00").unwrap(), DbValue::Time(time));
+    assert_eq!(DbValue::Time(time), DbValue::Time(time.with_timezone(&Utc).fixed_offset()));
+
+    for format in [Format::Bincode, Format::Json, Format::MessagePack] {
+        let path = dir.path().join(format!("{format:?}"));
+        let mut db = SavedDatabase::create_with("db".to_string(), &path, format).unwrap();
+        db.create_table("t".to_string(), vec![DbType::Time]).unwrap();
+        db.insert_row("t".to_string(), Row(vec![DbValue::Time(time)])).unwrap();
+        db.save().unwrap();
+        drop(db);
+
+        let db = SavedDatabase::load_from_disk(&path).unwrap();
+        let DbValue::Time(loaded) = db.get_table("t".to_string()).unwrap().rows()[0].0[0] else {
+            panic!("not a time");
+        };
+        assert_eq!(loaded.offset().local_minus_utc(), 2 * 3600, "{format:?}");
+    }
+}
+
+#[test]
+fn contains_row() {
+    let mut table = Table::new("t".to_string(), vec![DbType::Int, DbType::Real]);
+    table.insert_row(Row(vec![DbValue::Int(1), DbValue::Real(0.5)])).unwrap();
+    table.insert_row(Row(vec![DbValue::Int(2), DbValue::Real(f64::NAN)])).unwrap();
+    assert!(table.contains_row(&Row(vec![DbValue::Int(1), DbValue::Real(0.5)])));
+    assert!(!table.contains_row(&Row(vec![DbValue::Int(1), DbValue::Real(1.5)])));
+    assert!(!table.contains_row(&Row(vec![DbValue::Int(1)])));
+    assert!(!table.contains_row(&Row(vec![DbValue::Int(2), DbValue::Real(f64::NAN)])));
+}
+
+#[test]
+fn apply_template() {
+    let dir = tempdir().unwrap();
+    let mut db = SavedDatabase::create("db".to_string(), dir.path().join("db")).unwrap();
+    let names = |db: &SavedDatabase| {
+        let mut names = db.get_table_names();
+        names.sort();
+        names
+    };
+    let template = vec![
+        ("users".to_string(), vec![DbType::UInt, DbType::String]),
+        ("events".to_string(), vec![DbType::UInt, DbType::Time]),
+    ];
+    db.apply_template(template.clone(), false).unwrap();
+    assert_eq!(names(&db), ["events", "users"]);
+    assert_eq!(db.get_table("users".to_string()).unwrap().schema(), [DbType::UInt, DbType::String]);
+    assert_eq!(db.get_table("events".to_string()).unwrap().schema(), [DbType::UInt, DbType::Time]);
+
+    db.remove_table("events".to_string()).unwrap();
+    assert!(matches!(db.apply_template(template.clone(), false), Err(DbError::TableIsAlreadyPresent(name)) if name == "users"));
+    assert_eq!(names(&db), ["users"]);
+    db.apply_template(template.clone(), true).unwrap();
+    assert_eq!(names(&db), ["events", "users"]);
+
+    let twice = vec![("logs".to_string(), vec![DbType::Int]), ("logs".to_string(), vec![DbType::Int])];
+    assert!(db.apply_template(twice, true).is_err());
+    assert_eq!(db.table_count(), 2);
+}
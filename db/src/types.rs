@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use std::io;
+use std::str::FromStr;
 use chrono::prelude::*;
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -9,16 +10,184 @@ pub enum DbType {
     Real,
     Char,
     String,
-    Time
+    Time,
+    UInt,
+    Blob,
+}
+
+impl Display for DbType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DbType::Int => "int",
+            DbType::Real => "real",
+            DbType::Char => "char",
+            DbType::String => "string",
+            DbType::Time => "time",
+            DbType::UInt => "uint",
+            DbType::Blob => "blob",
+        })
+    }
+}
+
+impl FromStr for DbType {
+    type Err = DbError;
+
+    /// Accepts the names produced by `Display`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "int" => Ok(DbType::Int),
+            "real" => Ok(DbType::Real),
+            "char" => Ok(DbType::Char),
+            "string" => Ok(DbType::String),
+            "time" => Ok(DbType::Time),
+            "uint" => Ok(DbType::UInt),
+            "blob" => Ok(DbType::Blob),
+            _ => Err(DbError::UnknownType(s.to_string())),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialOrd, PartialEq, Serialize, Deserialize)]
 pub enum DbValue {
     Int(i64),
-    Real(f64),
+    Real(#[serde(with = "real")] f64),
     Char(char),
     String(String),
-    Time(DateTime<Utc>)
+    /// A point in time with the offset it was given in, which is kept through saving and
+    /// shown by `Display`. Values for the same instant are equal whatever their offsets.
+    Time(DateTime<FixedOffset>),
+    UInt(u64),
+    Blob(#[serde(with = "blob")] Vec<u8>),
+}
+
+/// Human-readable formats such as JSON have no representation for non-finite numbers,
+/// so those are written as the strings "NaN", "inf" and "-inf" there.
+mod real {
+    use serde::de::{Deserialize, Deserializer, Error};
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match *value {
+            x if !serializer.is_human_readable() || x.is_finite() => serializer.serialize_f64(x),
+            x if x.is_nan() => serializer.serialize_str("NaN"),
+            x if x > 0.0 => serializer.serialize_str("inf"),
+            _ => serializer.serialize_str("-inf"),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        if !deserializer.is_human_readable() {
+            return f64::deserialize(deserializer);
+        }
+
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Number(f64),
+            Name(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Number(x) => Ok(x),
+            Repr::Name(name) => match name.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                _ => Err(D::Error::custom(format!("invalid real {name:?}"))),
+            },
+        }
+    }
+}
+
+/// Blobs are base64 strings in human-readable formats and plain bytes elsewhere.
+mod blob {
+    use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Visitor};
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&super::base64::encode(value))
+        } else {
+            serializer.serialize_bytes(value)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            super::base64::decode(&text).ok_or_else(|| D::Error::custom(format!("invalid base64 {text:?}")))
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("bytes")
+        }
+
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+/// Standard base64 with padding, as written for blobs by JSON exports.
+pub(crate) mod base64 {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub fn encode(bytes: &[u8]) -> String {
+        let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let group = chunk.iter().enumerate().fold(0u32, |group, (i, &b)| group | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    text.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        text
+    }
+
+    /// `None` unless `text` is padded base64.
+    pub fn decode(text: &str) -> Option<Vec<u8>> {
+        let text = text.as_bytes();
+        if !text.len().is_multiple_of(4) {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+        for (n, chunk) in text.chunks(4).enumerate() {
+            let last = n + 1 == text.len() / 4;
+            let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+            if padding > 2 || (padding > 0 && !last) {
+                return None;
+            }
+            let mut group = 0u32;
+            for &c in &chunk[..4 - padding] {
+                group = group << 6 | ALPHABET.iter().position(|&a| a == c)? as u32;
+            }
+            group <<= 6 * padding;
+            bytes.extend(&group.to_be_bytes()[1..4 - padding]);
+        }
+        Some(bytes)
+    }
 }
 
 impl DbValue {
@@ -29,18 +198,87 @@ impl DbValue {
             Self::Char(_) => DbType::Char,
             Self::String(_) => DbType::String,
             Self::Time(_) => DbType::Time,
+            Self::UInt(_) => DbType::UInt,
+            Self::Blob(_) => DbType::Blob,
+        }
+    }
+
+    /// Total order for sorting, unlike `partial_cmp`: reals are ordered by
+    /// `f64::total_cmp`, which puts NaN after every number.
+    pub(crate) fn sort_cmp(&self, other: &DbValue) -> std::cmp::Ordering {
+        match (self, other) {
+            (Self::Real(a), Self::Real(b)) => a.total_cmp(b),
+            (a, b) => a.partial_cmp(b).expect("only reals are unordered"),
         }
     }
+
+    /// Parses textual input as a value of type `r#type`; times must be RFC3339 and blobs
+    /// base64.
+    pub fn parse(r#type: DbType, s: &str) -> Result<DbValue, DbError> {
+        let error = || DbError::ParseError {
+            expected: r#type,
+            input: s.to_string(),
+        };
+        match r#type {
+            DbType::Int => s.parse().map(DbValue::Int).map_err(|_| error()),
+            DbType::UInt => s.parse().map(DbValue::UInt).map_err(|_| error()),
+            DbType::Real => s.parse().map(DbValue::Real).map_err(|_| error()),
+            DbType::Char => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(DbValue::Char(c)),
+                    _ => Err(error()),
+                }
+            }
+            DbType::String => Ok(DbValue::String(s.to_string())),
+            DbType::Time => DateTime::parse_from_rfc3339(s).map(DbValue::Time).map_err(|_| error()),
+            DbType::Blob => base64::decode(s).map(DbValue::Blob).ok_or_else(error),
+        }
+    }
+
+    /// Converts to a value of type `r#type` with the same meaning: integers become reals
+    /// or integers of the other signedness if in range, reals without a fraction become
+    /// integers in range, and chars become strings or back if a single one. `None` if
+    /// the value has no such counterpart.
+    pub fn coerce(self, r#type: DbType) -> Option<DbValue> {
+        use DbValue::*;
+        Some(match (self, r#type) {
+            (value, r#type) if value.get_type() == r#type => value,
+            (Int(x), DbType::Real) => Real(x as f64),
+            (UInt(x), DbType::Real) => Real(x as f64),
+            (Int(x), DbType::UInt) => UInt(x.try_into().ok()?),
+            (UInt(x), DbType::Int) => Int(x.try_into().ok()?),
+            // 2^63 and 2^64 are exact as floats, unlike the largest integers.
+            (Real(x), DbType::Int) if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 => Int(x as i64),
+            (Real(x), DbType::UInt) if x.fract() == 0.0 && x >= 0.0 && x < u64::MAX as f64 => UInt(x as u64),
+            (Char(c), DbType::String) => String(c.to_string()),
+            (String(s), DbType::Char) => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Char(c),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        })
+    }
 }
 
 impl Display for DbValue {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             DbValue::Int(x) => f.write_str(&x.to_string())?,
+            DbValue::UInt(x) => f.write_str(&x.to_string())?,
             DbValue::Real(x) => f.write_str(&x.to_string())?,
             DbValue::String(x) => f.write_str(&x.to_string())?,
             DbValue::Char(x) => f.write_str(&x.to_string())?,
-            DbValue::Time(x) => f.write_str(&x.to_string())?
+            DbValue::Time(x) => f.write_str(&x.to_string())?,
+            DbValue::Blob(x) => {
+                f.write_str("0x")?;
+                for byte in x {
+                    write!(f, "{byte:02x}")?;
+                }
+            }
         }
         Ok(())
     }
@@ -57,6 +295,16 @@ impl Row {
     pub fn schema(&self) -> Vec<DbType> {
         self.0.iter().map(|v| v.get_type()).collect()
     }
+
+    /// Like `==`, but times also need the same offset, so that storing `other` instead
+    /// would change nothing that is saved or shown.
+    pub(crate) fn is_identical(&self, other: &Row) -> bool {
+        self.0.len() == other.0.len()
+            && self.0.iter().zip(&other.0).all(|pair| match pair {
+                (DbValue::Time(a), DbValue::Time(b)) => a == b && a.offset() == b.offset(),
+                (a, b) => a == b,
+            })
+    }
 }
 
 impl Display for Row {
@@ -75,12 +323,123 @@ pub enum DbError {
     Io(#[from] io::Error),
     #[error("De(serialization error): {0}")]
     Serde(#[from] bincode::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("MessagePack encoding error: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decoding error: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("Unknown type {0}")]
+    UnknownType(String),
+    #[error("Cannot parse {input:?} as {expected}")]
+    ParseError { expected: DbType, input: String },
     #[error("Row does not fit table's schema")]
     IncorrectRow,
+    #[error("Cannot coerce {value} in column {column} to {to}")]
+    CannotCoerce { column: usize, value: String, to: DbType },
+    #[error("Row has {got} values, expected {expected}")]
+    RowLengthMismatch { expected: usize, got: usize },
+    #[error("Column {column} expects {expected:?}, got {got:?}")]
+    ColumnTypeMismatch {
+        column: usize,
+        expected: DbType,
+        got: DbType,
+    },
+    #[error("Blob of {len} bytes in column {column} exceeds the limit of {max} bytes")]
+    BlobTooLarge { column: usize, len: usize, max: usize },
+    #[error("String of {len} bytes in column {column} exceeds the limit of {max} bytes")]
+    StringTooLong { column: usize, len: usize, max: usize },
+    #[error("Check on column {column} failed: {reason}")]
+    CheckViolation { column: usize, reason: String },
+    #[error("File {0} already exists")]
+    FileExists(String),
+    #[error("Invalid database name {0:?}")]
+    InvalidDatabaseName(String),
     #[error("Table {0} is already present")]
     TableIsAlreadyPresent(String),
     #[error("Table {0} is missing")]
     TableIsMissing(String),
+    #[error("Row {0} is out of range")]
+    RowIndexOutOfRange(usize),
+    #[error("Rows {0:?} are out of range")]
+    RowIndicesOutOfRange(Vec<usize>),
+    #[error("No row has id {0}")]
+    RowIdNotFound(u64),
+    #[error("Column {0} is out of range")]
+    ColumnOutOfRange(usize),
+    #[error("Sum of column {0} overflows its type")]
+    SumOverflow(usize),
+    #[error("Invalid expression: {0}")]
+    InvalidExpression(String),
+    #[error("Expression overflows for row {row}")]
+    ArithmeticOverflow { row: usize },
+    #[error("Table {0} is not a materialized projection")]
+    NotMaterialized(String),
     #[error("Invalid state for table {0}")]
     InvalidTableState(String),
+    #[error("File {path} of table {table} is missing")]
+    MissingTableFile { table: String, path: String },
+    #[error("Unsupported format version {found}, this build reads version {supported}")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error("Unknown file format {0}")]
+    UnknownFormat(u8),
+    #[error("{0} does not exist")]
+    FileNotFound(String),
+    #[error("{0} is a directory but not a database stored as one")]
+    IsADirectory(String),
+    #[error("Save path {0} is a directory, expected a file")]
+    SavePathIsADirectory(String),
+    #[error("{0} is empty")]
+    EmptyFile(String),
+    #[error("{0} is not a database file")]
+    NotADatabaseFile(String),
+    #[error("Corrupt data in {path}{}", offset.map(|offset| format!(" at byte {offset}")).unwrap_or_default())]
+    CorruptData { path: String, offset: Option<u64> },
+    #[error("Unknown file header flags {0:#04x}")]
+    UnknownFlags(u8),
+    #[error(
+        "Payload encodes integers {}-endian and {}, only little-endian fixed-width ones are supported",
+        if *big_endian { "big" } else { "little" },
+        if *varint { "variable-length" } else { "fixed-width" }
+    )]
+    ForeignEncoding { big_endian: bool, varint: bool },
+    #[error("Compression requires the zstd feature")]
+    CompressionUnsupported,
+    #[error("Checksum mismatch: header says {expected:#010x}, payload hashes to {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[error("Database {path} is locked{}", holder_pid.map(|pid| format!(" by process {pid}")).unwrap_or_default())]
+    DatabaseLocked { path: String, holder_pid: Option<u32> },
+    #[error("Database was opened read-only")]
+    ReadOnly,
+    #[error("Database is memory-mapped, materialize it to make changes")]
+    MemoryMapped,
+    #[error("Wrong passphrase")]
+    BadPassphrase,
+    #[error("Database is encrypted, a passphrase is required")]
+    PassphraseRequired,
+    #[error("Database is not encrypted")]
+    NotEncrypted,
+    #[error("Write-ahead log record of {0} bytes exceeds the limit of 4 GiB")]
+    WalRecordTooLarge(usize),
+    #[error("Write-ahead log record {index} does not apply to the saved database: {source}")]
+    WalReplayFailed { index: usize, source: Box<DbError> },
+    #[error("Payload decompresses to more than {max} bytes")]
+    PayloadTooLarge { max: u64 },
+    #[error("File holds {count} {what}, more than the limit of {max}")]
+    LimitExceeded { what: &'static str, count: usize, max: usize },
+    #[error("Corrupt payload: {0}")]
+    CorruptPayload(String),
+    #[error("SQL syntax error at byte {offset}: {message}")]
+    SqlSyntax { offset: usize, message: String },
+    #[error("Invalid JSON export: {0}")]
+    InvalidJsonExport(String),
+    #[error("Invalid value in table {table}, row {row}, column {column}: {reason}")]
+    JsonValueMismatch {
+        table: String,
+        row: usize,
+        column: usize,
+        reason: String,
+    },
+    #[error("Auto-increment sequence of column {column} has no values left")]
+    AutoIncrementExhausted { column: usize },
 }
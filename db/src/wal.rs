@@ -0,0 +1,155 @@
+use crate::encryption::{self, Encryption, Unlock};
+use crate::format;
+use crate::layout::with_suffix;
+use bincode::Options;
+use crate::{CheckConstraint, ColumnDefault, DbError, DbSnapshot, DbType, Row, Table};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A single logged mutation of a `SavedDatabase`. Records are tagged with the index of
+/// their variant, so new variants go at the end.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TxOp {
+    CreateTable { name: String, schema: Vec<DbType> },
+    InsertTable { table: Box<Table> },
+    RemoveTable { name: String },
+    InsertRow { table: String, row: Row },
+    UpdateRow { table: String, index: usize, row: Row },
+    RemoveRow { table: String, index: usize },
+    Projection { table: String, columns: Vec<bool>, new_table: String },
+    CreateMaterialized { source: String, columns: Vec<bool>, name: String },
+    RefreshMaterialized { name: String },
+    AddCheck { table: String, check: CheckConstraint },
+    SetAutoIncrement { table: String, column: usize },
+    RenameTable { old: String, new: String },
+    CopyTable { source: String, target: String, with_rows: bool },
+    /// A `Projection` whose values are coerced into `schema`. Separate from it so that
+    /// logs written before it existed still decode.
+    CoercedProjection { table: String, columns: Vec<bool>, new_table: String, schema: Vec<DbType> },
+    SetDefault { table: String, column: usize, default: Option<ColumnDefault> },
+    SetMaxBlobLen { table: String, max: usize },
+    /// `(table, columns, new_table)` projections applied together, or none of them.
+    ProjectMany { specs: Vec<(String, Vec<bool>, String)> },
+    /// `SavedDatabase::restore`, which subscribers see as `ChangeEvent::Restored` instead.
+    Restore { snapshot: Box<DbSnapshot> },
+}
+
+/// When appended records are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FsyncPolicy {
+    /// Sync after every record, so an acknowledged mutation survives power loss.
+    #[default]
+    Always,
+    /// Leave flushing to the OS, which survives a crash of the process but not of the
+    /// machine.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("unknown fsync policy {s:?}, expected always or never")),
+        }
+    }
+}
+
+/// Records found in a log, and where the first corrupt one starts if any.
+pub(crate) struct WalContent {
+    pub(crate) ops: Vec<TxOp>,
+    pub(crate) corrupt_at: Option<u64>,
+}
+
+/// Length and checksum in front of every record.
+const FRAME_LEN: usize = 8;
+
+pub(crate) fn wal_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".wal")
+}
+
+/// Every record is framed by its little-endian u32 length and the CRC32 of the
+/// bincode-serialized operation that follows, sealed with `encryption` for encrypted
+/// databases.
+pub(crate) fn append(path: &Path, op: &TxOp, sync: FsyncPolicy, encryption: Option<&Encryption>) -> Result<(), DbError> {
+    let mut payload = format::bincode_options().serialize(op)?;
+    if let Some(encryption) = encryption {
+        payload = encryption.seal(&payload);
+    }
+    let len = u32::try_from(payload.len()).map_err(|_| DbError::WalRecordTooLarge(payload.len()))?;
+    let mut record = Vec::with_capacity(FRAME_LEN + payload.len());
+    record.extend(len.to_le_bytes());
+    record.extend(crc32fast::hash(&payload).to_le_bytes());
+    record.extend(payload);
+
+    let mut file = OpenOptions::new().create(true).append(true).open(wal_path(path))?;
+    file.write_all(&record)?;
+    if sync == FsyncPolicy::Always {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+pub(crate) fn truncate(path: &Path) -> Result<(), DbError> {
+    File::create(wal_path(path))?;
+    Ok(())
+}
+
+/// Cuts the log off at `valid`, the offset of a torn or corrupt record, so that records
+/// appended later stay readable.
+pub(crate) fn repair(path: &Path, valid: u64) -> Result<(), DbError> {
+    OpenOptions::new().write(true).open(wal_path(path))?.set_len(valid)?;
+    Ok(())
+}
+
+/// Reads records up to the first torn or corrupt one, leaving the file as it is. Records
+/// of encrypted databases are opened with `encryption`, failing with `CorruptPayload` if
+/// one was sealed with another key or modified.
+pub(crate) fn read(path: &Path, encryption: Option<&Encryption>) -> Result<WalContent, DbError> {
+    let content = match std::fs::read(wal_path(path)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(WalContent { ops: Vec::new(), corrupt_at: None }),
+        Err(e) => return Err(e.into()),
+    };
+    let mut offset = 0;
+    let mut ops = Vec::new();
+    while offset < content.len() {
+        let Some(payload) = record_at(&content[offset..]) else {
+            break;
+        };
+        let opened = match encryption {
+            Some(encryption) => Some(
+                encryption::open(payload, Unlock::Key(encryption))
+                    .map_err(|error| match error {
+                        DbError::BadPassphrase => DbError::CorruptPayload("write-ahead log record is sealed with another key".to_string()),
+                        error => error,
+                    })?
+                    .0,
+            ),
+            None => None,
+        };
+        let plain = opened.as_deref().unwrap_or(payload);
+        let Ok(op) = format::bincode_options().with_limit(plain.len() as u64).deserialize(plain) else {
+            break;
+        };
+        ops.push(op);
+        offset += FRAME_LEN + payload.len();
+    }
+    let corrupt_at = (offset < content.len()).then_some(offset as u64);
+    Ok(WalContent { ops, corrupt_at })
+}
+
+/// The payload of the record at the start of `bytes`, if it is complete and matches its
+/// checksum.
+fn record_at(bytes: &[u8]) -> Option<&[u8]> {
+    let frame = bytes.get(..FRAME_LEN)?;
+    let len = u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(frame[4..].try_into().unwrap());
+    let payload = bytes.get(FRAME_LEN..FRAME_LEN + len)?;
+    (crc32fast::hash(payload) == checksum).then_some(payload)
+}
@@ -1,14 +1,7 @@
-use actix_web::http::StatusCode;
 use actix_web::web::Data;
 use actix_web::{web, HttpServer, App, Responder, HttpResponse};
-use futures::{future, prelude::*};
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv6Addr};
 use std::sync::Arc;
-use tarpc::{
-    server::{self, incoming::Incoming, Channel},
-    tokio_serde::formats::Json,
-};
 use tokio::sync::Mutex;
 
 use db::{DbType, SavedDatabase, Row};
@@ -20,6 +13,8 @@ struct CreateRequest {
 }
 async fn create(database: web::Data<Arc<Mutex<Option<SavedDatabase>>>>, request: web::Json<CreateRequest>) -> impl Responder {
     let mut lock = database.lock().await;
+    // Release the lock on the old database in case it is the same file.
+    lock.take();
     let new_db = SavedDatabase::create(request.name.clone(), request.path.clone()).unwrap();
     lock.replace(new_db);
     HttpResponse::Ok()
@@ -31,6 +26,7 @@ struct OpenRequest {
 }
 async fn open(database: web::Data<Arc<Mutex<Option<SavedDatabase>>>>, request: web::Json<OpenRequest>) -> impl Responder {
     let mut lock = database.lock().await;
+    lock.take();
     let new_db = SavedDatabase::load_from_disk(request.path.clone()).unwrap();
     lock.replace(new_db);
     HttpResponse::Ok()
@@ -93,9 +89,7 @@ struct RemoveRowRequest {
 async fn remove_row(database: web::Data<Arc<Mutex<Option<SavedDatabase>>>>, request: web::Json<RemoveRowRequest>) -> impl Responder {
     let mut lock = database.lock().await;
     if let Some(db) = lock.as_mut() {
-        if let Ok(table) = db.get_table_mut(request.table.clone()) {
-            table.remove_row(request.index.clone());
-        }
+        let _ = db.remove_row(request.table.clone(), request.index);
     }
     HttpResponse::Ok()
 }
@@ -108,9 +102,7 @@ struct InsertRowRequest {
 async fn insert_row(database: web::Data<Arc<Mutex<Option<SavedDatabase>>>>, request: web::Json<InsertRowRequest>) -> impl Responder {
     let mut lock = database.lock().await;
     if let Some(db) = lock.as_mut() {
-        if let Ok(table) = db.get_table_mut(request.table.clone()) {
-            let _ = table.insert_row(request.row.clone());
-        }
+        let _ = db.insert_row(request.table.clone(), request.row.clone());
     }
     HttpResponse::Ok()
 }
@@ -159,7 +151,7 @@ struct ProjectionRequest {
 async fn projection(database: web::Data<Arc<Mutex<Option<SavedDatabase>>>>, request: web::Json<ProjectionRequest>) -> impl Responder {
     let mut lock = database.lock().await;
     if let Some(db) = lock.as_mut() {
-        let _ = db.projection(request.table.clone(), request.rows.clone(), request.new_table.clone());
+        let _ = db.projection(request.table.clone(), request.rows.clone(), request.new_table.clone(), None);
     }
     HttpResponse::Ok()
 }